@@ -0,0 +1,138 @@
+//! EvtxRecordInfoの作成処理のベンチマーク。
+//! 既定ではtest_files/recovery/security.evtxを使う。HAYABUSA_BENCH_EVTXで別のevtxファイルもしくはディレクトリを指定できる。
+//! evtxファイルからレコードを読み込めない場合はサンプルのレコードを使う。使ったデータとレコード数は標準エラーに表示する。
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use evtx::{EvtxParser, ParserSettings};
+use hayabusa::detections::configs;
+use hayabusa::detections::utils;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const SAMPLE_RECORD: &str = r#"
+{
+    "Event": {
+        "System": {
+            "EventID": 4688,
+            "Channel": "Security",
+            "Computer": "DESKTOP-ICHIICHI",
+            "TimeCreated_attributes": {"SystemTime": "2021-12-12T10:30:00.000000Z"}
+        },
+        "EventData": {
+            "SubjectUserName": "hayabusa",
+            "NewProcessName": "C:\\Windows\\System32\\cmd.exe",
+            "CommandLine": "cmd.exe /c whoami /all",
+            "ParentProcessName": "C:\\Windows\\explorer.exe"
+        }
+    },
+    "Event_attributes": {"xmlns": "http://schemas.microsoft.com/win/2004/08/events/event"}
+}"#;
+
+fn collect_evtx_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    if dir.is_file() {
+        files.push(dir.to_path_buf());
+        return;
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_evtx_files(&path, files);
+        } else if path.extension().unwrap_or_default() == "evtx" {
+            files.push(path);
+        }
+    }
+}
+
+fn load_records() -> Vec<Value> {
+    let input = env::var("HAYABUSA_BENCH_EVTX")
+        .unwrap_or_else(|_| "test_files/recovery/security.evtx".to_string());
+    let mut files = vec![];
+    collect_evtx_files(Path::new(&input), &mut files);
+
+    let mut records = vec![];
+    for file in &files {
+        let parser = EvtxParser::from_path(file);
+        if parser.is_err() {
+            continue;
+        }
+        let settings = ParserSettings::default()
+            .separate_json_attributes(true)
+            .num_threads(0);
+        let mut parser = parser.unwrap().with_configuration(settings);
+        records.extend(
+            parser
+                .records_json_value()
+                .filter_map(|record| record.ok().map(|record| record.data)),
+        );
+    }
+    if records.is_empty() {
+        eprintln!("No records were read from {}. Using the sample record.", input);
+        let record: Value = serde_json::from_str(SAMPLE_RECORD).unwrap();
+        records = vec![record; 1000];
+    } else {
+        eprintln!(
+            "Using {} records from {} file(s) in {}.",
+            records.len(),
+            files.len(),
+            input
+        );
+    }
+    records
+}
+
+fn bench_create_rec_info(c: &mut Criterion) {
+    let records = load_records();
+    let alias_config = Arc::new(configs::load_eventkey_alias(
+        "test_files/config/eventkey_alias.txt",
+    ));
+    let keys: Vec<String> = ["EventID", "Channel", "CommandLine", "NewProcessName"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+
+    let mut group = c.benchmark_group("create_rec_info");
+    // 変更前はキーワード検索を行うルールの有無に関わらず、全てのレコードを文字列に変換していた
+    for with_data_string in [true, false] {
+        let name = if with_data_string {
+            "with_data_string"
+        } else {
+            "without_data_string"
+        };
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || records.clone(),
+                |records| {
+                    for record in records {
+                        black_box(utils::create_rec_info_with_alias(
+                            record,
+                            "bench.evtx".to_string(),
+                            &keys,
+                            &alias_config,
+                            with_data_string,
+                        ));
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+
+    // 変更前は--full-data指定時に全てのレコードのrecord_informationを作成していたが、検知したレコードのみ作成するようにした
+    c.bench_function("create_recordinfos", |b| {
+        b.iter(|| {
+            for record in &records {
+                black_box(utils::create_recordinfos(record, " | "));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, bench_create_rec_info);
+criterion_main!(benches);
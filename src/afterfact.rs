@@ -1,23 +1,37 @@
 use crate::detections::configs;
 use crate::detections::print;
 use crate::detections::print::AlertMessage;
+use crate::detections::print::DetectInfo;
+use crate::detections::sink::{DetectionEvent, DetectionSink};
 use crate::detections::utils;
+use crate::timeline::process_tree::{ProcessRef, ProcessTreeSink};
 use chrono::{DateTime, Local, TimeZone, Utc};
 use colored::*;
 use csv::QuoteStyle;
 use hashbrown::HashMap;
 use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::io::BufWriter;
 use std::io::Write;
+use std::path::Path;
 use std::process;
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct CsvFormat<'a> {
-    timestamp: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_seen: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<usize>,
     computer: &'a str,
     event_i_d: &'a str,
     level: &'a str,
@@ -33,7 +47,14 @@ pub struct CsvFormat<'a> {
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct DisplayFormat<'a> {
-    timestamp: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_seen: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<String>,
     pub computer: &'a str,
     pub event_i_d: &'a str,
     pub level: &'a str,
@@ -43,11 +64,201 @@ pub struct DisplayFormat<'a> {
     pub record_information: Option<&'a str>,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JsonFormat<'a> {
+    timestamp: &'a str,
+    computer: &'a str,
+    event_i_d: &'a str,
+    level: &'a str,
+    mitre_attack: &'a str,
+    rule_title: &'a str,
+    rule_i_d: &'a str,
+    details: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record_information: Option<&'a str>,
+    rule_path: &'a str,
+    file_path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record: Option<&'a Value>,
+}
+
+/// 検知するたびにCSVの1行を出力するsink。検知した順に出力するため、出力は時刻順にはならない
+pub struct CsvSink<W: Write + Send> {
+    wtr: Mutex<csv::Writer<W>>,
+}
+
+impl<W: Write + Send> CsvSink<W> {
+    pub fn new(writer: W) -> CsvSink<W> {
+        CsvSink {
+            wtr: Mutex::new(csv::WriterBuilder::new().from_writer(writer)),
+        }
+    }
+
+    /// 既存のCSVファイルに追記するために、ヘッダを出力しないsinkを作成する
+    pub fn append(writer: W) -> CsvSink<W> {
+        CsvSink {
+            wtr: Mutex::new(
+                csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(writer),
+            ),
+        }
+    }
+
+    pub fn into_inner(self) -> Result<W, String> {
+        self.wtr
+            .into_inner()
+            .unwrap()
+            .into_inner()
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl<W: Write + Send> DetectionSink for CsvSink<W> {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        let detect_info = event.detect_info;
+        let timestamp = format_time(&event.time);
+        self.wtr
+            .lock()
+            .unwrap()
+            .serialize(CsvFormat {
+                timestamp: Option::Some(&timestamp),
+                first_seen: Option::None,
+                last_seen: Option::None,
+                count: Option::None,
+                level: output_level(&detect_info.level),
+                computer: &detect_info.computername,
+                event_i_d: &detect_info.eventid,
+                mitre_attack: &detect_info.tag_info,
+                rule_title: &detect_info.alert,
+                details: &detect_info.detail,
+                record_information: detect_info.record_information.as_deref(),
+                file_path: &detect_info.filepath,
+                rule_path: &detect_info.rulepath,
+            })
+            .map_err(|err| err.to_string())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.wtr
+            .lock()
+            .unwrap()
+            .flush()
+            .map_err(|err| err.to_string())
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        self.flush()
+    }
+}
+
+/// 出力を保留している検知結果のJSONと、プロセスツリーを探すための情報
+type PendingJson = (Value, Option<ProcessRef>);
+
+/// 検知するたびにJSON Lines形式で1行出力するsink。aggregation condition以外の検知結果には元のレコードも出力する
+pub struct JsonSink<W: Write + Send> {
+    writer: Mutex<W>,
+    /// --process-treeの場合は、プロセスツリーを作成し終わるまで検知結果を保持する
+    pending: Option<Mutex<Vec<PendingJson>>>,
+    process_tree: Option<Arc<ProcessTreeSink>>,
+}
+
+impl<W: Write + Send> JsonSink<W> {
+    pub fn new(writer: W) -> JsonSink<W> {
+        JsonSink {
+            writer: Mutex::new(writer),
+            pending: Option::None,
+            process_tree: Option::None,
+        }
+    }
+
+    /// 検知結果にprocess_treeのプロセスツリー(ProcessTree)を追加する。
+    /// 全てのファイルのプロセスが揃ってから追加するため、検知結果はfinishでまとめて出力する。
+    /// process_treeはこのsinkより先にfinishを呼び出してプロセスツリーを作成しておく必要がある
+    pub fn with_process_tree(mut self, process_tree: Arc<ProcessTreeSink>) -> JsonSink<W> {
+        self.pending = Option::Some(Mutex::new(vec![]));
+        self.process_tree = Option::Some(process_tree);
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap()
+    }
+}
+
+impl<W: Write + Send> DetectionSink for JsonSink<W> {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        let detect_info = event.detect_info;
+        let timestamp = format_time(&event.time);
+        let json = JsonFormat {
+            timestamp: &timestamp,
+            computer: &detect_info.computername,
+            event_i_d: &detect_info.eventid,
+            level: output_level(&detect_info.level),
+            mitre_attack: &detect_info.tag_info,
+            rule_title: &detect_info.alert,
+            rule_i_d: event.rule.yaml["id"].as_str().unwrap_or(""),
+            details: &detect_info.detail,
+            record_information: detect_info.record_information.as_deref(),
+            rule_path: &detect_info.rulepath,
+            file_path: &detect_info.filepath,
+            record: event.record.map(|record_info| &record_info.record),
+        };
+        if let Some(pending) = &self.pending {
+            let value = serde_json::to_value(&json).map_err(|err| err.to_string())?;
+            let process = event
+                .record
+                .and_then(|record_info| ProcessRef::from_record(&record_info.record, event.time));
+            pending.lock().unwrap().push((value, process));
+            return Ok(());
+        }
+        let line = serde_json::to_string(&json).map_err(|err| err.to_string())?;
+        writeln!(self.writer.lock().unwrap(), "{}", line).map_err(|err| err.to_string())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.writer
+            .lock()
+            .unwrap()
+            .flush()
+            .map_err(|err| err.to_string())
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        if let (Some(pending), Some(process_tree)) = (&self.pending, &self.process_tree) {
+            let tree = process_tree.tree();
+            let mut writer = self.writer.lock().unwrap();
+            for (mut value, process) in std::mem::take(&mut *pending.lock().unwrap()) {
+                if let Some(i) = process.and_then(|process| tree.find(&process)) {
+                    value["ProcessTree"] = tree.to_json(i);
+                }
+                writeln!(writer, "{}", value).map_err(|err| err.to_string())?;
+            }
+        }
+        self.flush()
+    }
+}
+
+/// 出力時のlevelの表記。informationalはinfoと表記する
+pub fn output_level(level: &str) -> &str {
+    if level == "informational" {
+        "info"
+    } else {
+        level
+    }
+}
+
 /// level_color.txtファイルを読み込み対応する文字色のマッピングを返却する関数
 pub fn set_output_color() -> Option<HashMap<String, Vec<u8>>> {
     if !configs::CONFIG.read().unwrap().args.is_present("color") {
         return None;
     }
+    load_level_color()
+}
+
+/// --colorの指定に関わらずlevel_color.txtファイルを読み込む。TUIでは常に色を付けて表示するために使う
+pub fn load_level_color() -> Option<HashMap<String, Vec<u8>>> {
     let read_result = utils::read_csv("config/level_color.txt");
     if read_result.is_err() {
         // color情報がない場合は通常の白色の出力が出てくるのみで動作への影響を与えない為warnとして処理する
@@ -94,17 +305,16 @@ pub fn after_fact() {
     };
 
     let mut displayflag = false;
+    let mut appendflag = false;
     let mut target: Box<dyn io::Write> =
         if let Some(csv_path) = configs::CONFIG.read().unwrap().args.value_of("output") {
-            // ファイル出力する場合
-            match File::create(csv_path) {
-                Ok(file) => Box::new(BufWriter::new(file)),
+            match open_output(Path::new(csv_path)) {
+                Ok((file, append)) => {
+                    appendflag = append;
+                    Box::new(BufWriter::new(file))
+                }
                 Err(err) => {
-                    AlertMessage::alert(
-                        &mut BufWriter::new(std::io::stderr().lock()),
-                        &format!("Failed to open file. {}", err),
-                    )
-                    .ok();
+                    AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
                     process::exit(1);
                 }
             }
@@ -114,14 +324,86 @@ pub fn after_fact() {
             Box::new(BufWriter::new(io::stdout()))
         };
     let color_map = set_output_color();
-    if let Err(err) = emit_csv(&mut target, displayflag, color_map) {
+    let dedupflag = configs::CONFIG.read().unwrap().args.is_present("dedup");
+    if let Err(err) = emit_csv(&mut target, displayflag, dedupflag, appendflag, color_map) {
         fn_emit_csv_err(Box::new(err));
     }
 }
 
+/// 出力ファイルを開く。state-fileを指定した場合は前回の出力ファイルに追記するか、--rotate-outputの場合は前回の出力ファイルの名前を変更してから出力する。
+/// 前回の出力ファイルに追記する場合はtrueを返す
+pub fn open_output(path: &Path) -> Result<(File, bool), String> {
+    let args = &configs::CONFIG.read().unwrap().args;
+    let mut append = false;
+    if args.is_present("state-file") && path.exists() {
+        if args.is_present("rotate-output") {
+            rotate_output(path).map_err(|e| format!("Failed to rotate file. {}", e))?;
+        } else {
+            append = true;
+        }
+    }
+    let file = if append {
+        OpenOptions::new().append(true).open(path)
+    } else {
+        File::create(path)
+    };
+    file.map(|file| (file, append))
+        .map_err(|e| format!("Failed to open file. {} {}", path.display(), e))
+}
+
+/// 前回の出力ファイルの名前に現在時刻を付与して変更する。(例: results.csv -> results-20220501120000.csv)
+fn rotate_output(path: &Path) -> io::Result<()> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let timestamp = Local::now().format("%Y%m%d%H%M%S");
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, timestamp, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, timestamp),
+    };
+    fs::rename(path, path.with_file_name(file_name))
+}
+
+/// 出力対象の1行分の検知情報。dedupオプション指定時は同一の検知をまとめた件数と最初/最後の検知時刻を保持する
+struct DetectRow<'a> {
+    first_seen: &'a DateTime<Utc>,
+    last_seen: &'a DateTime<Utc>,
+    count: usize,
+    detect_info: &'a DetectInfo,
+}
+
+/// ルールのパス、コンピュータ名、detailが同一の検知を1行にまとめる関数。出力順は最初に検知された時刻順となる
+fn dedup_rows<'a>(messages: &'a BTreeMap<DateTime<Utc>, Vec<DetectInfo>>) -> Vec<DetectRow<'a>> {
+    let mut rows: Vec<DetectRow> = vec![];
+    let mut row_index: HashMap<(&str, &str, &str), usize> = HashMap::new();
+    for (time, detect_infos) in messages.iter() {
+        for detect_info in detect_infos {
+            let key = (
+                detect_info.rulepath.as_str(),
+                detect_info.computername.as_str(),
+                detect_info.detail.as_str(),
+            );
+            if let Some(idx) = row_index.get(&key) {
+                let row = &mut rows[*idx];
+                row.last_seen = time;
+                row.count += 1;
+            } else {
+                row_index.insert(key, rows.len());
+                rows.push(DetectRow {
+                    first_seen: time,
+                    last_seen: time,
+                    count: 1,
+                    detect_info,
+                });
+            }
+        }
+    }
+    rows
+}
+
 fn emit_csv<W: std::io::Write>(
     writer: &mut W,
     displayflag: bool,
+    dedupflag: bool,
+    appendflag: bool,
     color_map: Option<HashMap<String, Vec<u8>>>,
 ) -> io::Result<()> {
     let mut wtr = if displayflag {
@@ -131,7 +413,10 @@ fn emit_csv<W: std::io::Write>(
             .delimiter(b'|')
             .from_writer(writer)
     } else {
-        csv::WriterBuilder::new().from_writer(writer)
+        // 既存のファイルに追記する場合はヘッダを出力しない
+        csv::WriterBuilder::new()
+            .has_headers(!appendflag)
+            .from_writer(writer)
     };
 
     let messages = print::MESSAGES.lock().unwrap();
@@ -140,62 +425,87 @@ fn emit_csv<W: std::io::Write>(
     let mut unique_detect_counts_by_level: Vec<u128> = vec![0; 6];
     let mut detected_rule_files: Vec<String> = Vec::new();
 
-    for (time, detect_infos) in messages.iter() {
-        for detect_info in detect_infos {
-            let mut level = detect_info.level.to_string();
-            if level == "informational" {
-                level = "info".to_string();
-            }
-            if displayflag {
-                let colors = color_map
-                    .as_ref()
-                    .map(|cl_mp| _get_output_color(cl_mp, &detect_info.level));
-                let colors = colors.as_ref();
-
-                let recinfo = detect_info
-                    .record_information
-                    .as_ref()
-                    .map(|recinfo| _format_cell(recinfo, ColPos::Last, colors));
-                let details = detect_info
-                    .detail
-                    .chars()
-                    .filter(|&c| !c.is_control())
-                    .collect::<String>();
-
-                let dispformat = DisplayFormat {
-                    timestamp: &_format_cell(&format_time(time), ColPos::First, colors),
-                    level: &_format_cell(&level, ColPos::Other, colors),
-                    computer: &_format_cell(&detect_info.computername, ColPos::Other, colors),
-                    event_i_d: &_format_cell(&detect_info.eventid, ColPos::Other, colors),
-                    rule_title: &_format_cell(&detect_info.alert, ColPos::Other, colors),
-                    details: &_format_cell(&details, ColPos::Other, colors),
-                    record_information: recinfo.as_deref(),
-                };
-                wtr.serialize(dispformat)?;
-            } else {
-                // csv出力時フォーマット
-                wtr.serialize(CsvFormat {
-                    timestamp: &format_time(time),
-                    level: &level,
-                    computer: &detect_info.computername,
-                    event_i_d: &detect_info.eventid,
-                    mitre_attack: &detect_info.tag_info,
-                    rule_title: &detect_info.alert,
-                    details: &detect_info.detail,
-                    record_information: detect_info.record_information.as_deref(),
-                    file_path: &detect_info.filepath,
-                    rule_path: &detect_info.rulepath,
-                })?;
-            }
-            let level_suffix = *configs::LEVELMAP
-                .get(&detect_info.level.to_uppercase())
-                .unwrap_or(&0) as usize;
-            if !detected_rule_files.contains(&detect_info.rulepath) {
-                detected_rule_files.push(detect_info.rulepath.clone());
-                unique_detect_counts_by_level[level_suffix] += 1;
-            }
-            total_detect_counts_by_level[level_suffix] += 1;
+    let detect_rows: Vec<DetectRow> = if dedupflag {
+        dedup_rows(messages.iter())
+    } else {
+        messages
+            .iter()
+            .iter()
+            .flat_map(|(time, detect_infos)| {
+                detect_infos.iter().map(move |detect_info| DetectRow {
+                    first_seen: time,
+                    last_seen: time,
+                    count: 1,
+                    detect_info,
+                })
+            })
+            .collect()
+    };
+
+    for detect_row in detect_rows {
+        let detect_info = detect_row.detect_info;
+        let level = output_level(&detect_info.level).to_string();
+        let first_seen = format_time(detect_row.first_seen);
+        let last_seen = format_time(detect_row.last_seen);
+        if displayflag {
+            let colors = color_map
+                .as_ref()
+                .map(|cl_mp| _get_output_color(cl_mp, &detect_info.level));
+            let colors = colors.as_ref();
+
+            let recinfo = detect_info
+                .record_information
+                .as_ref()
+                .map(|recinfo| _format_cell(recinfo, ColPos::Last, colors));
+            let details = detect_info
+                .detail
+                .chars()
+                .filter(|&c| !c.is_control())
+                .collect::<String>();
+
+            let first_seen = _format_cell(&first_seen, ColPos::First, colors);
+            let last_seen = _format_cell(&last_seen, ColPos::Other, colors);
+            let dispformat = DisplayFormat {
+                timestamp: (!dedupflag).then_some(first_seen.as_str()),
+                first_seen: dedupflag.then_some(first_seen.as_str()),
+                last_seen: dedupflag.then_some(last_seen.as_str()),
+                count: dedupflag
+                    .then(|| _format_cell(&detect_row.count.to_string(), ColPos::Other, colors)),
+                level: &_format_cell(&level, ColPos::Other, colors),
+                computer: &_format_cell(&detect_info.computername, ColPos::Other, colors),
+                event_i_d: &_format_cell(&detect_info.eventid, ColPos::Other, colors),
+                rule_title: &_format_cell(&detect_info.alert, ColPos::Other, colors),
+                details: &_format_cell(&details, ColPos::Other, colors),
+                record_information: recinfo.as_deref(),
+            };
+            wtr.serialize(dispformat)?;
+        } else {
+            // csv出力時フォーマット
+            wtr.serialize(CsvFormat {
+                timestamp: (!dedupflag).then_some(first_seen.as_str()),
+                first_seen: dedupflag.then_some(first_seen.as_str()),
+                last_seen: dedupflag.then_some(last_seen.as_str()),
+                count: dedupflag.then_some(detect_row.count),
+                level: &level,
+                computer: &detect_info.computername,
+                event_i_d: &detect_info.eventid,
+                mitre_attack: &detect_info.tag_info,
+                rule_title: &detect_info.alert,
+                details: &detect_info.detail,
+                record_information: detect_info.record_information.as_deref(),
+                file_path: &detect_info.filepath,
+                rule_path: &detect_info.rulepath,
+            })?;
+        }
+        let level_suffix = *configs::LEVELMAP
+            .get(&detect_info.level.to_uppercase())
+            .unwrap_or(&0) as usize;
+        if !detected_rule_files.contains(&detect_info.rulepath) {
+            detected_rule_files.push(detect_info.rulepath.clone());
+            unique_detect_counts_by_level[level_suffix] += 1;
         }
+        // dedupでまとめた行も元の検知数で集計する
+        total_detect_counts_by_level[level_suffix] += detect_row.count as u128;
     }
     println!();
 
@@ -297,7 +607,7 @@ fn _get_output_color(color_map: &HashMap<String, Vec<u8>>, level: &str) -> Vec<u
     output_color
 }
 
-fn format_time(time: &DateTime<Utc>) -> String {
+pub fn format_time(time: &DateTime<Utc>) -> String {
     if configs::CONFIG.read().unwrap().args.is_present("utc") {
         format_rfc(time)
     } else {
@@ -321,17 +631,31 @@ where
 #[cfg(test)]
 mod tests {
     use crate::afterfact::emit_csv;
+    use crate::afterfact::rotate_output;
+    use crate::afterfact::{CsvSink, JsonSink};
+    use crate::detections::configs;
     use crate::detections::print;
     use crate::detections::print::DetectInfo;
+    use crate::detections::rule::create_rule;
+    use crate::detections::sink::{DetectionEvent, DetectionSink};
+    use crate::detections::utils;
+    use crate::timeline::process_tree::ProcessTreeSink;
     use chrono::{Local, TimeZone, Utc};
     use serde_json::Value;
     use std::fs::File;
-    use std::fs::{read_to_string, remove_file};
+    use std::fs::{read_dir, read_to_string, remove_file};
     use std::io;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use yaml_rust::YamlLoader;
+
+    /// MESSAGESを使うテストを並列に実行しないためのロック
+    static MESSAGES_TEST_LOCK: Mutex<()> = Mutex::new(());
 
     #[test]
     fn test_emit_csv() {
         //テストの並列処理によって読み込みの順序が担保できずstatic変数の内容が担保が取れない為、このテストはシーケンシャルで行う
+        let _lock = MESSAGES_TEST_LOCK.lock().unwrap();
         test_emit_csv_output();
         test_emit_csv_output();
     }
@@ -365,6 +689,7 @@ mod tests {
             "##;
             let event: Value = serde_json::from_str(val).unwrap();
             messages.insert(
+                &configs::EVENTKEY_ALIAS,
                 &event,
                 output.to_string(),
                 DetectInfo {
@@ -411,7 +736,7 @@ mod tests {
                 + testfilepath
                 + "\n";
         let mut file: Box<dyn io::Write> = Box::new(File::create("./test_emit_csv.csv").unwrap());
-        assert!(emit_csv(&mut file, false, None).is_ok());
+        assert!(emit_csv(&mut file, false, false, false, None).is_ok());
         match read_to_string("./test_emit_csv.csv") {
             Err(_) => panic!("Failed to open file."),
             Ok(s) => {
@@ -450,6 +775,7 @@ mod tests {
             "##;
             let event: Value = serde_json::from_str(val).unwrap();
             messages.insert(
+                &configs::EVENTKEY_ALIAS,
                 &event,
                 output.to_string(),
                 DetectInfo {
@@ -513,7 +839,7 @@ mod tests {
 
         let mut file: Box<dyn io::Write> =
             Box::new(File::create("./test_emit_csv_display.txt").unwrap());
-        assert!(emit_csv(&mut file, true, None).is_ok());
+        assert!(emit_csv(&mut file, true, false, false, None).is_ok());
         match read_to_string("./test_emit_csv_display.txt") {
             Err(_) => panic!("Failed to open file."),
             Ok(s) => {
@@ -523,6 +849,237 @@ mod tests {
         assert!(remove_file("./test_emit_csv_display.txt").is_ok());
     }
 
+    #[test]
+    fn test_emit_csv_dedup() {
+        let _lock = MESSAGES_TEST_LOCK.lock().unwrap();
+        let test_filepath = "test3.evtx";
+        let test_rulepath = "test-rule3.yml";
+        let test_computername = "testcomputer3";
+        let times = [
+            "1996-02-27T01:05:01Z",
+            "1996-02-27T01:06:01Z",
+            "1996-02-27T01:07:01Z",
+        ];
+        {
+            let mut messages = print::MESSAGES.lock().unwrap();
+            messages.clear();
+            for (i, time) in times.iter().enumerate() {
+                // 最後の1件だけdetailが異なるため別の行として出力される
+                let detail = if i < 2 {
+                    "logon failure"
+                } else {
+                    "logon success"
+                };
+                messages.insert_message(
+                    DetectInfo {
+                        filepath: test_filepath.to_string(),
+                        rulepath: test_rulepath.to_string(),
+                        level: "low".to_string(),
+                        computername: test_computername.to_string(),
+                        eventid: "4625".to_string(),
+                        alert: "test_title3".to_string(),
+                        detail: detail.to_string(),
+                        tag_info: String::default(),
+                        record_information: Option::None,
+                    },
+                    Utc.datetime_from_str(time, "%Y-%m-%dT%H:%M:%SZ").unwrap(),
+                );
+            }
+        }
+        let format_expect_time = |time: &str| {
+            Utc.datetime_from_str(time, "%Y-%m-%dT%H:%M:%SZ")
+                .unwrap()
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S%.3f %:z")
+                .to_string()
+        };
+        let expect = "FirstSeen,LastSeen,Count,Computer,EventID,Level,MitreAttack,RuleTitle,Details,RulePath,FilePath\n".to_string()
+            + &format!(
+                "{},{},2,testcomputer3,4625,low,,test_title3,logon failure,test-rule3.yml,test3.evtx\n",
+                format_expect_time(times[0]),
+                format_expect_time(times[1])
+            )
+            + &format!(
+                "{},{},1,testcomputer3,4625,low,,test_title3,logon success,test-rule3.yml,test3.evtx\n",
+                format_expect_time(times[2]),
+                format_expect_time(times[2])
+            );
+
+        let mut file: Box<dyn io::Write> =
+            Box::new(File::create("./test_emit_csv_dedup.csv").unwrap());
+        assert!(emit_csv(&mut file, false, true, false, None).is_ok());
+        match read_to_string("./test_emit_csv_dedup.csv") {
+            Err(_) => panic!("Failed to open file."),
+            Ok(s) => {
+                assert_eq!(s, expect);
+            }
+        };
+        assert!(remove_file("./test_emit_csv_dedup.csv").is_ok());
+    }
+
+    #[test]
+    fn test_rotate_output() {
+        let csv_path = Path::new("./test_rotate_output.csv");
+        File::create(csv_path).unwrap();
+        assert!(rotate_output(csv_path).is_ok());
+        assert!(!csv_path.exists());
+
+        let rotated: Vec<_> = read_dir(".")
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("test_rotate_output-") && name.ends_with(".csv"))
+            .collect();
+        assert_eq!(rotated.len(), 1);
+        assert!(remove_file(&rotated[0]).is_ok());
+    }
+
+    fn create_sink_test_detect_info() -> DetectInfo {
+        DetectInfo {
+            filepath: "test.evtx".to_string(),
+            rulepath: "test-rule.yml".to_string(),
+            level: "informational".to_string(),
+            computername: "testcomputer".to_string(),
+            eventid: "4624".to_string(),
+            alert: "test_title".to_string(),
+            detail: "logon success".to_string(),
+            tag_info: String::default(),
+            record_information: Option::None,
+        }
+    }
+
+    #[test]
+    fn test_csv_sink() {
+        let rule_yaml = YamlLoader::load_from_str("title: test_title")
+            .unwrap()
+            .remove(0);
+        let rule = create_rule("test-rule.yml".to_string(), rule_yaml);
+        let detect_info = create_sink_test_detect_info();
+        let time = Utc.ymd(1996, 2, 27).and_hms(1, 5, 1);
+        let sink = CsvSink::new(vec![]);
+        let event = DetectionEvent {
+            time,
+            detect_info: &detect_info,
+            record: Option::None,
+            rule: &rule,
+        };
+        assert!(sink.on_detect(&event).is_ok());
+        assert!(sink.finish().is_ok());
+
+        let expect_time = time
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S%.3f %:z")
+            .to_string();
+        let expect =
+            "Timestamp,Computer,EventID,Level,MitreAttack,RuleTitle,Details,RulePath,FilePath\n"
+                .to_string()
+                + &format!(
+                    "{},testcomputer,4624,info,,test_title,logon success,test-rule.yml,test.evtx\n",
+                    expect_time
+                );
+        let output = String::from_utf8(sink.into_inner().unwrap()).unwrap();
+        assert_eq!(output, expect);
+
+        // 追記する場合はヘッダを出力しない
+        let sink = CsvSink::append(vec![]);
+        assert!(sink.on_detect(&event).is_ok());
+        assert!(sink.flush().is_ok());
+        let output = String::from_utf8(sink.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            format!(
+                "{},testcomputer,4624,info,,test_title,logon success,test-rule.yml,test.evtx\n",
+                expect_time
+            )
+        );
+    }
+
+    #[test]
+    fn test_json_sink() {
+        let rule_yaml = YamlLoader::load_from_str(
+            "title: test_title\nid: 1d0e7d7c-4c2a-4d53-9b5b-3c5e2a0bd9f1",
+        )
+        .unwrap()
+        .remove(0);
+        let rule = create_rule("test-rule.yml".to_string(), rule_yaml);
+        let detect_info = create_sink_test_detect_info();
+        let record: Value =
+            serde_json::from_str(r#"{"Event": {"System": {"EventID": 4624}}}"#).unwrap();
+        let record_info = utils::create_rec_info(record, "test.evtx".to_string(), &[]);
+        let sink = JsonSink::new(vec![]);
+        for record in [Option::Some(&record_info), Option::None] {
+            let event = DetectionEvent {
+                time: Utc.ymd(1996, 2, 27).and_hms(1, 5, 1),
+                detect_info: &detect_info,
+                record,
+                rule: &rule,
+            };
+            assert!(sink.on_detect(&event).is_ok());
+        }
+        assert!(sink.finish().is_ok());
+
+        let output = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["RuleID"], "1d0e7d7c-4c2a-4d53-9b5b-3c5e2a0bd9f1");
+        assert_eq!(lines[0]["EventID"], "4624");
+        assert_eq!(lines[0]["Level"], "info");
+        assert_eq!(lines[0]["Record"]["Event"]["System"]["EventID"], 4624);
+        assert!(lines[1].get("Record").is_none());
+    }
+
+    #[test]
+    fn test_json_sink_process_tree() {
+        let record = serde_json::json!({
+            "Event": {
+                "System": {
+                    "EventID": 1,
+                    "Channel": "Microsoft-Windows-Sysmon/Operational",
+                    "Computer": "testcomputer",
+                    "TimeCreated_attributes": {"SystemTime": "1996-02-27T01:05:01Z"}
+                },
+                "EventData": {"ProcessGuid": "{1}", "ProcessId": 10, "Image": "cmd.exe"}
+            }
+        });
+        let rule_yaml = YamlLoader::load_from_str("title: test_title")
+            .unwrap()
+            .remove(0);
+        let rule = create_rule("test-rule.yml".to_string(), rule_yaml);
+        let detect_info = create_sink_test_detect_info();
+        let process_tree = Arc::new(ProcessTreeSink::new());
+        let sink = JsonSink::new(vec![]).with_process_tree(Arc::clone(&process_tree));
+        let record_info = utils::create_rec_info(record.clone(), "test.evtx".to_string(), &[]);
+        let event = DetectionEvent {
+            time: Utc.ymd(1996, 2, 27).and_hms(1, 5, 1),
+            detect_info: &detect_info,
+            record: Option::Some(&record_info),
+            rule: &rule,
+        };
+        assert!(sink.on_detect(&event).is_ok());
+        assert!(sink.flush().is_ok());
+        let records = vec![utils::create_rec_info(
+            record.clone(),
+            "test.evtx".to_string(),
+            &[],
+        )];
+        assert!(process_tree.on_records(&records).is_ok());
+        assert!(process_tree.finish().is_ok());
+        assert!(sink.finish().is_ok());
+
+        // プロセスツリーを作成した後のfinishでまとめて出力する
+        let output = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["ProcessTree"]["Process"]["Image"], "cmd.exe");
+        assert_eq!(lines[0]["ProcessTree"]["Ancestors"], serde_json::json!([]));
+    }
+
     fn get_white_color_string(target: &str) -> String {
         let white_color_header = "\u{1b}[38;2;255;255;255m";
         let white_color_footer = "\u{1b}[0m";
@@ -0,0 +1,293 @@
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// 対応しているアーカイブ(.zip、.tar.gz、.tgz)のパスかを返す
+pub fn is_archive(path: &Path) -> bool {
+    get_archive_type(path).is_some()
+}
+
+enum ArchiveType {
+    Zip,
+    TarGz,
+}
+
+fn get_archive_type(path: &Path) -> Option<ArchiveType> {
+    let file_name = path.file_name()?.to_str()?.to_lowercase();
+    if file_name.ends_with(".zip") {
+        Some(ArchiveType::Zip)
+    } else if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+        Some(ArchiveType::TarGz)
+    } else {
+        None
+    }
+}
+
+/// アーカイブのメンバーが隠しファイルでない.evtxファイルかを返す
+fn is_target_member(member: &str) -> bool {
+    let file_name = member.rsplit('/').next().unwrap_or("");
+    file_name.ends_with(".evtx") && !file_name.trim().starts_with('.')
+}
+
+/// アーカイブ内の.evtxファイルを列挙し、アーカイブのパスにメンバーのパスを連結したパスを返す。
+/// このパスが検知結果のFilePathとして出力される。
+pub fn collect_evtx_members(archive_path: &Path) -> Result<Vec<PathBuf>, String> {
+    let members = match get_archive_type(archive_path) {
+        Some(ArchiveType::Zip) => list_zip_members(archive_path),
+        Some(ArchiveType::TarGz) => list_tar_gz_members(archive_path),
+        None => {
+            return Err(format!(
+                "Unsupported archive format. [file:{}]",
+                archive_path.display()
+            ))
+        }
+    }
+    .map_err(|e| {
+        format!(
+            "Failed to read archive. [file:{}] {}",
+            archive_path.display(),
+            e
+        )
+    })?;
+
+    Ok(members
+        .into_iter()
+        .filter(|member| is_target_member(member))
+        .map(|member| archive_path.join(member))
+        .collect())
+}
+
+fn list_zip_members(archive_path: &Path) -> Result<Vec<String>, String> {
+    let file = File::open(archive_path).map_err(|e| e.to_string())?;
+    let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+    let mut ret = vec![];
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i).map_err(|e| e.to_string())?;
+        if entry.is_file() {
+            ret.push(member_name(Path::new(entry.name())));
+        }
+    }
+    Ok(ret)
+}
+
+fn list_tar_gz_members(archive_path: &Path) -> Result<Vec<String>, String> {
+    let file = File::open(archive_path).map_err(|e| e.to_string())?;
+    let mut tar = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+    let mut ret = vec![];
+    for entry in tar.entries().map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path().map_err(|e| e.to_string())?;
+        ret.push(member_name(&path));
+    }
+    Ok(ret)
+}
+
+/// アーカイブ内のパスを「/」区切りのメンバー名に変換する。「./」や「..」は取り除く。
+/// メンバーの列挙と読み込みで同じ名前になるように、アーカイブ内のパスは必ずこの関数で変換する
+fn member_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// collect_evtx_membersで作成したパスをアーカイブのパスとメンバー名に分割する。
+/// ディスク上に存在するファイルやアーカイブ内のパスでない場合はNoneを返す。
+pub fn split_member_path(path: &Path) -> Option<(PathBuf, String)> {
+    if path.exists() {
+        return None;
+    }
+    path.ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.is_file() && is_archive(ancestor))
+        .map(|archive_path| {
+            let member = member_name(path.strip_prefix(archive_path).unwrap());
+            (archive_path.to_path_buf(), member)
+        })
+}
+
+/// tar.gzのようにランダムアクセスできず、メンバーごとにread_memberを呼び出すとアーカイブを先頭から展開し直すアーカイブかを返す。
+/// このようなアーカイブはfor_each_memberで1回だけ展開しながら解析する
+pub fn is_sequential(archive_path: &Path) -> bool {
+    matches!(get_archive_type(archive_path), Some(ArchiveType::TarGz))
+}
+
+/// アーカイブ内のメンバーをメモリ上に展開して返す。
+pub fn read_member(archive_path: &Path, member: &str) -> Result<Vec<u8>, String> {
+    let mut ret = Err(format!("{} is not found in the archive.", member));
+    for_each_member(archive_path, &[member.to_string()], |_, data| ret = data)?;
+    ret
+}
+
+/// アーカイブを1回だけ展開し、membersに含まれるメンバーを読み込むたびにメンバー名と展開した内容をfに渡す。
+/// アーカイブ内に見つからなかったメンバーは最後にエラーとしてfに渡す
+pub fn for_each_member<F: FnMut(&str, Result<Vec<u8>, String>)>(
+    archive_path: &Path,
+    members: &[String],
+    mut f: F,
+) -> Result<(), String> {
+    let mut remains: Vec<&str> = members.iter().map(|member| member.as_str()).collect();
+    match get_archive_type(archive_path) {
+        Some(ArchiveType::Zip) => {
+            let file = File::open(archive_path).map_err(|e| e.to_string())?;
+            let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i).map_err(|e| e.to_string())?;
+                let name = member_name(Path::new(entry.name()));
+                if let Some(pos) = remains.iter().position(|member| *member == name) {
+                    let member = remains.swap_remove(pos);
+                    let mut buf = vec![];
+                    let data = entry.read_to_end(&mut buf).map(|_| buf);
+                    f(member, data.map_err(|e| e.to_string()));
+                }
+            }
+        }
+        Some(ArchiveType::TarGz) => {
+            // tarはランダムアクセスできないため、先頭から順番に対象のメンバーを探す
+            let file = File::open(archive_path).map_err(|e| e.to_string())?;
+            let mut tar = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+            for entry in tar.entries().map_err(|e| e.to_string())? {
+                if remains.is_empty() {
+                    break;
+                }
+                let mut entry = entry.map_err(|e| e.to_string())?;
+                let name = member_name(&entry.path().map_err(|e| e.to_string())?);
+                if let Some(pos) = remains.iter().position(|member| *member == name) {
+                    let member = remains.swap_remove(pos);
+                    let mut buf = vec![];
+                    let data = entry.read_to_end(&mut buf).map(|_| buf);
+                    f(member, data.map_err(|e| e.to_string()));
+                }
+            }
+        }
+        None => {
+            return Err(format!(
+                "Unsupported archive format. [file:{}]",
+                archive_path.display()
+            ))
+        }
+    }
+    for member in remains {
+        f(
+            member,
+            Err(format!("{} is not found in the archive.", member)),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::{
+        collect_evtx_members, for_each_member, is_archive, is_sequential, read_member,
+        split_member_path,
+    };
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs::{remove_file, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    const MEMBERS: [(&str, &[u8]); 4] = [
+        ("Logs/Security.evtx", b"security"),
+        ("Logs/.hidden.evtx", b"hidden"),
+        ("./Logs/System.evtx", b"system"),
+        ("readme.txt", b"readme"),
+    ];
+
+    fn assert_archive(archive_path: &str) {
+        let members = collect_evtx_members(Path::new(archive_path)).unwrap();
+        let expect = Path::new(archive_path).join("Logs/Security.evtx");
+        // 「./」を含むメンバー名は列挙と読み込みで同じ名前に変換される
+        let system = Path::new(archive_path).join("Logs/System.evtx");
+        assert_eq!(members, vec![expect.clone(), system.clone()]);
+
+        let (archive, member) = split_member_path(&expect).unwrap();
+        assert_eq!(archive, PathBuf::from(archive_path));
+        assert_eq!(member, "Logs/Security.evtx");
+        assert_eq!(read_member(&archive, &member).unwrap(), b"security");
+        let (_, member) = split_member_path(&system).unwrap();
+        assert_eq!(read_member(&archive, &member).unwrap(), b"system");
+        assert!(read_member(&archive, "Logs/Application.evtx").is_err());
+
+        // アーカイブ内の順番でメンバーを渡し、見つからなかったメンバーは最後にエラーとして渡す
+        let mut results = vec![];
+        let members = [
+            "Logs/System.evtx".to_string(),
+            "Logs/Application.evtx".to_string(),
+            "Logs/Security.evtx".to_string(),
+        ];
+        for_each_member(&archive, &members, |member, data| {
+            results.push((member.to_string(), data.ok()));
+        })
+        .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                ("Logs/Security.evtx".to_string(), Some(b"security".to_vec())),
+                ("Logs/System.evtx".to_string(), Some(b"system".to_vec())),
+                ("Logs/Application.evtx".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_archive() {
+        assert!(is_archive(Path::new("triage.zip")));
+        assert!(is_archive(Path::new("triage.ZIP")));
+        assert!(is_archive(Path::new("triage.tar.gz")));
+        assert!(is_archive(Path::new("triage.tgz")));
+        assert!(!is_archive(Path::new("Security.evtx")));
+        assert!(!is_archive(Path::new("triage.tar")));
+        assert!(is_sequential(Path::new("triage.tgz")));
+        assert!(!is_sequential(Path::new("triage.zip")));
+    }
+
+    #[test]
+    fn test_zip_archive() {
+        let archive_path = "./test_archive.zip";
+        {
+            let mut zip = zip::ZipWriter::new(File::create(archive_path).unwrap());
+            for (name, data) in MEMBERS {
+                zip.start_file(name, zip::write::FileOptions::default())
+                    .unwrap();
+                zip.write_all(data).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+        assert_archive(archive_path);
+        assert!(remove_file(archive_path).is_ok());
+    }
+
+    #[test]
+    fn test_tar_gz_archive() {
+        let archive_path = "./test_archive.tar.gz";
+        {
+            let encoder = GzEncoder::new(File::create(archive_path).unwrap(), Compression::fast());
+            let mut tar = tar::Builder::new(encoder);
+            for (name, data) in MEMBERS {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                tar.append_data(&mut header, name, data).unwrap();
+            }
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+        assert_archive(archive_path);
+        assert!(remove_file(archive_path).is_ok());
+    }
+
+    #[test]
+    fn test_split_member_path_not_archive() {
+        assert!(split_member_path(Path::new("test_files/evtx/test1.evtx")).is_none());
+        assert!(split_member_path(Path::new("test_files/evtx/notfound.evtx")).is_none());
+    }
+}
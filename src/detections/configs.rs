@@ -1,5 +1,4 @@
 use crate::detections::pivot::PivotKeyword;
-use crate::detections::pivot::PIVOT_KEYWORD;
 use crate::detections::print::AlertMessage;
 use crate::detections::utils;
 use chrono::{DateTime, Utc};
@@ -9,9 +8,10 @@ use hashbrown::HashSet;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::io::BufWriter;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 lazy_static! {
-    pub static ref CONFIG: RwLock<ConfigReader> = RwLock::new(ConfigReader::new());
+    // コマンドライン引数はhayabusaのバイナリがmain関数で読み込む。ライブラリとして使う場合は引数を持たないデフォルトの設定となる
+    pub static ref CONFIG: RwLock<ConfigReader> = RwLock::new(ConfigReader::default());
     pub static ref LEVELMAP: HashMap<String, u128> = {
         let mut levelmap = HashMap::new();
         levelmap.insert("INFORMATIONAL".to_owned(), 1);
@@ -21,9 +21,8 @@ lazy_static! {
         levelmap.insert("CRITICAL".to_owned(), 5);
         levelmap
     };
-    pub static ref EVENTKEY_ALIAS: EventKeyAliasConfig = load_eventkey_alias(&format!(
-        "{}/eventkey_alias.txt",
-        CONFIG.read().unwrap().folder_path
+    pub static ref EVENTKEY_ALIAS: Arc<EventKeyAliasConfig> = Arc::new(load_eventkey_alias(
+        &format!("{}/eventkey_alias.txt", CONFIG.read().unwrap().folder_path)
     ));
     pub static ref IDS_REGEX: Regex =
         Regex::new(r"^[0-9a-z]{8}-[0-9a-z]{4}-[0-9a-z]{4}-[0-9a-z]{4}-[0-9a-z]{12}$").unwrap();
@@ -38,12 +37,19 @@ pub struct ConfigReader {
 }
 
 impl Default for ConfigReader {
+    /// コマンドライン引数を持たない設定。ライブラリとして使う場合の設定となる
     fn default() -> Self {
-        Self::new()
+        ConfigReader {
+            args: ArgMatches::default(),
+            folder_path: "rules/config".to_string(),
+            event_timeline_config: EventInfoConfig::new(),
+            target_eventids: TargetEventIds::new(),
+        }
     }
 }
 
 impl ConfigReader {
+    /// コマンドライン引数を読み込む。プロセスの引数をパースするため、hayabusaのバイナリからのみ呼び出す。
     pub fn new() -> Self {
         let arg = build_app();
         let folder_path_str = arg.value_of("config").unwrap_or("rules/config").to_string();
@@ -66,22 +72,45 @@ fn build_app<'a>() -> ArgMatches<'a> {
         })
         .unwrap();
 
-    if is_test_mode() {
-        return ArgMatches::default();
-    }
-
-    let usages = "-d --directory=[DIRECTORY] 'Directory of multiple .evtx files.'
-    -f --filepath=[FILEPATH] 'File path to one .evtx file.'
+    let usages = "-d --directory=[DIRECTORY] 'Directory of multiple .evtx files. .zip and .tar.gz archives in the directory are also scanned.'
+    -f --filepath=[FILEPATH] 'File path to one .evtx file or a .zip/.tar.gz archive containing .evtx files.'
     -F --full-data 'Print all field information.'
+    --recover-records 'Recover records from damaged .evtx files and raw disk/memory images by scanning for chunk and record signatures.'
     -r --rules=[RULEDIRECTORY/RULEFILE] 'Rule file or directory (default: ./rules)'
     -c --color 'Output with color. (Terminal needs to support True Color.)'
     -C --config=[RULECONFIGDIRECTORY] 'Rule config folder. (Default: ./rules/config)'
     -o --output=[CSV_TIMELINE] 'Save the timeline in CSV format. (Example: results.csv)'
+    --json-output=[JSONL_TIMELINE] 'Save the timeline in JSON Lines format with the original records. It can be opened later with --tui-file. (Example: results.jsonl)'
+    --tui 'Browse the detections in an interactive terminal UI after the scan.'
+    --tui-file=[JSONL_TIMELINE] 'Browse the detections of a timeline saved with --json-output in the terminal UI.'
+    --state-file=[STATE_FILE] 'Save the scanned records of each .evtx file and only scan new files and records on later runs. The results are appended to the --output file. (Example: state.json)'
+    --rotate-output 'With --state-file, rename the previous --output and --json-output files with a timestamp instead of appending to them.'
+    --watch=[DIRECTORY] 'Keep the rules loaded and continuously scan new and modified .evtx files in the directory. Detections are output immediately. Press Ctrl+C to stop.'
+    --watch-interval=[SECONDS] 'Interval to check the --watch directory for changes. (Default: 10)'
+    --serve=[ADDRESS] 'Keep the rules loaded and serve a REST API to scan uploaded or local .evtx files. (Example: 127.0.0.1:8080)'
+    --serve-token=[TOKEN] 'Require the token in the Authorization header (Bearer TOKEN) of every --serve request.'
+    --serve-root=[DIRECTORY] 'Only allow --serve jobs to scan local paths under the directory.'
+    --notify 'Send a scan summary and detections to Slack. WEBHOOK_URL and CHANNEL are read from the .env file.'
+    --email 'Send a digest of the scan results by email after the scan. The SMTP settings are read from the .env file.'
+    --webhook=[URL] 'Send detections as JSON to the webhook URL.'
+    --webhook-template=[FILE] 'JSON template of the --webhook request body. Fields are written as %RuleTitle%, %Level%, etc...'
+    --syslog=[HOST:PORT] 'Send detections to the syslog server in RFC 5424 format.'
+    --syslog-protocol=[PROTOCOL] 'Protocol of --syslog: udp or tcp. (Default: udp)'
+    --syslog-format=[FORMAT] 'Message format of --syslog: rfc5424, cef or leef. (Default: rfc5424)'
+    --dead-letter=[FILE] 'Save the alerts that could not be sent with --webhook or --syslog after retrying. (Default: ./logs/dead-letter.jsonl)'
+    --notify-level=[LEVEL] 'Minimum level of detections to send with --notify, --webhook and --syslog. (Default: high)'
+    --graph=[FILE] 'Save the users, computers, IP addresses and processes in the detections and their relationships as GraphML, or as JSON when the file name ends with .json.'
+    --graph-min-level=[LEVEL] 'Minimum level of detections to add to --graph. (Default: informational)'
+    --graph-start=[TIME] 'Start time of detections to add to --graph. (Example: '2018/11/28 12:00:00 +09:00')'
+    --graph-end=[TIME] 'End time of detections to add to --graph. (Example: '2018/11/28 12:00:00 +09:00')'
+    --dedup 'Collapse detections with the same rule, computer and details into one row with FirstSeen, LastSeen and Count columns.'
     -v --verbose 'Output verbose information.'
     -D --enable-deprecated-rules 'Enable rules marked as deprecated.'
     -n --enable-noisy-rules 'Enable rules marked as noisy.'
     -u --update-rules 'Update to the latest rules in the hayabusa-rules github repository.'
     -m --min-level=[LEVEL] 'Minimum level for rules. (Default: informational)'
+    --ioc-file=[FILE] 'Match the hashes, IP addresses, domains, file paths and named pipes in a CSV or JSON IOC file against every record and output the matches as detections.'
+    --geoip=[MMDB] 'Add the country and ASN of public IP addresses as IpAddress_country, IpAddress_asn and IpAddress_asn_org fields from a local MaxMind .mmdb file or a directory of .mmdb files.'
     -l --live-analysis 'Analyze the local C:\\Windows\\System32\\winevt\\Logs folder (Windows Only. Administrator privileges required.)'
     --start-timeline=[STARTTIMELINE] 'Start time of the event logs to load. (Example: '2018/11/28 12:00:00 +09:00')'
     --end-timeline=[ENDTIMELINE] 'End time of the event logs to load. (Example: '2018/11/28 12:00:00 +09:00')'
@@ -90,9 +119,19 @@ fn build_app<'a>() -> ArgMatches<'a> {
     -U --utc 'Output time in UTC format. (Default: local time)'
     -t --thread-number=[NUMBER] 'Thread number. (Default: Optimal number for performance.)'
     -s --statistics 'Prints statistics of event IDs.'
+    -S --aggregate-statistics 'Prints statistics of event IDs by channel and provider aggregated across all files. (Saved as CSV or JSON with --output.)'
+    --histogram=[INTERVAL] 'Prints event counts per computer and channel in time intervals (hour or day) with log gaps and log cleared events. (Default: hour)'
+    --gap-threshold=[HOURS] 'Hours without events to report as a gap in the histogram. (Default: 24)'
+    --coverage 'Prints which rules cannot detect anything because the channels and event IDs they reference are not in the scanned files, and the audit settings to enable.'
+    --process-tree 'Reconstruct process trees from Sysmon 1 and Security 4688 events and print the ancestors and children of the detected processes. (Also added to --json-output.)'
+    --sessions 'Group the events and detections into logon sessions by computer and logon ID. (Saved as CSV or JSON with --output.)'
+    --session=[ID] 'Print the details and events of one logon session. (Example: DC01:0x3e7 or 0x3e7)'
+    -L --logon-summary 'Prints a summary of successful and failed logons by user, logon type and source.'
     -q --quiet 'Quiet mode. Do not display the launch banner.'
     -Q --quiet-errors 'Quiet errors mode. Do not save error logs.'
     -p --pivot-keywords-list 'Create a list of pivot keywords.'
+    --pivot-min-level=[LEVEL] 'Minimum rule level to collect pivot keywords from. (Default: low)'
+    --pivot-format=[FORMAT] 'Output format of the pivot keywords saved with --output: csv or json. (Default: csv)'
     --contributors 'Prints the list of contributors.'";
     App::new(&program)
         .about("Hayabusa: Aiming to be the world's greatest Windows event log analysis tool!")
@@ -109,16 +148,6 @@ fn build_app<'a>() -> ArgMatches<'a> {
         .get_matches()
 }
 
-fn is_test_mode() -> bool {
-    for i in std::env::args() {
-        if i == "--test" {
-            return true;
-        }
-    }
-
-    false
-}
-
 #[derive(Debug, Clone)]
 pub struct TargetEventIds {
     ids: HashSet<String>,
@@ -146,7 +175,7 @@ impl TargetEventIds {
     }
 }
 
-fn load_target_ids(path: &str) -> TargetEventIds {
+pub fn load_target_ids(path: &str) -> TargetEventIds {
     let mut ret = TargetEventIds::new();
     let lines = utils::read_txt(path); // ファイルが存在しなければエラーとする
     if lines.is_err() {
@@ -180,32 +209,35 @@ impl Default for TargetEventTime {
     }
 }
 
+/// --start-timeline等で指定した時刻を読み込む
+pub fn parse_target_time(time: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    DateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S %z") // 2014-11-28 21:00:09 +09:00
+        .or_else(|_| DateTime::parse_from_str(time, "%Y/%m/%d %H:%M:%S %z")) // 2014/11/28 21:00:09 +09:00
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
 impl TargetEventTime {
     pub fn new() -> Self {
         let start_time =
             if let Some(s_time) = CONFIG.read().unwrap().args.value_of("start-timeline") {
-                match DateTime::parse_from_str(s_time, "%Y-%m-%d %H:%M:%S %z") // 2014-11-28 21:00:09 +09:00
-                .or_else(|_| DateTime::parse_from_str(s_time, "%Y/%m/%d %H:%M:%S %z")) // 2014/11/28 21:00:09 +09:00
-            {
-                Ok(dt) => Some(dt.with_timezone(&Utc)),
-                Err(err) => {
-                    AlertMessage::alert(
-                        &mut BufWriter::new(std::io::stderr().lock()),
-                        &format!("start-timeline field: {}", err),
-                    )
-                    .ok();
-                    None
+                match parse_target_time(s_time) {
+                    Ok(dt) => Some(dt),
+                    Err(err) => {
+                        AlertMessage::alert(
+                            &mut BufWriter::new(std::io::stderr().lock()),
+                            &format!("start-timeline field: {}", err),
+                        )
+                        .ok();
+                        None
+                    }
                 }
-            }
             } else {
                 None
             };
         let end_time = if let Some(e_time) = CONFIG.read().unwrap().args.value_of("end-timeline") {
-            match DateTime::parse_from_str(e_time, "%Y-%m-%d %H:%M:%S %z") // 2014-11-28 21:00:09 +09:00
-            .or_else(|_| DateTime::parse_from_str(e_time, "%Y/%m/%d %H:%M:%S %z")) // 2014/11/28 21:00:09 +09:00
-        {
-            Ok(dt) => Some(dt.with_timezone(&Utc)),
-            Err(err) => {
+            match parse_target_time(e_time) {
+                Ok(dt) => Some(dt),
+                Err(err) => {
                     AlertMessage::alert(
                         &mut BufWriter::new(std::io::stderr().lock()),
                         &format!("end-timeline field: {}", err),
@@ -277,21 +309,18 @@ impl Default for EventKeyAliasConfig {
     }
 }
 
-fn load_eventkey_alias(path: &str) -> EventKeyAliasConfig {
-    let mut config = EventKeyAliasConfig::new();
-
+pub fn load_eventkey_alias(path: &str) -> EventKeyAliasConfig {
     // eventkey_aliasが読み込めなかったらエラーで終了とする。
-    let read_result = utils::read_csv(path);
-    if read_result.is_err() {
-        AlertMessage::alert(
-            &mut BufWriter::new(std::io::stderr().lock()),
-            read_result.as_ref().unwrap_err(),
-        )
-        .ok();
-        return config;
-    }
+    read_eventkey_alias(path).unwrap_or_else(|err| {
+        AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+        EventKeyAliasConfig::new()
+    })
+}
 
-    read_result.unwrap().into_iter().for_each(|line| {
+/// eventkey_alias.txtを読み込む。ファイルが読み込めなかった場合はエラーメッセージを返す
+pub fn read_eventkey_alias(path: &str) -> Result<EventKeyAliasConfig, String> {
+    let mut config = EventKeyAliasConfig::new();
+    utils::read_csv(path)?.into_iter().for_each(|line| {
         if line.len() != 2 {
             return;
         }
@@ -312,41 +341,26 @@ fn load_eventkey_alias(path: &str) -> EventKeyAliasConfig {
             .insert(alias.to_owned(), splits);
     });
     config.key_to_eventkey.shrink_to_fit();
-    config
+    Result::Ok(config)
 }
 
-///設定ファイルを読み込み、keyとfieldsのマップをPIVOT_KEYWORD大域変数にロードする。
-pub fn load_pivot_keywords(path: &str) {
-    let read_result = utils::read_txt(path);
-    if read_result.is_err() {
-        AlertMessage::alert(
-            &mut BufWriter::new(std::io::stderr().lock()),
-            read_result.as_ref().unwrap_err(),
-        )
-        .ok();
-    }
-
-    read_result.unwrap().into_iter().for_each(|line| {
+///設定ファイルを読み込み、ピボットキーワードの種類(key)とfieldsのマップを返す。
+pub fn load_pivot_keywords(path: &str) -> Result<HashMap<String, PivotKeyword>, String> {
+    let mut pivot_keywords: HashMap<String, PivotKeyword> = HashMap::new();
+    utils::read_txt(path)?.into_iter().for_each(|line| {
         let map: Vec<&str> = line.split('.').collect();
         if map.len() != 2 {
             return;
         }
 
         //存在しなければ、keyを作成
-        PIVOT_KEYWORD
-            .write()
-            .unwrap()
+        pivot_keywords
             .entry(map[0].to_string())
-            .or_insert(PivotKeyword::new());
-
-        PIVOT_KEYWORD
-            .write()
-            .unwrap()
-            .get_mut(&map[0].to_string())
-            .unwrap()
+            .or_default()
             .fields
             .insert(map[1].to_string());
     });
+    Result::Ok(pivot_keywords)
 }
 
 #[derive(Debug, Clone)]
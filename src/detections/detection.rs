@@ -1,25 +1,23 @@
 extern crate csv;
 
-use crate::detections::configs;
-use crate::detections::pivot::insert_pivot_keyword;
-use crate::detections::print::AlertMessage;
+use crate::detections::configs::EventKeyAliasConfig;
+use crate::detections::ioc::{IocMatch, IocMatcher};
 use crate::detections::print::DetectInfo;
-use crate::detections::print::ERROR_LOG_STACK;
-use crate::detections::print::MESSAGES;
-use crate::detections::print::PIVOT_KEYWORD_LIST_FLAG;
-use crate::detections::print::QUIET_ERRORS_FLAG;
-use crate::detections::print::STATISTICS_FLAG;
+use crate::detections::print::ErrorLog;
+use crate::detections::print::Message;
 use crate::detections::print::TAGS_CONFIG;
 use crate::detections::rule;
 use crate::detections::rule::AggResult;
 use crate::detections::rule::RuleNode;
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::detections::utils;
 use crate::detections::utils::get_serde_number_to_string;
 use crate::filter;
 use crate::yaml::ParseYaml;
+use chrono::{TimeZone, Utc};
 use hashbrown;
 use hashbrown::HashMap;
 use serde_json::Value;
-use std::io::BufWriter;
 use std::sync::Arc;
 use tokio::{runtime::Runtime, spawn, task::JoinHandle};
 
@@ -30,9 +28,9 @@ const DIRPATH_RULES: &str = "rules";
 pub struct EvtxRecordInfo {
     pub evtx_filepath: String, // イベントファイルのファイルパス　ログで出力するときに使う
     pub record: Value,         // 1レコード分のデータをJSON形式にシリアライズしたもの
-    pub data_string: String,
+    pub data_string: Option<String>, // キーワード検索を行うルールがある場合のみ、レコード全体を文字列に変換したものを設定する
     pub key_2_value: hashbrown::HashMap<String, String>,
-    pub record_information: Option<String>,
+    pub alias_config: Arc<EventKeyAliasConfig>, // key_2_valueの作成に使用したeventkey_aliasの設定
 }
 
 impl EvtxRecordInfo {
@@ -41,45 +39,122 @@ impl EvtxRecordInfo {
     }
 }
 
-#[derive(Debug)]
 pub struct Detection {
     rules: Vec<RuleNode>,
+    sinks: Arc<Vec<Arc<dyn DetectionSink>>>,
+    full_data_separator: Option<String>,
+    ioc_matcher: Option<Arc<IocMatcher>>,
+    error_log: ErrorLog,
 }
 
 impl Detection {
-    pub fn new(rule_nodes: Vec<RuleNode>) -> Detection {
-        Detection { rules: rule_nodes }
+    /// 検知結果はsinksに渡す。full_data_separatorがSomeの場合は検知したレコードの全フィールドをその区切り文字で連結してrecord_informationに設定する。
+    /// aggregation conditionの検知結果には空のrecord_informationを設定する
+    pub fn new(
+        rule_nodes: Vec<RuleNode>,
+        sinks: Vec<Arc<dyn DetectionSink>>,
+        full_data_separator: Option<String>,
+    ) -> Detection {
+        Detection {
+            rules: rule_nodes,
+            sinks: Arc::new(sinks),
+            full_data_separator,
+            ioc_matcher: Option::None,
+            error_log: ErrorLog::default(),
+        }
+    }
+
+    /// ルールと一緒に全てのレコードをIOCと照合する
+    pub fn with_ioc_matcher(mut self, ioc_matcher: Option<Arc<IocMatcher>>) -> Self {
+        self.ioc_matcher = ioc_matcher;
+        self
+    }
+
+    /// 検知処理で発生したエラーの出力先
+    pub fn with_error_log(mut self, error_log: ErrorLog) -> Self {
+        self.error_log = error_log;
+        self
+    }
+
+    /// recordsに対してルールを実行し、IOCを照合する。ioc_only_recordsはルールを実行せずにIOCとのみ照合する
+    pub fn start(
+        self,
+        rt: &Runtime,
+        records: Vec<EvtxRecordInfo>,
+        ioc_only_records: Vec<EvtxRecordInfo>,
+    ) -> Self {
+        rt.block_on(self.execute_rules(records, ioc_only_records))
+    }
+
+    pub fn has_ioc_matcher(&self) -> bool {
+        self.ioc_matcher.is_some()
+    }
+
+    /// 同じルールと出力先を持つDetectionを作成する。
+    /// aggregation conditionのカウント情報はRuleNodeごとに保持されるため、複数のスレッドで同時に検知する場合はスレッドごとにDetectionを作成し、最後にmergeで統合する
+    pub fn fork(&self) -> Detection {
+        self.fork_with_sinks(Arc::clone(&self.sinks))
+    }
+
+    /// 同じルールを持ち、出力先が異なるDetectionを作成する。ルールファイルを再度読み込まずに別の検知処理を行う場合に使う
+    pub fn with_sinks(&self, sinks: Vec<Arc<dyn DetectionSink>>) -> Detection {
+        self.fork_with_sinks(Arc::new(sinks))
+    }
+
+    fn fork_with_sinks(&self, sinks: Arc<Vec<Arc<dyn DetectionSink>>>) -> Detection {
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let error_log = rule.error_log.clone();
+                let mut rule = rule::create_rule(rule.rulepath.clone(), rule.yaml.clone());
+                rule.error_log = error_log;
+                // 元のルールの初期化に成功しているため、エラーにはならない
+                rule.init().ok();
+                rule
+            })
+            .collect();
+        Detection {
+            rules,
+            sinks,
+            full_data_separator: self.full_data_separator.clone(),
+            ioc_matcher: self.ioc_matcher.clone(),
+            error_log: self.error_log.clone(),
+        }
     }
 
-    pub fn start(self, rt: &Runtime, records: Vec<EvtxRecordInfo>) -> Self {
-        rt.block_on(self.execute_rules(records))
+    pub fn rules(&self) -> &[RuleNode] {
+        &self.rules
     }
 
-    // ルールファイルをパースします。
+    /// forkで作成したDetectionのaggregation conditionのカウント情報を統合する
+    pub fn merge(&mut self, other: &mut Detection) {
+        for (rule, other_rule) in self.rules.iter_mut().zip(other.rules.iter_mut()) {
+            rule.merge_countdata(other_rule);
+        }
+    }
+
+    // ルールファイルをパースします。読み込み時のエラーはerror_logに出力し、print_load_infoがtrueの場合は読み込んだルールの数を表示します。
     pub fn parse_rule_files(
         level: String,
         rulespath: Option<&str>,
         exclude_ids: &filter::RuleExclude,
+        enable_deprecated_rules: bool,
+        error_log: &ErrorLog,
+        print_load_info: bool,
     ) -> Vec<RuleNode> {
         // ルールファイルのパースを実行
-        let mut rulefile_loader = ParseYaml::new();
+        let mut rulefile_loader =
+            ParseYaml::with_error_log(enable_deprecated_rules, error_log.clone());
         let result_readdir =
             rulefile_loader.read_dir(rulespath.unwrap_or(DIRPATH_RULES), &level, exclude_ids);
         if result_readdir.is_err() {
-            let errmsg = format!("{}", result_readdir.unwrap_err());
-            if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &errmsg).ok();
-            }
-            if !*QUIET_ERRORS_FLAG {
-                ERROR_LOG_STACK
-                    .lock()
-                    .unwrap()
-                    .push(format!("[ERROR] {}", errmsg));
-            }
+            error_log.error(&format!("{}", result_readdir.unwrap_err()));
             return vec![];
         }
         let mut parseerror_count = rulefile_loader.errorrule_count;
         let return_if_success = |mut rule: RuleNode| {
+            rule.error_log = error_log.clone();
             let err_msgs_result = rule.init();
             if err_msgs_result.is_ok() {
                 return Option::Some(rule);
@@ -87,27 +162,11 @@ impl Detection {
 
             // ruleファイルのパースに失敗した場合はエラー出力
             err_msgs_result.err().iter().for_each(|err_msgs| {
-                let errmsg_body =
-                    format!("Failed to parse rule file. (FilePath : {})", rule.rulepath);
-                if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                    AlertMessage::warn(&mut std::io::stdout().lock(), &errmsg_body).ok();
-
-                    err_msgs.iter().for_each(|err_msg| {
-                        AlertMessage::warn(&mut std::io::stdout().lock(), err_msg).ok();
-                    });
-                }
-                if !*QUIET_ERRORS_FLAG {
-                    ERROR_LOG_STACK
-                        .lock()
-                        .unwrap()
-                        .push(format!("[WARN] {}", errmsg_body));
-                    err_msgs.iter().for_each(|err_msg| {
-                        ERROR_LOG_STACK
-                            .lock()
-                            .unwrap()
-                            .push(format!("[WARN] {}", err_msg));
-                    });
-                }
+                error_log.warn(&format!(
+                    "Failed to parse rule file. (FilePath : {})",
+                    rule.rulepath
+                ));
+                err_msgs.iter().for_each(|err_msg| error_log.warn(err_msg));
                 parseerror_count += 1;
                 println!(); // 一行開けるためのprintln
             });
@@ -120,16 +179,22 @@ impl Detection {
             .map(|rule_file_tuple| rule::create_rule(rule_file_tuple.0, rule_file_tuple.1))
             .filter_map(return_if_success)
             .collect();
-        Detection::print_rule_load_info(
-            &rulefile_loader.rulecounter,
-            &parseerror_count,
-            &rulefile_loader.ignorerule_count,
-        );
+        if print_load_info {
+            Detection::print_rule_load_info(
+                &rulefile_loader.rulecounter,
+                &parseerror_count,
+                &rulefile_loader.ignorerule_count,
+            );
+        }
         ret
     }
 
     // 複数のイベントレコードに対して、複数のルールを1個実行します。
-    async fn execute_rules(mut self, records: Vec<EvtxRecordInfo>) -> Self {
+    async fn execute_rules(
+        mut self,
+        records: Vec<EvtxRecordInfo>,
+        ioc_only_records: Vec<EvtxRecordInfo>,
+    ) -> Self {
         let records_arc = Arc::new(records);
         // // 各rule毎にスレッドを作成して、スレッドを起動する。
         let rules = self.rules;
@@ -137,9 +202,34 @@ impl Detection {
             .into_iter()
             .map(|rule| {
                 let records_cloned = Arc::clone(&records_arc);
-                spawn(async move { Detection::execute_rule(rule, records_cloned) })
+                let sinks = Arc::clone(&self.sinks);
+                let full_data_separator = self.full_data_separator.clone();
+                spawn(async move {
+                    Detection::execute_rule(
+                        rule,
+                        records_cloned,
+                        &sinks,
+                        full_data_separator.as_deref(),
+                    )
+                })
             })
             .collect();
+        let ioc_handle = self.ioc_matcher.as_ref().map(|ioc_matcher| {
+            let ioc_matcher = Arc::clone(ioc_matcher);
+            let records_cloned = Arc::clone(&records_arc);
+            let sinks = Arc::clone(&self.sinks);
+            let full_data_separator = self.full_data_separator.clone();
+            let error_log = self.error_log.clone();
+            spawn(async move {
+                Detection::execute_ioc(
+                    &ioc_matcher,
+                    records_cloned.iter().chain(ioc_only_records.iter()),
+                    &sinks,
+                    full_data_separator.as_deref(),
+                    &error_log,
+                )
+            })
+        });
 
         // 全スレッドの実行完了を待機
         let mut rules = vec![];
@@ -147,6 +237,9 @@ impl Detection {
             let ret_rule = handle.await.unwrap();
             rules.push(ret_rule);
         }
+        if let Some(ioc_handle) = ioc_handle {
+            ioc_handle.await.unwrap();
+        }
 
         // この関数の先頭でrules.into_iter()を呼び出している。それにより所有権がmapのruleを経由し、execute_ruleの引数に渡しているruleに移っているので、self.rulesには所有権が無くなっている。
         // 所有権を失ったメンバー変数を持つオブジェクトをreturnするコードを書くと、コンパイラが怒になるので(E0382という番号のコンパイルエラー)、ここでself.rulesに所有権を戻している。
@@ -156,7 +249,7 @@ impl Detection {
         self
     }
 
-    pub fn add_aggcondition_msges(self, rt: &Runtime) {
+    pub fn add_aggcondition_msges(&self, rt: &Runtime) {
         return rt.block_on(self.add_aggcondition_msg());
     }
 
@@ -168,13 +261,30 @@ impl Detection {
 
             let agg_results = rule.judge_satisfy_aggcondition();
             for value in agg_results {
-                Detection::insert_agg_message(rule, value);
+                self.insert_agg_message(rule, value);
             }
         }
     }
 
+    /// aggregation conditionを判定して、判定済みのカウント情報を削除する。
+    /// watchモードで新しいレコードを検知するたびに呼び出すため、同じ検知結果は一度しか出力されない
+    pub fn add_rolling_aggcondition_msges(&mut self) {
+        let mut rules = std::mem::take(&mut self.rules);
+        for rule in rules.iter_mut() {
+            for value in rule.judge_satisfy_aggcondition_rolling() {
+                self.insert_agg_message(rule, value);
+            }
+        }
+        self.rules = rules;
+    }
+
     // 複数のイベントレコードに対して、ルールを1個実行します。
-    fn execute_rule(mut rule: RuleNode, records: Arc<Vec<EvtxRecordInfo>>) -> RuleNode {
+    fn execute_rule(
+        mut rule: RuleNode,
+        records: Arc<Vec<EvtxRecordInfo>>,
+        sinks: &[Arc<dyn DetectionSink>],
+        full_data_separator: Option<&str>,
+    ) -> RuleNode {
         let agg_condition = rule.has_agg_condition();
         for record_info in records.as_ref() {
             let result = rule.select(record_info);
@@ -182,14 +292,9 @@ impl Detection {
                 continue;
             }
 
-            if *PIVOT_KEYWORD_LIST_FLAG {
-                insert_pivot_keyword(&record_info.record);
-                continue;
-            }
-
             // aggregation conditionが存在しない場合はそのまま出力対応を行う
             if !agg_condition {
-                Detection::insert_message(&rule, record_info);
+                Detection::insert_message(&rule, record_info, sinks, full_data_separator);
             }
         }
 
@@ -197,7 +302,12 @@ impl Detection {
     }
 
     /// 条件に合致したレコードを表示するための関数
-    fn insert_message(rule: &RuleNode, record_info: &EvtxRecordInfo) {
+    fn insert_message(
+        rule: &RuleNode,
+        record_info: &EvtxRecordInfo,
+        sinks: &[Arc<dyn DetectionSink>],
+        full_data_separator: Option<&str>,
+    ) {
         let tag_info: Vec<String> = rule.yaml["tags"]
             .as_vec()
             .unwrap_or(&Vec::default())
@@ -206,10 +316,9 @@ impl Detection {
             .map(|str| str.to_owned())
             .collect();
 
-        let recinfo = record_info
-            .record_information
-            .as_ref()
-            .map(|recinfo| recinfo.to_string());
+        // 全フィールドの情報は検知したレコードのみ作成する
+        let recinfo = full_data_separator
+            .map(|separator| utils::create_recordinfos(&record_info.record, separator));
         let detect_info = DetectInfo {
             filepath: record_info.evtx_filepath.to_string(),
             rulepath: rule.rulepath.to_string(),
@@ -220,19 +329,88 @@ impl Detection {
             eventid: get_serde_number_to_string(&record_info.record["Event"]["System"]["EventID"])
                 .unwrap_or_else(|| "-".to_owned()),
             alert: rule.yaml["title"].as_str().unwrap_or("").to_string(),
-            detail: String::default(),
+            detail: Message::parse_message(
+                &record_info.alias_config,
+                &record_info.record,
+                rule.yaml["details"].as_str().unwrap_or("").to_string(),
+            ),
             tag_info: tag_info.join(" | "),
             record_information: recinfo,
         };
-        MESSAGES.lock().unwrap().insert(
-            &record_info.record,
-            rule.yaml["details"].as_str().unwrap_or("").to_string(),
-            detect_info,
-        );
+        let default_time = Utc.ymd(1970, 1, 1).and_hms(0, 0, 0);
+        let event = DetectionEvent {
+            time: Message::get_event_time(&record_info.record).unwrap_or(default_time),
+            detect_info: &detect_info,
+            record: Option::Some(record_info),
+            rule,
+        };
+        Detection::send_to_sinks(sinks, &event, &rule.error_log);
+    }
+
+    /// 複数のイベントレコードをIOCと照合します。
+    fn execute_ioc<'a, I: Iterator<Item = &'a EvtxRecordInfo>>(
+        ioc_matcher: &IocMatcher,
+        records: I,
+        sinks: &[Arc<dyn DetectionSink>],
+        full_data_separator: Option<&str>,
+        error_log: &ErrorLog,
+    ) {
+        for record_info in records {
+            for ioc_match in
+                ioc_matcher.find_matches(&record_info.alias_config, &record_info.record)
+            {
+                Detection::insert_ioc_message(
+                    &ioc_match,
+                    record_info,
+                    sinks,
+                    full_data_separator,
+                    error_log,
+                );
+            }
+        }
+    }
+
+    /// IOCに一致したレコードを表示するための関数。IOCのファイルパスとIOCの種類、レベルを持つルールとして出力先に渡す
+    fn insert_ioc_message(
+        ioc_match: &IocMatch,
+        record_info: &EvtxRecordInfo,
+        sinks: &[Arc<dyn DetectionSink>],
+        full_data_separator: Option<&str>,
+        error_log: &ErrorLog,
+    ) {
+        let indicator = ioc_match.indicator;
+        let rule = ioc_match.rule;
+        let recinfo = full_data_separator
+            .map(|separator| utils::create_recordinfos(&record_info.record, separator));
+        let detect_info = DetectInfo {
+            filepath: record_info.evtx_filepath.to_string(),
+            rulepath: rule.rulepath.to_string(),
+            level: indicator.level.to_string(),
+            computername: record_info.record["Event"]["System"]["Computer"]
+                .to_string()
+                .replace('\"', ""),
+            eventid: get_serde_number_to_string(&record_info.record["Event"]["System"]["EventID"])
+                .unwrap_or_else(|| "-".to_owned()),
+            alert: rule.yaml["title"].as_str().unwrap_or("").to_string(),
+            detail: format!(
+                "IOC: {} ¦ Field: {} ¦ Source: {} ¦ Desc: {}",
+                indicator.value, ioc_match.field, indicator.source, indicator.description
+            ),
+            tag_info: String::default(),
+            record_information: recinfo,
+        };
+        let default_time = Utc.ymd(1970, 1, 1).and_hms(0, 0, 0);
+        let event = DetectionEvent {
+            time: Message::get_event_time(&record_info.record).unwrap_or(default_time),
+            detect_info: &detect_info,
+            record: Option::Some(record_info),
+            rule,
+        };
+        Detection::send_to_sinks(sinks, &event, error_log);
     }
 
     /// insert aggregation condition detection message to output stack
-    fn insert_agg_message(rule: &RuleNode, agg_result: AggResult) {
+    fn insert_agg_message(&self, rule: &RuleNode, agg_result: AggResult) {
         let tag_info: Vec<String> = rule.yaml["tags"]
             .as_vec()
             .unwrap_or(&Vec::default())
@@ -241,7 +419,7 @@ impl Detection {
             .map(|str| str.to_owned())
             .collect();
         let output = Detection::create_count_output(rule, &agg_result);
-        let rec_info = if configs::CONFIG.read().unwrap().args.is_present("full-data") {
+        let rec_info = if self.full_data_separator.is_some() {
             Option::Some(String::default())
         } else {
             Option::None
@@ -258,10 +436,66 @@ impl Detection {
             tag_info: tag_info.join(" : "),
         };
 
-        MESSAGES
-            .lock()
-            .unwrap()
-            .insert_message(detect_info, agg_result.start_timedate)
+        let event = DetectionEvent {
+            time: agg_result.start_timedate,
+            detect_info: &detect_info,
+            record: Option::None,
+            rule,
+        };
+        Detection::send_to_sinks(&self.sinks, &event, &self.error_log);
+    }
+
+    /// 検知結果を全てのsinkに渡す。sinkでエラーが発生した場合もほかのsinkへの出力は継続する
+    fn send_to_sinks(
+        sinks: &[Arc<dyn DetectionSink>],
+        event: &DetectionEvent,
+        error_log: &ErrorLog,
+    ) {
+        for sink in sinks {
+            if let Err(err) = sink.on_detect(event) {
+                error_log.error(&format!(
+                    "Failed to output detection result. RuleFile:{} Error:{}",
+                    event.rule.rulepath, err
+                ));
+            }
+        }
+    }
+
+    /// ルールを実行する前に、レコードを全てのsinkに渡す。sinkでエラーが発生した場合もほかのsinkへの出力は継続する
+    pub fn send_records_to_sinks(&self, records: &[EvtxRecordInfo]) {
+        for sink in self.sinks.iter() {
+            if let Err(err) = sink.on_records(records) {
+                self.error_log
+                    .error(&format!("Failed to collect records. Error:{}", err));
+            }
+        }
+    }
+
+    /// 全てのsinkの出力を書き出す。
+    pub fn flush_sinks(&self) -> Result<(), String> {
+        self.call_sinks(|sink| sink.flush())
+    }
+
+    /// 全てのsinkの終了処理を行う。aggregation conditionの検知結果を追加した後に呼び出す
+    pub fn finish_sinks(&self) -> Result<(), String> {
+        self.call_sinks(|sink| sink.finish())
+    }
+
+    /// 全てのsinkに対してfを呼び出し、エラーが発生した場合はまとめて返す
+    fn call_sinks<F: Fn(&dyn DetectionSink) -> Result<(), String>>(
+        &self,
+        f: F,
+    ) -> Result<(), String> {
+        let errors: Vec<String> = self
+            .sinks
+            .iter()
+            .filter_map(|sink| f(sink.as_ref()).err())
+            .collect();
+        if errors.is_empty() {
+            Result::Ok(())
+        } else {
+            Result::Err(errors.join("\n"))
+        }
     }
 
     ///aggregation conditionのcount部分の検知出力文の文字列を返す関数
@@ -314,9 +548,6 @@ impl Detection {
         parseerror_count: &u128,
         ignore_count: &u128,
     ) {
-        if *STATISTICS_FLAG {
-            return;
-        }
         let mut total = parseerror_count + ignore_count;
         rc.into_iter().for_each(|(key, value)| {
             println!("{} rules: {}", key, value);
@@ -336,6 +567,7 @@ impl Detection {
 mod tests {
 
     use crate::detections::detection::Detection;
+    use crate::detections::print::ErrorLog;
     use crate::detections::rule::create_rule;
     use crate::detections::rule::AggResult;
     use crate::filter;
@@ -346,8 +578,14 @@ mod tests {
     fn test_parse_rule_files() {
         let level = "informational";
         let opt_rule_path = Some("./test_files/rules/level_yaml");
-        let cole =
-            Detection::parse_rule_files(level.to_owned(), opt_rule_path, &filter::exclude_ids());
+        let cole = Detection::parse_rule_files(
+            level.to_owned(),
+            opt_rule_path,
+            &filter::exclude_ids(),
+            false,
+            &ErrorLog::default(),
+            true,
+        );
         assert_eq!(5, cole.len());
     }
 
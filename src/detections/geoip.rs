@@ -0,0 +1,266 @@
+use crate::detections::configs::EventKeyAliasConfig;
+use crate::detections::utils;
+use maxminddb::{geoip2, Reader};
+use serde_json::Value;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// 国とASNを追加するIPアドレスのフィールド。eventkey_aliasで解決する
+const IP_FIELDS: [&str; 6] = [
+    "IpAddress",
+    "SourceIp",
+    "DestinationIp",
+    "SourceAddress",
+    "DestAddress",
+    "ClientAddress",
+];
+
+/// 1つのIPアドレスの国とASN
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoIpInfo {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub asn_org: Option<String>,
+}
+
+/// ローカルのMaxMind DB(.mmdb)ファイルを使い、レコードのIPアドレスのフィールドに国とASNの仮想フィールドを追加する。
+/// 国はGeoIP2/GeoLite2のCountryもしくはCity、ASNはASNのデータベースから取得し、database_typeで判別する
+pub struct GeoIpEnricher {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl fmt::Debug for GeoIpEnricher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let database_type = |reader: &Option<Reader<Vec<u8>>>| {
+            reader.as_ref().map(|r| r.metadata.database_type.clone())
+        };
+        f.debug_struct("GeoIpEnricher")
+            .field("country", &database_type(&self.country))
+            .field("asn", &database_type(&self.asn))
+            .finish()
+    }
+}
+
+impl GeoIpEnricher {
+    /// .mmdbファイル、もしくは.mmdbファイルを持つディレクトリを読み込む
+    pub fn load(path: &str) -> Result<GeoIpEnricher, String> {
+        let mut mmdb_paths = vec![];
+        if Path::new(path).is_dir() {
+            let entries = std::fs::read_dir(path)
+                .map_err(|e| format!("Failed to open the GeoIP directory. {} {}", path, e))?;
+            for entry in entries.flatten() {
+                let entry_path = entry.path();
+                if entry_path
+                    .extension()
+                    .map(|ext| ext.eq_ignore_ascii_case("mmdb"))
+                    .unwrap_or(false)
+                {
+                    mmdb_paths.push(entry_path);
+                }
+            }
+            mmdb_paths.sort();
+        } else {
+            mmdb_paths.push(Path::new(path).to_path_buf());
+        }
+
+        let mut enricher = GeoIpEnricher {
+            country: Option::None,
+            asn: Option::None,
+        };
+        for mmdb_path in mmdb_paths {
+            let reader = Reader::open_readfile(&mmdb_path).map_err(|e| {
+                format!(
+                    "Failed to load the GeoIP database. {} {}",
+                    mmdb_path.display(),
+                    e
+                )
+            })?;
+            let database_type = reader.metadata.database_type.to_lowercase();
+            if database_type.contains("asn") {
+                enricher.asn = Option::Some(reader);
+            } else if database_type.contains("country") || database_type.contains("city") {
+                enricher.country = Option::Some(reader);
+            }
+        }
+        if enricher.country.is_none() && enricher.asn.is_none() {
+            return Result::Err(format!(
+                "No GeoIP2/GeoLite2 Country, City or ASN database was found. {}",
+                path
+            ));
+        }
+        Result::Ok(enricher)
+    }
+
+    /// IPアドレスの国とASNを取得する。プライベートアドレスとループバックアドレス、データベースに無いアドレスはNoneを返す
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoIpInfo> {
+        if !is_public_ip(&ip) {
+            return Option::None;
+        }
+        let mut info = GeoIpInfo::default();
+        if let Some(reader) = &self.country {
+            if let Ok(country) = reader.lookup::<geoip2::Country>(ip) {
+                info.country = country
+                    .country
+                    .or(country.registered_country)
+                    .and_then(|country| country.iso_code)
+                    .map(|iso_code| iso_code.to_string());
+            }
+        }
+        if let Some(reader) = &self.asn {
+            if let Ok(asn) = reader.lookup::<geoip2::Asn>(ip) {
+                info.asn = asn.autonomous_system_number;
+                info.asn_org = asn
+                    .autonomous_system_organization
+                    .map(|org| org.to_string());
+            }
+        }
+        (info != GeoIpInfo::default()).then_some(info)
+    }
+
+    /// レコードのIPアドレスのフィールドごとに、<フィールド名>_country、<フィールド名>_asn、<フィールド名>_asn_orgをEvent.EventDataに追加する。
+    /// 追加したフィールドはほかのフィールドと同様に、ルールの条件やdetailsで使うことができる
+    pub fn enrich(&self, alias_config: &EventKeyAliasConfig, record: &mut Value) {
+        enrich_record(alias_config, record, |ip| self.lookup(ip));
+    }
+}
+
+fn enrich_record<F: Fn(IpAddr) -> Option<GeoIpInfo>>(
+    alias_config: &EventKeyAliasConfig,
+    record: &mut Value,
+    lookup: F,
+) {
+    let mut fields = vec![];
+    for field in IP_FIELDS {
+        let ip = utils::get_event_value_with_alias(alias_config, field, record)
+            .and_then(|value| value.as_str())
+            .and_then(parse_ip);
+        if let Some(info) = ip.and_then(&lookup) {
+            fields.push((field, info));
+        }
+    }
+    if fields.is_empty() {
+        return;
+    }
+
+    let event_data = match record["Event"]["EventData"].as_object_mut() {
+        Some(event_data) => event_data,
+        None => return,
+    };
+    for (field, info) in fields {
+        if let Some(country) = info.country {
+            event_data.insert(format!("{}_country", field), Value::String(country));
+        }
+        if let Some(asn) = info.asn {
+            event_data.insert(format!("{}_asn", field), Value::from(asn));
+        }
+        if let Some(asn_org) = info.asn_org {
+            event_data.insert(format!("{}_asn_org", field), Value::String(asn_org));
+        }
+    }
+}
+
+/// Sysmonの「::ffff:」で始まるIPv4アドレスはIPv4アドレスとして扱う
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let ip: IpAddr = value.trim().parse().ok()?;
+    match ip {
+        IpAddr::V6(v6) => Option::Some(
+            v6.to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(IpAddr::V6(v6)),
+        ),
+        IpAddr::V4(_) => Option::Some(ip),
+    }
+}
+
+/// プライベートアドレス、ループバックアドレス、リンクローカルアドレスなどのインターネット上に無いアドレスはfalseを返す
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_public_ipv4(v4),
+        IpAddr::V6(v6) => is_public_ipv6(v6),
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    // 100.64.0.0/10はキャリアグレードNATのアドレス
+    let is_shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || is_shared)
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    // fc00::/7はユニークローカルアドレス、fe80::/10はリンクローカルアドレス
+    let is_unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00;
+    let is_link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || is_unique_local
+        || is_link_local)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::configs::EVENTKEY_ALIAS;
+    use crate::detections::geoip::{enrich_record, is_public_ip, parse_ip, GeoIpInfo};
+    use serde_json::Value;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in ["8.8.8.8", "2001:4860:4860::8888", "::ffff:203.0.113.10"] {
+            assert!(is_public_ip(&parse_ip(ip).unwrap()), "{}", ip);
+        }
+        for ip in [
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(!is_public_ip(&parse_ip(ip).unwrap()), "{}", ip);
+        }
+        assert_eq!(parse_ip("-"), Option::None);
+    }
+
+    #[test]
+    fn test_enrich_record() {
+        let record_str = r#"{"Event": {"System": {"EventID": 4624}, "EventData": {
+            "IpAddress": "::ffff:203.0.113.10",
+            "WorkstationName": "WS01"
+        }}}"#;
+        let mut record: Value = serde_json::from_str(record_str).unwrap();
+        enrich_record(&EVENTKEY_ALIAS, &mut record, |ip| {
+            assert_eq!(ip, "203.0.113.10".parse::<IpAddr>().unwrap());
+            Option::Some(GeoIpInfo {
+                country: Option::Some("JP".to_string()),
+                asn: Option::Some(64500),
+                asn_org: Option::None,
+            })
+        });
+        let event_data = &record["Event"]["EventData"];
+        assert_eq!(event_data["IpAddress_country"], "JP");
+        assert_eq!(event_data["IpAddress_asn"], 64500);
+        assert!(event_data.get("IpAddress_asn_org").is_none());
+        assert!(event_data.get("SourceIp_country").is_none());
+
+        // 検索結果が無いアドレスにはフィールドを追加しない
+        let mut record: Value = serde_json::from_str(record_str).unwrap();
+        enrich_record(&EVENTKEY_ALIAS, &mut record, |_| Option::None);
+        assert!(record["Event"]["EventData"]
+            .get("IpAddress_country")
+            .is_none());
+    }
+}
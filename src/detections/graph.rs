@@ -0,0 +1,588 @@
+use crate::afterfact::format_time;
+use crate::detections::configs::{EventKeyAliasConfig, LEVELMAP};
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::detections::utils::{self, get_serde_number_to_string};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// グラフのノードの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EntityKind {
+    User,
+    Computer,
+    Ip,
+    Process,
+}
+
+impl EntityKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EntityKind::User => "user",
+            EntityKind::Computer => "computer",
+            EntityKind::Ip => "ip",
+            EntityKind::Process => "process",
+        }
+    }
+}
+
+/// ノードにするフィールド。eventkey_aliasで解決する。
+/// Computerは接続元のワークステーション名で、検知したイベントのコンピュータ(Event.System.Computer)は常にノードにする
+const ENTITY_FIELDS: [(EntityKind, &str); 8] = [
+    (EntityKind::User, "SubjectUserName"),
+    (EntityKind::User, "TargetUserName"),
+    (EntityKind::User, "User"),
+    (EntityKind::Computer, "WorkstationName"),
+    (EntityKind::Ip, "IpAddress"),
+    (EntityKind::Process, "Image"),
+    (EntityKind::Process, "NewProcessName"),
+    (EntityKind::Process, "ProcessName"),
+];
+
+/// エッジのラベルにするログオンIDのフィールド。先に見つかったものを使う
+const LOGON_ID_FIELDS: [&str; 2] = ["TargetLogonId", "SubjectLogonId"];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Entity {
+    pub kind: EntityKind,
+    pub name: String,
+}
+
+impl Entity {
+    pub fn new(kind: EntityKind, name: &str) -> Entity {
+        Entity {
+            kind,
+            name: name.to_string(),
+        }
+    }
+
+    /// ノードのID。種類ごとに名前が重複しないように種類を前に付ける
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.kind.name(), self.name)
+    }
+}
+
+/// 同じノード間で同じルール、ログオンIDのエッジは1つにまとめる
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EdgeKey {
+    pub source: Entity,
+    pub target: Entity,
+    pub rule_title: String,
+    pub level: String,
+    pub logon_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EdgeStats {
+    pub count: usize,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// 検知結果から作成したユーザ、コンピュータ、IPアドレス、プロセスの関係を表すグラフ
+#[derive(Debug, Default)]
+pub struct EntityGraph {
+    pub edges: BTreeMap<EdgeKey, EdgeStats>,
+}
+
+impl EntityGraph {
+    pub fn new() -> EntityGraph {
+        EntityGraph::default()
+    }
+
+    /// 検知したレコードからノードを取り出し、接続元(IPアドレス、ワークステーション) -> ユーザ -> コンピュータ、ユーザ -> プロセスのエッジを追加する。
+    /// ユーザが見つからない場合は、接続元とプロセスを検知したコンピュータにつなげる。フィールドはalias_configで解決する
+    pub fn add(
+        &mut self,
+        alias_config: &EventKeyAliasConfig,
+        record: &Value,
+        rule_title: &str,
+        level: &str,
+        time: DateTime<Utc>,
+    ) {
+        let host = match record["Event"]["System"]["Computer"].as_str() {
+            Some(computer) => Entity::new(EntityKind::Computer, computer),
+            None => return,
+        };
+        let entities = get_entities(alias_config, record);
+        let find = |kind: EntityKind| -> Vec<&Entity> {
+            entities
+                .iter()
+                .filter(|entity| entity.kind == kind && **entity != host)
+                .collect()
+        };
+        let users = find(EntityKind::User);
+        let sources: Vec<&Entity> = find(EntityKind::Ip)
+            .into_iter()
+            .chain(find(EntityKind::Computer))
+            .collect();
+        let processes = find(EntityKind::Process);
+        let logon_id = LOGON_ID_FIELDS
+            .iter()
+            .filter_map(|field| get_value(alias_config, field, record))
+            .next();
+
+        let mut edges: Vec<(&Entity, &Entity)> = vec![];
+        if users.is_empty() {
+            edges.extend(sources.iter().map(|source| (*source, &host)));
+            edges.extend(processes.iter().map(|process| (&host, *process)));
+        } else {
+            for user in &users {
+                edges.push((user, &host));
+                edges.extend(sources.iter().map(|source| (*source, *user)));
+                edges.extend(processes.iter().map(|process| (*user, *process)));
+            }
+        }
+        for (source, target) in edges {
+            let key = EdgeKey {
+                source: source.clone(),
+                target: target.clone(),
+                rule_title: rule_title.to_string(),
+                level: level.to_string(),
+                logon_id: logon_id.clone(),
+            };
+            let stats = self.edges.entry(key).or_insert(EdgeStats {
+                count: 0,
+                first_seen: time,
+                last_seen: time,
+            });
+            stats.count += 1;
+            stats.first_seen = stats.first_seen.min(time);
+            stats.last_seen = stats.last_seen.max(time);
+        }
+    }
+
+    /// エッジの両端のノードを種類と名前の順に返す
+    pub fn nodes(&self) -> Vec<&Entity> {
+        let mut nodes: Vec<&Entity> = self
+            .edges
+            .keys()
+            .flat_map(|key| [&key.source, &key.target])
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+
+    pub fn write_graphml<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            writer,
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+        )?;
+        for (id, domain, name, attr_type) in [
+            ("type", "node", "type", "string"),
+            ("label", "node", "label", "string"),
+            ("rule", "edge", "rule", "string"),
+            ("level", "edge", "level", "string"),
+            ("logon_id", "edge", "logon_id", "string"),
+            ("first_seen", "edge", "first_seen", "string"),
+            ("last_seen", "edge", "last_seen", "string"),
+            ("count", "edge", "count", "int"),
+        ] {
+            writeln!(
+                writer,
+                r#"  <key id="{}" for="{}" attr.name="{}" attr.type="{}"/>"#,
+                id, domain, name, attr_type
+            )?;
+        }
+        writeln!(writer, r#"  <graph id="hayabusa" edgedefault="directed">"#)?;
+        for node in self.nodes() {
+            writeln!(writer, r#"    <node id="{}">"#, escape_xml(&node.id()))?;
+            writeln!(
+                writer,
+                r#"      <data key="type">{}</data>"#,
+                node.kind.name()
+            )?;
+            writeln!(
+                writer,
+                r#"      <data key="label">{}</data>"#,
+                escape_xml(&node.name)
+            )?;
+            writeln!(writer, "    </node>")?;
+        }
+        for (i, (key, stats)) in self.edges.iter().enumerate() {
+            writeln!(
+                writer,
+                r#"    <edge id="e{}" source="{}" target="{}">"#,
+                i,
+                escape_xml(&key.source.id()),
+                escape_xml(&key.target.id())
+            )?;
+            let mut data = vec![
+                ("rule", key.rule_title.to_string()),
+                ("level", key.level.to_string()),
+                ("first_seen", format_time(&stats.first_seen)),
+                ("last_seen", format_time(&stats.last_seen)),
+                ("count", stats.count.to_string()),
+            ];
+            if let Some(logon_id) = &key.logon_id {
+                data.insert(2, ("logon_id", logon_id.to_string()));
+            }
+            for (name, value) in data {
+                writeln!(
+                    writer,
+                    r#"      <data key="{}">{}</data>"#,
+                    name,
+                    escape_xml(&value)
+                )?;
+            }
+            writeln!(writer, "    </edge>")?;
+        }
+        writeln!(writer, "  </graph>")?;
+        writeln!(writer, "</graphml>")
+    }
+
+    /// 可視化ツールで読み込めるように、nodesとedgesの配列を持つJSONにする
+    pub fn to_json(&self) -> Value {
+        let nodes: Vec<Value> = self
+            .nodes()
+            .iter()
+            .map(|node| json!({"id": node.id(), "type": node.kind.name(), "label": node.name}))
+            .collect();
+        let edges: Vec<Value> = self
+            .edges
+            .iter()
+            .map(|(key, stats)| {
+                json!({
+                    "source": key.source.id(),
+                    "target": key.target.id(),
+                    "rule": key.rule_title,
+                    "level": key.level,
+                    "logon_id": key.logon_id,
+                    "first_seen": format_time(&stats.first_seen),
+                    "last_seen": format_time(&stats.last_seen),
+                    "count": stats.count,
+                })
+            })
+            .collect();
+        json!({"nodes": nodes, "edges": edges})
+    }
+}
+
+/// レコードからENTITY_FIELDSの値を取り出す
+fn get_entities(alias_config: &EventKeyAliasConfig, record: &Value) -> Vec<Entity> {
+    let mut entities: Vec<Entity> = ENTITY_FIELDS
+        .iter()
+        .filter_map(|(kind, field)| {
+            get_value(alias_config, field, record).map(|value| Entity::new(*kind, &value))
+        })
+        .collect();
+    entities.sort();
+    entities.dedup();
+    entities
+}
+
+/// eventkey_aliasで解決したフィールドの値を取得する。フィールドが無い場合やノードにしない値の場合はNoneを返す
+fn get_value(alias_config: &EventKeyAliasConfig, field: &str, record: &Value) -> Option<String> {
+    let value = utils::get_event_value_with_alias(alias_config, field, record)
+        .filter(|value| !value.is_null())?;
+    get_serde_number_to_string(value).filter(|value| is_entity_value(value))
+}
+
+/// ピボットキーワードと同様に、空の値とループバックアドレスはノードにしない
+fn is_entity_value(value: &str) -> bool {
+    !(value.is_empty() || value == "-" || value == "127.0.0.1" || value == "::1")
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// 検知結果からエンティティの関係のグラフを作成し、全ての検知処理が終了した後にGraphMLもしくはJSONのファイルに保存するsink
+pub struct GraphSink {
+    path: PathBuf,
+    min_level: String,
+    start_time: Option<DateTime<Utc>>,
+    end_time: Option<DateTime<Utc>>,
+    graph: Mutex<EntityGraph>,
+}
+
+impl GraphSink {
+    /// pathの拡張子が.jsonの場合はJSON、それ以外はGraphMLで保存する
+    pub fn new(path: PathBuf) -> GraphSink {
+        GraphSink {
+            path,
+            min_level: "informational".to_string(),
+            start_time: Option::None,
+            end_time: Option::None,
+            graph: Mutex::new(EntityGraph::new()),
+        }
+    }
+
+    /// グラフに追加する検知結果の最低レベル
+    pub fn min_level(mut self, min_level: &str) -> Self {
+        self.min_level = min_level.to_string();
+        self
+    }
+
+    /// グラフに追加する検知結果の期間
+    pub fn time_window(
+        mut self,
+        start_time: Option<DateTime<Utc>>,
+        end_time: Option<DateTime<Utc>>,
+    ) -> Self {
+        self.start_time = start_time;
+        self.end_time = end_time;
+        self
+    }
+
+    fn is_target(&self, event: &DetectionEvent) -> bool {
+        let level = LEVELMAP
+            .get(&event.detect_info.level.to_uppercase())
+            .unwrap_or(&0);
+        let min_level = LEVELMAP.get(&self.min_level.to_uppercase()).unwrap_or(&1);
+        level >= min_level
+            && !matches!(self.start_time, Some(start) if event.time < start)
+            && !matches!(self.end_time, Some(end) if event.time > end)
+    }
+}
+
+impl DetectionSink for GraphSink {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        // aggregation conditionの検知結果は元のレコードが無いため、グラフに追加しない
+        if let Some(record_info) = event.record {
+            if self.is_target(event) {
+                self.graph.lock().unwrap().add(
+                    &record_info.alias_config,
+                    &record_info.record,
+                    &event.detect_info.alert,
+                    &event.detect_info.level,
+                    event.time,
+                );
+            }
+        }
+        Result::Ok(())
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        let file = File::create(&self.path)
+            .map_err(|e| format!("Failed to open file. {} {}", self.path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+        let graph = self.graph.lock().unwrap();
+        if utils::is_json_output(&self.path.to_string_lossy()) {
+            serde_json::to_writer_pretty(&mut writer, &graph.to_json())
+                .map_err(|e| e.to_string())?;
+        } else {
+            graph
+                .write_graphml(&mut writer)
+                .map_err(|e| e.to_string())?;
+        }
+        writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::configs::{read_eventkey_alias, EVENTKEY_ALIAS};
+    use crate::detections::graph::{Entity, EntityGraph, EntityKind, GraphSink};
+    use crate::detections::print::DetectInfo;
+    use crate::detections::rule::create_rule;
+    use crate::detections::sink::{DetectionEvent, DetectionSink};
+    use crate::detections::utils;
+    use chrono::{TimeZone, Utc};
+    use serde_json::Value;
+    use std::fs::{read_to_string, remove_file, write};
+    use std::path::PathBuf;
+    use yaml_rust::YamlLoader;
+
+    fn create_record(user: &str, ip: &str, logon_id: &str) -> Value {
+        serde_json::json!({
+            "Event": {
+                "System": {"Computer": "DC01", "EventID": 4624},
+                "EventData": {
+                    "TargetUserName": user,
+                    "IpAddress": ip,
+                    "WorkstationName": "-",
+                    "TargetLogonId": logon_id,
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_add_edges() {
+        let mut graph = EntityGraph::new();
+        let time1 = Utc.ymd(2022, 1, 1).and_hms(0, 0, 0);
+        let time2 = Utc.ymd(2022, 1, 2).and_hms(0, 0, 0);
+        graph.add(
+            &EVENTKEY_ALIAS,
+            &create_record("alice", "10.0.0.1", "0x1"),
+            "Logon",
+            "low",
+            time2,
+        );
+        graph.add(
+            &EVENTKEY_ALIAS,
+            &create_record("alice", "10.0.0.1", "0x1"),
+            "Logon",
+            "low",
+            time1,
+        );
+        // ユーザが無い場合は接続元をコンピュータにつなげる。ループバックアドレスはノードにしない
+        graph.add(
+            &EVENTKEY_ALIAS,
+            &create_record("-", "10.0.0.2", "-"),
+            "Scan",
+            "medium",
+            time1,
+        );
+        graph.add(
+            &EVENTKEY_ALIAS,
+            &create_record("-", "127.0.0.1", "-"),
+            "Scan",
+            "medium",
+            time1,
+        );
+
+        let alice = Entity::new(EntityKind::User, "alice");
+        let host = Entity::new(EntityKind::Computer, "DC01");
+        let edges: Vec<(String, String, Option<String>, usize)> = graph
+            .edges
+            .iter()
+            .map(|(key, stats)| {
+                (
+                    key.source.id(),
+                    key.target.id(),
+                    key.logon_id.clone(),
+                    stats.count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                (alice.id(), host.id(), Some("0x1".to_string()), 2),
+                (
+                    "ip:10.0.0.1".to_string(),
+                    alice.id(),
+                    Some("0x1".to_string()),
+                    2
+                ),
+                ("ip:10.0.0.2".to_string(), host.id(), None, 1),
+            ]
+        );
+        let stats = graph.edges.values().next().unwrap();
+        assert_eq!((stats.first_seen, stats.last_seen), (time1, time2));
+        assert_eq!(graph.nodes().len(), 4);
+
+        // フィールドはレコードのeventkey_aliasの設定で解決する
+        let alias_path = "./test_graph_eventkey_alias.txt";
+        write(
+            alias_path,
+            "alias,event_key\nTargetUserName,Event.EventData.AccountName\n",
+        )
+        .unwrap();
+        let alias_config = read_eventkey_alias(alias_path).unwrap();
+        remove_file(alias_path).unwrap();
+        let mut graph = EntityGraph::new();
+        graph.add(
+            &alias_config,
+            &serde_json::json!({
+                "Event": {"System": {"Computer": "DC01"}, "EventData": {"AccountName": "bob"}}
+            }),
+            "Logon",
+            "low",
+            time1,
+        );
+        let bob = Entity::new(EntityKind::User, "bob");
+        assert_eq!(
+            graph.edges.keys().next().map(|key| key.source.id()),
+            Some(bob.id())
+        );
+    }
+
+    #[test]
+    fn test_graph_output() {
+        let mut graph = EntityGraph::new();
+        let time = Utc.ymd(2022, 1, 1).and_hms(0, 0, 0);
+        graph.add(
+            &EVENTKEY_ALIAS,
+            &create_record("a<b>", "10.0.0.1", "0x1"),
+            "R&D",
+            "high",
+            time,
+        );
+
+        let json = graph.to_json();
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 3);
+        assert_eq!(json["nodes"][0]["id"], "user:a<b>");
+        assert_eq!(json["edges"][0]["source"], "user:a<b>");
+        assert_eq!(json["edges"][0]["target"], "computer:DC01");
+        assert_eq!(json["edges"][0]["rule"], "R&D");
+        assert_eq!(json["edges"][0]["logon_id"], "0x1");
+        assert_eq!(json["edges"][0]["count"], 1);
+
+        let mut graphml = vec![];
+        graph.write_graphml(&mut graphml).unwrap();
+        let graphml = String::from_utf8(graphml).unwrap();
+        assert!(graphml.contains(r#"<node id="user:a&lt;b&gt;">"#));
+        assert!(
+            graphml.contains(r#"<edge id="e0" source="user:a&lt;b&gt;" target="computer:DC01">"#)
+        );
+        assert!(graphml.contains(r#"<data key="rule">R&amp;D</data>"#));
+        assert!(graphml.contains(r#"<data key="logon_id">0x1</data>"#));
+        assert!(graphml.ends_with("</graphml>\n"));
+    }
+
+    #[test]
+    fn test_graph_sink_filter() {
+        let path = PathBuf::from("./test_graph_sink.json");
+        let sink = GraphSink::new(path.clone())
+            .min_level("medium")
+            .time_window(
+                Some(Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)),
+                Some(Utc.ymd(2022, 1, 31).and_hms(0, 0, 0)),
+            );
+        let rule_yaml = YamlLoader::load_from_str("title: test").unwrap().remove(0);
+        let rule = create_rule("test-rule.yml".to_string(), rule_yaml);
+        // bobはレベルが低く、daveは期間外のためグラフに追加しない
+        for (user, level, time) in [
+            ("alice", "high", Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)),
+            ("bob", "low", Utc.ymd(2022, 1, 2).and_hms(0, 0, 0)),
+            ("carol", "medium", Utc.ymd(2022, 1, 31).and_hms(0, 0, 0)),
+            ("dave", "critical", Utc.ymd(2021, 12, 31).and_hms(0, 0, 0)),
+        ] {
+            let record = create_record(user, "-", "-");
+            let detect_info = DetectInfo {
+                filepath: "test.evtx".to_string(),
+                rulepath: "test-rule.yml".to_string(),
+                level: level.to_string(),
+                computername: "DC01".to_string(),
+                eventid: "4624".to_string(),
+                alert: "test".to_string(),
+                detail: "detail".to_string(),
+                tag_info: String::default(),
+                record_information: Option::None,
+            };
+            let record_info = utils::create_rec_info(record, "test.evtx".to_string(), &[]);
+            let event = DetectionEvent {
+                time,
+                detect_info: &detect_info,
+                record: Option::Some(&record_info),
+                rule: &rule,
+            };
+            assert!(sink.on_detect(&event).is_ok());
+        }
+        assert!(sink.finish().is_ok());
+
+        let json: Value = serde_json::from_str(&read_to_string(&path).unwrap()).unwrap();
+        remove_file(&path).unwrap();
+        let sources: Vec<&str> = json["edges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|edge| edge["source"].as_str().unwrap())
+            .collect();
+        assert_eq!(sources, vec!["user:alice", "user:carol"]);
+    }
+}
@@ -0,0 +1,585 @@
+use crate::detections::configs::{EventKeyAliasConfig, LEVELMAP};
+use crate::detections::rule::{self, RuleNode};
+use crate::detections::utils::{self, get_serde_number_to_string};
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder};
+use hashbrown::{HashMap, HashSet};
+use serde_json::Value;
+use std::path::Path;
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+/// IOCの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IocType {
+    Hash,
+    Ip,
+    Domain,
+    Path,
+    Pipe,
+}
+
+impl IocType {
+    /// IOCファイルの種類の列の値を変換する。ハッシュはアルゴリズム名も受け付ける
+    pub fn parse(value: &str) -> Option<IocType> {
+        match value.trim().to_lowercase().as_str() {
+            "hash" | "md5" | "sha1" | "sha256" | "imphash" => Option::Some(IocType::Hash),
+            "ip" | "ipv4" | "ipv6" | "ip-addr" => Option::Some(IocType::Ip),
+            "domain" | "hostname" | "fqdn" => Option::Some(IocType::Domain),
+            "path" | "file" | "filepath" | "filename" => Option::Some(IocType::Path),
+            "pipe" | "named-pipe" | "namedpipe" => Option::Some(IocType::Pipe),
+            _ => Option::None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IocType::Hash => "Hash",
+            IocType::Ip => "IP",
+            IocType::Domain => "Domain",
+            IocType::Path => "Path",
+            IocType::Pipe => "Pipe",
+        }
+    }
+
+    /// 照合するフィールド。eventkey_aliasで解決する
+    fn fields(&self) -> &'static [&'static str] {
+        match self {
+            IocType::Hash => &["Hashes", "Hash"],
+            IocType::Ip => &[
+                "IpAddress",
+                "SourceIp",
+                "DestinationIp",
+                "SourceAddress",
+                "DestAddress",
+                "ClientAddress",
+                "QueryResults",
+            ],
+            IocType::Domain => &["QueryName", "DestinationHostname", "TargetServerName"],
+            IocType::Path => &[
+                "Image",
+                "ParentImage",
+                "TargetFilename",
+                "ImageLoaded",
+                "NewProcessName",
+                "ParentProcessName",
+                "ProcessName",
+                "CommandLine",
+                "ParentCommandLine",
+            ],
+            IocType::Pipe => &["PipeName", "RelativeTargetName"],
+        }
+    }
+}
+
+/// IOCファイルの1行分の情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Indicator {
+    pub ioc_type: IocType,
+    pub value: String,
+    pub source: String,
+    pub description: String,
+    pub level: String,
+}
+
+impl Indicator {
+    pub fn new(ioc_type: IocType, value: &str, source: &str, description: &str) -> Indicator {
+        Indicator {
+            ioc_type,
+            value: value.trim().to_string(),
+            source: source.to_string(),
+            description: description.to_string(),
+            level: "high".to_string(),
+        }
+    }
+
+    /// 検知結果の出力先に渡すルールのYAML。タイトルとレベルだけを持つ
+    pub fn to_rule_yaml(&self) -> Yaml {
+        let mut hash = Hash::new();
+        hash.insert(
+            Yaml::String("title".to_string()),
+            Yaml::String(format!("IOC Match ({})", self.ioc_type.name())),
+        );
+        hash.insert(
+            Yaml::String("level".to_string()),
+            Yaml::String(self.level.to_string()),
+        );
+        Yaml::Hash(hash)
+    }
+}
+
+/// レコードに一致したIOC
+#[derive(Debug)]
+pub struct IocMatch<'a> {
+    pub indicator: &'a Indicator,
+    /// 検知結果の出力先に渡すルール
+    pub rule: &'a RuleNode,
+    pub field: &'static str,
+}
+
+/// IOCファイルの全ての指標を全てのレコードと照合する。
+/// ハッシュとIPアドレスは完全一致、ドメインはサブドメインを含めて一致するかをHashMapで判定し、
+/// ファイルパスと名前付きパイプは大文字小文字を区別しない部分一致をAho-Corasickでまとめて判定する。
+/// 大文字小文字の区別はASCIIの文字のみ無視するため、ASCII以外の文字は大文字小文字が一致する場合のみ一致する
+#[derive(Debug)]
+pub struct IocMatcher {
+    pub path: String,
+    indicators: Vec<Indicator>,
+    /// 指標ごとの検知結果の出力先に渡すルール。一致するたびに作成しないように読み込み時に作成する
+    rules: Vec<RuleNode>,
+    hashes: HashMap<String, Vec<usize>>,
+    ips: HashMap<String, Vec<usize>>,
+    domains: HashMap<String, Vec<usize>>,
+    paths: Option<(AhoCorasick, Vec<usize>)>,
+    pipes: Option<(AhoCorasick, Vec<usize>)>,
+}
+
+impl IocMatcher {
+    /// IOCファイルを読み込む。拡張子が.jsonの場合はJSON、それ以外はCSVとして読み込む
+    pub fn load(path: &str) -> Result<IocMatcher, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to open the IOC file. {} {}", path, e))?;
+        let source = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let indicators = if utils::is_json_output(path) {
+            parse_json(&contents, &source)
+        } else {
+            parse_csv(&contents, &source)
+        }
+        .map_err(|e| format!("Failed to load the IOC file. {} {}", path, e))?;
+        if indicators.is_empty() {
+            return Result::Err(format!(
+                "No indicators were found in the IOC file. {}",
+                path
+            ));
+        }
+        Result::Ok(IocMatcher::new(path, indicators))
+    }
+
+    pub fn new(path: &str, indicators: Vec<Indicator>) -> IocMatcher {
+        let mut hashes: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut ips: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut domains: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut paths = vec![];
+        let mut pipes = vec![];
+        for (idx, indicator) in indicators.iter().enumerate() {
+            match indicator.ioc_type {
+                IocType::Hash => hashes
+                    .entry(indicator.value.to_uppercase())
+                    .or_default()
+                    .push(idx),
+                IocType::Ip => ips
+                    .entry(normalize_ip(&indicator.value))
+                    .or_default()
+                    .push(idx),
+                IocType::Domain => domains
+                    .entry(normalize_domain(&indicator.value))
+                    .or_default()
+                    .push(idx),
+                IocType::Path => paths.push(idx),
+                IocType::Pipe => pipes.push(idx),
+            }
+        }
+        let build_automaton = |idxes: Vec<usize>| {
+            if idxes.is_empty() {
+                return Option::None;
+            }
+            let automaton = AhoCorasickBuilder::new()
+                .ascii_case_insensitive(true)
+                .build(idxes.iter().map(|idx| &indicators[*idx].value));
+            Option::Some((automaton, idxes))
+        };
+        let rules = indicators
+            .iter()
+            .map(|indicator| rule::create_rule(path.to_string(), indicator.to_rule_yaml()))
+            .collect();
+        IocMatcher {
+            path: path.to_string(),
+            paths: build_automaton(paths),
+            pipes: build_automaton(pipes),
+            indicators,
+            rules,
+            hashes,
+            ips,
+            domains,
+        }
+    }
+
+    pub fn indicators(&self) -> &[Indicator] {
+        &self.indicators
+    }
+
+    /// レコードに一致したIOCを返す。同じIOCが複数のフィールドに一致した場合は最初のフィールドのみ返す
+    pub fn find_matches(
+        &self,
+        alias_config: &EventKeyAliasConfig,
+        record: &Value,
+    ) -> Vec<IocMatch<'_>> {
+        let mut matched_idxes = HashSet::new();
+        let mut ret = vec![];
+        let mut push = |idx: usize, field: &'static str| {
+            if matched_idxes.insert(idx) {
+                ret.push(IocMatch {
+                    indicator: &self.indicators[idx],
+                    rule: &self.rules[idx],
+                    field,
+                });
+            }
+        };
+
+        if !self.hashes.is_empty() {
+            for field in IocType::Hash.fields() {
+                let value = get_value(alias_config, field, record);
+                // SysmonのHashesは「SHA1=...,MD5=...」の形式なので、アルゴリズム名を除いたハッシュ値ごとに照合する
+                for hash in value.iter().flat_map(|value| value.split(',')) {
+                    let hash = hash.rsplit('=').next().unwrap_or_default();
+                    for idx in self
+                        .hashes
+                        .get(&hash.trim().to_uppercase())
+                        .into_iter()
+                        .flatten()
+                    {
+                        push(*idx, field);
+                    }
+                }
+            }
+        }
+        if !self.ips.is_empty() {
+            for field in IocType::Ip.fields() {
+                let value = get_value(alias_config, field, record);
+                // SysmonのQueryResultsは「;」区切りで複数のアドレスを持つ
+                let ips = value
+                    .iter()
+                    .flat_map(|value| value.split(|c: char| c == ';' || c.is_whitespace()));
+                for ip in ips {
+                    for idx in self.ips.get(&normalize_ip(ip)).into_iter().flatten() {
+                        push(*idx, field);
+                    }
+                }
+            }
+        }
+        if !self.domains.is_empty() {
+            for field in IocType::Domain.fields() {
+                let domain = match get_value(alias_config, field, record) {
+                    Some(domain) => normalize_domain(&domain),
+                    None => continue,
+                };
+                // 親ドメインのIOCにも一致させるため、先頭のラベルを1つずつ取り除きながら照合する
+                let mut suffix = domain.as_str();
+                loop {
+                    for idx in self.domains.get(suffix).into_iter().flatten() {
+                        push(*idx, field);
+                    }
+                    match suffix.split_once('.') {
+                        Some((_, parent)) => suffix = parent,
+                        None => break,
+                    }
+                }
+            }
+        }
+        for (ioc_type, automaton) in [(IocType::Path, &self.paths), (IocType::Pipe, &self.pipes)] {
+            let (automaton, idxes) = match automaton {
+                Some(automaton) => automaton,
+                None => continue,
+            };
+            for field in ioc_type.fields() {
+                let value = match get_value(alias_config, field, record) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                for found in automaton.find_overlapping_iter(&value) {
+                    let indicator = &self.indicators[idxes[found.pattern()]];
+                    if is_segment_start(&value, found.start(), &indicator.value) {
+                        push(idxes[found.pattern()], field);
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// パスの区切りとみなす文字。コマンドラインの引用符と空白も含む
+const SEGMENT_SEPARATORS: [char; 5] = ['\\', '/', '"', '\'', ' '];
+
+/// 「evil.exe」が「notevil.exe」に一致しないように、一致した位置がパスの要素の先頭かを判定する。
+/// 指標が区切り文字で始まる場合は、指標自体が要素の先頭を表すため一致した位置を問わない
+fn is_segment_start(value: &str, start: usize, pattern: &str) -> bool {
+    start == 0
+        || pattern.starts_with(SEGMENT_SEPARATORS)
+        || value[..start].ends_with(SEGMENT_SEPARATORS)
+}
+
+fn get_value(alias_config: &EventKeyAliasConfig, field: &str, record: &Value) -> Option<String> {
+    let value = utils::get_event_value_with_alias(alias_config, field, record)
+        .filter(|value| !value.is_null())?;
+    get_serde_number_to_string(value).filter(|value| !value.is_empty() && value != "-")
+}
+
+/// Sysmonのアドレスの「::ffff:」の接頭辞と括弧を取り除いて比較する
+fn normalize_ip(ip: &str) -> String {
+    let ip = ip.trim().trim_start_matches('[').trim_end_matches(']');
+    ip.strip_prefix("::ffff:").unwrap_or(ip).to_lowercase()
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// type,value,source,description,levelの列を持つCSVを読み込む。source以降の列は省略できる
+fn parse_csv(contents: &str, default_source: &str) -> Result<Vec<Indicator>, String> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .comment(Option::Some(b'#'))
+        .from_reader(contents.as_bytes());
+    let headers: Vec<String> = rdr
+        .headers()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|header| header.trim().to_lowercase())
+        .collect();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let (type_col, value_col) = match (column("type"), column("value")) {
+        (Some(type_col), Some(value_col)) => (type_col, value_col),
+        _ => return Result::Err("The type and value columns are required.".to_string()),
+    };
+    let (source_col, description_col, level_col) =
+        (column("source"), column("description"), column("level"));
+
+    let mut ret = vec![];
+    for (line, record) in rdr.records().enumerate() {
+        let record = record.map_err(|e| e.to_string())?;
+        let get = |col: Option<usize>| col.and_then(|col| record.get(col)).unwrap_or("");
+        let value = get(Option::Some(value_col));
+        if value.trim().is_empty() {
+            continue;
+        }
+        let ioc_type = IocType::parse(get(Option::Some(type_col))).ok_or_else(|| {
+            format!(
+                "Unknown IOC type. [line:{}] [type:{}]",
+                line + 2,
+                get(Option::Some(type_col))
+            )
+        })?;
+        let source = get(source_col);
+        let mut indicator = Indicator::new(
+            ioc_type,
+            value,
+            if source.is_empty() {
+                default_source
+            } else {
+                source
+            },
+            get(description_col),
+        );
+        if !get(level_col).trim().is_empty() {
+            indicator.level =
+                parse_level(get(level_col)).map_err(|e| format!("{} [line:{}]", e, line + 2))?;
+        }
+        ret.push(indicator);
+    }
+    Result::Ok(ret)
+}
+
+/// type,value,source,description,levelを持つオブジェクトの配列、もしくはindicators/objectsにその配列を持つJSONを読み込む。
+/// STIXのindicatorのように、valueの代わりに単純な比較のpatternを持つオブジェクトにも対応する
+fn parse_json(contents: &str, default_source: &str) -> Result<Vec<Indicator>, String> {
+    let json: Value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    let objects = json
+        .as_array()
+        .or_else(|| json["indicators"].as_array())
+        .or_else(|| json["objects"].as_array())
+        .ok_or_else(|| "An array of indicators was not found.".to_string())?;
+
+    let mut ret = vec![];
+    for object in objects {
+        let (ioc_type, value) = if let Some(pattern) = object["pattern"].as_str() {
+            parse_stix_pattern(pattern)
+                .ok_or_else(|| format!("Unsupported STIX pattern. [pattern:{}]", pattern))?
+        } else if let Some(value) = object["value"].as_str() {
+            let ioc_type = object["type"].as_str().unwrap_or_default();
+            let ioc_type = IocType::parse(ioc_type)
+                .ok_or_else(|| format!("Unknown IOC type. [type:{}]", ioc_type))?;
+            (ioc_type, value.to_string())
+        } else {
+            // STIXのbundleのindicator以外のオブジェクトは読み飛ばす
+            continue;
+        };
+        let description = object["description"]
+            .as_str()
+            .or_else(|| object["name"].as_str())
+            .unwrap_or_default();
+        let mut indicator = Indicator::new(
+            ioc_type,
+            &value,
+            object["source"].as_str().unwrap_or(default_source),
+            description,
+        );
+        if let Some(level) = object["level"].as_str() {
+            indicator.level = parse_level(level)?;
+        }
+        ret.push(indicator);
+    }
+    Result::Ok(ret)
+}
+
+/// IOCファイルのレベルの値を確認して小文字にする
+fn parse_level(level: &str) -> Result<String, String> {
+    let level = level.trim();
+    if !LEVELMAP.contains_key(&level.to_uppercase()) {
+        return Result::Err(format!("Unknown level. [level:{}]", level));
+    }
+    Result::Ok(level.to_lowercase())
+}
+
+/// 「[ipv4-addr:value = '10.0.0.1']」のような1つの比較のみのSTIXのpatternを変換する
+fn parse_stix_pattern(pattern: &str) -> Option<(IocType, String)> {
+    let pattern = pattern.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (object_path, value) = pattern.split_once('=')?;
+    let value = value.trim().strip_prefix('\'')?.strip_suffix('\'')?;
+    if value.contains('\'') {
+        return Option::None;
+    }
+    let object_path = object_path.trim();
+    let ioc_type = if object_path.starts_with("file:hashes") {
+        IocType::Hash
+    } else if object_path.starts_with("ipv4-addr:") || object_path.starts_with("ipv6-addr:") {
+        IocType::Ip
+    } else if object_path.starts_with("domain-name:") {
+        IocType::Domain
+    } else if object_path.starts_with("file:") || object_path.starts_with("directory:") {
+        IocType::Path
+    } else {
+        return Option::None;
+    };
+    Option::Some((ioc_type, value.replace("\\\\", "\\")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::configs::EVENTKEY_ALIAS;
+    use crate::detections::ioc::{parse_csv, parse_json, IocMatcher, IocType};
+    use serde_json::Value;
+
+    fn create_matcher() -> IocMatcher {
+        let contents = r#"type,value,source,description,level
+sha256,1F5A1F31E4C0B8D5A1D3B6E0B2BB7D5C6E0A9F0A8B8C3D2E1F0A9B8C7D6E5F4A,intel-a,Cobalt Strike beacon,critical
+ip,203.0.113.10,intel-a,C2 server
+domain,evil.example,intel-b,Phishing domain
+path,\Users\Public\beacon.exe,,Dropped payload
+pipe,\msagent_,intel-b,Cobalt Strike pipe
+"#;
+        IocMatcher::new("iocs.csv", parse_csv(contents, "iocs.csv").unwrap())
+    }
+
+    fn find_values(matcher: &IocMatcher, record: &str) -> Vec<(String, &'static str)> {
+        let record: Value = serde_json::from_str(record).unwrap();
+        matcher
+            .find_matches(&EVENTKEY_ALIAS, &record)
+            .into_iter()
+            .map(|found| (found.indicator.value.clone(), found.field))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let matcher = create_matcher();
+        let indicators = matcher.indicators();
+        assert_eq!(indicators.len(), 5);
+        assert_eq!(indicators[0].ioc_type, IocType::Hash);
+        assert_eq!(indicators[0].level, "critical");
+        assert_eq!(indicators[1].level, "high");
+        assert_eq!(indicators[3].source, "iocs.csv");
+        assert_eq!(indicators[4].description, "Cobalt Strike pipe");
+        assert!(parse_csv("type,value\nunknown,abc\n", "iocs.csv").is_err());
+        assert!(parse_csv("value\nabc\n", "iocs.csv").is_err());
+        assert_eq!(
+            parse_csv("type,value,level\nip,10.0.0.1,sever\n", "iocs.csv"),
+            Result::Err("Unknown level. [level:sever] [line:2]".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let contents = r#"{"objects": [
+            {"type": "identity", "name": "intel-c"},
+            {"type": "indicator", "name": "C2 domain", "pattern": "[domain-name:value = 'bad.example']"},
+            {"type": "indicator", "pattern": "[file:hashes.'SHA-256' = 'abcd']", "source": "intel-c", "level": "Medium"},
+            {"type": "ip", "value": "198.51.100.1", "description": "Scanner"}
+        ]}"#;
+        let indicators = parse_json(contents, "iocs.json").unwrap();
+        assert_eq!(indicators.len(), 3);
+        assert_eq!(indicators[0].ioc_type, IocType::Domain);
+        assert_eq!(indicators[0].value, "bad.example");
+        assert_eq!(indicators[0].description, "C2 domain");
+        assert_eq!(indicators[0].source, "iocs.json");
+        assert_eq!(indicators[1].ioc_type, IocType::Hash);
+        assert_eq!(indicators[1].level, "medium");
+        assert_eq!(indicators[2].ioc_type, IocType::Ip);
+        assert!(parse_json(
+            r#"[{"type": "indicator", "pattern": "[ipv4-addr:value = '1.1.1.1' AND ipv4-addr:value = '2.2.2.2']"}]"#,
+            "iocs.json"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_find_matches() {
+        let matcher = create_matcher();
+        let process = r#"{"Event": {"System": {"EventID": 1}, "EventData": {
+            "Image": "C:\\users\\public\\Beacon.exe",
+            "Hashes": "SHA1=AAAA,MD5=BBBB,SHA256=1f5a1f31e4c0b8d5a1d3b6e0b2bb7d5c6e0a9f0a8b8c3d2e1f0a9b8c7d6e5f4a"
+        }}}"#;
+        assert_eq!(
+            find_values(&matcher, process),
+            vec![
+                (
+                    "1F5A1F31E4C0B8D5A1D3B6E0B2BB7D5C6E0A9F0A8B8C3D2E1F0A9B8C7D6E5F4A".to_string(),
+                    "Hashes"
+                ),
+                ("\\Users\\Public\\beacon.exe".to_string(), "Image"),
+            ]
+        );
+
+        let dns = r#"{"Event": {"System": {"EventID": 22}, "EventData": {
+            "QueryName": "cdn.EVIL.example",
+            "QueryResults": "type:  5 cdn.example;::ffff:203.0.113.10;"
+        }}}"#;
+        assert_eq!(
+            find_values(&matcher, dns),
+            vec![
+                ("203.0.113.10".to_string(), "QueryResults"),
+                ("evil.example".to_string(), "QueryName"),
+            ]
+        );
+
+        let pipe =
+            r#"{"Event": {"System": {"EventID": 17}, "EventData": {"PipeName": "\\MSAgent_f3"}}}"#;
+        assert_eq!(
+            find_values(&matcher, pipe),
+            vec![("\\msagent_".to_string(), "PipeName")]
+        );
+
+        let not_matched = r#"{"Event": {"System": {"EventID": 22}, "EventData": {"QueryName": "notevil.example", "IpAddress": "203.0.113.1"}}}"#;
+        assert!(find_values(&matcher, not_matched).is_empty());
+    }
+
+    #[test]
+    fn test_find_matches_path_boundary() {
+        // パスの要素の先頭から一致した場合のみ一致とする
+        let matcher = IocMatcher::new(
+            "iocs.csv",
+            parse_csv("type,value\npath,evil.exe\n", "iocs.csv").unwrap(),
+        );
+        let find = |image: &str| {
+            let record = serde_json::json!({"Event": {"EventData": {"Image": image}}});
+            matcher.find_matches(&EVENTKEY_ALIAS, &record).len()
+        };
+        assert_eq!(find("evil.exe"), 1);
+        assert_eq!(find("C:\\Temp\\EVIL.exe"), 1);
+        assert_eq!(find("/tmp/evil.exe"), 1);
+        assert_eq!(find("\"C:\\notevil.exe\" evil.exe"), 1);
+        assert_eq!(find("C:\\Temp\\notevil.exe"), 0);
+    }
+}
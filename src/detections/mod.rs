@@ -1,6 +1,10 @@
 pub mod configs;
 pub mod detection;
+pub mod geoip;
+pub mod graph;
+pub mod ioc;
 pub mod pivot;
 pub mod print;
 pub mod rule;
+pub mod sink;
 pub mod utils;
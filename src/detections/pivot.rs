@@ -1,21 +1,51 @@
+use chrono::{DateTime, Utc};
 use hashbrown::HashMap;
 use hashbrown::HashSet;
-use lazy_static::lazy_static;
-use serde_json::Value;
-use std::sync::RwLock;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::{Mutex, MutexGuard};
 
-use crate::detections::configs;
-use crate::detections::utils::get_serde_number_to_string;
+use crate::afterfact::format_time;
+use crate::detections::configs::LEVELMAP;
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::print::Message;
+use crate::detections::rule::RuleNode;
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::detections::utils::{self, get_serde_number_to_string};
 
 #[derive(Debug)]
 pub struct PivotKeyword {
-    pub keywords: HashSet<String>,
+    pub keywords: HashMap<String, KeywordStats>,
     pub fields: HashSet<String>,
 }
 
-lazy_static! {
-    pub static ref PIVOT_KEYWORD: RwLock<HashMap<String, PivotKeyword>> =
-        RwLock::new(HashMap::new());
+/// キーワードが見つかった回数、最初と最後の時刻、検知したルールとコンピュータ
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KeywordStats {
+    pub count: usize,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    /// (ルールのタイトル, レベル)
+    pub rules: BTreeSet<(String, String)>,
+    pub computers: BTreeSet<String>,
+}
+
+impl KeywordStats {
+    fn add(
+        &mut self,
+        time: Option<DateTime<Utc>>,
+        rule: (String, String),
+        computer: Option<String>,
+    ) {
+        self.count += 1;
+        self.first_seen = utils::min_time(self.first_seen, time);
+        self.last_seen = utils::max_time(self.last_seen, time);
+        self.rules.insert(rule);
+        if let Some(computer) = computer {
+            self.computers.insert(computer);
+        }
+    }
 }
 
 impl Default for PivotKeyword {
@@ -27,75 +57,204 @@ impl Default for PivotKeyword {
 impl PivotKeyword {
     pub fn new() -> PivotKeyword {
         PivotKeyword {
-            keywords: HashSet::new(),
+            keywords: HashMap::new(),
             fields: HashSet::new(),
         }
     }
+
+    /// 見つかった回数の多い順に並べ替えたキーワード。回数が同じ場合はキーワードの順にする
+    pub fn sorted_keywords(&self) -> Vec<(&String, &KeywordStats)> {
+        let mut keywords: Vec<(&String, &KeywordStats)> = self.keywords.iter().collect();
+        keywords.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(b.0)));
+        keywords
+    }
+
+    /// キーワードごとの集計結果をCSVで出力する
+    pub fn write_csv<W: Write>(&self, w: W) -> Result<(), String> {
+        let mut wtr = csv::Writer::from_writer(w);
+        wtr.write_record([
+            "Keyword",
+            "Count",
+            "FirstSeen",
+            "LastSeen",
+            "Rules",
+            "Levels",
+            "Computers",
+        ])
+        .map_err(|e| e.to_string())?;
+        for (keyword, stats) in self.sorted_keywords() {
+            let titles: Vec<&str> = stats
+                .rules
+                .iter()
+                .map(|(title, _)| title.as_str())
+                .collect();
+            let levels: BTreeSet<&str> = stats
+                .rules
+                .iter()
+                .map(|(_, level)| level.as_str())
+                .collect();
+            let computers: Vec<&str> = stats.computers.iter().map(|c| c.as_str()).collect();
+            wtr.write_record([
+                keyword.to_owned(),
+                stats.count.to_string(),
+                format_seen(&stats.first_seen),
+                format_seen(&stats.last_seen),
+                titles.join(" ¦ "),
+                levels.into_iter().collect::<Vec<&str>>().join(" ¦ "),
+                computers.join(" ¦ "),
+            ])
+            .map_err(|e| e.to_string())?;
+        }
+        wtr.flush().map_err(|e| e.to_string())
+    }
+
+    /// キーワードごとの集計結果をJSONの値にする
+    pub fn to_json(&self) -> Value {
+        let keywords: Vec<Value> = self
+            .sorted_keywords()
+            .into_iter()
+            .map(|(keyword, stats)| {
+                let rules: Vec<Value> = stats
+                    .rules
+                    .iter()
+                    .map(|(title, level)| json!({"Title": title, "Level": level}))
+                    .collect();
+                json!({
+                    "Keyword": keyword,
+                    "Count": stats.count,
+                    "FirstSeen": format_seen(&stats.first_seen),
+                    "LastSeen": format_seen(&stats.last_seen),
+                    "Rules": rules,
+                    "Computers": stats.computers,
+                })
+            })
+            .collect();
+        let mut fields: Vec<&String> = self.fields.iter().collect();
+        fields.sort();
+        json!({"Fields": fields, "Keywords": keywords})
+    }
+}
+
+fn format_seen(time: &Option<DateTime<Utc>>) -> String {
+    match time {
+        Some(time) => format_time(time),
+        None => "-".to_string(),
+    }
+}
+
+/// ルールに合致したレコードからピボットキーワードを収集するsink。
+/// 検知結果のルールのlevelがmin_level以上の場合、キーワードの種類ごとのフィールドの値を見つかった回数、時刻、ルール、コンピュータと一緒に記録する。
+/// aggregation conditionの検知結果は元のレコードが無いため収集しない
+pub struct PivotKeywordSink {
+    pivot_keywords: Mutex<HashMap<String, PivotKeyword>>,
+    min_level: u128,
 }
 
-///levelがlowより大きいレコードの場合、keywordがrecord内にみつかれば、
-///それをPIVOT_KEYWORD.keywordsに入れる。
-pub fn insert_pivot_keyword(event_record: &Value) {
-    //levelがlow異常なら続ける
-    let mut is_exist_event_key = false;
-    let mut tmp_event_record: &Value = event_record;
-    for s in ["Event", "System", "Level"] {
-        if let Some(record) = tmp_event_record.get(s) {
-            is_exist_event_key = true;
-            tmp_event_record = record;
+impl PivotKeywordSink {
+    /// pivot_keywordsはconfigs::load_pivot_keywordsで読み込んだキーワードの種類とフィールド。
+    /// min_levelはScanOptionsのpivot_min_levelを指定する。不正なレベルは呼び出し元でエラーにする
+    pub fn new(pivot_keywords: HashMap<String, PivotKeyword>, min_level: &str) -> PivotKeywordSink {
+        PivotKeywordSink {
+            pivot_keywords: Mutex::new(pivot_keywords),
+            min_level: *LEVELMAP.get(&min_level.to_uppercase()).unwrap_or(&0),
         }
     }
-    if is_exist_event_key {
-        let hash_value = get_serde_number_to_string(tmp_event_record);
 
-        if hash_value.is_some() && hash_value.as_ref().unwrap() == "infomational"
-            || hash_value.as_ref().unwrap() == "undefined"
-            || hash_value.as_ref().unwrap() == "-"
-        {
+    /// キーワードの種類ごとの収集結果
+    pub fn pivot_keywords(&self) -> MutexGuard<'_, HashMap<String, PivotKeyword>> {
+        self.pivot_keywords.lock().unwrap()
+    }
+
+    fn add(&self, record_info: &EvtxRecordInfo, rule: &RuleNode) {
+        // イベントのLevelではなく、ルールのlevelがmin_level以上の場合のみ収集する
+        let rule_level = rule.yaml["level"].as_str().unwrap_or("-");
+        if *LEVELMAP.get(&rule_level.to_uppercase()).unwrap_or(&0) < self.min_level {
             return;
         }
-    } else {
-        return;
-    }
 
-    for (_, pivot) in PIVOT_KEYWORD.write().unwrap().iter_mut() {
-        for field in &pivot.fields {
-            if let Some(array_str) = configs::EVENTKEY_ALIAS.get_event_key(&String::from(field)) {
-                let split: Vec<&str> = array_str.split('.').collect();
-                let mut is_exist_event_key = false;
-                let mut tmp_event_record: &Value = event_record;
-                for s in split {
-                    if let Some(record) = tmp_event_record.get(s) {
-                        is_exist_event_key = true;
-                        tmp_event_record = record;
+        let record = &record_info.record;
+        let time = Message::get_event_time(record);
+        let rule_info = (
+            rule.yaml["title"].as_str().unwrap_or("-").to_string(),
+            rule_level.to_string(),
+        );
+        let computer = record["Event"]["System"]["Computer"]
+            .as_str()
+            .map(|computer| computer.to_string());
+
+        for pivot in self.pivot_keywords().values_mut() {
+            for field in &pivot.fields {
+                let value =
+                    utils::get_event_value_with_alias(&record_info.alias_config, field, record)
+                        .filter(|value| !value.is_null())
+                        .and_then(get_serde_number_to_string);
+                if let Some(value) = value {
+                    if value == "-" || value == "127.0.0.1" || value == "::1" {
+                        continue;
                     }
-                }
-                if is_exist_event_key {
-                    let hash_value = get_serde_number_to_string(tmp_event_record);
-
-                    if let Some(value) = hash_value {
-                        if value == "-" || value == "127.0.0.1" || value == "::1" {
-                            continue;
-                        }
-                        pivot.keywords.insert(value);
-                    };
+                    pivot.keywords.entry(value).or_default().add(
+                        time,
+                        rule_info.clone(),
+                        computer.clone(),
+                    );
                 }
             }
         }
     }
 }
 
+impl DetectionSink for PivotKeywordSink {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        if let Some(record_info) = event.record {
+            self.add(record_info, event.rule);
+        }
+        Result::Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::detections::configs::load_pivot_keywords;
-    use crate::detections::pivot::insert_pivot_keyword;
-    use crate::detections::pivot::PIVOT_KEYWORD;
-    use serde_json;
+    use crate::detections::configs::{load_pivot_keywords, read_eventkey_alias};
+    use crate::detections::pivot::{PivotKeyword, PivotKeywordSink};
+    use crate::detections::rule::{create_rule, RuleNode};
+    use crate::detections::utils;
+    use chrono::{TimeZone, Utc};
+    use serde_json::{self, Value};
+    use std::sync::Arc;
+    use yaml_rust::YamlLoader;
+
+    fn create_test_rule(level: &str) -> RuleNode {
+        let rule_yaml =
+            YamlLoader::load_from_str(&format!("title: test_title\nlevel: '{}'", level))
+                .unwrap()
+                .remove(0);
+        create_rule("testpath".to_string(), rule_yaml)
+    }
+
+    fn create_test_sink() -> PivotKeywordSink {
+        PivotKeywordSink::new(
+            load_pivot_keywords("test_files/config/pivot_keywords.txt").unwrap(),
+            "low",
+        )
+    }
+
+    /// ルールのlevelがlevelの検知結果としてレコードをsinkに追加する
+    fn insert_test_record(sink: &PivotKeywordSink, record: Value, level: &str) {
+        let alias_config =
+            Arc::new(read_eventkey_alias("test_files/config/eventkey_alias.txt").unwrap());
+        let record_info = utils::create_rec_info_with_alias(
+            record,
+            "testpath".to_string(),
+            &[],
+            &alias_config,
+            false,
+        );
+        sink.add(&record_info, &create_test_rule(level));
+    }
 
-    //PIVOT_KEYWORDはグローバルなので、他の関数の影響も考慮する必要がある。
     #[test]
     fn insert_pivot_keyword_local_ip4() {
-        load_pivot_keywords("test_files/config/pivot_keywords.txt");
+        let sink = create_test_sink();
         let record_json_str = r#"
         {
             "Event": { 
@@ -107,20 +266,20 @@ mod tests {
                 }
             }
         }"#;
-        insert_pivot_keyword(&serde_json::from_str(record_json_str).unwrap());
+        insert_test_record(
+            &sink,
+            serde_json::from_str(record_json_str).unwrap(),
+            "high",
+        );
 
-        assert!(!PIVOT_KEYWORD
-            .write()
-            .unwrap()
-            .get_mut("Ip Addresses")
-            .unwrap()
+        assert!(!sink.pivot_keywords()["Ip Addresses"]
             .keywords
-            .contains("127.0.0.1"));
+            .contains_key("127.0.0.1"));
     }
 
     #[test]
     fn insert_pivot_keyword_ip4() {
-        load_pivot_keywords("test_files/config/pivot_keywords.txt");
+        let sink = create_test_sink();
         let record_json_str = r#"
         {
             "Event": { 
@@ -132,20 +291,20 @@ mod tests {
                 }
             }
         }"#;
-        insert_pivot_keyword(&serde_json::from_str(record_json_str).unwrap());
+        insert_test_record(
+            &sink,
+            serde_json::from_str(record_json_str).unwrap(),
+            "high",
+        );
 
-        assert!(PIVOT_KEYWORD
-            .write()
-            .unwrap()
-            .get_mut("Ip Addresses")
-            .unwrap()
+        assert!(sink.pivot_keywords()["Ip Addresses"]
             .keywords
-            .contains("10.0.0.1"));
+            .contains_key("10.0.0.1"));
     }
 
     #[test]
     fn insert_pivot_keyword_ip_empty() {
-        load_pivot_keywords("test_files/config/pivot_keywords.txt");
+        let sink = create_test_sink();
         let record_json_str = r#"
         {
             "Event": { 
@@ -157,20 +316,20 @@ mod tests {
                 }
             }
         }"#;
-        insert_pivot_keyword(&serde_json::from_str(record_json_str).unwrap());
+        insert_test_record(
+            &sink,
+            serde_json::from_str(record_json_str).unwrap(),
+            "high",
+        );
 
-        assert!(!PIVOT_KEYWORD
-            .write()
-            .unwrap()
-            .get_mut("Ip Addresses")
-            .unwrap()
+        assert!(!sink.pivot_keywords()["Ip Addresses"]
             .keywords
-            .contains("-"));
+            .contains_key("-"));
     }
 
     #[test]
     fn insert_pivot_keyword_local_ip6() {
-        load_pivot_keywords("test_files/config/pivot_keywords.txt");
+        let sink = create_test_sink();
         let record_json_str = r#"
         {
             "Event": { 
@@ -182,89 +341,167 @@ mod tests {
                 }
             }
         }"#;
-        insert_pivot_keyword(&serde_json::from_str(record_json_str).unwrap());
+        insert_test_record(
+            &sink,
+            serde_json::from_str(record_json_str).unwrap(),
+            "high",
+        );
 
-        assert!(!PIVOT_KEYWORD
-            .write()
-            .unwrap()
-            .get_mut("Ip Addresses")
-            .unwrap()
+        assert!(!sink.pivot_keywords()["Ip Addresses"]
             .keywords
-            .contains("::1"));
+            .contains_key("::1"));
     }
 
     #[test]
     fn insert_pivot_keyword_level_infomational() {
-        load_pivot_keywords("test_files/config/pivot_keywords.txt");
+        let sink = create_test_sink();
         let record_json_str = r#"
         {
             "Event": { 
                 "System": {
-                    "Level": "infomational"
+                    "Level": 4
                 },
                 "EventData": {
                     "IpAddress": "10.0.0.2"
                 }
             }
         }"#;
-        insert_pivot_keyword(&serde_json::from_str(record_json_str).unwrap());
+        insert_test_record(
+            &sink,
+            serde_json::from_str(record_json_str).unwrap(),
+            "informational",
+        );
 
-        assert!(!PIVOT_KEYWORD
-            .write()
-            .unwrap()
-            .get_mut("Ip Addresses")
-            .unwrap()
+        assert!(!sink.pivot_keywords()["Ip Addresses"]
             .keywords
-            .contains("10.0.0.2"));
+            .contains_key("10.0.0.2"));
     }
 
     #[test]
     fn insert_pivot_keyword_level_low() {
-        load_pivot_keywords("test_files/config/pivot_keywords.txt");
+        let sink = create_test_sink();
         let record_json_str = r#"
         {
             "Event": { 
                 "System": {
-                    "Level": "low"
+                    "Level": 4
                 },
                 "EventData": {
                     "IpAddress": "10.0.0.1"
                 }
             }
         }"#;
-        insert_pivot_keyword(&serde_json::from_str(record_json_str).unwrap());
+        insert_test_record(&sink, serde_json::from_str(record_json_str).unwrap(), "low");
 
-        assert!(PIVOT_KEYWORD
-            .write()
-            .unwrap()
-            .get_mut("Ip Addresses")
-            .unwrap()
+        assert!(sink.pivot_keywords()["Ip Addresses"]
             .keywords
-            .contains("10.0.0.1"));
+            .contains_key("10.0.0.1"));
     }
 
     #[test]
     fn insert_pivot_keyword_level_none() {
-        load_pivot_keywords("test_files/config/pivot_keywords.txt");
+        let sink = create_test_sink();
         let record_json_str = r#"
         {
             "Event": { 
                 "System": {
-                    "Level": "-"
+                    "Level": 4
                 },
                 "EventData": {
                     "IpAddress": "10.0.0.3"
                 }
             }
         }"#;
-        insert_pivot_keyword(&serde_json::from_str(record_json_str).unwrap());
+        insert_test_record(&sink, serde_json::from_str(record_json_str).unwrap(), "-");
 
-        assert!(!PIVOT_KEYWORD
-            .write()
-            .unwrap()
-            .get_mut("Ip Addresses")
-            .unwrap()
+        assert!(!sink.pivot_keywords()["Ip Addresses"]
             .keywords
-            .contains("10.0.0.3"));
+            .contains_key("10.0.0.3"));
+    }
+
+    #[test]
+    fn insert_pivot_keyword_stats() {
+        let sink = create_test_sink();
+        for (time, computer) in [
+            ("2022-01-02T00:00:00Z", "PC1"),
+            ("2022-01-01T00:00:00Z", "PC2"),
+        ] {
+            let record = serde_json::json!({
+                "Event": {
+                    "System": {
+                        "Level": "high",
+                        "Computer": computer,
+                        "TimeCreated_attributes": {"SystemTime": time}
+                    },
+                    "EventData": {"IpAddress": "10.0.0.9"}
+                }
+            });
+            insert_test_record(&sink, record, "high");
+        }
+
+        let pivot_keyword = sink.pivot_keywords();
+        let stats = &pivot_keyword["Ip Addresses"].keywords["10.0.0.9"];
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.first_seen, Some(Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)));
+        assert_eq!(stats.last_seen, Some(Utc.ymd(2022, 1, 2).and_hms(0, 0, 0)));
+        assert!(stats
+            .rules
+            .contains(&("test_title".to_string(), "high".to_string())));
+        assert_eq!(stats.computers.len(), 2);
+    }
+
+    #[test]
+    fn insert_pivot_keyword_independent_sinks() {
+        // sinkごとに収集するため、同時に実行する別のEngineの結果は混ざらない
+        let sink = create_test_sink();
+        let other_sink = create_test_sink();
+        let record = serde_json::json!({"Event": {"EventData": {"IpAddress": "10.0.0.4"}}});
+        insert_test_record(&sink, record, "high");
+
+        assert!(sink.pivot_keywords()["Ip Addresses"]
+            .keywords
+            .contains_key("10.0.0.4"));
+        assert!(other_sink.pivot_keywords()["Ip Addresses"]
+            .keywords
+            .is_empty());
+    }
+
+    #[test]
+    fn pivot_keyword_output() {
+        let mut pivot = PivotKeyword::new();
+        pivot.fields.insert("IpAddress".to_string());
+        let rule = ("test_title".to_string(), "high".to_string());
+        pivot
+            .keywords
+            .entry("10.0.0.1".to_string())
+            .or_default()
+            .add(Option::None, rule.clone(), Some("PC1".to_string()));
+        for _ in 0..2 {
+            pivot
+                .keywords
+                .entry("10.0.0.2".to_string())
+                .or_default()
+                .add(Option::None, rule.clone(), Some("PC2".to_string()));
+        }
+
+        let mut csv = vec![];
+        assert!(pivot.write_csv(&mut csv).is_ok());
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Keyword,Count,FirstSeen,LastSeen,Rules,Levels,Computers",
+                "10.0.0.2,2,-,-,test_title,high,PC2",
+                "10.0.0.1,1,-,-,test_title,high,PC1",
+            ]
+        );
+
+        let json = pivot.to_json();
+        assert_eq!(json["Fields"][0], "IpAddress");
+        assert_eq!(json["Keywords"][0]["Keyword"], "10.0.0.2");
+        assert_eq!(json["Keywords"][0]["Count"], 2);
+        assert_eq!(json["Keywords"][1]["Rules"][0]["Level"], "high");
+        assert_eq!(json["Keywords"][1]["Computers"][0], "PC1");
     }
 }
@@ -1,5 +1,6 @@
 extern crate lazy_static;
 use crate::detections::configs;
+use crate::detections::configs::EventKeyAliasConfig;
 use crate::detections::utils;
 use crate::detections::utils::get_serde_number_to_string;
 use chrono::{DateTime, Local, TimeZone, Utc};
@@ -14,7 +15,7 @@ use std::fs::File;
 use std::io::BufWriter;
 use std::io::{self, Write};
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug)]
 pub struct Message {
@@ -36,8 +37,17 @@ pub struct DetectInfo {
 
 pub struct AlertMessage {}
 
+/// 検知処理で発生したエラーとワーニングの出力先。verboseが有効な場合は表示もする。
+/// hayabusaのバイナリではコマンドライン引数から作成してERROR_LOG_STACKにログを追加し、ライブラリとして使う場合はEngineごとにログを保持する
+#[derive(Debug, Clone, Default)]
+pub struct ErrorLog {
+    verbose: bool,
+    quiet_errors: bool,
+    logs: Arc<Mutex<Vec<String>>>,
+}
+
 lazy_static! {
-    pub static ref MESSAGES: Mutex<Message> = Mutex::new(Message::new());
+    pub static ref MESSAGES: Arc<Mutex<Message>> = Arc::new(Mutex::new(Message::new()));
     pub static ref ALIASREGEX: Regex = Regex::new(r"%[a-zA-Z0-9-_]+%").unwrap();
     pub static ref ERROR_LOG_PATH: String = format!(
         "./logs/errorlog-{}.log",
@@ -48,12 +58,35 @@ lazy_static! {
         .unwrap()
         .args
         .is_present("quiet-errors");
-    pub static ref ERROR_LOG_STACK: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    pub static ref ERROR_LOG_STACK: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
     pub static ref STATISTICS_FLAG: bool = configs::CONFIG
         .read()
         .unwrap()
         .args
-        .is_present("statistics");
+        .is_present("statistics")
+        || *AGGREGATE_STATISTICS_FLAG;
+    pub static ref AGGREGATE_STATISTICS_FLAG: bool = configs::CONFIG
+        .read()
+        .unwrap()
+        .args
+        .is_present("aggregate-statistics");
+    pub static ref LOGON_SUMMARY_FLAG: bool = configs::CONFIG
+        .read()
+        .unwrap()
+        .args
+        .is_present("logon-summary");
+    pub static ref HISTOGRAM_FLAG: bool =
+        configs::CONFIG.read().unwrap().args.is_present("histogram");
+    pub static ref COVERAGE_FLAG: bool =
+        configs::CONFIG.read().unwrap().args.is_present("coverage");
+    pub static ref SESSIONS_FLAG: bool =
+        configs::CONFIG.read().unwrap().args.is_present("sessions")
+            || configs::CONFIG.read().unwrap().args.is_present("session");
+    pub static ref PROCESS_TREE_FLAG: bool = configs::CONFIG
+        .read()
+        .unwrap()
+        .args
+        .is_present("process-tree");
     pub static ref TAGS_CONFIG: HashMap<String, String> =
         Message::create_tags_config("config/output_tag.txt");
     pub static ref PIVOT_KEYWORD_LIST_FLAG: bool = configs::CONFIG
@@ -113,14 +146,25 @@ impl Message {
     }
 
     /// メッセージを設定
-    pub fn insert(&mut self, event_record: &Value, output: String, mut detect_info: DetectInfo) {
-        detect_info.detail = self.parse_message(event_record, output);
+    pub fn insert(
+        &mut self,
+        alias_config: &EventKeyAliasConfig,
+        event_record: &Value,
+        output: String,
+        mut detect_info: DetectInfo,
+    ) {
+        detect_info.detail = Message::parse_message(alias_config, event_record, output);
         let default_time = Utc.ymd(1970, 1, 1).and_hms(0, 0, 0);
         let time = Message::get_event_time(event_record).unwrap_or(default_time);
         self.insert_message(detect_info, time)
     }
 
-    fn parse_message(&mut self, event_record: &Value, output: String) -> String {
+    /// detailsに記載されたエイリアス(%Alias%)をレコードの値に置き換える
+    pub fn parse_message(
+        alias_config: &EventKeyAliasConfig,
+        event_record: &Value,
+        output: String,
+    ) -> String {
         let mut return_message: String = output;
         let mut hash_map: HashMap<String, String> = HashMap::new();
         for caps in ALIASREGEX.captures_iter(&return_message) {
@@ -132,12 +176,11 @@ impl Message {
                 .take(target_length)
                 .collect::<String>();
 
-            let array_str =
-                if let Some(_array_str) = configs::EVENTKEY_ALIAS.get_event_key(&target_str) {
-                    _array_str.to_string()
-                } else {
-                    "Event.EventData.".to_owned() + &target_str
-                };
+            let array_str = if let Some(_array_str) = alias_config.get_event_key(&target_str) {
+                _array_str.to_string()
+            } else {
+                "Event.EventData.".to_owned() + &target_str
+            };
 
             let split: Vec<&str> = array_str.split('.').collect();
             let mut is_exist_event_key = false;
@@ -207,6 +250,61 @@ impl Message {
     }
 }
 
+impl ErrorLog {
+    /// verboseが有効な場合はエラーとワーニングを表示する。quiet_errorsが有効な場合はログに追加しない
+    pub fn new(verbose: bool, quiet_errors: bool) -> ErrorLog {
+        ErrorLog {
+            verbose,
+            quiet_errors,
+            logs: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// コマンドライン引数のverboseとquiet-errorsを使い、ERROR_LOG_STACKにログを追加するErrorLogを作成する
+    pub fn from_config() -> ErrorLog {
+        ErrorLog {
+            verbose: configs::CONFIG.read().unwrap().args.is_present("verbose"),
+            quiet_errors: *QUIET_ERRORS_FLAG,
+            logs: Arc::clone(&ERROR_LOG_STACK),
+        }
+    }
+
+    pub fn is_verbose(&self) -> bool {
+        self.verbose
+    }
+
+    /// ERRORメッセージを標準エラー出力に表示してログに追加する
+    pub fn error(&self, contents: &str) {
+        if self.verbose {
+            AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), contents).ok();
+        }
+        if !self.quiet_errors {
+            self.logs
+                .lock()
+                .unwrap()
+                .push(format!("[ERROR] {}", contents));
+        }
+    }
+
+    /// WARNメッセージを標準出力に表示してログに追加する
+    pub fn warn(&self, contents: &str) {
+        if self.verbose {
+            AlertMessage::warn(&mut std::io::stdout().lock(), contents).ok();
+        }
+        if !self.quiet_errors {
+            self.logs
+                .lock()
+                .unwrap()
+                .push(format!("[WARN] {}", contents));
+        }
+    }
+
+    /// これまでに追加したログ
+    pub fn logs(&self) -> Vec<String> {
+        self.logs.lock().unwrap().clone()
+    }
+}
+
 impl AlertMessage {
     ///対象のディレクトリが存在することを確認後、最初の定型文を追加して、ファイルのbufwriterを返す関数
     pub fn create_error_log(path_str: String) {
@@ -250,12 +348,26 @@ impl AlertMessage {
 
 #[cfg(test)]
 mod tests {
+    use crate::detections::configs;
     use crate::detections::print::DetectInfo;
-    use crate::detections::print::{AlertMessage, Message};
+    use crate::detections::print::{AlertMessage, ErrorLog, Message};
     use hashbrown::HashMap;
     use serde_json::Value;
     use std::io::BufWriter;
 
+    #[test]
+    fn test_error_log() {
+        let error_log = ErrorLog::new(false, false);
+        error_log.error("error");
+        error_log.clone().warn("warn");
+        assert_eq!(error_log.logs(), vec!["[ERROR] error", "[WARN] warn"]);
+
+        // quiet_errorsが有効な場合はログに追加しない
+        let quiet_error_log = ErrorLog::new(false, true);
+        quiet_error_log.error("error");
+        assert!(quiet_error_log.logs().is_empty());
+    }
+
     #[test]
     fn test_create_and_append_message() {
         let mut message = Message::new();
@@ -275,6 +387,7 @@ mod tests {
     "##;
         let event_record_1: Value = serde_json::from_str(json_str_1).unwrap();
         message.insert(
+            &configs::EVENTKEY_ALIAS,
             &event_record_1,
             "CommandLine1: %CommandLine%".to_string(),
             DetectInfo {
@@ -306,6 +419,7 @@ mod tests {
     "##;
         let event_record_2: Value = serde_json::from_str(json_str_2).unwrap();
         message.insert(
+            &configs::EVENTKEY_ALIAS,
             &event_record_2,
             "CommandLine2: %CommandLine%".to_string(),
             DetectInfo {
@@ -337,6 +451,7 @@ mod tests {
     "##;
         let event_record_3: Value = serde_json::from_str(json_str_3).unwrap();
         message.insert(
+            &configs::EVENTKEY_ALIAS,
             &event_record_3,
             "CommandLine3: %CommandLine%".to_string(),
             DetectInfo {
@@ -363,6 +478,7 @@ mod tests {
     "##;
         let event_record_4: Value = serde_json::from_str(json_str_4).unwrap();
         message.insert(
+            &configs::EVENTKEY_ALIAS,
             &event_record_4,
             "CommandLine4: %CommandLine%".to_string(),
             DetectInfo {
@@ -401,7 +517,6 @@ mod tests {
     #[test]
     /// outputで指定されているキー(eventkey_alias.txt内で設定済み)から対象のレコード内の情報でメッセージをパースしているか確認する関数
     fn test_parse_message() {
-        let mut message = Message::new();
         let json_str = r##"
         {
             "Event": {
@@ -420,7 +535,8 @@ mod tests {
         let event_record: Value = serde_json::from_str(json_str).unwrap();
         let expected = "commandline:parsetest1 computername:testcomputer1";
         assert_eq!(
-            message.parse_message(
+            Message::parse_message(
+                &configs::EVENTKEY_ALIAS,
                 &event_record,
                 "commandline:%CommandLine% computername:%ComputerName%".to_owned()
             ),
@@ -430,7 +546,6 @@ mod tests {
 
     #[test]
     fn test_parse_message_auto_search() {
-        let mut message = Message::new();
         let json_str = r##"
         {
             "Event": {
@@ -443,7 +558,11 @@ mod tests {
         let event_record: Value = serde_json::from_str(json_str).unwrap();
         let expected = "alias:no_alias";
         assert_eq!(
-            message.parse_message(&event_record, "alias:%NoAlias%".to_owned()),
+            Message::parse_message(
+                &configs::EVENTKEY_ALIAS,
+                &event_record,
+                "alias:%NoAlias%".to_owned()
+            ),
             expected,
         );
     }
@@ -451,7 +570,6 @@ mod tests {
     #[test]
     /// outputで指定されているキーが、eventkey_alias.txt内で設定されていない場合の出力テスト
     fn test_parse_message_not_exist_key_in_output() {
-        let mut message = Message::new();
         let json_str = r##"
         {
             "Event": {
@@ -469,14 +587,17 @@ mod tests {
         let event_record: Value = serde_json::from_str(json_str).unwrap();
         let expected = "NoExistAlias:%NoAliasNoHit%";
         assert_eq!(
-            message.parse_message(&event_record, "NoExistAlias:%NoAliasNoHit%".to_owned()),
+            Message::parse_message(
+                &configs::EVENTKEY_ALIAS,
+                &event_record,
+                "NoExistAlias:%NoAliasNoHit%".to_owned()
+            ),
             expected,
         );
     }
     #[test]
     /// outputで指定されているキー(eventkey_alias.txt内で設定済み)が対象のレコード内に該当する情報がない場合の出力テスト
     fn test_parse_message_not_exist_value_in_record() {
-        let mut message = Message::new();
         let json_str = r##"
         {
             "Event": {
@@ -494,7 +615,8 @@ mod tests {
         let event_record: Value = serde_json::from_str(json_str).unwrap();
         let expected = "commandline:parsetest3 computername:%ComputerName%";
         assert_eq!(
-            message.parse_message(
+            Message::parse_message(
+                &configs::EVENTKEY_ALIAS,
                 &event_record,
                 "commandline:%CommandLine% computername:%ComputerName%".to_owned()
             ),
@@ -1,14 +1,10 @@
-use crate::detections::configs;
-use crate::detections::print::AlertMessage;
-use crate::detections::print::ERROR_LOG_STACK;
-use crate::detections::print::QUIET_ERRORS_FLAG;
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::print::ErrorLog;
 use crate::detections::rule::AggResult;
 use crate::detections::rule::Message;
 use crate::detections::rule::RuleNode;
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use hashbrown::HashMap;
-use serde_json::Value;
-use std::io::BufWriter;
 use std::num::ParseIntError;
 use std::path::Path;
 
@@ -17,8 +13,9 @@ use crate::detections::rule::aggregation_parser::AggregationConditionToken;
 use crate::detections::utils;
 
 /// 検知された際にカウント情報を投入する関数
-pub fn count(rule: &mut RuleNode, record: &Value) {
-    let key = create_count_key(rule, record);
+pub fn count(rule: &mut RuleNode, record_info: &EvtxRecordInfo) {
+    let record = &record_info.record;
+    let key = create_count_key(rule, record_info);
     let field_name: String = match rule.get_agg_condition() {
         None => String::default(),
         Some(aggcondition) => aggcondition
@@ -28,7 +25,7 @@ pub fn count(rule: &mut RuleNode, record: &Value) {
             .to_owned(),
     };
     let field_value =
-        get_alias_value_in_record(rule, &field_name, record, false).unwrap_or_default();
+        get_alias_value_in_record(rule, &field_name, record_info, false).unwrap_or_default();
     let default_time = Utc.ymd(1977, 1, 1).and_hms(0, 0, 0);
     countup(
         rule,
@@ -58,15 +55,23 @@ pub fn countup(
 fn get_alias_value_in_record(
     rule: &RuleNode,
     alias: &str,
-    record: &Value,
+    record_info: &EvtxRecordInfo,
     is_by_alias: bool,
 ) -> Option<String> {
     if alias.is_empty() {
         return None;
     }
-    match utils::get_event_value(alias, record) {
+    let record = &record_info.record;
+    match utils::get_event_value_with_alias(&record_info.alias_config, alias, record) {
         Some(value) => Some(value.to_string().replace('\"', "")),
         None => {
+            let event_id = utils::get_event_value_with_alias(
+                &record_info.alias_config,
+                &utils::get_event_id_key(),
+                record,
+            )
+            .map(|event_id| event_id.to_string())
+            .unwrap_or_default();
             let errmsg = match is_by_alias {
                 true => format!(
           "count by clause alias value not found in count process. rule file:{} EventID:{}",
@@ -75,7 +80,7 @@ fn get_alias_value_in_record(
             .unwrap()
             .to_str()
             .unwrap(),
-          utils::get_event_value(&utils::get_event_id_key(), record).unwrap()
+          event_id
         ),
                 false => format!(
           "count field clause alias value not found in count process. rule file:{} EventID:{}",
@@ -84,18 +89,10 @@ fn get_alias_value_in_record(
             .unwrap()
             .to_str()
             .unwrap(),
-          utils::get_event_value(&utils::get_event_id_key(), record).unwrap()
+          event_id
         ),
             };
-            if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &errmsg).ok();
-            }
-            if !*QUIET_ERRORS_FLAG {
-                ERROR_LOG_STACK
-                    .lock()
-                    .unwrap()
-                    .push(format!("[ERROR] {}", errmsg));
-            }
+            rule.error_log.error(&errmsg);
             None
         }
     }
@@ -104,11 +101,11 @@ fn get_alias_value_in_record(
 /// countでgroupbyなどの情報を区分するためのハッシュマップのキーを作成する関数。
 /// 以下の場合は空文字を返却
 /// groupbyの指定がない、groubpbyで指定したエイリアスがレコードに存在しない場合は_のみとする。空文字ではキーを指定してデータを取得することができなかった
-pub fn create_count_key(rule: &RuleNode, record: &Value) -> String {
+pub fn create_count_key(rule: &RuleNode, record_info: &EvtxRecordInfo) -> String {
     let agg_condition = rule.get_agg_condition().unwrap();
     if agg_condition._by_field_name.is_some() {
         let by_field_key = agg_condition._by_field_name.as_ref().unwrap();
-        get_alias_value_in_record(rule, by_field_key, record, true)
+        get_alias_value_in_record(rule, by_field_key, record_info, true)
             .unwrap_or_else(|| "_".to_string())
     } else {
         "_".to_string()
@@ -126,6 +123,56 @@ pub fn aggregation_condition_select(rule: &RuleNode) -> Vec<AggResult> {
     ret
 }
 
+/// watchモードでtimeframeがないルールのカウント情報を保持する秒数。
+/// watchモードは終了しないため、条件を満たさないレコードが増え続けないように最新のレコードから1日より前のレコードは削除する
+const ROLLING_DEFAULT_TIMEFRAME: i64 = 86400;
+
+/// watchモードで新しいレコードを検知するたびにaggregation conditionを判定する関数。
+/// 条件を満たしたtimeframe内のレコードは削除して、同じ検知結果を再度出力しないようにする。
+/// また、最新のレコードからtimeframe以上前のレコードは今後条件を満たすtimeframeに含まれないため削除する。
+/// timeframeがないルールはROLLING_DEFAULT_TIMEFRAMEをtimeframeとして判定する。
+///
+/// ==と<、<=はレコードが増えると条件を満たさなくなるため、timeframeの期間が全て経過した(期間より後のレコードを検知した)timeframeのみ判定する
+pub fn rolling_aggregation_condition_select(rule: &mut RuleNode) -> Vec<AggResult> {
+    let frame_sec = get_sec_timeframe(rule).unwrap_or(ROLLING_DEFAULT_TIMEFRAME);
+    let frame = Duration::seconds(frame_sec);
+    let mut value_map = std::mem::take(&mut rule.countdata);
+    let latest = value_map
+        .values()
+        .flatten()
+        .map(|data| data.record_time)
+        .max();
+    let latest = match latest {
+        Some(latest) => latest,
+        None => return Vec::new(),
+    };
+    let wait_full_frame = !matches!(
+        rule.get_agg_condition()
+            .map(|agg_condition| &agg_condition._cmp_op),
+        Some(AggregationConditionToken::GE) | Some(AggregationConditionToken::GT)
+    );
+
+    let mut ret = Vec::new();
+    for (key, value) in value_map.iter_mut() {
+        let agg_results: Vec<AggResult> = judge_timeframe_with_frame(rule, value, key, frame_sec)
+            .into_iter()
+            .filter(|agg_result| !wait_full_frame || agg_result.start_timedate + frame < latest)
+            .collect();
+        for agg_result in &agg_results {
+            let start = agg_result.start_timedate;
+            value.retain(|data| data.record_time < start || data.record_time > start + frame);
+        }
+        ret.extend(agg_results);
+    }
+
+    for value in value_map.values_mut() {
+        value.retain(|data| data.record_time >= latest - frame);
+    }
+    value_map.retain(|_, value| !value.is_empty());
+    rule.countdata = value_map;
+    ret
+}
+
 /// aggregation condition内での条件式を文字として返す関数
 pub fn get_str_agg_eq(rule: &RuleNode) -> String {
     //この関数はaggregation ruleのパースが正常終了した後に呼ばれる想定のためOptionの判定は行わない
@@ -172,7 +219,7 @@ pub struct TimeFrameInfo {
 
 impl TimeFrameInfo {
     /// timeframeの文字列をパースし、構造体を返す関数
-    pub fn parse_tframe(value: String) -> TimeFrameInfo {
+    pub fn parse_tframe(value: String, error_log: &ErrorLog) -> TimeFrameInfo {
         let mut ttype: String = "".to_string();
         let mut tnum = value.clone();
         if value.contains('s') {
@@ -189,15 +236,7 @@ impl TimeFrameInfo {
             tnum.retain(|c| c != 'd');
         } else {
             let errmsg = format!("Timeframe is invalid. Input value:{}", value);
-            if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &errmsg).ok();
-            }
-            if !*QUIET_ERRORS_FLAG {
-                ERROR_LOG_STACK
-                    .lock()
-                    .unwrap()
-                    .push(format!("[ERROR] {}", errmsg));
-            }
+            error_log.error(&errmsg);
         }
         TimeFrameInfo {
             timetype: ttype,
@@ -225,15 +264,7 @@ pub fn get_sec_timeframe(rule: &RuleNode) -> Option<i64> {
         }
         Err(err) => {
             let errmsg = format!("Timeframe number is invalid. timeframe. {}", err);
-            if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &errmsg).ok();
-            }
-            if !*QUIET_ERRORS_FLAG {
-                ERROR_LOG_STACK
-                    .lock()
-                    .unwrap()
-                    .push(format!("[ERROR] {}", errmsg));
-            }
+            rule.error_log.error(&errmsg);
             Option::None
         }
     }
@@ -449,6 +480,33 @@ pub fn judge_timeframe(
     rule: &RuleNode,
     time_datas: &[AggRecordTimeInfo],
     key: &str,
+) -> Vec<AggResult> {
+    if time_datas.is_empty() {
+        return Vec::new();
+    }
+
+    // timeframeの設定がルールにない時は最初と最後の要素の時間差をtimeframeに設定する。
+    let first = time_datas
+        .iter()
+        .map(|data| data.record_time)
+        .min()
+        .unwrap();
+    let last = time_datas
+        .iter()
+        .map(|data| data.record_time)
+        .max()
+        .unwrap();
+    let def_frame = last.timestamp() - first.timestamp();
+    let frame = get_sec_timeframe(rule).unwrap_or(def_frame);
+    judge_timeframe_with_frame(rule, time_datas, key, frame)
+}
+
+/// timeframeの秒数を指定してjudge_timeframeと同じ判定を行う関数
+fn judge_timeframe_with_frame(
+    rule: &RuleNode,
+    time_datas: &[AggRecordTimeInfo],
+    key: &str,
+    frame: i64,
 ) -> Vec<AggResult> {
     let mut ret: Vec<AggResult> = Vec::new();
     if time_datas.is_empty() {
@@ -459,11 +517,6 @@ pub fn judge_timeframe(
     let mut datas = time_datas.to_owned();
     datas.sort_by(|a, b| a.record_time.cmp(&b.record_time));
 
-    // timeframeの設定がルールにない時は最初と最後の要素の時間差をtimeframeに設定する。
-    let def_frame = datas.last().unwrap().record_time.timestamp()
-        - datas.first().unwrap().record_time.timestamp();
-    let frame = get_sec_timeframe(rule).unwrap_or(def_frame);
-
     // left <= i < rightの範囲にあるdata[i]がtimeframe内にあるデータであると考える
     let mut left: i64 = 0;
     let mut right: i64 = 0;
@@ -499,6 +552,7 @@ mod tests {
     use crate::detections;
     use crate::detections::rule::create_rule;
     use crate::detections::rule::AggResult;
+    use crate::detections::rule::RuleNode;
     use crate::detections::utils;
     use hashbrown::HashMap;
 
@@ -835,6 +889,173 @@ mod tests {
         let judge_result = rule_node.judge_satisfy_aggcondition();
         assert_eq!(judge_result.len(), 0);
     }
+    #[test]
+    /// 別々のRuleNodeでカウントしたレコードを統合した場合に、1つのRuleNodeでカウントした場合と同じ判定結果になることを確認する
+    fn test_merge_countdata() {
+        let record_str: &str = r#"
+        {
+          "Event": {
+            "System": {
+              "EventID": 7040,
+              "Channel": "System",
+              "TimeCreated_attributes": {
+                "SystemTime": "1977-01-01T00:05:00Z"
+              }
+            }
+          }
+        }"#;
+        let rule_str = r#"
+        enabled: true
+        detection:
+            selection1:
+                Channel: 'System'
+            condition: selection1 | count() >= 2
+            timeframe: 1h
+        "#;
+        let mut rule_nodes: Vec<_> = [SIMPLE_RECORD_STR, record_str]
+            .iter()
+            .map(|record| {
+                let test = YamlLoader::load_from_str(rule_str).unwrap().remove(0);
+                let mut rule_node = create_rule("testpath".to_string(), test);
+                assert!(rule_node.init().is_ok());
+                let keys = detections::rule::get_detection_keys(&rule_node);
+                let recinfo = utils::create_rec_info(
+                    serde_json::from_str(record).unwrap(),
+                    "testpath".to_owned(),
+                    &keys,
+                );
+                let _result = rule_node.select(&recinfo);
+                assert!(rule_node.check_exist_countdata());
+                // 1件ずつでは条件を満たさない
+                assert_eq!(rule_node.judge_satisfy_aggcondition().len(), 0);
+                rule_node
+            })
+            .collect();
+        let mut other = rule_nodes.pop().unwrap();
+        let mut rule_node = rule_nodes.pop().unwrap();
+        rule_node.merge_countdata(&mut other);
+        assert!(!other.check_exist_countdata());
+        assert_eq!(rule_node.countdata.get("_").unwrap().len(), 2);
+
+        let judge_result = rule_node.judge_satisfy_aggcondition();
+        assert_eq!(judge_result.len(), 1);
+        assert_eq!(judge_result[0].data, 2);
+        assert_eq!(
+            judge_result[0].start_timedate,
+            Utc.ymd(1977, 1, 1).and_hms(0, 0, 0)
+        );
+    }
+
+    #[test]
+    /// 逐次判定した場合に、判定済みのtimeframeと古いレコードが再度判定されないことを確認する
+    fn test_rolling_aggregation_condition_select() {
+        let mut rule_node = create_rolling_rule("count() >= 2", Some("1h"));
+
+        let judge_result = select_rolling(
+            &mut rule_node,
+            &["1977-01-01T00:00:00Z", "1977-01-01T00:10:00Z"],
+        );
+        assert_eq!(judge_result.len(), 1);
+        assert_eq!(
+            judge_result[0].start_timedate,
+            Utc.ymd(1977, 1, 1).and_hms(0, 0, 0)
+        );
+        // 判定済みのtimeframeのレコードは削除される
+        assert!(!rule_node.check_exist_countdata());
+
+        // timeframe以上前のレコードは削除される
+        assert!(select_rolling(&mut rule_node, &["1977-01-01T00:20:00Z"]).is_empty());
+        assert!(select_rolling(&mut rule_node, &["1977-01-01T03:00:00Z"]).is_empty());
+        assert_eq!(rule_node.countdata.get("_").unwrap().len(), 1);
+
+        let judge_result = select_rolling(&mut rule_node, &["1977-01-01T03:30:00Z"]);
+        assert_eq!(judge_result.len(), 1);
+        assert_eq!(judge_result[0].data, 2);
+        assert_eq!(
+            judge_result[0].start_timedate,
+            Utc.ymd(1977, 1, 1).and_hms(3, 0, 0)
+        );
+    }
+
+    /// EventID 7040のレコードをtimesの時刻で検知させてから、逐次判定した結果を返す
+    fn select_rolling(rule_node: &mut RuleNode, times: &[&str]) -> Vec<AggResult> {
+        let keys = detections::rule::get_detection_keys(rule_node);
+        for time in times {
+            let record_str = format!(
+                r#"{{"Event": {{"System": {{"EventID": 7040, "TimeCreated_attributes": {{"SystemTime": "{}"}}}}}}}}"#,
+                time
+            );
+            let recinfo = utils::create_rec_info(
+                serde_json::from_str(&record_str).unwrap(),
+                "testpath".to_owned(),
+                &keys,
+            );
+            assert!(rule_node.select(&recinfo));
+        }
+        rule_node.judge_satisfy_aggcondition_rolling()
+    }
+
+    fn create_rolling_rule(condition: &str, timeframe: Option<&str>) -> RuleNode {
+        let timeframe = timeframe
+            .map(|timeframe| format!("timeframe: {}", timeframe))
+            .unwrap_or_default();
+        let rule_str = format!(
+            r#"
+        enabled: true
+        detection:
+            selection1:
+                EventID: 7040
+            condition: selection1 | {}
+            {}
+        "#,
+            condition, timeframe
+        );
+        let test = YamlLoader::load_from_str(&rule_str).unwrap().remove(0);
+        let mut rule_node = create_rule("testpath".to_string(), test);
+        assert!(rule_node.init().is_ok());
+        rule_node
+    }
+
+    #[test]
+    /// 逐次判定した場合に、<の条件はtimeframeの期間が経過するまで判定しないことを確認する
+    fn test_rolling_aggregation_condition_select_lt() {
+        let mut rule_node = create_rolling_rule("count() < 3", Some("1h"));
+
+        // timeframeの途中では1件しかなくても検知しない
+        assert!(select_rolling(&mut rule_node, &["1977-01-01T00:00:00Z"]).is_empty());
+        assert!(select_rolling(&mut rule_node, &["1977-01-01T00:30:00Z"]).is_empty());
+
+        // timeframeより後のレコードを検知したら、最初のtimeframeの2件を判定する
+        let judge_result = select_rolling(&mut rule_node, &["1977-01-01T01:30:00Z"]);
+        assert_eq!(judge_result.len(), 1);
+        assert_eq!(judge_result[0].data, 2);
+        assert_eq!(
+            judge_result[0].start_timedate,
+            Utc.ymd(1977, 1, 1).and_hms(0, 0, 0)
+        );
+        assert_eq!(rule_node.countdata.get("_").unwrap().len(), 1);
+    }
+
+    #[test]
+    /// timeframeがないルールは逐次判定するとROLLING_DEFAULT_TIMEFRAMEより前のレコードを削除することを確認する
+    fn test_rolling_aggregation_condition_select_without_timeframe() {
+        let mut rule_node = create_rolling_rule("count() >= 3", None);
+        assert!(select_rolling(
+            &mut rule_node,
+            &["1977-01-01T00:00:00Z", "1977-01-01T12:00:00Z"]
+        )
+        .is_empty());
+        assert_eq!(rule_node.countdata.get("_").unwrap().len(), 2);
+
+        assert!(select_rolling(&mut rule_node, &["1977-01-02T06:00:00Z"]).is_empty());
+        assert_eq!(rule_node.countdata.get("_").unwrap().len(), 2);
+
+        let judge_result = select_rolling(&mut rule_node, &["1977-01-02T07:00:00Z"]);
+        assert_eq!(judge_result.len(), 1);
+        assert_eq!(judge_result[0].data, 3);
+        assert!(!rule_node.check_exist_countdata());
+    }
+
     #[test]
     /// countでカッコ内の記載、byの記載両方がありtimeframe内に存在する場合にruleでcountの検知ができることを確認する
     fn test_count_exist_field_and_by_with_timeframe() {
@@ -1,4 +1,5 @@
 extern crate regex;
+use crate::detections::print::ErrorLog;
 use crate::detections::print::Message;
 
 use chrono::{DateTime, Utc};
@@ -10,7 +11,9 @@ use yaml_rust::Yaml;
 
 mod matchers;
 mod selectionnodes;
-use self::selectionnodes::{LeafSelectionNode, SelectionNode};
+use self::selectionnodes::{
+    LeafSelectionNode, NotSelectionNode, OrSelectionNode, RefSelectionNode, SelectionNode,
+};
 mod aggregation_parser;
 use self::aggregation_parser::AggregationParseInfo;
 
@@ -30,6 +33,8 @@ pub struct RuleNode {
     pub yaml: Yaml,
     detection: DetectionNode,
     countdata: HashMap<String, Vec<AggRecordTimeInfo>>,
+    /// ルールの初期化と検知処理で発生したエラーの出力先
+    pub error_log: ErrorLog,
 }
 
 impl Debug for RuleNode {
@@ -48,6 +53,7 @@ impl RuleNode {
             yaml: yaml_data,
             detection: DetectionNode::new(),
             countdata: HashMap::new(),
+            error_log: ErrorLog::default(),
         }
     }
 
@@ -55,7 +61,9 @@ impl RuleNode {
         let mut errmsgs: Vec<String> = vec![];
 
         // detection node initialization
-        let detection_result = self.detection.init(&self.yaml["detection"]);
+        let detection_result = self
+            .detection
+            .init(&self.yaml["detection"], &self.error_log);
         if let Err(err_detail) = detection_result {
             errmsgs.extend(err_detail);
         }
@@ -70,7 +78,7 @@ impl RuleNode {
     pub fn select(&mut self, event_record: &EvtxRecordInfo) -> bool {
         let result = self.detection.select(event_record);
         if result && self.has_agg_condition() {
-            count::count(self, &event_record.record);
+            count::count(self, event_record);
         }
         result
     }
@@ -87,9 +95,26 @@ impl RuleNode {
         ret.append(&mut count::aggregation_condition_select(self));
         ret
     }
+    /// Aggregation Conditionの結果を配列で返却し、判定済みのカウント情報を削除する関数。watchモードで使う
+    pub fn judge_satisfy_aggcondition_rolling(&mut self) -> Vec<AggResult> {
+        if !self.has_agg_condition() {
+            return Vec::new();
+        }
+        count::rolling_aggregation_condition_select(self)
+    }
     pub fn check_exist_countdata(&self) -> bool {
         !self.countdata.is_empty()
     }
+    /// 別のスレッドで同じルールを実行した結果のcountdataを統合する関数。
+    /// judge_timeframeでは時刻順にソートしてから判定するため、統合する順番は判定結果に影響しない
+    pub fn merge_countdata(&mut self, other: &mut RuleNode) {
+        for (key, mut time_datas) in other.countdata.drain() {
+            self.countdata
+                .entry(key)
+                .or_insert_with(Vec::new)
+                .append(&mut time_datas);
+        }
+    }
     /// ルール内のAggregationParseInfo(Aggregation Condition)を取得する関数
     pub fn get_agg_condition(&self) -> Option<&AggregationParseInfo> {
         if self.detection.aggregation_condition.as_ref().is_some() {
@@ -126,6 +151,82 @@ pub fn get_detection_keys(node: &RuleNode) -> Vec<String> {
     ret
 }
 
+/// RuleNodeのconditionでnotを付けずに参照しているselectionの連想配列ごとに、指定したキーに指定されている値の一覧を取得する。
+/// 連想配列のリストを指定しているselectionは、リストの要素ごとに値を取得する。
+pub fn get_selection_values(node: &RuleNode, keys: &[&str]) -> Vec<HashMap<String, Vec<String>>> {
+    let condition = match &node.detection.condition {
+        Some(condition) => condition.as_ref(),
+        None => return vec![],
+    };
+    let mut ret = vec![];
+    for selection in get_positive_selections(condition) {
+        for map in get_selection_maps(selection) {
+            let mut nodes = map.get_descendants();
+            nodes.push(map);
+            let mut values: HashMap<String, Vec<String>> = HashMap::new();
+            for leaf in nodes
+                .iter()
+                .filter_map(|node| node.downcast_ref::<LeafSelectionNode>())
+                .filter(|leaf| keys.contains(&leaf.get_key().as_str()))
+            {
+                if let Some(value) = leaf.get_select_value() {
+                    values
+                        .entry(leaf.get_key().to_string())
+                        .or_default()
+                        .push(value);
+                }
+            }
+            if !values.is_empty() {
+                ret.push(values);
+            }
+        }
+    }
+    ret
+}
+
+/// conditionでnotを付けずに参照しているselectionを取得する
+fn get_positive_selections(node: &dyn SelectionNode) -> Vec<&dyn SelectionNode> {
+    if node.is::<NotSelectionNode>() {
+        return vec![];
+    }
+    if node.is::<RefSelectionNode>() {
+        return node.get_childs();
+    }
+    node.get_childs()
+        .into_iter()
+        .flat_map(get_positive_selections)
+        .collect()
+}
+
+/// selectionを連想配列ごとに分ける。リストはOR条件のため、リストの要素ごとに分ける
+fn get_selection_maps(selection: &dyn SelectionNode) -> Vec<&dyn SelectionNode> {
+    if selection.is::<OrSelectionNode>() {
+        return selection
+            .get_childs()
+            .into_iter()
+            .flat_map(get_selection_maps)
+            .collect();
+    }
+    vec![selection]
+}
+
+/// RuleNodeのdetectionにキーワード検索を行うselectionが含まれるかを返す。
+/// キーワード検索はレコード全体を文字列に変換して検索するため、含まれる場合のみEvtxRecordInfoのdata_stringを作成する。
+pub fn has_keyword_search(node: &RuleNode) -> bool {
+    node.detection.name_to_selection.values().any(|selection| {
+        let selection: &dyn SelectionNode = selection.as_ref().as_ref();
+        let mut nodes = selection.get_descendants();
+        nodes.push(selection);
+        nodes.iter().any(|node| {
+            node.is::<LeafSelectionNode>()
+                && node
+                    .downcast_ref::<LeafSelectionNode>()
+                    .unwrap()
+                    .is_keyword_search()
+        })
+    })
+}
+
 /// Ruleファイルのdetectionを表すノード
 struct DetectionNode {
     pub name_to_selection: HashMap<String, Arc<Box<dyn SelectionNode>>>,
@@ -144,14 +245,17 @@ impl DetectionNode {
         }
     }
 
-    fn init(&mut self, detection_yaml: &Yaml) -> Result<(), Vec<String>> {
+    fn init(&mut self, detection_yaml: &Yaml, error_log: &ErrorLog) -> Result<(), Vec<String>> {
         // selection nodeの初期化
         self.parse_name_to_selection(detection_yaml)?;
 
         //timeframeに指定されている値を取得
         let timeframe = &detection_yaml["timeframe"].as_str();
         if timeframe.is_some() {
-            self.timeframe = Some(TimeFrameInfo::parse_tframe(timeframe.unwrap().to_string()));
+            self.timeframe = Some(TimeFrameInfo::parse_tframe(
+                timeframe.unwrap().to_string(),
+                error_log,
+            ));
         }
 
         // conditionに指定されている式を取得
@@ -971,4 +1075,30 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_has_keyword_search() {
+        // キーを指定しない配列のselectionはキーワード検索になる
+        let rule_str = r#"
+        enabled: true
+        detection:
+            selection:
+                - 'mimikatz'
+                - 'sekurlsa'
+            condition: selection
+        details: 'command=%CommandLine%'
+        "#;
+        assert!(super::has_keyword_search(&parse_rule_from_str(rule_str)));
+
+        let rule_str = r#"
+        enabled: true
+        detection:
+            selection:
+                Channel: Security
+                EventID: [4624, 4625]
+            condition: selection
+        details: 'command=%CommandLine%'
+        "#;
+        assert!(!super::has_keyword_search(&parse_rule_from_str(rule_str)));
+    }
 }
@@ -250,6 +250,21 @@ impl LeafSelectionNode {
         &self.key
     }
 
+    /// selectionに指定されている値を文字列で取得する。文字列と数値以外の場合はNoneを返す
+    pub fn get_select_value(&self) -> Option<String> {
+        match &self.select_value {
+            Yaml::String(value) => Option::Some(value.to_owned()),
+            Yaml::Integer(value) => Option::Some(value.to_string()),
+            Yaml::Real(value) => Option::Some(value.to_owned()),
+            _ => Option::None,
+        }
+    }
+
+    /// キーを指定せずにレコード全体を文字列として検索する(キーワード検索)かを返す
+    pub fn is_keyword_search(&self) -> bool {
+        self.key_list.is_empty()
+    }
+
     pub fn get_keys(&self) -> Vec<&String> {
         let mut keys = vec![];
         if !self.key.is_empty() {
@@ -282,7 +297,7 @@ impl LeafSelectionNode {
     fn get_event_value<'a>(&self, record: &'a EvtxRecordInfo) -> Option<&'a String> {
         // keyが指定されたいない場合は
         if self.key_list.is_empty() {
-            return Option::Some(&record.data_string);
+            return record.data_string.as_ref();
         }
 
         record.get_value(self.get_key())
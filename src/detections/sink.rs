@@ -0,0 +1,88 @@
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::print::{DetectInfo, Message};
+use crate::detections::rule::RuleNode;
+use chrono::{DateTime, Utc};
+use std::sync::Mutex;
+
+/// 検知結果1件分の情報。sinkに渡される
+pub struct DetectionEvent<'a> {
+    /// 検知したレコードの時刻。aggregation conditionの場合は条件を満たした期間の開始時刻
+    pub time: DateTime<Utc>,
+    pub detect_info: &'a DetectInfo,
+    /// 検知したレコードと、フィールドの解決に使うeventkey_aliasの設定。aggregation conditionの場合はNone
+    pub record: Option<&'a EvtxRecordInfo>,
+    /// 検知したルール。ルールのidやtagなどのメタデータはrule.yamlから取得する
+    pub rule: &'a RuleNode,
+}
+
+/// 検知結果の出力先を表すtrait。
+/// Detectionはルールごとにスレッドを作成して検知するため、on_detectは複数のスレッドから同時に呼び出される。
+pub trait DetectionSink: Send + Sync {
+    /// 検知するたびに呼び出される。エラーの場合はエラーログに出力して処理を継続する
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String>;
+
+    /// 解析するレコードごとに、ルールを実行する前に呼び出される。ログオンの集計などの全てのレコードを対象にするレポートで使う
+    fn on_records(&self, _records: &[EvtxRecordInfo]) -> Result<(), String> {
+        Result::Ok(())
+    }
+
+    /// watchモードで新しいレコードの検知処理が終わるたびに呼び出される。出力をバッファしている場合は書き出す
+    fn flush(&self) -> Result<(), String> {
+        Result::Ok(())
+    }
+
+    /// 全ての検知処理(aggregation conditionを含む)が終了した後に呼び出される
+    fn finish(&self) -> Result<(), String> {
+        Result::Ok(())
+    }
+}
+
+/// 時刻順に検知結果を保持するタイムライン。after_factはこのタイムラインから結果を出力する
+impl DetectionSink for Mutex<Message> {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        self.lock()
+            .unwrap()
+            .insert_message(event.detect_info.clone(), event.time);
+        Result::Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::print::{DetectInfo, Message};
+    use crate::detections::rule::create_rule;
+    use crate::detections::sink::{DetectionEvent, DetectionSink};
+    use chrono::{TimeZone, Utc};
+    use std::sync::Mutex;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_message_sink() {
+        let rule_yaml = YamlLoader::load_from_str("title: test").unwrap().remove(0);
+        let rule = create_rule("test-rule.yml".to_string(), rule_yaml);
+        let detect_info = DetectInfo {
+            filepath: "test.evtx".to_string(),
+            rulepath: "test-rule.yml".to_string(),
+            level: "high".to_string(),
+            computername: "testcomputer".to_string(),
+            eventid: "1".to_string(),
+            alert: "test".to_string(),
+            detail: "detail".to_string(),
+            tag_info: String::default(),
+            record_information: Option::None,
+        };
+        let time = Utc.ymd(1996, 2, 27).and_hms(1, 5, 1);
+        let sink = Mutex::new(Message::new());
+        for _ in 0..2 {
+            let event = DetectionEvent {
+                time,
+                detect_info: &detect_info,
+                record: Option::None,
+                rule: &rule,
+            };
+            assert!(sink.on_detect(&event).is_ok());
+        }
+        assert!(sink.finish().is_ok());
+        assert_eq!(sink.lock().unwrap().get(time).len(), 2);
+    }
+}
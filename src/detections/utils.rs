@@ -3,6 +3,7 @@ extern crate csv;
 extern crate regex;
 
 use crate::detections::configs;
+use crate::detections::configs::EventKeyAliasConfig;
 
 use tokio::runtime::Builder;
 use tokio::runtime::Runtime;
@@ -16,6 +17,7 @@ use std::io::prelude::*;
 use std::io::{BufRead, BufReader};
 use std::str;
 use std::string::String;
+use std::sync::Arc;
 use std::vec;
 
 use super::detection::EvtxRecordInfo;
@@ -62,6 +64,29 @@ pub fn value_to_string(value: &Value) -> Option<String> {
     }
 }
 
+/// 値を出力用の文字列に変換する。値が無い場合や空の場合は"-"を返す
+pub fn value_to_output_string(value: &Value) -> String {
+    value_to_string(value)
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// 2つの時刻のうち古い方を返す。片方しか無い場合はその時刻を返す
+pub fn min_time(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Option::Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// 2つの時刻のうち新しい方を返す。片方しか無い場合はその時刻を返す
+pub fn max_time(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Option::Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
 pub fn read_txt(filename: &str) -> Result<Vec<String>, String> {
     let f = File::open(filename);
     if f.is_err() {
@@ -104,8 +129,12 @@ pub fn read_csv(filename: &str) -> Result<Vec<Vec<String>>, String> {
     Result::Ok(ret)
 }
 
-pub fn is_target_event_id(s: &str) -> bool {
-    configs::CONFIG.read().unwrap().target_eventids.is_target(s)
+/// 出力先のファイルの拡張子が.jsonかを返す。JSONとCSVのどちらで出力するかの判定に使う
+pub fn is_json_output(path: &str) -> bool {
+    std::path::Path::new(path)
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("json"))
+        .unwrap_or(false)
 }
 
 pub fn get_event_id_key() -> String {
@@ -142,15 +171,24 @@ pub fn get_serde_number_to_string(value: &serde_json::Value) -> Option<String> {
 }
 
 pub fn get_event_value<'a>(key: &str, event_value: &'a Value) -> Option<&'a Value> {
+    get_event_value_with_alias(&configs::EVENTKEY_ALIAS, key, event_value)
+}
+
+/// 指定したeventkey_aliasの設定でエイリアスを解決して、レコードから値を取得します。
+pub fn get_event_value_with_alias<'a>(
+    alias_config: &EventKeyAliasConfig,
+    key: &str,
+    event_value: &'a Value,
+) -> Option<&'a Value> {
     if key.is_empty() {
         return Option::None;
     }
 
-    let event_key = configs::EVENTKEY_ALIAS.get_event_key(key);
+    let event_key = alias_config.get_event_key(key);
     let mut ret: &Value = event_value;
     if let Some(event_key) = event_key {
         // get_event_keyが取得できてget_event_key_splitが取得できないことはない
-        let splits = configs::EVENTKEY_ALIAS.get_event_key_split(key);
+        let splits = alias_config.get_event_key_split(key);
         let mut start_idx = 0;
         for key in splits.unwrap() {
             if !ret.is_object() {
@@ -181,19 +219,9 @@ pub fn get_event_value<'a>(key: &str, event_value: &'a Value) -> Option<&'a Valu
     }
 }
 
-pub fn get_thread_num() -> usize {
-    let def_thread_num_str = num_cpus::get().to_string();
-    let conf = configs::CONFIG.read().unwrap();
-    let threadnum = &conf
-        .args
-        .value_of("thread-number")
-        .unwrap_or(def_thread_num_str.as_str());
-    threadnum.parse::<usize>().unwrap()
-}
-
-pub fn create_tokio_runtime() -> Runtime {
+pub fn create_tokio_runtime(thread_number: usize) -> Runtime {
     Builder::new_multi_thread()
-        .worker_threads(get_thread_num())
+        .worker_threads(thread_number)
         .thread_name("yea-thread")
         .build()
         .unwrap()
@@ -201,6 +229,18 @@ pub fn create_tokio_runtime() -> Runtime {
 
 // EvtxRecordInfoを作成します。
 pub fn create_rec_info(data: Value, path: String, keys: &[String]) -> EvtxRecordInfo {
+    create_rec_info_with_alias(data, path, keys, &configs::EVENTKEY_ALIAS, true)
+}
+
+/// 指定したeventkey_aliasの設定でEvtxRecordInfoを作成します。
+/// キーワード検索を行うルールがない場合はwith_data_stringをfalseにすると、レコード全体を文字列に変換する処理を省略します。
+pub fn create_rec_info_with_alias(
+    data: Value,
+    path: String,
+    keys: &[String],
+    alias_config: &Arc<EventKeyAliasConfig>,
+    with_data_string: bool,
+) -> EvtxRecordInfo {
     // 高速化のための処理
 
     // 例えば、Value型から"Event.System.EventID"の値を取得しようとすると、value["Event"]["System"]["EventID"]のように3回アクセスする必要がある。
@@ -210,7 +250,7 @@ pub fn create_rec_info(data: Value, path: String, keys: &[String]) -> EvtxRecord
     // それと、serde_jsonでは内部的に標準ライブラリのhashmapを使用しているが、hashbrownを使った方が早くなるらしい。
     let mut key_2_values = hashbrown::HashMap::new();
     for key in keys {
-        let val = get_event_value(key, &data);
+        let val = get_event_value_with_alias(alias_config, key, &data);
         if val.is_none() {
             continue;
         }
@@ -224,25 +264,20 @@ pub fn create_rec_info(data: Value, path: String, keys: &[String]) -> EvtxRecord
     }
 
     // EvtxRecordInfoを作る
-    let data_str = data.to_string();
-    let rec_info = if configs::CONFIG.read().unwrap().args.is_present("full-data") {
-        Option::Some(create_recordinfos(&data))
-    } else {
-        Option::None
-    };
+    let data_str = with_data_string.then(|| data.to_string());
     EvtxRecordInfo {
         evtx_filepath: path,
         record: data,
         data_string: data_str,
         key_2_value: key_2_values,
-        record_information: rec_info,
+        alias_config: Arc::clone(alias_config),
     }
 }
 
 /**
  * CSVのrecord infoカラムに出力する文字列を作る
  */
-fn create_recordinfos(record: &Value) -> String {
+pub fn create_recordinfos(record: &Value, separator: &str) -> String {
     let mut output = vec![];
     _collect_recordinfo(&mut vec![], "", record, &mut output);
 
@@ -263,12 +298,7 @@ fn create_recordinfos(record: &Value) -> String {
         })
         .collect();
 
-    // 標準出力する時はセルがハイプ区切りになるので、パイプ区切りにしない
-    if configs::CONFIG.read().unwrap().args.is_present("output") {
-        summary.join(" | ")
-    } else {
-        summary.join(" ")
-    }
+    summary.join(separator)
 }
 
 /**
@@ -329,6 +359,7 @@ fn _collect_recordinfo<'a>(
 #[cfg(test)]
 mod tests {
     use crate::detections::utils;
+    use chrono::{TimeZone, Utc};
     use regex::Regex;
     use serde_json::Value;
 
@@ -346,7 +377,7 @@ mod tests {
 
         match serde_json::from_str(record_json_str) {
             Ok(record) => {
-                let ret = utils::create_recordinfos(&record);
+                let ret = utils::create_recordinfos(&record, " ");
                 // Systemは除外される/属性(_attributesも除外される)/key順に並ぶ
                 let expected = "AccessMask:%%1369 Process:lsass.exe User:u1".to_string();
                 assert_eq!(ret, expected);
@@ -380,7 +411,7 @@ mod tests {
 
         match serde_json::from_str(record_json_str) {
             Ok(record) => {
-                let ret = utils::create_recordinfos(&record);
+                let ret = utils::create_recordinfos(&record, " ");
                 // Systemは除外される/属性(_attributesも除外される)/key順に並ぶ
                 let expected = "Binary:hogehoge Data: Data:Data1 Data:DataData2 Data:DataDataData3"
                     .to_string();
@@ -479,4 +510,33 @@ mod tests {
 
         assert!(utils::get_serde_number_to_string(&event_record["Event"]["EventData"]).is_none());
     }
+
+    #[test]
+    fn test_value_to_output_string() {
+        let event_data: Value = serde_json::from_str(
+            r#"{"LogonType": 3, "IpAddress": " 10.0.0.1 ", "WorkstationName": ""}"#,
+        )
+        .unwrap();
+        assert_eq!(utils::value_to_output_string(&event_data["LogonType"]), "3");
+        assert_eq!(
+            utils::value_to_output_string(&event_data["IpAddress"]),
+            "10.0.0.1"
+        );
+        assert_eq!(
+            utils::value_to_output_string(&event_data["WorkstationName"]),
+            "-"
+        );
+        assert_eq!(utils::value_to_output_string(&event_data["NotFound"]), "-");
+    }
+
+    #[test]
+    fn test_min_max_time() {
+        let a = Some(Utc.ymd(2022, 1, 1).and_hms(0, 0, 0));
+        let b = Some(Utc.ymd(2022, 1, 2).and_hms(0, 0, 0));
+        assert_eq!(utils::min_time(a, b), a);
+        assert_eq!(utils::max_time(a, b), b);
+        assert_eq!(utils::min_time(None, b), b);
+        assert_eq!(utils::max_time(a, None), a);
+        assert_eq!(utils::min_time(None, None), None);
+    }
 }
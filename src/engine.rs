@@ -0,0 +1,736 @@
+use crate::detections::configs::{self, ConfigReader, EventKeyAliasConfig, TargetEventIds};
+use crate::detections::detection::{Detection, EvtxRecordInfo};
+use crate::detections::geoip::GeoIpEnricher;
+use crate::detections::ioc::IocMatcher;
+use crate::detections::print::{ErrorLog, HISTOGRAM_FLAG, LOGON_SUMMARY_FLAG, STATISTICS_FLAG};
+use crate::detections::rule::{get_detection_keys, has_keyword_search, RuleNode};
+use crate::detections::sink::DetectionSink;
+use crate::detections::utils;
+use crate::filter;
+use evtx::{EvtxParser, ParserSettings};
+use hashbrown::HashSet;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::runtime::Runtime;
+use tokio::spawn;
+use tokio::task::JoinHandle;
+
+// 一度にtimelineやdetectionを実行する行数
+pub const MAX_DETECT_RECORDS: usize = 5000;
+
+/// 検知処理の設定。hayabusaのバイナリではコマンドライン引数から作成し、ライブラリとして使う場合はビルダー形式で設定する。
+///
+/// ```ignore
+/// let options = ScanOptions::new()
+///     .rules_path("./rules")
+///     .config_path("./rules/config")
+///     .min_level("high");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    rules_path: String,
+    config_path: String,
+    min_level: String,
+    enable_deprecated_rules: bool,
+    enable_noisy_rules: bool,
+    full_data: bool,
+    record_info_separator: String,
+    target_eventids: TargetEventIds,
+    thread_number: usize,
+    ioc_matcher: Option<Arc<IocMatcher>>,
+    geoip: Option<Arc<GeoIpEnricher>>,
+    pivot_min_level: String,
+    print_rule_load_info: bool,
+    error_log: ErrorLog,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScanOptions {
+    pub fn new() -> ScanOptions {
+        ScanOptions {
+            rules_path: "rules".to_string(),
+            config_path: "rules/config".to_string(),
+            min_level: "informational".to_string(),
+            enable_deprecated_rules: false,
+            enable_noisy_rules: false,
+            full_data: false,
+            record_info_separator: " | ".to_string(),
+            target_eventids: TargetEventIds::new(),
+            thread_number: num_cpus::get(),
+            ioc_matcher: Option::None,
+            geoip: Option::None,
+            pivot_min_level: "low".to_string(),
+            print_rule_load_info: true,
+            error_log: ErrorLog::default(),
+        }
+    }
+
+    /// コマンドライン引数の設定からScanOptionsを作成する。
+    pub fn from_config(conf: &ConfigReader) -> ScanOptions {
+        let args = &conf.args;
+        let mut options = ScanOptions::new()
+            .config_path(&conf.folder_path)
+            .min_level(args.value_of("min-level").unwrap_or("informational"))
+            .enable_deprecated_rules(args.is_present("enable-deprecated-rules"))
+            .enable_noisy_rules(args.is_present("enable-noisy-rules"))
+            .full_data(args.is_present("full-data"))
+            .target_eventids(conf.target_eventids.clone())
+            .pivot_min_level(args.value_of("pivot-min-level").unwrap_or("low"))
+            // 集計結果だけを出力する場合はルールの読み込み結果を表示しない
+            .print_rule_load_info(!(*STATISTICS_FLAG || *LOGON_SUMMARY_FLAG || *HISTOGRAM_FLAG))
+            .error_log(ErrorLog::from_config());
+        if let Some(rules_path) = args.value_of("rules") {
+            options = options.rules_path(rules_path);
+        }
+        // 標準出力する時はセルがハイプ区切りになるので、パイプ区切りにしない
+        if !args.is_present("output") {
+            options = options.record_info_separator(" ");
+        }
+        if let Some(thread_number) = args.value_of("thread-number") {
+            options = options.thread_number(thread_number.parse::<usize>().unwrap());
+        }
+        options
+    }
+
+    /// ルールファイルまたはルールファイルを持つディレクトリ (デフォルト: rules)
+    pub fn rules_path(mut self, rules_path: &str) -> Self {
+        self.rules_path = rules_path.to_string();
+        self
+    }
+
+    /// eventkey_alias.txtなどのルールのコンフィグディレクトリ (デフォルト: rules/config)
+    pub fn config_path(mut self, config_path: &str) -> Self {
+        self.config_path = config_path.to_string();
+        self
+    }
+
+    /// 読み込むルールの最低レベル (デフォルト: informational)
+    pub fn min_level(mut self, min_level: &str) -> Self {
+        self.min_level = min_level.to_string();
+        self
+    }
+
+    pub fn enable_deprecated_rules(mut self, enable: bool) -> Self {
+        self.enable_deprecated_rules = enable;
+        self
+    }
+
+    pub fn enable_noisy_rules(mut self, enable: bool) -> Self {
+        self.enable_noisy_rules = enable;
+        self
+    }
+
+    /// 検知結果のrecord_informationに全フィールドの情報を設定する
+    pub fn full_data(mut self, full_data: bool) -> Self {
+        self.full_data = full_data;
+        self
+    }
+
+    /// full_dataを有効にした場合のフィールドの区切り文字 (デフォルト: " | ")
+    pub fn record_info_separator(mut self, separator: &str) -> Self {
+        self.record_info_separator = separator.to_string();
+        self
+    }
+
+    /// 検知対象とするEventID。空の場合は全てのEventIDを対象とする
+    pub fn target_eventids(mut self, target_eventids: TargetEventIds) -> Self {
+        self.target_eventids = target_eventids;
+        self
+    }
+
+    /// 検知処理に使うスレッド数。scan_filesで同時に解析するevtxファイルの数にも使う (デフォルト: CPUのコア数)
+    pub fn thread_number(mut self, thread_number: usize) -> Self {
+        self.thread_number = thread_number;
+        self
+    }
+
+    /// ルールと一緒に全てのレコードと照合するIOC (デフォルト: なし)
+    pub fn ioc_matcher(mut self, ioc_matcher: IocMatcher) -> Self {
+        self.ioc_matcher = Option::Some(Arc::new(ioc_matcher));
+        self
+    }
+
+    /// 検知処理の前にレコードのIPアドレスのフィールドに国とASNのフィールドを追加する (デフォルト: なし)
+    pub fn geoip(mut self, geoip: GeoIpEnricher) -> Self {
+        self.geoip = Option::Some(Arc::new(geoip));
+        self
+    }
+
+    /// ピボットキーワードを収集するルールの最低レベル (デフォルト: low)。PivotKeywordSinkの作成時にget_pivot_min_levelで参照する
+    pub fn pivot_min_level(mut self, pivot_min_level: &str) -> Self {
+        self.pivot_min_level = pivot_min_level.to_string();
+        self
+    }
+
+    pub fn get_pivot_min_level(&self) -> &str {
+        &self.pivot_min_level
+    }
+
+    /// ルールを読み込んだ時にレベルごとのルール数を標準出力に表示する (デフォルト: true)
+    pub fn print_rule_load_info(mut self, print_rule_load_info: bool) -> Self {
+        self.print_rule_load_info = print_rule_load_info;
+        self
+    }
+
+    /// ルールの読み込みと検知処理で発生したエラーとワーニングの出力先 (デフォルト: 表示せずにErrorLogの中だけに保持する)
+    pub fn error_log(mut self, error_log: ErrorLog) -> Self {
+        self.error_log = error_log;
+        self
+    }
+}
+
+/// ルールと設定、検知結果の出力先を保持して検知処理を行う。
+/// グローバルなコマンドライン引数を参照しないため、1つのプロセス内で複数のEngineを同時に実行できる。
+/// エラーログはScanOptionsで設定したErrorLogに出力される。
+///
+/// 検知処理は複数のスレッドから同時に呼び出せる。aggregation conditionのカウント情報が混ざらないように、
+/// 同時に実行する検知処理ごとにルールを複製したDetectionを使い、finishでカウント情報を統合してからaggregation conditionを判定する。
+pub struct Engine {
+    rt: Runtime,
+    /// 複製元のDetection。finishで全てのDetectionのカウント情報をこのDetectionに統合する
+    detection: Detection,
+    /// 検知処理に使っていないDetection
+    idle_detections: Mutex<Vec<Detection>>,
+    thread_number: usize,
+    rule_keys: Arc<Vec<String>>,
+    alias_config: Arc<EventKeyAliasConfig>,
+    target_eventids: TargetEventIds,
+    /// キーワード検索を行うルールが読み込まれている場合のみ、レコード全体を文字列に変換する
+    with_data_string: bool,
+    geoip: Option<Arc<GeoIpEnricher>>,
+    error_log: ErrorLog,
+}
+
+impl Engine {
+    /// ルールを読み込んでEngineを作成する。検知結果はsinksに渡される。
+    pub fn new(options: ScanOptions, sinks: Vec<Arc<dyn DetectionSink>>) -> Result<Engine, String> {
+        let rules = Detection::parse_rule_files(
+            options.min_level.to_uppercase(),
+            Option::Some(&options.rules_path),
+            &filter::exclude_ids_in(
+                &options.config_path,
+                options.enable_noisy_rules,
+                &options.error_log,
+            ),
+            options.enable_deprecated_rules,
+            &options.error_log,
+            options.print_rule_load_info,
+        );
+        if rules.is_empty() {
+            return Result::Err(format!(
+                "No rules were loaded. [rules:{}]",
+                options.rules_path
+            ));
+        }
+
+        let alias_config =
+            configs::read_eventkey_alias(&format!("{}/eventkey_alias.txt", options.config_path))
+                .unwrap_or_else(|err| {
+                    options.error_log.error(&err);
+                    EventKeyAliasConfig::new()
+                });
+        let rt = utils::create_tokio_runtime(options.thread_number);
+        Result::Ok(Engine {
+            rt,
+            rule_keys: Arc::new(Engine::get_all_keys(&rules)),
+            with_data_string: rules.iter().any(has_keyword_search),
+            detection: Detection::new(
+                rules,
+                sinks,
+                options.full_data.then_some(options.record_info_separator),
+            )
+            .with_ioc_matcher(options.ioc_matcher)
+            .with_error_log(options.error_log.clone()),
+            idle_detections: Mutex::new(vec![]),
+            thread_number: options.thread_number.max(1),
+            alias_config: Arc::new(alias_config),
+            target_eventids: options.target_eventids,
+            geoip: options.geoip,
+            error_log: options.error_log,
+        })
+    }
+
+    /// 読み込み済みのルールを使い、出力先がsinksのEngineを作成する。
+    /// ルールファイルを再度読み込まないため、serveモードのようにルールを読み込んだままジョブごとに検知結果を分けて解析する場合に使う
+    pub fn with_sinks(&self, sinks: Vec<Arc<dyn DetectionSink>>) -> Engine {
+        Engine {
+            rt: utils::create_tokio_runtime(self.thread_number),
+            detection: self.detection.with_sinks(sinks),
+            idle_detections: Mutex::new(vec![]),
+            thread_number: self.thread_number,
+            rule_keys: Arc::clone(&self.rule_keys),
+            alias_config: Arc::clone(&self.alias_config),
+            target_eventids: self.target_eventids.clone(),
+            with_data_string: self.with_data_string,
+            geoip: self.geoip.clone(),
+            error_log: self.error_log.clone(),
+        }
+    }
+
+    /// 読み込んだルール
+    pub fn rules(&self) -> &[RuleNode] {
+        self.detection.rules()
+    }
+
+    fn get_all_keys(rules: &[RuleNode]) -> Vec<String> {
+        let mut key_set = HashSet::new();
+        for rule in rules {
+            let keys = get_detection_keys(rule);
+            key_set.extend(keys);
+        }
+
+        let ret: Vec<String> = key_set.into_iter().collect();
+        ret
+    }
+
+    pub fn runtime(&self) -> &Runtime {
+        &self.rt
+    }
+
+    /// ルールの読み込みと検知処理で発生したエラーとワーニング
+    pub fn error_log(&self) -> &ErrorLog {
+        &self.error_log
+    }
+
+    // target_eventidsの設定を元にフィルタする。
+    pub fn is_target_event_id(&self, data: &Value) -> bool {
+        let eventid =
+            utils::get_event_value_with_alias(&self.alias_config, &utils::get_event_id_key(), data);
+        if eventid.is_none() {
+            return true;
+        }
+
+        match eventid.unwrap() {
+            Value::String(s) => self.target_eventids.is_target(s),
+            Value::Number(n) => self.target_eventids.is_target(&n.to_string()),
+            _ => true, // レコードからEventIdが取得できない場合は、特にフィルタしない
+        }
+    }
+
+    /// 解析するレコードかを返す。IOCを照合する場合は、target_eventidsで除外するレコードもIOCと照合するために解析する
+    pub fn is_scan_target(&self, data: &Value) -> bool {
+        self.detection.has_ioc_matcher() || self.is_target_event_id(data)
+    }
+
+    /// レコードとそのファイルパスからEvtxRecordInfoを作成する。GeoIPのデータベースが設定されている場合はレコードに国とASNのフィールドを追加する。
+    pub fn create_rec_infos(&self, records: Vec<(Value, String)>) -> Vec<EvtxRecordInfo> {
+        self.rt.block_on(async {
+            let threads: Vec<JoinHandle<EvtxRecordInfo>> = records
+                .into_iter()
+                .map(|(mut rec, path)| -> JoinHandle<EvtxRecordInfo> {
+                    let rule_keys = Arc::clone(&self.rule_keys);
+                    let alias_config = Arc::clone(&self.alias_config);
+                    let with_data_string = self.with_data_string;
+                    let geoip = self.geoip.clone();
+                    spawn(async move {
+                        // ルールの条件でも使えるように、key_2_valueを作成する前に国とASNのフィールドを追加する
+                        if let Some(geoip) = geoip {
+                            geoip.enrich(&alias_config, &mut rec);
+                        }
+                        utils::create_rec_info_with_alias(
+                            rec,
+                            path,
+                            &rule_keys,
+                            &alias_config,
+                            with_data_string,
+                        )
+                    })
+                })
+                .collect();
+
+            let mut ret = vec![];
+            for thread in threads.into_iter() {
+                ret.push(thread.await.unwrap());
+            }
+            ret
+        })
+    }
+
+    /// ルールを実行せずに、レコードをsinkのon_recordsに渡す。ログオンの集計などのレポートのみを作成する場合に使う
+    pub fn collect(&self, records: &[EvtxRecordInfo]) {
+        self.detection.send_records_to_sinks(records);
+    }
+
+    /// レコードをsinkのon_recordsに渡してから、複数のレコードに対してルールを実行する。
+    /// target_eventidsで除外するレコードは、sinkに渡さずにIOCとのみ照合する
+    pub fn detect(&self, records: Vec<EvtxRecordInfo>) {
+        let (records, ioc_only_records): (Vec<EvtxRecordInfo>, Vec<EvtxRecordInfo>) = records
+            .into_iter()
+            .partition(|record| self.is_target_event_id(&record.record));
+        self.collect(&records);
+        let detection = self.idle_detections.lock().unwrap().pop();
+        let detection = detection.unwrap_or_else(|| self.detection.fork());
+        let detection = detection.start(&self.rt, records, ioc_only_records);
+        self.idle_detections.lock().unwrap().push(detection);
+    }
+
+    /// itemsをthread_numberの数のスレッドで並列に処理する。evtxファイル単位で並列に解析するために使う
+    pub fn par_for_each<T: Send, F: Fn(T) + Sync>(&self, items: Vec<T>, f: F) {
+        let thread_number = self.thread_number.min(items.len());
+        let items = Mutex::new(items.into_iter());
+        thread::scope(|s| {
+            for _ in 0..thread_number {
+                s.spawn(|| loop {
+                    let item = items.lock().unwrap().next();
+                    match item {
+                        Some(item) => f(item),
+                        None => break,
+                    }
+                });
+            }
+        });
+    }
+
+    /// JSON形式のレコードに対してルールを実行する。pathは検知結果のファイルパスとして出力される。
+    pub fn scan_records<I: IntoIterator<Item = Value>>(&self, path: &str, records: I) {
+        self.scan_records_with_progress(path, records, |_| true);
+    }
+
+    /// scan_recordsと同じ処理を行い、MAX_DETECT_RECORDS件ごとに検知したレコード数をon_progressに渡す。
+    /// on_progressがfalseを返した場合は残りのレコードを解析せずに中断する
+    pub fn scan_records_with_progress<I, F>(&self, path: &str, records: I, mut on_progress: F)
+    where
+        I: IntoIterator<Item = Value>,
+        F: FnMut(usize) -> bool,
+    {
+        let mut records = records.into_iter();
+        loop {
+            let records_per_detect: Vec<(Value, String)> = records
+                .by_ref()
+                .filter(|data| self.is_scan_target(data))
+                .take(MAX_DETECT_RECORDS)
+                .map(|data| (data, path.to_string()))
+                .collect();
+            if records_per_detect.is_empty() {
+                break;
+            }
+
+            let records_per_detect = self.create_rec_infos(records_per_detect);
+            let record_count = records_per_detect.len();
+            self.detect(records_per_detect);
+            if !on_progress(record_count) {
+                break;
+            }
+        }
+    }
+
+    /// evtxファイルを1ファイル分解析する。
+    pub fn scan_file(&self, evtx_filepath: &Path) -> Result<(), String> {
+        self.scan_file_with_progress(evtx_filepath, |_| true)
+    }
+
+    /// evtxファイルを1ファイル分解析する。進捗の通知と中断はscan_records_with_progressと同じ
+    pub fn scan_file_with_progress<F: FnMut(usize) -> bool>(
+        &self,
+        evtx_filepath: &Path,
+        on_progress: F,
+    ) -> Result<(), String> {
+        let mut parse_config = ParserSettings::default();
+        parse_config = parse_config.separate_json_attributes(true); // XMLのattributeをJSONに変換する時のルールを設定
+        parse_config = parse_config.num_threads(0); // 設定しないと遅かったので、設定しておく。
+        let mut parser = EvtxParser::from_path(evtx_filepath)
+            .map_err(|e| e.to_string())?
+            .with_configuration(parse_config);
+
+        let path = evtx_filepath.display().to_string();
+        let records = parser
+            .records_json_value()
+            .filter_map(|record| match record {
+                Ok(record) => Option::Some(record.data),
+                Err(e) => {
+                    // パースに失敗している場合、エラーメッセージを出力
+                    self.error_log.error(&format!(
+                        "Failed to parse event file. EventFile:{} Error:{}",
+                        path, e
+                    ));
+                    Option::None
+                }
+            });
+        self.scan_records_with_progress(&path, records, on_progress);
+        Result::Ok(())
+    }
+
+    /// 複数のevtxファイルを並列に解析する。戻り値はevtx_filepathsと同じ順番で各ファイルの解析結果を返す。
+    pub fn scan_files(&self, evtx_filepaths: &[PathBuf]) -> Vec<Result<(), String>> {
+        let results = Mutex::new(vec![Result::Ok(()); evtx_filepaths.len()]);
+        self.par_for_each(
+            evtx_filepaths.iter().enumerate().collect(),
+            |(idx, evtx_filepath)| {
+                let result = self.scan_file(evtx_filepath);
+                results.lock().unwrap()[idx] = result;
+            },
+        );
+        results.into_inner().unwrap()
+    }
+
+    /// aggregation conditionを判定して、sinkの出力を書き出す。watchモードのように検知処理を終了せずに新しいレコードを解析し続ける場合に、
+    /// 解析するたびに呼び出す。aggregation conditionはtimeframe単位で判定し、判定済みのカウント情報は削除するため同じ検知結果は一度しか出力されない
+    pub fn flush(&mut self) -> Result<(), String> {
+        for other in self.idle_detections.get_mut().unwrap().iter_mut() {
+            self.detection.merge(other);
+        }
+        self.detection.add_rolling_aggcondition_msges();
+        self.detection.flush_sinks()
+    }
+
+    /// aggregation conditionの検知結果を追加して検知処理を終了する。sinkの終了処理でエラーが発生した場合はErrを返す。
+    pub fn finish(self) -> Result<(), String> {
+        let mut detection = self.detection;
+        for mut other in self.idle_detections.into_inner().unwrap() {
+            detection.merge(&mut other);
+        }
+        detection.add_aggcondition_msges(&self.rt);
+        let ret = detection.finish_sinks();
+        self.rt.shutdown_background();
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::configs::load_target_ids;
+    use crate::detections::ioc::{Indicator, IocMatcher, IocType};
+    use crate::detections::print::{ErrorLog, Message};
+    use crate::detections::sink::DetectionSink;
+    use crate::engine::{Engine, ScanOptions, MAX_DETECT_RECORDS};
+    use serde_json::Value;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn create_engine(min_level: &str, messages: &Arc<Mutex<Message>>) -> Engine {
+        let options = ScanOptions::new()
+            .rules_path("./test_files/rules/level_yaml")
+            .config_path("./test_files/config")
+            .min_level(min_level)
+            .thread_number(2);
+        let sink: Arc<dyn DetectionSink> = Arc::clone(messages) as Arc<dyn DetectionSink>;
+        Engine::new(options, vec![sink]).unwrap()
+    }
+
+    fn create_record() -> Value {
+        let record_json_str = r#"
+        {
+            "Event": {
+                "System": {
+                    "EventID": 1,
+                    "TimeCreated_attributes": {"SystemTime": "1996-02-27T01:05:01Z"}
+                },
+                "EventData": {"EventLog": "Sysmon", "CommandLine": "hoge.exe"}
+            }
+        }"#;
+        serde_json::from_str(record_json_str).unwrap()
+    }
+
+    fn count_messages(messages: Arc<Mutex<Message>>) -> usize {
+        messages
+            .lock()
+            .unwrap()
+            .iter()
+            .values()
+            .map(|detect_infos| detect_infos.len())
+            .sum()
+    }
+
+    #[test]
+    fn test_scan_records() {
+        let messages = Arc::new(Mutex::new(Message::new()));
+        let engine = create_engine("informational", &messages);
+        engine.scan_records("test.evtx", vec![create_record()]);
+        assert!(engine.finish().is_ok());
+        assert_eq!(count_messages(Arc::clone(&messages)), 5);
+        for detect_infos in messages.lock().unwrap().iter().values() {
+            for detect_info in detect_infos {
+                assert_eq!(detect_info.filepath, "test.evtx");
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_records_with_progress() {
+        // on_progressがfalseを返した場合は残りのレコードを解析しない
+        let messages = Arc::new(Mutex::new(Message::new()));
+        let engine = create_engine("critical", &messages);
+        let mut progress = vec![];
+        engine.scan_records_with_progress(
+            "test.evtx",
+            vec![create_record(); MAX_DETECT_RECORDS + 10],
+            |count| {
+                progress.push(count);
+                false
+            },
+        );
+        assert!(engine.finish().is_ok());
+        assert_eq!(progress, vec![MAX_DETECT_RECORDS]);
+        assert_eq!(count_messages(messages), MAX_DETECT_RECORDS);
+    }
+
+    #[test]
+    fn test_with_sinks() {
+        // 読み込み済みのルールで、検知結果を別のsinkに出力する
+        let messages = Arc::new(Mutex::new(Message::new()));
+        let engine = create_engine("high", &messages);
+        let other_messages = Arc::new(Mutex::new(Message::new()));
+        let sink: Arc<dyn DetectionSink> = Arc::clone(&other_messages) as Arc<dyn DetectionSink>;
+        let other_engine = engine.with_sinks(vec![sink]);
+        assert_eq!(other_engine.rules().len(), engine.rules().len());
+        other_engine.scan_records("test.evtx", vec![create_record()]);
+        assert!(other_engine.finish().is_ok());
+        assert!(engine.finish().is_ok());
+        assert_eq!(count_messages(messages), 0);
+        assert_eq!(count_messages(other_messages), 2);
+    }
+
+    #[test]
+    fn test_scan_records_with_ioc() {
+        // ルールの検知結果に加えて、IOCに一致したレコードがIOCの情報を持つ検知結果として出力されることを確認する
+        let messages = Arc::new(Mutex::new(Message::new()));
+        let indicator = Indicator::new(IocType::Path, "HOGE.exe", "intel", "Test payload");
+        let options = ScanOptions::new()
+            .rules_path("./test_files/rules/level_yaml")
+            .config_path("./test_files/config")
+            .min_level("critical")
+            .thread_number(2)
+            .ioc_matcher(IocMatcher::new("iocs.csv", vec![indicator]));
+        let sink: Arc<dyn DetectionSink> = Arc::clone(&messages) as Arc<dyn DetectionSink>;
+        let engine = Engine::new(options, vec![sink]).unwrap();
+        engine.scan_records("test.evtx", vec![create_record()]);
+        assert!(engine.finish().is_ok());
+        assert_eq!(count_messages(Arc::clone(&messages)), 2);
+        let messages = messages.lock().unwrap();
+        let ioc_info = messages
+            .iter()
+            .values()
+            .flatten()
+            .find(|detect_info| detect_info.rulepath == "iocs.csv")
+            .unwrap();
+        assert_eq!(ioc_info.alert, "IOC Match (Path)");
+        assert_eq!(ioc_info.level, "high");
+        assert_eq!(
+            ioc_info.detail,
+            "IOC: HOGE.exe ¦ Field: CommandLine ¦ Source: intel ¦ Desc: Test payload"
+        );
+    }
+
+    #[test]
+    fn test_scan_records_with_ioc_not_target_event_id() {
+        // target_eventidsで除外するEventIDのレコードは、ルールを実行せずにIOCとのみ照合する
+        let messages = Arc::new(Mutex::new(Message::new()));
+        let indicator = Indicator::new(IocType::Path, "HOGE.exe", "intel", "Test payload");
+        let options = ScanOptions::new()
+            .rules_path("./test_files/rules/level_yaml")
+            .config_path("./test_files/config")
+            .min_level("critical")
+            .thread_number(2)
+            .target_eventids(load_target_ids("./test_files/config/target_eventids.txt"))
+            .ioc_matcher(IocMatcher::new("iocs.csv", vec![indicator]));
+        let sink: Arc<dyn DetectionSink> = Arc::clone(&messages) as Arc<dyn DetectionSink>;
+        let engine = Engine::new(options, vec![sink]).unwrap();
+        assert!(!engine.is_target_event_id(&create_record()));
+        engine.scan_records("test.evtx", vec![create_record()]);
+        assert!(engine.finish().is_ok());
+        let messages = messages.lock().unwrap();
+        let rulepaths: Vec<&str> = messages
+            .iter()
+            .values()
+            .flatten()
+            .map(|detect_info| detect_info.rulepath.as_str())
+            .collect();
+        assert_eq!(rulepaths, vec!["iocs.csv"]);
+    }
+
+    #[test]
+    fn test_no_rules() {
+        let options = ScanOptions::new().rules_path("./test_files/rules/notfound");
+        assert!(Engine::new(options, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_error_log() {
+        // エラーとワーニングはグローバルなERROR_LOG_STACKではなくScanOptionsで設定したErrorLogに出力される
+        let error_log = ErrorLog::new(false, false);
+        let options = ScanOptions::new()
+            .rules_path("./test_files/rules/level_yaml")
+            .config_path("./test_files/config/notfound")
+            .print_rule_load_info(false)
+            .error_log(error_log.clone());
+        let engine = Engine::new(options, vec![]).unwrap();
+        assert!(engine
+            .scan_file(Path::new("./test_files/evtx/notfound.evtx"))
+            .is_err());
+        assert!(engine.finish().is_ok());
+        assert_eq!(
+            error_log.logs(),
+            vec![
+                "[WARN] ./test_files/config/notfound/noisy_rules.txt does not exist",
+                "[WARN] ./test_files/config/notfound/exclude_rules.txt does not exist",
+                "[ERROR] Cannot open file. [file:./test_files/config/notfound/eventkey_alias.txt]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parallel_scan_aggregation() {
+        // 10件ずつ4スレッドで検知しても、aggregation conditionは合計の40件で判定されることを確認する
+        let messages = Arc::new(Mutex::new(Message::new()));
+        let options = ScanOptions::new()
+            .rules_path("./test_files/rules/aggregation")
+            .config_path("./test_files/config")
+            .thread_number(4);
+        let sink: Arc<dyn DetectionSink> = Arc::clone(&messages) as Arc<dyn DetectionSink>;
+        let engine = Engine::new(options, vec![sink]).unwrap();
+        engine.par_for_each(vec!["1.evtx", "2.evtx", "3.evtx", "4.evtx"], |path| {
+            engine.scan_records(path, vec![create_record(); 10]);
+        });
+        assert!(engine.finish().is_ok());
+        assert_eq!(count_messages(messages), 1);
+    }
+
+    #[test]
+    fn test_flush_aggregation() {
+        // flushのたびにaggregation conditionを判定し、判定済みのレコードはfinishで再度検知されないことを確認する
+        let messages = Arc::new(Mutex::new(Message::new()));
+        let options = ScanOptions::new()
+            .rules_path("./test_files/rules/aggregation")
+            .config_path("./test_files/config")
+            .thread_number(2);
+        let sink: Arc<dyn DetectionSink> = Arc::clone(&messages) as Arc<dyn DetectionSink>;
+        let mut engine = Engine::new(options, vec![sink]).unwrap();
+        engine.scan_records("1.evtx", vec![create_record(); 30]);
+        assert!(engine.flush().is_ok());
+        assert_eq!(count_messages(Arc::clone(&messages)), 0);
+        engine.scan_records("1.evtx", vec![create_record(); 10]);
+        assert!(engine.flush().is_ok());
+        assert_eq!(count_messages(Arc::clone(&messages)), 1);
+        assert!(engine.flush().is_ok());
+        assert!(engine.finish().is_ok());
+        assert_eq!(count_messages(messages), 1);
+    }
+
+    #[test]
+    fn test_concurrent_engines() {
+        // 異なる設定のEngineを同時に実行しても検知結果が混ざらないことを確認する
+        let handles: Vec<_> = [("informational", 5), ("high", 2), ("critical", 1)]
+            .into_iter()
+            .map(|(min_level, expect)| {
+                thread::spawn(move || {
+                    let messages = Arc::new(Mutex::new(Message::new()));
+                    let engine = create_engine(min_level, &messages);
+                    engine.scan_records("test.evtx", vec![create_record(); 10]);
+                    assert!(engine.finish().is_ok());
+                    assert_eq!(count_messages(messages), expect * 10);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
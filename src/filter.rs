@@ -1,11 +1,8 @@
 use crate::detections::configs;
-use crate::detections::print::AlertMessage;
-use crate::detections::print::ERROR_LOG_STACK;
-use crate::detections::print::QUIET_ERRORS_FLAG;
+use crate::detections::print::ErrorLog;
 use hashbrown::HashSet;
 use regex::Regex;
 use std::fs::File;
-use std::io::BufWriter;
 use std::io::{BufRead, BufReader};
 
 #[derive(Debug)]
@@ -20,47 +17,38 @@ pub struct RuleExclude {
 }
 
 pub fn exclude_ids() -> RuleExclude {
+    let conf = configs::CONFIG.read().unwrap();
+    exclude_ids_in(
+        &conf.folder_path,
+        conf.args.is_present("enable-noisy-rules"),
+        &ErrorLog::from_config(),
+    )
+}
+
+/// 指定したルールのコンフィグディレクトリのnoisy_rules.txtとexclude_rules.txtから除外するルールのIDを読み込む
+pub fn exclude_ids_in(
+    config_path: &str,
+    enable_noisy_rules: bool,
+    error_log: &ErrorLog,
+) -> RuleExclude {
     let mut exclude_ids = RuleExclude {
         no_use_rule: HashSet::new(),
     };
 
-    if !configs::CONFIG
-        .read()
-        .unwrap()
-        .args
-        .is_present("enable-noisy-rules")
-    {
-        exclude_ids.insert_ids(&format!(
-            "{}/noisy_rules.txt",
-            configs::CONFIG.read().unwrap().folder_path
-        ));
+    if !enable_noisy_rules {
+        exclude_ids.insert_ids(&format!("{}/noisy_rules.txt", config_path), error_log);
     };
 
-    exclude_ids.insert_ids(&format!(
-        "{}/exclude_rules.txt",
-        configs::CONFIG.read().unwrap().folder_path
-    ));
+    exclude_ids.insert_ids(&format!("{}/exclude_rules.txt", config_path), error_log);
 
     exclude_ids
 }
 
 impl RuleExclude {
-    fn insert_ids(&mut self, filename: &str) {
+    fn insert_ids(&mut self, filename: &str, error_log: &ErrorLog) {
         let f = File::open(filename);
         if f.is_err() {
-            if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                AlertMessage::warn(
-                    &mut BufWriter::new(std::io::stderr().lock()),
-                    &format!("{} does not exist", filename),
-                )
-                .ok();
-            }
-            if !*QUIET_ERRORS_FLAG {
-                ERROR_LOG_STACK
-                    .lock()
-                    .unwrap()
-                    .push(format!("{} does not exist", filename));
-            }
+            error_log.warn(&format!("{} does not exist", filename));
             return;
         }
         let reader = BufReader::new(f.unwrap());
@@ -1,8 +1,14 @@
 pub mod afterfact;
+pub mod archive;
 pub mod detections;
+pub mod engine;
 pub mod filter;
 pub mod notify;
 pub mod omikuji;
 pub mod options;
+pub mod recovery;
+pub mod server;
+pub mod state;
 pub mod timeline;
+pub mod tui;
 pub mod yaml;
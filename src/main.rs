@@ -5,61 +5,83 @@ extern crate serde_derive;
 #[cfg(target_os = "windows")]
 extern crate static_vcruntime;
 
-use chrono::{DateTime, Datelike, Local, TimeZone};
+use chrono::{DateTime, Datelike, Local, TimeZone, Utc};
 use evtx::{EvtxParser, ParserSettings};
 use git2::Repository;
 use hashbrown::{HashMap, HashSet};
-use hayabusa::detections::configs::load_pivot_keywords;
-use hayabusa::detections::detection::{self, EvtxRecordInfo};
-use hayabusa::detections::pivot::PIVOT_KEYWORD;
+use hayabusa::afterfact::{after_fact, open_output, CsvSink, JsonSink};
+use hayabusa::archive;
+use hayabusa::detections::configs::{load_pivot_keywords, parse_target_time};
+use hayabusa::detections::geoip::GeoIpEnricher;
+use hayabusa::detections::graph::GraphSink;
+use hayabusa::detections::ioc::IocMatcher;
+use hayabusa::detections::pivot::{PivotKeyword, PivotKeywordSink};
 use hayabusa::detections::print::{
-    AlertMessage, ERROR_LOG_PATH, ERROR_LOG_STACK, PIVOT_KEYWORD_LIST_FLAG, QUIET_ERRORS_FLAG,
-    STATISTICS_FLAG,
+    AlertMessage, ErrorLog, AGGREGATE_STATISTICS_FLAG, COVERAGE_FLAG, ERROR_LOG_PATH,
+    ERROR_LOG_STACK, HISTOGRAM_FLAG, LOGON_SUMMARY_FLAG, MESSAGES, PIVOT_KEYWORD_LIST_FLAG,
+    PROCESS_TREE_FLAG, QUIET_ERRORS_FLAG, SESSIONS_FLAG, STATISTICS_FLAG,
 };
-use hayabusa::detections::rule::{get_detection_keys, RuleNode};
+use hayabusa::detections::sink::DetectionSink;
+use hayabusa::engine::{Engine, ScanOptions, MAX_DETECT_RECORDS};
 use hayabusa::filter;
+use hayabusa::notify::email::{EmailConfig, EmailNotify};
+use hayabusa::notify::forward::RetryPolicy;
+use hayabusa::notify::slack::{SlackNotify, SlackSink};
+use hayabusa::notify::syslog::{SyslogFormat, SyslogProtocol, SyslogSink};
+use hayabusa::notify::webhook::{self, WebhookSink};
 use hayabusa::omikuji::Omikuji;
 use hayabusa::options::level_tuning::LevelTuning;
+use hayabusa::recovery::RecordCarver;
+use hayabusa::server::{self, Server};
+use hayabusa::state::{self, FileState, NewRecords, ScanState};
+use hayabusa::timeline::coverage::{output_coverage, LogSources};
+use hayabusa::timeline::histogram::{output_histogram, Histogram, HistogramConfig};
+use hayabusa::timeline::logon_summary::{output_logon_summary, LogonSummary};
+use hayabusa::timeline::process_tree::{output_process_trees, ProcessTreeSink};
+use hayabusa::timeline::sessions::{output_sessions, SessionFilter, SessionSink};
+use hayabusa::timeline::statistics::{output_aggregated_statistics, AggregatedStatistics};
+use hayabusa::tui::{self, TimelineSink};
 use hayabusa::yaml::ParseYaml;
-use hayabusa::{afterfact::after_fact, detections::utils};
 use hayabusa::{detections::configs, timeline::timelines::Timeline};
 use hhmmss::Hhmmss;
 use pbr::ProgressBar;
 use serde_json::Value;
 use std::cmp::Ordering;
 use std::ffi::{OsStr, OsString};
-use std::fmt::Display;
 use std::fs::create_dir;
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom};
+use std::net::TcpListener;
 use std::path::Path;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use std::{
     env,
     fs::{self, File},
     path::PathBuf,
     vec,
 };
-use tokio::runtime::Runtime;
-use tokio::spawn;
-use tokio::task::JoinHandle;
 
 #[cfg(target_os = "windows")]
 use is_elevated::is_elevated;
 
-// 一度にtimelineやdetectionを実行する行数
-const MAX_DETECT_RECORDS: usize = 5000;
+// evtxファイルとアーカイブ内のファイルを同じParserで扱うためのtrait
+trait ReadSeek: Read + Seek + Send {}
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+// パースしたレコードと、復旧したレコードの場合はファイル内のオフセット
+type RecordResult = Result<(Value, Option<u64>), String>;
+
+const PIVOT_KEYWORDS_PATH: &str = "config/pivot_keywords.txt";
 
 fn main() {
+    // コマンドライン引数を読み込む。ライブラリとして使う場合はengine::ScanOptionsで設定する
+    *configs::CONFIG.write().unwrap() = configs::ConfigReader::new();
     let mut app = App::new();
     app.exec();
-    app.rt.shutdown_background();
 }
 
-pub struct App {
-    rt: Runtime,
-    rule_keys: Vec<String>,
-}
+pub struct App {}
 
 impl Default for App {
     fn default() -> Self {
@@ -69,17 +91,10 @@ impl Default for App {
 
 impl App {
     pub fn new() -> App {
-        App {
-            rt: utils::create_tokio_runtime(),
-            rule_keys: Vec::new(),
-        }
+        App {}
     }
 
     fn exec(&mut self) {
-        if *PIVOT_KEYWORD_LIST_FLAG {
-            load_pivot_keywords("config/pivot_keywords.txt");
-        }
-
         let analysis_start_time: DateTime<Local> = Local::now();
 
         // Show usage when no arguments.
@@ -144,9 +159,77 @@ impl App {
             return;
         }
 
+        if configs::CONFIG.read().unwrap().args.is_present("notify")
+            && !SlackNotify::check_setting()
+        {
+            AlertMessage::alert(
+                &mut BufWriter::new(std::io::stderr().lock()),
+                "--notify requires WEBHOOK_URL and CHANNEL in the .env file.",
+            )
+            .ok();
+            return;
+        }
+
+        if configs::CONFIG.read().unwrap().args.is_present("email") {
+            if let Err(err) = App::create_email_config() {
+                AlertMessage::alert(
+                    &mut BufWriter::new(std::io::stderr().lock()),
+                    &format!(
+                        "--email requires the SMTP settings in the .env file. {}",
+                        err
+                    ),
+                )
+                .ok();
+                return;
+            }
+        }
+
+        if let Some(format) = configs::CONFIG
+            .read()
+            .unwrap()
+            .args
+            .value_of("pivot-format")
+        {
+            if !format.eq_ignore_ascii_case("csv") && !format.eq_ignore_ascii_case("json") {
+                AlertMessage::alert(
+                    &mut BufWriter::new(std::io::stderr().lock()),
+                    "--pivot-format must be csv or json.",
+                )
+                .ok();
+                return;
+            }
+        }
+
+        if let Some(level) = configs::CONFIG
+            .read()
+            .unwrap()
+            .args
+            .value_of("pivot-min-level")
+        {
+            if !configs::LEVELMAP.contains_key(&level.to_uppercase()) {
+                AlertMessage::alert(
+                    &mut BufWriter::new(std::io::stderr().lock()),
+                    &format!("Invalid pivot min level. {}", level),
+                )
+                .ok();
+                return;
+            }
+        }
+
         if let Some(csv_path) = configs::CONFIG.read().unwrap().args.value_of("output") {
-            for (key, _) in PIVOT_KEYWORD.read().unwrap().iter() {
-                let keywords_file_name = csv_path.to_owned() + "-" + key + ".txt";
+            let keywords_files = if *PIVOT_KEYWORD_LIST_FLAG {
+                match load_pivot_keywords(PIVOT_KEYWORDS_PATH) {
+                    Ok(pivot_keywords) => App::pivot_output_files(csv_path, &pivot_keywords),
+                    Err(err) => {
+                        AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err)
+                            .ok();
+                        return;
+                    }
+                }
+            } else {
+                vec![]
+            };
+            for keywords_file_name in keywords_files {
                 if Path::new(&keywords_file_name).exists() {
                     AlertMessage::alert(
                         &mut BufWriter::new(std::io::stderr().lock()),
@@ -159,7 +242,15 @@ impl App {
                     return;
                 }
             }
-            if Path::new(csv_path).exists() {
+            // state-file、watchを指定した場合は前回の出力ファイルに追記する
+            if Path::new(csv_path).exists()
+                && !configs::CONFIG
+                    .read()
+                    .unwrap()
+                    .args
+                    .is_present("state-file")
+                && !configs::CONFIG.read().unwrap().args.is_present("watch")
+            {
                 AlertMessage::alert(
                     &mut BufWriter::new(std::io::stderr().lock()),
                     &format!(
@@ -176,7 +267,29 @@ impl App {
             println!("Generating Event ID Statistics");
             println!();
         }
-        if configs::CONFIG
+        if *LOGON_SUMMARY_FLAG {
+            println!("Generating Logon Summary");
+            println!();
+        }
+        if *HISTOGRAM_FLAG {
+            if let Err(err) = HistogramConfig::from_args() {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                return;
+            }
+            println!("Generating Event Timeline Histogram");
+            println!();
+        }
+        if let Some(json_path) = configs::CONFIG.read().unwrap().args.value_of("tui-file") {
+            let ret = tui::load_json_timeline(Path::new(json_path)).and_then(tui::run);
+            if let Err(err) = ret {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+            }
+            return;
+        } else if let Some(address) = configs::CONFIG.read().unwrap().args.value_of("serve") {
+            self.serve(address);
+        } else if let Some(directory) = configs::CONFIG.read().unwrap().args.value_of("watch") {
+            self.watch_directory(directory);
+        } else if configs::CONFIG
             .read()
             .unwrap()
             .args
@@ -188,7 +301,23 @@ impl App {
             }
             self.analysis_files(live_analysis_list.unwrap());
         } else if let Some(filepath) = configs::CONFIG.read().unwrap().args.value_of("filepath") {
-            if !filepath.ends_with(".evtx")
+            if archive::is_archive(Path::new(filepath)) {
+                let evtx_files = self.collect_archive_evtxfiles(Path::new(filepath));
+                if evtx_files.is_empty() {
+                    AlertMessage::alert(
+                        &mut BufWriter::new(std::io::stderr().lock()),
+                        "No .evtx files were found.",
+                    )
+                    .ok();
+                    return;
+                }
+                self.analysis_files(evtx_files);
+            } else if !(filepath.ends_with(".evtx")
+                || configs::CONFIG
+                    .read()
+                    .unwrap()
+                    .args
+                    .is_present("recover-records"))
                 || Path::new(filepath)
                     .file_stem()
                     .unwrap_or_else(|| OsStr::new("."))
@@ -199,12 +328,13 @@ impl App {
             {
                 AlertMessage::alert(
                     &mut BufWriter::new(std::io::stderr().lock()),
-                    "--filepath only accepts .evtx files. Hidden files are ignored.",
+                    "--filepath only accepts .evtx files or archives (.zip, .tar.gz, .tgz) unless --recover-records is specified. Hidden files are ignored.",
                 )
                 .ok();
                 return;
+            } else {
+                self.analysis_files(vec![PathBuf::from(filepath)]);
             }
-            self.analysis_files(vec![PathBuf::from(filepath)]);
         } else if let Some(directory) = configs::CONFIG.read().unwrap().args.value_of("directory") {
             let evtx_files = self.collect_evtxfiles(directory);
             if evtx_files.is_empty() {
@@ -270,62 +400,109 @@ impl App {
         if ERROR_LOG_STACK.lock().unwrap().len() > 0 {
             AlertMessage::create_error_log(ERROR_LOG_PATH.to_string());
         }
+    }
 
-        if *PIVOT_KEYWORD_LIST_FLAG {
-            //ファイル出力の場合
-            if let Some(pivot_file) = configs::CONFIG.read().unwrap().args.value_of("output") {
-                for (key, pivot_keyword) in PIVOT_KEYWORD.read().unwrap().iter() {
-                    let mut f = BufWriter::new(
-                        fs::File::create(pivot_file.to_owned() + "-" + key + ".txt").unwrap(),
-                    );
-                    let mut output = "".to_string();
-                    output += &format!("{}: ", key).to_string();
-
-                    output += "( ";
-                    for i in pivot_keyword.fields.iter() {
-                        output += &format!("%{}% ", i).to_string();
-                    }
-                    output += "):";
-                    output += "\n";
-
-                    for i in pivot_keyword.keywords.iter() {
-                        output += &format!("{}\n", i).to_string();
-                    }
+    /// ピボットキーワードを見つかった回数の多い順に出力する。
+    /// --pivot-formatがjsonの場合は1つのJSONファイル、それ以外はキーワードの種類ごとのCSVファイルに出力する
+    fn output_pivot_keywords(sink: &PivotKeywordSink) {
+        let pivot_keyword = sink.pivot_keywords();
+        let mut keys: Vec<&String> = pivot_keyword.keys().collect();
+        keys.sort();
+        //ファイル出力の場合
+        if let Some(pivot_file) = configs::CONFIG.read().unwrap().args.value_of("output") {
+            let output_files = App::pivot_output_files(pivot_file, &pivot_keyword);
+            let ret = if App::is_pivot_json() {
+                let json: serde_json::Map<String, Value> = keys
+                    .iter()
+                    .map(|key| (key.to_string(), pivot_keyword[*key].to_json()))
+                    .collect();
+                File::create(&output_files[0])
+                    .map_err(|e| e.to_string())
+                    .and_then(|file| {
+                        serde_json::to_writer_pretty(BufWriter::new(file), &json)
+                            .map_err(|e| e.to_string())
+                    })
+            } else {
+                keys.iter()
+                    .zip(output_files.iter())
+                    .try_for_each(|(key, path)| {
+                        File::create(path)
+                            .map_err(|e| e.to_string())
+                            .and_then(|file| pivot_keyword[*key].write_csv(BufWriter::new(file)))
+                    })
+            };
+            if let Err(err) = ret {
+                AlertMessage::alert(
+                    &mut BufWriter::new(std::io::stderr().lock()),
+                    &format!("Failed to write the pivot keywords. {}", err),
+                )
+                .ok();
+                return;
+            }
 
-                    f.write_all(output.as_bytes()).unwrap();
+            //output to stdout
+            let mut output = "Pivot keyword results saved to the following files:\n".to_string();
+            for path in output_files {
+                output += &(path + "\n");
+            }
+            println!("{}", output);
+        } else {
+            //標準出力の場合
+            let mut output = "The following pivot keywords were found:\n".to_string();
+            for key in keys {
+                let pivot = &pivot_keyword[key];
+                output += &format!("{}: ", key).to_string();
+
+                output += "( ";
+                for i in pivot.fields.iter() {
+                    output += &format!("%{}% ", i).to_string();
                 }
-
-                //output to stdout
-                let mut output =
-                    "Pivot keyword results saved to the following files:\n".to_string();
-                for (key, _) in PIVOT_KEYWORD.read().unwrap().iter() {
-                    output += &(pivot_file.to_owned() + "-" + key + ".txt" + "\n");
+                output += "):";
+                output += "\n";
+
+                for (keyword, stats) in pivot.sorted_keywords() {
+                    output += &format!(
+                        "{} (Count: {}, Rules: {}, Computers: {})\n",
+                        keyword,
+                        stats.count,
+                        stats.rules.len(),
+                        stats.computers.len()
+                    );
                 }
-                println!("{}", output);
-            } else {
-                //標準出力の場合
-                let mut output = "The following pivot keywords were found:\n".to_string();
-                for (key, pivot_keyword) in PIVOT_KEYWORD.read().unwrap().iter() {
-                    output += &format!("{}: ", key).to_string();
-
-                    output += "( ";
-                    for i in pivot_keyword.fields.iter() {
-                        output += &format!("%{}% ", i).to_string();
-                    }
-                    output += "):";
-                    output += "\n";
 
-                    for i in pivot_keyword.keywords.iter() {
-                        output += &format!("{}\n", i).to_string();
-                    }
-
-                    output += "\n";
-                }
-                print!("{}", output);
+                output += "\n";
             }
+            print!("{}", output);
         }
     }
 
+    /// --pivot-formatにjsonが指定されているかを返す
+    fn is_pivot_json() -> bool {
+        configs::CONFIG
+            .read()
+            .unwrap()
+            .args
+            .value_of("pivot-format")
+            .unwrap_or("csv")
+            .eq_ignore_ascii_case("json")
+    }
+
+    /// ピボットキーワードの出力先のファイル。--outputのファイル名に、JSONの場合は-pivot-keywords.json、CSVの場合はキーワードの種類ごとに-<KeywordName>.csvを付ける。
+    /// 検知結果も--outputのファイルに出力するため、同じファイルにならないようにする
+    fn pivot_output_files(
+        pivot_file: &str,
+        pivot_keywords: &HashMap<String, PivotKeyword>,
+    ) -> Vec<String> {
+        if App::is_pivot_json() {
+            return vec![pivot_file.to_owned() + "-pivot-keywords.json"];
+        }
+        let mut keys: Vec<&String> = pivot_keywords.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| pivot_file.to_owned() + "-" + key + ".csv")
+            .collect()
+    }
+
     #[cfg(not(target_os = "windows"))]
     fn collect_liveanalysis_files(&self) -> Option<Vec<PathBuf>> {
         AlertMessage::alert(
@@ -362,6 +539,11 @@ impl App {
     }
 
     fn collect_evtxfiles(&self, dirpath: &str) -> Vec<PathBuf> {
+        // -dにアーカイブが直接指定された場合
+        if Path::new(dirpath).is_file() && archive::is_archive(Path::new(dirpath)) {
+            return self.collect_archive_evtxfiles(Path::new(dirpath));
+        }
+
         let entries = fs::read_dir(dirpath);
         if entries.is_err() {
             let errmsg = format!("{}", entries.unwrap_err());
@@ -390,6 +572,8 @@ impl App {
                     ret.extend(subdir_ret);
                     Option::Some(())
                 });
+            } else if archive::is_archive(&path) {
+                ret.extend(self.collect_archive_evtxfiles(&path));
             } else {
                 let path_str = path.to_str().unwrap_or("");
                 if path_str.ends_with(".evtx")
@@ -408,6 +592,26 @@ impl App {
         ret
     }
 
+    /// アーカイブ内の.evtxファイルを列挙する。パスはアーカイブのパスにメンバーのパスを連結したものとなる。
+    fn collect_archive_evtxfiles(&self, archive_path: &Path) -> Vec<PathBuf> {
+        match archive::collect_evtx_members(archive_path) {
+            Ok(members) => members,
+            Err(errmsg) => {
+                if configs::CONFIG.read().unwrap().args.is_present("verbose") {
+                    AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &errmsg)
+                        .ok();
+                }
+                if !*QUIET_ERRORS_FLAG {
+                    ERROR_LOG_STACK
+                        .lock()
+                        .unwrap()
+                        .push(format!("[ERROR] {}", errmsg));
+                }
+                vec![]
+            }
+        }
+    }
+
     fn print_contributors(&self) {
         match fs::read_to_string("./contributors.txt") {
             Ok(contents) => println!("{}", contents),
@@ -422,22 +626,103 @@ impl App {
     }
 
     fn analysis_files(&mut self, evtx_files: Vec<PathBuf>) {
-        let level = configs::CONFIG
+        println!("Analyzing event files: {:?}", evtx_files.len());
+
+        let options = match App::create_scan_options() {
+            Ok(options) => options,
+            Err(err) => {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                return;
+            }
+        };
+        let mut sinks: Vec<Arc<dyn DetectionSink>> =
+            vec![Arc::clone(&MESSAGES) as Arc<dyn DetectionSink>];
+        match App::create_notify_sinks() {
+            Ok(notify_sinks) => sinks.extend(notify_sinks),
+            Err(err) => {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                return;
+            }
+        }
+        match App::create_graph_sink() {
+            Ok(Some(graph_sink)) => sinks.push(Arc::new(graph_sink)),
+            Ok(None) => {}
+            Err(err) => {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                return;
+            }
+        }
+        // sinkのfinishは追加した順に呼び出されるため、JsonSinkのfinishより前にプロセスツリーを作成するように先に追加する
+        let process_tree_sink = App::add_sink(&mut sinks, *PROCESS_TREE_FLAG, ProcessTreeSink::new);
+        // CSVと同じように、state-fileを指定した場合は前回の出力ファイルに追記するか、--rotate-outputの場合は名前を変更してから出力する
+        if let Some(json_path) = configs::CONFIG.read().unwrap().args.value_of("json-output") {
+            match open_output(Path::new(json_path)) {
+                Ok((file, _)) => {
+                    let sink = JsonSink::new(BufWriter::new(file));
+                    match &process_tree_sink {
+                        Some(process_tree_sink) => sinks.push(Arc::new(
+                            sink.with_process_tree(Arc::clone(process_tree_sink)),
+                        )),
+                        None => sinks.push(Arc::new(sink)),
+                    }
+                }
+                Err(err) => {
+                    AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                    return;
+                }
+            }
+        }
+        // --tuiの場合は元のレコードを含めて検知結果を保持し、スキャン後に表示する
+        let timeline_sink = configs::CONFIG
             .read()
             .unwrap()
             .args
-            .value_of("min-level")
-            .unwrap_or("informational")
-            .to_uppercase();
-        println!("Analyzing event files: {:?}", evtx_files.len());
-
-        let rule_files = detection::Detection::parse_rule_files(
-            level,
-            configs::CONFIG.read().unwrap().args.value_of("rules"),
-            &filter::exclude_ids(),
-        );
-
-        if rule_files.is_empty() {
+            .is_present("tui")
+            .then(|| Arc::new(TimelineSink::new()));
+        if let Some(timeline_sink) = &timeline_sink {
+            sinks.push(Arc::clone(timeline_sink) as Arc<dyn DetectionSink>);
+        }
+        let sessions = App::add_sink(&mut sinks, *SESSIONS_FLAG, || {
+            SessionSink::new(
+                configs::CONFIG
+                    .read()
+                    .unwrap()
+                    .args
+                    .value_of("session")
+                    .map(SessionFilter::parse),
+            )
+        });
+        // 全てのレコードを集計するレポートは、Engineからレコードを受け取るsinkとして追加する
+        let logon_summary = App::add_sink(&mut sinks, *LOGON_SUMMARY_FLAG, || {
+            Mutex::new(LogonSummary::new())
+        });
+        let aggregated_statistics = App::add_sink(&mut sinks, *AGGREGATE_STATISTICS_FLAG, || {
+            Mutex::new(AggregatedStatistics::new())
+        });
+        let histogram = App::add_sink(&mut sinks, *HISTOGRAM_FLAG, || Mutex::new(Histogram::new()));
+        let pivot_keywords = if *PIVOT_KEYWORD_LIST_FLAG {
+            match load_pivot_keywords(PIVOT_KEYWORDS_PATH) {
+                Ok(pivot_keywords) => Some(pivot_keywords),
+                Err(err) => {
+                    AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let pivot_keyword_sink = pivot_keywords.map(|pivot_keywords| {
+            let sink = Arc::new(PivotKeywordSink::new(
+                pivot_keywords,
+                options.get_pivot_min_level(),
+            ));
+            sinks.push(Arc::clone(&sink) as Arc<dyn DetectionSink>);
+            sink
+        });
+        let log_sources =
+            App::add_sink(&mut sinks, *COVERAGE_FLAG, || Mutex::new(LogSources::new()));
+        let engine = Engine::new(options, sinks);
+        if engine.is_err() {
             AlertMessage::alert(
                 &mut BufWriter::new(std::io::stderr().lock()),
                 "No rules were loaded. Please download the latest rules with the --update-rules option.\r\n",
@@ -446,20 +731,492 @@ impl App {
             return;
         }
 
+        // state-fileが指定された場合は前回までに解析したレコードをスキップする
+        let state_path = configs::CONFIG
+            .read()
+            .unwrap()
+            .args
+            .value_of("state-file")
+            .map(PathBuf::from);
+        let state = match &state_path {
+            Some(state_path) => match ScanState::load(state_path) {
+                Ok(state) => Option::Some(Mutex::new(state)),
+                Err(err) => {
+                    AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                    return;
+                }
+            },
+            None => Option::None,
+        };
+
         let mut pb = ProgressBar::new(evtx_files.len() as u64);
         pb.show_speed = false;
-        self.rule_keys = self.get_all_keys(&rule_files);
-        let mut detection = detection::Detection::new(rule_files);
+        let pb = Mutex::new(pb);
+        let engine = engine.unwrap();
+        self.analysis_evtx_files(evtx_files, &engine, state.as_ref(), || {
+            pb.lock().unwrap().inc();
+        });
+        // カバレッジは読み込んだルールを参照するため、engineを終了する前に出力する
+        if let Some(log_sources) = &log_sources {
+            output_coverage(engine.rules(), &log_sources.lock().unwrap());
+        }
+        let finish_result = engine.finish();
+        if let Err(err) = &finish_result {
+            AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), err).ok();
+        }
+        // 複数のレポートを指定した場合は全てのレポートを出力する
+        if let Some(histogram) = &histogram {
+            output_histogram(&histogram.lock().unwrap());
+        }
+        if let Some(aggregated_statistics) = &aggregated_statistics {
+            output_aggregated_statistics(&aggregated_statistics.lock().unwrap());
+        }
+        if let Some(logon_summary) = &logon_summary {
+            output_logon_summary(&logon_summary.lock().unwrap());
+        }
+        if let Some(sessions) = &sessions {
+            output_sessions(sessions);
+        }
+        let has_report = histogram.is_some()
+            || aggregated_statistics.is_some()
+            || logon_summary.is_some()
+            || sessions.is_some();
+        if !has_report && !*STATISTICS_FLAG && !*COVERAGE_FLAG {
+            // --tuiで--outputを指定していない場合は、標準出力に検知結果を出力しない
+            if timeline_sink.is_none() || configs::CONFIG.read().unwrap().args.is_present("output")
+            {
+                after_fact();
+            }
+            if let Some(process_tree_sink) = &process_tree_sink {
+                output_process_trees(process_tree_sink);
+            }
+            if configs::CONFIG.read().unwrap().args.is_present("email") {
+                App::send_email();
+            }
+        }
+        if let Some(pivot_keyword_sink) = &pivot_keyword_sink {
+            App::output_pivot_keywords(pivot_keyword_sink);
+        }
+        // 出力に失敗したレコードを次回も解析し直すため、全ての出力が終わってから解析済みの状態を保存する。
+        // after_factは出力に失敗した場合にプロセスを終了するため、ここには到達しない
+        if let (Some(state_path), Some(state)) = (state_path, state) {
+            if finish_result.is_ok() {
+                if let Err(err) = state.into_inner().unwrap().save(&state_path) {
+                    AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                }
+            }
+        }
+        if let Some(timeline_sink) = timeline_sink {
+            if let Err(err) = tui::run(timeline_sink.take_entries()) {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+            }
+        }
+    }
+
+    /// enabledがtrueの場合はsinkを作成してsinksに追加する。スキャン後にsinkの結果を出力するために、追加したsinkを返す
+    fn add_sink<S: DetectionSink + 'static, F: FnOnce() -> S>(
+        sinks: &mut Vec<Arc<dyn DetectionSink>>,
+        enabled: bool,
+        create: F,
+    ) -> Option<Arc<S>> {
+        let sink = enabled.then(|| Arc::new(create()));
+        if let Some(sink) = &sink {
+            sinks.push(Arc::clone(sink) as Arc<dyn DetectionSink>);
+        }
+        sink
+    }
+
+    /// .envのSMTPの設定を読み込む。EMAIL_ATTACHMENTがcsvの場合は--outputのファイルを添付する
+    fn create_email_config() -> Result<EmailConfig, String> {
+        let args = &configs::CONFIG.read().unwrap().args;
+        EmailConfig::from_env(args.value_of("output").map(Path::new))
+    }
+
+    /// スキャン結果のダイジェストをメールで送信する
+    fn send_email() {
+        let ret = App::create_email_config()
+            .and_then(|config| EmailNotify::notify(&config, &MESSAGES.lock().unwrap()));
+        if let Err(err) = ret {
+            AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+        }
+    }
+
+    /// evtxファイル単位で並列に解析し、1ファイル解析するたびにon_analyzedを呼び出す。
+    /// tar.gzのアーカイブ内のファイルは、アーカイブを1回だけ展開しながら順番に解析する
+    fn analysis_evtx_files<F: Fn() + Sync>(
+        &self,
+        evtx_files: Vec<PathBuf>,
+        engine: &Engine,
+        state: Option<&Mutex<ScanState>>,
+        on_analyzed: F,
+    ) {
+        let mut targets: Vec<(PathBuf, Vec<String>)> = vec![];
+        let mut archive_idxes: HashMap<PathBuf, usize> = HashMap::new();
         for evtx_file in evtx_files {
-            if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                println!("Checking target evtx FilePath: {:?}", &evtx_file);
+            match archive::split_member_path(&evtx_file) {
+                Some((archive_path, member)) if archive::is_sequential(&archive_path) => {
+                    let idx = *archive_idxes
+                        .entry(archive_path.clone())
+                        .or_insert_with(|| {
+                            targets.push((archive_path, vec![]));
+                            targets.len() - 1
+                        });
+                    targets[idx].1.push(member);
+                }
+                _ => targets.push((evtx_file, vec![])),
+            }
+        }
+
+        engine.par_for_each(targets, |(path, members)| {
+            if members.is_empty() {
+                if configs::CONFIG.read().unwrap().args.is_present("verbose") {
+                    println!("Checking target evtx FilePath: {:?}", &path);
+                }
+                if let Some(reader) = App::open_evtx_reader(&path) {
+                    self.analysis_file(path, reader, engine, state);
+                }
+                on_analyzed();
+                return;
+            }
+            let ret = archive::for_each_member(&path, &members, |member, data| {
+                let evtx_filepath = path.join(member);
+                match data {
+                    Ok(data) => {
+                        if configs::CONFIG.read().unwrap().args.is_present("verbose") {
+                            println!("Checking target evtx FilePath: {:?}", &evtx_filepath);
+                        }
+                        self.analysis_file(
+                            evtx_filepath,
+                            Box::new(Cursor::new(data)),
+                            engine,
+                            state,
+                        );
+                    }
+                    Err(err) => App::output_open_error(&evtx_filepath, &err),
+                }
+                on_analyzed();
+            });
+            if let Err(err) = ret {
+                App::output_open_error(&path, &err);
+            }
+        });
+    }
+
+    /// --notify、--webhook、--syslogが指定された場合は、検知結果を通知・転送するsinkを作成する
+    fn create_notify_sinks() -> Result<Vec<Arc<dyn DetectionSink>>, String> {
+        let args = &configs::CONFIG.read().unwrap().args;
+        let min_level = args.value_of("notify-level").unwrap_or("high");
+        if !configs::LEVELMAP.contains_key(&min_level.to_uppercase()) {
+            return Err(format!("Invalid notify level. {}", min_level));
+        }
+        let mut sinks: Vec<Arc<dyn DetectionSink>> = vec![];
+        // 再送しても送信できなかったアラートはdead-letterファイルに保存する
+        let policy = RetryPolicy::new().dead_letter(PathBuf::from(
+            args.value_of("dead-letter")
+                .unwrap_or("./logs/dead-letter.jsonl"),
+        ));
+        if args.is_present("notify") {
+            if let Some(sink) = SlackSink::from_env(policy.clone(), ErrorLog::from_config()) {
+                sinks.push(Arc::new(sink.min_level(min_level)));
+            }
+        }
+        if let Some(url) = args.value_of("webhook") {
+            let template = match args.value_of("webhook-template") {
+                Some(path) => fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read webhook template. {} {}", path, e))?,
+                None => webhook::DEFAULT_TEMPLATE.to_string(),
+            };
+            let sink = WebhookSink::new(url, &template, policy.clone(), ErrorLog::from_config())?;
+            sinks.push(Arc::new(sink.min_level(min_level)));
+        }
+        if let Some(address) = args.value_of("syslog") {
+            let protocol = args.value_of("syslog-protocol").unwrap_or("udp");
+            let protocol = SyslogProtocol::parse(protocol)
+                .ok_or_else(|| format!("Invalid syslog protocol. {}", protocol))?;
+            let format = args.value_of("syslog-format").unwrap_or("rfc5424");
+            let format = SyslogFormat::parse(format)
+                .ok_or_else(|| format!("Invalid syslog format. {}", format))?;
+            let sink = SyslogSink::new(address, protocol, format, policy, ErrorLog::from_config())?;
+            sinks.push(Arc::new(sink.min_level(min_level)));
+        }
+        Ok(sinks)
+    }
+
+    /// コマンドライン引数から検知処理の設定を作成する。--ioc-fileと--geoipが指定された場合はそれぞれのファイルを読み込む
+    fn create_scan_options() -> Result<ScanOptions, String> {
+        let conf = configs::CONFIG.read().unwrap();
+        let mut options = ScanOptions::from_config(&conf);
+        if let Some(ioc_file) = conf.args.value_of("ioc-file") {
+            options = options.ioc_matcher(IocMatcher::load(ioc_file)?);
+        }
+        if let Some(geoip) = conf.args.value_of("geoip") {
+            options = options.geoip(GeoIpEnricher::load(geoip)?);
+        }
+        Ok(options)
+    }
+
+    /// --graphが指定された場合は、検知結果からエンティティの関係のグラフを作成するsinkを作成する
+    fn create_graph_sink() -> Result<Option<GraphSink>, String> {
+        let args = &configs::CONFIG.read().unwrap().args;
+        let path = match args.value_of("graph") {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(None),
+        };
+        let parse_time = |name: &str| -> Result<Option<DateTime<Utc>>, String> {
+            match args.value_of(name) {
+                Some(time) => parse_target_time(time)
+                    .map(Option::Some)
+                    .map_err(|e| format!("{} field: {}", name, e)),
+                None => Ok(None),
+            }
+        };
+        let min_level = args.value_of("graph-min-level").unwrap_or("informational");
+        if !configs::LEVELMAP.contains_key(&min_level.to_uppercase()) {
+            return Err(format!("Invalid graph min level. {}", min_level));
+        }
+        let sink = GraphSink::new(path)
+            .min_level(min_level)
+            .time_window(parse_time("graph-start")?, parse_time("graph-end")?);
+        Ok(Some(sink))
+    }
+
+    /// ルールを読み込んだまま、スキャンのジョブを受け付けるREST APIを--serveで指定したアドレスで提供する。Ctrl+Cで終了する
+    fn serve(&self, address: &str) {
+        let listener = match TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(err) => {
+                AlertMessage::alert(
+                    &mut BufWriter::new(std::io::stderr().lock()),
+                    &format!("Failed to listen on {}. {}", address, err),
+                )
+                .ok();
+                return;
+            }
+        };
+        let options = match App::create_scan_options() {
+            Ok(options) => options,
+            Err(err) => {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                return;
+            }
+        };
+        let engine = match Engine::new(options, vec![]) {
+            Ok(engine) => engine,
+            Err(_) => {
+                AlertMessage::alert(
+                    &mut BufWriter::new(std::io::stderr().lock()),
+                    "No rules were loaded. Please download the latest rules with the --update-rules option.\r\n",
+                )
+                .ok();
+                return;
+            }
+        };
+        println!(
+            "Serving the REST API on http://{} with {} rules. Press Ctrl+C to stop.",
+            address,
+            engine.rules().len()
+        );
+        // 他のプロセスとアップロードしたファイルが混ざらないように、プロセスごとのディレクトリに保存する
+        let upload_dir =
+            std::env::temp_dir().join(format!("hayabusa-uploads-{}", std::process::id()));
+        let mut server = Server::new(engine, upload_dir);
+        let args = &configs::CONFIG.read().unwrap().args;
+        match args.value_of("serve-token") {
+            Some(token) => server = server.token(token),
+            None => {
+                let is_loopback = listener
+                    .local_addr()
+                    .map(|addr| addr.ip().is_loopback())
+                    .unwrap_or(false);
+                if !is_loopback {
+                    AlertMessage::warn(
+                        &mut BufWriter::new(std::io::stderr().lock()),
+                        "The REST API is listening on a non-loopback address without --serve-token. Anyone who can connect can scan files on this machine.",
+                    )
+                    .ok();
+                }
+            }
+        }
+        if let Some(root) = args.value_of("serve-root") {
+            if !Path::new(root).is_dir() {
+                AlertMessage::alert(
+                    &mut BufWriter::new(std::io::stderr().lock()),
+                    &format!("--serve-root directory does not exist. {}", root),
+                )
+                .ok();
+                return;
+            }
+            server = server.root(PathBuf::from(root));
+        }
+        if let Err(err) = server::serve(listener, server) {
+            AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+        }
+    }
+
+    /// --watchで指定したディレクトリを定期的に確認し、追加・更新された.evtxファイルの新しいレコードのみを解析する。
+    /// ルールは読み込んだまま保持し、検知結果は確認するたびに--outputのファイルまたは標準出力にCSV形式で出力する。Ctrl+Cで終了する
+    fn watch_directory(&self, dirpath: &str) {
+        if !Path::new(dirpath).is_dir() {
+            AlertMessage::alert(
+                &mut BufWriter::new(std::io::stderr().lock()),
+                &format!("--watch directory does not exist. {}", dirpath),
+            )
+            .ok();
+            return;
+        }
+        let interval = match configs::CONFIG
+            .read()
+            .unwrap()
+            .args
+            .value_of("watch-interval")
+            .map(|interval| interval.parse::<u64>())
+        {
+            Some(Ok(interval)) => interval.max(1),
+            Some(Err(_)) => {
+                AlertMessage::alert(
+                    &mut BufWriter::new(std::io::stderr().lock()),
+                    "--watch-interval must be a number of seconds.",
+                )
+                .ok();
+                return;
+            }
+            None => 10,
+        };
+
+        // 出力先のCSVファイルが既に存在する場合はヘッダを出力せずに追記する
+        let sink: Arc<dyn DetectionSink> =
+            match configs::CONFIG.read().unwrap().args.value_of("output") {
+                Some(csv_path) => {
+                    let exists = fs::metadata(csv_path)
+                        .map(|metadata| metadata.len() > 0)
+                        .unwrap_or(false);
+                    match fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(csv_path)
+                    {
+                        Ok(file) if exists => Arc::new(CsvSink::append(file)),
+                        Ok(file) => Arc::new(CsvSink::new(file)),
+                        Err(err) => {
+                            AlertMessage::alert(
+                                &mut BufWriter::new(std::io::stderr().lock()),
+                                &format!("Failed to open file. {} {}", csv_path, err),
+                            )
+                            .ok();
+                            return;
+                        }
+                    }
+                }
+                None => Arc::new(CsvSink::new(std::io::stdout())),
+            };
+        let mut sinks = vec![sink];
+        // JSON Lines形式は1行ずつ出力するため、CSVと同じように既存のファイルに追記する
+        if let Some(json_path) = configs::CONFIG.read().unwrap().args.value_of("json-output") {
+            match fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(json_path)
+            {
+                Ok(file) => sinks.push(Arc::new(JsonSink::new(BufWriter::new(file)))),
+                Err(err) => {
+                    AlertMessage::alert(
+                        &mut BufWriter::new(std::io::stderr().lock()),
+                        &format!("Failed to open file. {} {}", json_path, err),
+                    )
+                    .ok();
+                    return;
+                }
             }
-            detection = self.analysis_file(evtx_file, detection);
-            pb.inc();
         }
-        detection.add_aggcondition_msges(&self.rt);
-        if !*STATISTICS_FLAG && !*PIVOT_KEYWORD_LIST_FLAG {
-            after_fact();
+        match App::create_notify_sinks() {
+            Ok(notify_sinks) => sinks.extend(notify_sinks),
+            Err(err) => {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                return;
+            }
+        }
+        let options = match App::create_scan_options() {
+            Ok(options) => options,
+            Err(err) => {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                return;
+            }
+        };
+        let mut engine = match Engine::new(options, sinks) {
+            Ok(engine) => engine,
+            Err(_) => {
+                AlertMessage::alert(
+                    &mut BufWriter::new(std::io::stderr().lock()),
+                    "No rules were loaded. Please download the latest rules with the --update-rules option.\r\n",
+                )
+                .ok();
+                return;
+            }
+        };
+
+        // 解析済みのレコードはメモリ上に保持する。state-fileが指定された場合は再起動しても続きから解析できるように保存する
+        let state_path = configs::CONFIG
+            .read()
+            .unwrap()
+            .args
+            .value_of("state-file")
+            .map(PathBuf::from);
+        let state = match &state_path {
+            Some(state_path) => match ScanState::load(state_path) {
+                Ok(state) => state,
+                Err(err) => {
+                    AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                    return;
+                }
+            },
+            None => ScanState::default(),
+        };
+        let state = Mutex::new(state);
+
+        println!("Watching directory: {} (Press Ctrl+C to stop.)", dirpath);
+        let mut file_versions: HashMap<PathBuf, (u64, SystemTime)> = HashMap::new();
+        loop {
+            // 前回から更新日時とサイズが変わったファイルのみを解析する
+            let evtx_files: Vec<PathBuf> = self
+                .collect_evtxfiles(dirpath)
+                .into_iter()
+                .filter(|evtx_file| {
+                    let metadata_path = archive::split_member_path(evtx_file)
+                        .map(|(archive_path, _)| archive_path)
+                        .unwrap_or_else(|| evtx_file.to_path_buf());
+                    let version = match fs::metadata(metadata_path) {
+                        Ok(metadata) => (
+                            metadata.len(),
+                            metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                        ),
+                        Err(_) => return false,
+                    };
+                    file_versions.insert(evtx_file.to_path_buf(), version) != Some(version)
+                })
+                .collect();
+            let analyzed = !evtx_files.is_empty();
+            if analyzed {
+                self.analysis_evtx_files(evtx_files, &engine, Some(&state), || {});
+            }
+            match engine.flush() {
+                // 検知結果を書き出した後に解析済みの状態を保存する
+                Ok(_) if analyzed => {
+                    if let Some(state_path) = &state_path {
+                        if let Err(err) = state.lock().unwrap().save(state_path) {
+                            AlertMessage::alert(
+                                &mut BufWriter::new(std::io::stderr().lock()),
+                                &err,
+                            )
+                            .ok();
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                }
+            }
+            thread::sleep(Duration::from_secs(interval));
         }
     }
 
@@ -467,17 +1224,55 @@ impl App {
     fn analysis_file(
         &self,
         evtx_filepath: PathBuf,
-        mut detection: detection::Detection,
-    ) -> detection::Detection {
+        mut reader: Box<dyn ReadSeek>,
+        engine: &Engine,
+        state: Option<&Mutex<ScanState>>,
+    ) {
         let path = evtx_filepath.display();
-        let parser = self.evtx_to_jsons(evtx_filepath.clone());
-        if parser.is_none() {
-            return detection;
-        }
-
         let mut tl = Timeline::new();
-        let mut parser = parser.unwrap();
-        let mut records = parser.records_json_value();
+        // state-fileが指定された場合は、前回までに解析したレコードのEventRecordIDを取得する
+        let file_identity = state.map(|_| App::get_file_identity(&evtx_filepath, &mut reader));
+        let last_record_id = match (state, &file_identity) {
+            (Some(state), Some((file_id, size))) => {
+                state
+                    .lock()
+                    .unwrap()
+                    .get_last_record_id(&path.to_string(), file_id, *size)
+            }
+            _ => 0,
+        };
+        let mut max_record_id = last_record_id;
+        let detect_rules =
+            !*STATISTICS_FLAG && !*LOGON_SUMMARY_FLAG && !*HISTOGRAM_FLAG && !*COVERAGE_FLAG;
+        let mut parser;
+        // recover-recordsオプションが指定された場合はシグネチャを探してチャンク/レコード単位で復旧する。見つかったオフセットを出力に含める
+        let mut records: Box<dyn Iterator<Item = RecordResult>> = if configs::CONFIG
+            .read()
+            .unwrap()
+            .args
+            .is_present("recover-records")
+        {
+            Box::new(
+                RecordCarver::new(reader, App::create_parser_settings())
+                    .map(|record| record.map(|record| (record.data, Some(record.offset)))),
+            )
+        } else if state.is_some() {
+            // 解析済みのレコードしか含まないチャンクはパースしない
+            Box::new(
+                NewRecords::new(reader, App::create_parser_settings(), last_record_id)
+                    .map(|record| record.map(|data| (data, None))),
+            )
+        } else {
+            parser = match self.evtx_to_jsons(&evtx_filepath, reader) {
+                Some(parser) => parser,
+                None => return,
+            };
+            Box::new(parser.records_json_value().map(|record| {
+                record
+                    .map(|record| (record.data, None))
+                    .map_err(|e| e.to_string())
+            }))
+        };
 
         loop {
             let mut records_per_detect = vec![];
@@ -509,105 +1304,137 @@ impl App {
                     continue;
                 }
 
-                // target_eventids.txtでフィルタする。
-                let data = record_result.unwrap().data;
-                if !self._is_target_event_id(&data) {
+                // state-fileで解析済みのレコードはスキップする。
+                let (data, offset) = record_result.unwrap();
+                if state.is_some() {
+                    if let Some(record_id) = data["Event"]["System"]["EventRecordID"].as_u64() {
+                        if record_id <= last_record_id {
+                            continue;
+                        }
+                        max_record_id = max_record_id.max(record_id);
+                    }
+                }
+
+                // target_eventids.txtでフィルタする。ルールを実行する場合は、除外するレコードもengineでIOCと照合する
+                let is_target = if detect_rules {
+                    engine.is_scan_target(&data)
+                } else {
+                    engine.is_target_event_id(&data)
+                };
+                if !is_target {
                     continue;
                 }
 
-                // EvtxRecordInfo構造体に変更
-                records_per_detect.push(data);
+                // 復旧したレコードはファイル内のオフセットをパスに付与する
+                let record_path = match offset {
+                    Some(offset) => format!("{}@0x{:x}", path, offset),
+                    None => path.to_string(),
+                };
+                records_per_detect.push((data, record_path));
             }
             if records_per_detect.is_empty() {
                 break;
             }
 
-            let records_per_detect = self.rt.block_on(App::create_rec_infos(
-                records_per_detect,
-                &path,
-                self.rule_keys.clone(),
-            ));
+            // EvtxRecordInfo構造体に変更
+            let records_per_detect = engine.create_rec_infos(records_per_detect);
 
             // timeline機能の実行
             tl.start(&records_per_detect);
 
-            if !*STATISTICS_FLAG {
+            if detect_rules {
                 // ruleファイルの検知
-                detection = detection.start(&self.rt, records_per_detect);
+                engine.detect(records_per_detect);
+            } else {
+                // ルールを実行せずに、レポートのsinkで集計する
+                engine.collect(&records_per_detect);
             }
         }
 
         tl.tm_stats_dsp_msg();
 
-        detection
+        // 解析した状態をstate-fileに保存するために記録する
+        if let (Some(state), Some((file_id, size))) = (state, file_identity) {
+            state.lock().unwrap().insert(
+                path.to_string(),
+                FileState {
+                    file_id,
+                    size,
+                    last_record_id: max_record_id,
+                },
+            );
+        }
     }
 
-    async fn create_rec_infos(
-        records_per_detect: Vec<Value>,
-        path: &dyn Display,
-        rule_keys: Vec<String>,
-    ) -> Vec<EvtxRecordInfo> {
-        let path = Arc::new(path.to_string());
-        let rule_keys = Arc::new(rule_keys);
-        let threads: Vec<JoinHandle<EvtxRecordInfo>> = {
-            let this = records_per_detect
-                .into_iter()
-                .map(|rec| -> JoinHandle<EvtxRecordInfo> {
-                    let arc_rule_keys = Arc::clone(&rule_keys);
-                    let arc_path = Arc::clone(&path);
-                    spawn(async move {
-                        utils::create_rec_info(rec, arc_path.to_string(), &arc_rule_keys)
-                    })
-                });
-            FromIterator::from_iter(this)
+    /// state-fileに保存するファイルの識別子とサイズを取得する。アーカイブ内のファイルはアーカイブの識別子にアーカイブ内のパスを付与する
+    fn get_file_identity(evtx_filepath: &Path, reader: &mut Box<dyn ReadSeek>) -> (String, u64) {
+        let size = reader.seek(SeekFrom::End(0)).unwrap_or(0);
+        reader.seek(SeekFrom::Start(0)).ok();
+        let file_id = match archive::split_member_path(evtx_filepath) {
+            Some((archive_path, member)) => fs::metadata(archive_path)
+                .map(|metadata| format!("{}/{}", state::get_file_id(&metadata), member)),
+            None => fs::metadata(evtx_filepath).map(|metadata| state::get_file_id(&metadata)),
         };
-
-        let mut ret = vec![];
-        for thread in threads.into_iter() {
-            ret.push(thread.await.unwrap());
-        }
-
-        ret
+        (file_id.unwrap_or_default(), size)
     }
 
-    fn get_all_keys(&self, rules: &[RuleNode]) -> Vec<String> {
-        let mut key_set = HashSet::new();
-        for rule in rules {
-            let keys = get_detection_keys(rule);
-            key_set.extend(keys);
+    fn open_evtx_reader(evtx_filepath: &Path) -> Option<Box<dyn ReadSeek>> {
+        // アーカイブ内のファイルはメモリ上に展開してからパースする
+        let reader: Result<Box<dyn ReadSeek>, String> =
+            match archive::split_member_path(evtx_filepath) {
+                Some((archive_path, member)) => archive::read_member(&archive_path, &member)
+                    .map(|buf| Box::new(Cursor::new(buf)) as Box<dyn ReadSeek>),
+                None => File::open(evtx_filepath)
+                    .map(|file| Box::new(file) as Box<dyn ReadSeek>)
+                    .map_err(|e| e.to_string()),
+            };
+        match reader {
+            Ok(reader) => Option::Some(reader),
+            Err(err) => {
+                App::output_open_error(evtx_filepath, &err);
+                Option::None
+            }
         }
-
-        let ret: Vec<String> = key_set.into_iter().collect();
-        ret
     }
 
-    // target_eventids.txtの設定を元にフィルタする。
-    fn _is_target_event_id(&self, data: &Value) -> bool {
-        let eventid = utils::get_event_value(&utils::get_event_id_key(), data);
-        if eventid.is_none() {
-            return true;
+    /// evtxファイルやアーカイブ内のファイルを開けなかった場合のエラーを出力する。アーカイブ内のファイルはアーカイブのパスにメンバー名を連結したパスを出力する
+    fn output_open_error(evtx_filepath: &Path, err: &str) {
+        let errmsg = format!(
+            "Failed to open event file. EventFile:{} Error:{}",
+            evtx_filepath.display(),
+            err
+        );
+        if configs::CONFIG.read().unwrap().args.is_present("verbose") {
+            AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &errmsg).ok();
         }
-
-        match eventid.unwrap() {
-            Value::String(s) => utils::is_target_event_id(s),
-            Value::Number(n) => utils::is_target_event_id(&n.to_string()),
-            _ => true, // レコードからEventIdが取得できない場合は、特にフィルタしない
+        if !*QUIET_ERRORS_FLAG {
+            ERROR_LOG_STACK
+                .lock()
+                .unwrap()
+                .push(format!("[ERROR] {}", errmsg));
         }
     }
 
-    fn evtx_to_jsons(&self, evtx_filepath: PathBuf) -> Option<EvtxParser<File>> {
-        match EvtxParser::from_path(evtx_filepath) {
-            Ok(evtx_parser) => {
-                // parserのデフォルト設定を変更
-                let mut parse_config = ParserSettings::default();
-                parse_config = parse_config.separate_json_attributes(true); // XMLのattributeをJSONに変換する時のルールを設定
-                parse_config = parse_config.num_threads(0); // 設定しないと遅かったので、設定しておく。
+    fn create_parser_settings() -> ParserSettings {
+        // parserのデフォルト設定を変更
+        let mut parse_config = ParserSettings::default();
+        parse_config = parse_config.separate_json_attributes(true); // XMLのattributeをJSONに変換する時のルールを設定
+        parse_config = parse_config.num_threads(0); // 設定しないと遅かったので、設定しておく。
+        parse_config
+    }
 
-                let evtx_parser = evtx_parser.with_configuration(parse_config);
+    fn evtx_to_jsons(
+        &self,
+        evtx_filepath: &Path,
+        reader: Box<dyn ReadSeek>,
+    ) -> Option<EvtxParser<Box<dyn ReadSeek>>> {
+        match EvtxParser::from_read_seek(reader) {
+            Ok(evtx_parser) => {
+                let evtx_parser = evtx_parser.with_configuration(App::create_parser_settings());
                 Option::Some(evtx_parser)
             }
             Err(e) => {
-                eprintln!("{}", e);
+                App::output_open_error(evtx_filepath, &e.to_string());
                 Option::None
             }
         }
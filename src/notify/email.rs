@@ -0,0 +1,701 @@
+use crate::detections::configs::LEVELMAP;
+use crate::detections::print::{DetectInfo, Message};
+use chrono::{DateTime, Local, Utc};
+use dotenv::dotenv;
+use hashbrown::HashMap;
+use native_tls::TlsConnector;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// ダイジェストに出力するルール、コンピュータの数
+const TOP_NUM: usize = 10;
+
+/// SMTPサーバとの接続。STARTTLSの後はTLSのストリームに置き換える
+trait ReadWrite: Read + Write + Send {}
+impl<T: Read + Write + Send> ReadWrite for T {}
+
+/// メールで送信する添付ファイル
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attachment {
+    /// --outputで出力したCSVファイル
+    Csv(PathBuf),
+    /// ダイジェストのHTML
+    Html,
+}
+
+/// .envから読み込むSMTPの設定
+#[derive(Debug, Clone)]
+pub struct EmailConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub starttls: bool,
+    pub from: String,
+    pub to: Vec<String>,
+    /// ダイジェストに出力するcritical、highの検知結果の最大数
+    pub max_rows: usize,
+    pub attachment: Option<Attachment>,
+}
+
+impl EmailConfig {
+    /// .envからSMTP_HOST、SMTP_PORT、SMTP_USERNAME、SMTP_PASSWORD、SMTP_STARTTLS、EMAIL_FROM、EMAIL_TO、EMAIL_MAX_ROWS、EMAIL_ATTACHMENTを読み込む。
+    /// output_pathは--outputで指定したファイルで、EMAIL_ATTACHMENTがcsvの場合に添付する
+    pub fn from_env(output_path: Option<&Path>) -> Result<EmailConfig, String> {
+        dotenv().ok();
+        let host = env::var("SMTP_HOST").map_err(|_| "SMTP_HOST not found".to_string())?;
+        let from = env::var("EMAIL_FROM").map_err(|_| "EMAIL_FROM not found".to_string())?;
+        let to: Vec<String> = env::var("EMAIL_TO")
+            .map_err(|_| "EMAIL_TO not found".to_string())?
+            .split(',')
+            .map(|to| to.trim().to_string())
+            .filter(|to| !to.is_empty())
+            .collect();
+        if to.is_empty() {
+            return Err("EMAIL_TO not found".to_string());
+        }
+        let port = match env::var("SMTP_PORT") {
+            Ok(port) => port
+                .parse()
+                .map_err(|_| format!("Invalid SMTP_PORT. {}", port))?,
+            Err(_) => 587,
+        };
+        let max_rows = match env::var("EMAIL_MAX_ROWS") {
+            Ok(max_rows) => max_rows
+                .parse()
+                .map_err(|_| format!("Invalid EMAIL_MAX_ROWS. {}", max_rows))?,
+            Err(_) => 20,
+        };
+        let attachment = match env::var("EMAIL_ATTACHMENT")
+            .unwrap_or_else(|_| "csv".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "csv" => output_path.map(|path| Attachment::Csv(path.to_path_buf())),
+            "html" => Some(Attachment::Html),
+            "none" => None,
+            attachment => return Err(format!("Invalid EMAIL_ATTACHMENT. {}", attachment)),
+        };
+        Ok(EmailConfig {
+            host,
+            port,
+            username: env::var("SMTP_USERNAME").ok().filter(|s| !s.is_empty()),
+            password: env::var("SMTP_PASSWORD").ok(),
+            starttls: env::var("SMTP_STARTTLS")
+                .map(|starttls| starttls.to_lowercase() != "false")
+                .unwrap_or(true),
+            from,
+            to,
+            max_rows,
+            attachment,
+        })
+    }
+}
+
+/// スキャン結果のダイジェスト
+#[derive(Debug, Default)]
+pub struct Digest {
+    total: usize,
+    /// レベルの高い順のレベルごとの検知数
+    level_counts: Vec<(String, usize)>,
+    /// 検知数の多い順のルールのタイトルごとの検知数
+    top_rules: Vec<(String, usize)>,
+    /// 検知数の多い順のコンピュータごとの検知数
+    top_hosts: Vec<(String, usize)>,
+    /// 時刻順のcritical、highの検知結果
+    rows: Vec<(DateTime<Utc>, DetectInfo)>,
+    /// max_rowsで省略する前のcritical、highの検知数
+    rows_total: usize,
+}
+
+impl Digest {
+    pub fn new(messages: &Message, max_rows: usize) -> Digest {
+        let mut digest = Digest::default();
+        let mut level_counts: HashMap<String, usize> = HashMap::new();
+        let mut rule_counts: HashMap<String, usize> = HashMap::new();
+        let mut host_counts: HashMap<String, usize> = HashMap::new();
+        for (time, detect_infos) in messages.iter() {
+            for detect_info in detect_infos {
+                digest.total += 1;
+                *level_counts
+                    .entry(detect_info.level.to_owned())
+                    .or_insert(0) += 1;
+                *rule_counts.entry(detect_info.alert.to_owned()).or_insert(0) += 1;
+                *host_counts
+                    .entry(detect_info.computername.to_owned())
+                    .or_insert(0) += 1;
+                if get_level_num(&detect_info.level) >= LEVELMAP["HIGH"] {
+                    digest.rows_total += 1;
+                    if digest.rows.len() < max_rows {
+                        digest.rows.push((*time, detect_info.clone()));
+                    }
+                }
+            }
+        }
+
+        digest.level_counts = level_counts.into_iter().collect();
+        digest
+            .level_counts
+            .sort_by_key(|(level, _)| std::cmp::Reverse(get_level_num(level)));
+        digest.top_rules = Digest::top(rule_counts);
+        digest.top_hosts = Digest::top(host_counts);
+        digest
+    }
+
+    /// 検知数の多い順にTOP_NUM件を返す。検知数が同じ場合は名前順にする
+    fn top(counts: HashMap<String, usize>) -> Vec<(String, usize)> {
+        let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(TOP_NUM);
+        counts
+    }
+
+    fn count_of(&self, level: &str) -> usize {
+        self.level_counts
+            .iter()
+            .find(|(l, _)| l == level)
+            .map(|(_, count)| *count)
+            .unwrap_or(0)
+    }
+
+    pub fn subject(&self) -> String {
+        format!(
+            "Hayabusa scan results: {} detections ({} critical, {} high)",
+            self.total,
+            self.count_of("critical"),
+            self.count_of("high")
+        )
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![
+            "Hayabusa scan results".to_string(),
+            String::default(),
+            format!("Total detections: {}", self.total),
+        ];
+        for (level, count) in &self.level_counts {
+            lines.push(format!("  {}: {}", level, count));
+        }
+        for (title, counts) in [
+            ("Top rules:", &self.top_rules),
+            ("Top computers:", &self.top_hosts),
+        ] {
+            lines.push(String::default());
+            lines.push(title.to_string());
+            for (name, count) in counts {
+                lines.push(format!("  {:>6}  {}", count, name));
+            }
+        }
+        lines.push(String::default());
+        lines.push(format!(
+            "Critical and high detections ({} of {}):",
+            self.rows.len(),
+            self.rows_total
+        ));
+        for (time, detect_info) in &self.rows {
+            lines.push(format!(
+                "  {} [{}] {} {} {}",
+                format_time(time),
+                detect_info.level,
+                detect_info.computername,
+                detect_info.alert,
+                detect_info.detail
+            ));
+        }
+        lines.join("\r\n")
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut html = String::from(
+            "<html><head><meta charset=\"UTF-8\"><title>Hayabusa scan results</title></head><body>\n<h1>Hayabusa scan results</h1>\n",
+        );
+        html.push_str(&format!("<p>Total detections: {}</p>\n", self.total));
+        html.push_str(&create_html_table(
+            &["Level", "Count"],
+            self.level_counts
+                .iter()
+                .map(|(level, count)| vec![level.to_owned(), count.to_string()]),
+        ));
+        for (title, counts) in [
+            ("Top rules", &self.top_rules),
+            ("Top computers", &self.top_hosts),
+        ] {
+            html.push_str(&format!("<h2>{}</h2>\n", title));
+            html.push_str(&create_html_table(
+                &["Count", "Name"],
+                counts
+                    .iter()
+                    .map(|(name, count)| vec![count.to_string(), name.to_owned()]),
+            ));
+        }
+        html.push_str(&format!(
+            "<h2>Critical and high detections ({} of {})</h2>\n",
+            self.rows.len(),
+            self.rows_total
+        ));
+        html.push_str(&create_html_table(
+            &[
+                "Timestamp",
+                "Level",
+                "Computer",
+                "EventID",
+                "RuleTitle",
+                "Details",
+            ],
+            self.rows.iter().map(|(time, detect_info)| {
+                vec![
+                    format_time(time),
+                    detect_info.level.to_owned(),
+                    detect_info.computername.to_owned(),
+                    detect_info.eventid.to_owned(),
+                    detect_info.alert.to_owned(),
+                    detect_info.detail.to_owned(),
+                ]
+            }),
+        ));
+        html.push_str("</body></html>\n");
+        html
+    }
+}
+
+fn get_level_num(level: &str) -> u128 {
+    *LEVELMAP.get(&level.to_uppercase()).unwrap_or(&0)
+}
+
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S%.3f %:z")
+        .to_string()
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn create_html_table<I: Iterator<Item = Vec<String>>>(header: &[&str], rows: I) -> String {
+    let mut table = String::from("<table border=\"1\">\n<tr>");
+    for column in header {
+        table.push_str(&format!("<th>{}</th>", column));
+    }
+    table.push_str("</tr>\n");
+    for row in rows {
+        table.push_str("<tr>");
+        for column in row {
+            table.push_str(&format!("<td>{}</td>", escape_html(&column)));
+        }
+        table.push_str("</tr>\n");
+    }
+    table.push_str("</table>\n");
+    table
+}
+
+/// base64でエンコードして76文字ごとに改行する
+fn encode_base64(data: &[u8]) -> String {
+    base64::encode(data)
+        .as_bytes()
+        .chunks(76)
+        .map(|line| String::from_utf8_lossy(line).to_string())
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+/// 件名などのヘッダに日本語を使えるようにMIMEエンコードする
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", base64::encode(value))
+    }
+}
+
+pub struct EmailNotify {}
+
+impl EmailNotify {
+    /// ダイジェストのメールを作成する。添付ファイルを読み込めない場合はErrを返す
+    pub fn create_mail(config: &EmailConfig, digest: &Digest) -> Result<String, String> {
+        let boundary = format!("hayabusa-{}", Utc::now().timestamp_nanos());
+        let mut mail = vec![
+            format!("From: {}", config.from),
+            format!("To: {}", config.to.join(", ")),
+            format!("Subject: {}", encode_header(&digest.subject())),
+            format!("Date: {}", Local::now().to_rfc2822()),
+            "MIME-Version: 1.0".to_string(),
+            format!(
+                "Content-Type: multipart/mixed; boundary=\"{}-mixed\"",
+                boundary
+            ),
+            String::default(),
+            format!("--{}-mixed", boundary),
+            format!(
+                "Content-Type: multipart/alternative; boundary=\"{}-alt\"",
+                boundary
+            ),
+            String::default(),
+        ];
+        for (content_type, body) in [
+            ("text/plain", digest.to_text()),
+            ("text/html", digest.to_html()),
+        ] {
+            mail.push(format!("--{}-alt", boundary));
+            mail.push(format!("Content-Type: {}; charset=UTF-8", content_type));
+            mail.push("Content-Transfer-Encoding: base64".to_string());
+            mail.push(String::default());
+            mail.push(encode_base64(body.as_bytes()));
+        }
+        mail.push(format!("--{}-alt--", boundary));
+
+        let attachment = match &config.attachment {
+            Some(Attachment::Csv(path)) => {
+                let data = fs::read(path)
+                    .map_err(|e| format!("Failed to read attachment. {} {}", path.display(), e))?;
+                let file_name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| "results.csv".to_string());
+                Some(("text/csv", file_name, data))
+            }
+            Some(Attachment::Html) => Some((
+                "text/html",
+                "hayabusa-report.html".to_string(),
+                digest.to_html().into_bytes(),
+            )),
+            None => None,
+        };
+        if let Some((content_type, file_name, data)) = attachment {
+            let file_name = encode_header(&file_name);
+            mail.push(format!("--{}-mixed", boundary));
+            mail.push(format!(
+                "Content-Type: {}; charset=UTF-8; name=\"{}\"",
+                content_type, file_name
+            ));
+            mail.push(format!(
+                "Content-Disposition: attachment; filename=\"{}\"",
+                file_name
+            ));
+            mail.push("Content-Transfer-Encoding: base64".to_string());
+            mail.push(String::default());
+            mail.push(encode_base64(&data));
+        }
+        mail.push(format!("--{}-mixed--", boundary));
+        Ok(mail.join("\r\n"))
+    }
+
+    /// スキャン結果のダイジェストをメールで送信する
+    pub fn notify(config: &EmailConfig, messages: &Message) -> Result<(), String> {
+        let digest = Digest::new(messages, config.max_rows);
+        let mail = EmailNotify::create_mail(config, &digest)?;
+        EmailNotify::send_mail(config, &mail)
+            .map_err(|e| format!("Email Notification Failed. {}", e))
+    }
+
+    /// SMTP_HOSTを名前解決したアドレスに順番に接続する。応答しないサーバで止まらないように60秒でタイムアウトする
+    fn connect(config: &EmailConfig) -> Result<TcpStream, String> {
+        let addrs = (config.host.as_str(), config.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve SMTP_HOST. {} {}", config.host, e))?;
+        let mut last_error = format!("Failed to resolve SMTP_HOST. {}", config.host);
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, Duration::from_secs(60)) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e.to_string(),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// パスワードが平文で流れないように、STARTTLSを使わない場合はループバックアドレスのSMTPサーバにのみ認証情報を送信する
+    fn check_auth(config: &EmailConfig, addr: &SocketAddr) -> Result<(), String> {
+        if config.username.is_some() && !config.starttls && !addr.ip().is_loopback() {
+            return Err(format!(
+                "Refused to send SMTP credentials without TLS. Set SMTP_STARTTLS=true or use a local SMTP server. {}",
+                config.host
+            ));
+        }
+        Ok(())
+    }
+
+    fn send_mail(config: &EmailConfig, mail: &str) -> Result<(), String> {
+        let stream = EmailNotify::connect(config)?;
+        EmailNotify::check_auth(config, &stream.peer_addr().map_err(|e| e.to_string())?)?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(60)))
+            .map_err(|e| e.to_string())?;
+        let mut smtp = SmtpConnection {
+            stream: Box::new(stream.try_clone().map_err(|e| e.to_string())?),
+        };
+        smtp.read_response(220)?;
+        smtp.command("EHLO hayabusa", 250)?;
+        if config.starttls {
+            smtp.command("STARTTLS", 220)?;
+            let connector = TlsConnector::new().map_err(|e| e.to_string())?;
+            let tls_stream = connector
+                .connect(&config.host, stream)
+                .map_err(|e| e.to_string())?;
+            smtp.stream = Box::new(tls_stream);
+            smtp.command("EHLO hayabusa", 250)?;
+        }
+        if let Some(username) = &config.username {
+            let password = config.password.as_deref().unwrap_or("");
+            let credentials = base64::encode(format!("\0{}\0{}", username, password));
+            smtp.command(&format!("AUTH PLAIN {}", credentials), 235)?;
+        }
+        smtp.command(&format!("MAIL FROM:<{}>", config.from), 250)?;
+        for to in &config.to {
+            smtp.command(&format!("RCPT TO:<{}>", to), 250)?;
+        }
+        smtp.command("DATA", 354)?;
+        // 行頭の.は..にエスケープする
+        let data: Vec<String> = mail
+            .split("\r\n")
+            .map(|line| match line.starts_with('.') {
+                true => format!(".{}", line),
+                false => line.to_string(),
+            })
+            .collect();
+        smtp.command(&format!("{}\r\n.", data.join("\r\n")), 250)?;
+        smtp.command("QUIT", 221)
+    }
+}
+
+struct SmtpConnection {
+    stream: Box<dyn ReadWrite>,
+}
+
+impl SmtpConnection {
+    fn command(&mut self, command: &str, expect: u16) -> Result<(), String> {
+        write!(self.stream, "{}\r\n", command).map_err(|e| e.to_string())?;
+        self.stream.flush().map_err(|e| e.to_string())?;
+        self.read_response(expect)
+    }
+
+    /// 複数行の応答(250-...)は最後の行まで読む。応答コードがexpectと異なる場合はErrを返す
+    fn read_response(&mut self, expect: u16) -> Result<(), String> {
+        loop {
+            let line = self.read_line()?;
+            let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            return match code {
+                Some(code) if code == expect => Ok(()),
+                _ => Err(format!("SMTP error. {}", line.trim_end())),
+            };
+        }
+    }
+
+    /// STARTTLSでストリームを置き換えるため、バッファリングせずに1バイトずつ読む
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = vec![];
+        let mut buf = [0; 1];
+        while !line.ends_with(b"\r\n") {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err("SMTP connection closed.".to_string()),
+                Ok(_) => line.push(buf[0]),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(String::from_utf8_lossy(&line).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::print::{DetectInfo, Message};
+    use crate::notify::email::{Attachment, Digest, EmailConfig, EmailNotify};
+    use crate::notify::test_utils::create_detect_info;
+    use chrono::{TimeZone, Utc};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::PathBuf;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    fn create_messages() -> Message {
+        let mut messages = Message::new();
+        for (sec, level, computer, alert) in [
+            (1, "critical", "host1", "Mimikatz"),
+            (2, "high", "host1", "Logon Failure"),
+            (3, "high", "host2", "Logon Failure"),
+            (4, "medium", "host2", "Logon Failure"),
+            (5, "low", "host2", "Service Installed"),
+        ] {
+            messages.insert_message(
+                DetectInfo {
+                    computername: computer.to_string(),
+                    alert: alert.to_string(),
+                    detail: "User: <hayabusa>".to_string(),
+                    ..create_detect_info(level)
+                },
+                Utc.ymd(1996, 2, 27).and_hms(1, 5, sec),
+            );
+        }
+        messages
+    }
+
+    fn create_config(port: u16, attachment: Option<Attachment>) -> EmailConfig {
+        EmailConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+            starttls: false,
+            from: "hayabusa@example.com".to_string(),
+            to: vec!["a@example.com".to_string(), "b@example.com".to_string()],
+            max_rows: 2,
+            attachment,
+        }
+    }
+
+    /// SMTPサーバの代わりに、受け取ったコマンドとメールの本文を返すローカルのSMTPサーバを起動する
+    fn start_smtp_server() -> (u16, Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = vec![];
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                let response: &[u8] = if line.starts_with("EHLO") {
+                    b"250-localhost\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 OK\r\n"
+                } else if line == "DATA" {
+                    stream.write_all(b"354 Start mail input\r\n").unwrap();
+                    let mut data = vec![];
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        data.push(line.trim_end().to_string());
+                    }
+                    received.push(format!("DATA\n{}", data.join("\n")));
+                    b"250 OK\r\n"
+                } else if line == "QUIT" {
+                    received.push(line);
+                    stream.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                if !line.starts_with("DATA") {
+                    received.push(line);
+                }
+                stream.write_all(response).unwrap();
+            }
+            sender.send(received).unwrap();
+        });
+        (port, receiver)
+    }
+
+    #[test]
+    fn test_digest() {
+        let digest = Digest::new(&create_messages(), 2);
+        assert_eq!(
+            digest.subject(),
+            "Hayabusa scan results: 5 detections (1 critical, 2 high)"
+        );
+        assert_eq!(
+            digest.level_counts,
+            vec![
+                ("critical".to_string(), 1),
+                ("high".to_string(), 2),
+                ("medium".to_string(), 1),
+                ("low".to_string(), 1)
+            ]
+        );
+        assert_eq!(digest.top_rules[0], ("Logon Failure".to_string(), 3));
+        assert_eq!(digest.top_hosts[0], ("host2".to_string(), 3));
+        // critical、highの検知結果は時刻順にmax_rows件まで出力する
+        assert_eq!(digest.rows_total, 3);
+        assert_eq!(digest.rows.len(), 2);
+        assert_eq!(digest.rows[0].1.alert, "Mimikatz");
+
+        let text = digest.to_text();
+        assert!(text.contains("Critical and high detections (2 of 3):"));
+        let html = digest.to_html();
+        assert!(html.contains("<td>User: &lt;hayabusa&gt;</td>"));
+    }
+
+    #[test]
+    fn test_send_mail() {
+        let (port, receiver) = start_smtp_server();
+        let config = create_config(port, Some(Attachment::Html));
+        assert!(EmailNotify::notify(&config, &create_messages()).is_ok());
+
+        let received = receiver.recv().unwrap();
+        assert_eq!(received[0], "EHLO hayabusa");
+        assert_eq!(
+            received[1],
+            format!("AUTH PLAIN {}", base64::encode("\0user\0pass"))
+        );
+        assert_eq!(received[2], "MAIL FROM:<hayabusa@example.com>");
+        assert_eq!(received[3], "RCPT TO:<a@example.com>");
+        assert_eq!(received[4], "RCPT TO:<b@example.com>");
+        assert_eq!(received[6], "QUIT");
+        let data = &received[5];
+        assert!(data.contains("Subject: Hayabusa scan results: 5 detections (1 critical, 2 high)"));
+        assert!(data.contains("Content-Disposition: attachment; filename=\"hayabusa-report.html\""));
+    }
+
+    #[test]
+    fn test_create_mail_attachment() {
+        let path = PathBuf::from("./test_files/config/level_tuning.txt");
+        let config = create_config(25, Some(Attachment::Csv(path.clone())));
+        let digest = Digest::new(&create_messages(), 2);
+        let mail = EmailNotify::create_mail(&config, &digest).unwrap();
+        assert!(mail.contains("Content-Type: text/csv; charset=UTF-8; name=\"level_tuning.txt\""));
+        let data = std::fs::read(&path).unwrap();
+        assert!(mail.contains(&base64::encode(&data)[..60]));
+
+        let config = create_config(25, Some(Attachment::Csv(PathBuf::from("./notfound.csv"))));
+        assert!(EmailNotify::create_mail(&config, &digest).is_err());
+    }
+
+    #[test]
+    fn test_smtp_error() {
+        // 接続できない場合はErrを返す
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let config = create_config(port, None);
+        assert!(EmailNotify::notify(&config, &create_messages()).is_err());
+    }
+
+    #[test]
+    fn test_check_auth() {
+        // STARTTLSを使わない場合、ループバックアドレス以外には認証情報を送信しない
+        let local: SocketAddr = "127.0.0.1:25".parse().unwrap();
+        let remote: SocketAddr = "192.0.2.1:25".parse().unwrap();
+        let config = create_config(25, None);
+        assert!(EmailNotify::check_auth(&config, &local).is_ok());
+        assert!(EmailNotify::check_auth(&config, &remote).is_err());
+        let starttls = EmailConfig {
+            starttls: true,
+            ..config.clone()
+        };
+        assert!(EmailNotify::check_auth(&starttls, &remote).is_ok());
+        let no_auth = EmailConfig {
+            username: None,
+            ..config
+        };
+        assert!(EmailNotify::check_auth(&no_auth, &remote).is_ok());
+    }
+}
@@ -0,0 +1,364 @@
+use crate::detections::configs::LEVELMAP;
+use crate::detections::print::ErrorLog;
+use chrono::Utc;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// 送信に失敗した場合の再送の設定
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: usize,
+    backoff: Duration,
+    drain_timeout: Duration,
+    dead_letter: Option<PathBuf>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_secs(1),
+            drain_timeout: Duration::from_secs(30),
+            dead_letter: Option::None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// 最初の送信に失敗した後に再送する回数 (デフォルト: 3)
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// 最初の再送までの待機時間。再送するたびに倍にする (デフォルト: 1秒)
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// finishを呼び出してからキューに残ったアラートを送信する最大の時間。
+    /// 経過した後は再送せず、残りのアラートは送信せずにdead-letterファイルに保存する (デフォルト: 30秒)
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// 再送しても送信できなかったアラートをJSON Lines形式で追記するファイル
+    pub fn dead_letter(mut self, dead_letter: PathBuf) -> Self {
+        self.dead_letter = Option::Some(dead_letter);
+        self
+    }
+}
+
+/// dead-letterファイルの1行分の情報
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    time: String,
+    destination: &'a str,
+    error: &'a str,
+    payload: &'a str,
+}
+
+/// アラートを別スレッドで順番に送信する。
+/// 検知処理を止めないようにon_detectではキューに追加するだけにして、送信と再送はこのスレッドで行う。
+pub struct Forwarder {
+    destination: String,
+    drain_timeout: Duration,
+    /// finishを呼び出した後、キューに残ったアラートの送信を打ち切る時刻
+    deadline: Arc<Mutex<Option<Instant>>>,
+    sender: Mutex<Option<Sender<String>>>,
+    /// 送信できなかったアラートの数と最後のエラー
+    worker: Mutex<Option<JoinHandle<(usize, String)>>>,
+}
+
+impl Forwarder {
+    /// sendで1件のアラートを送信するスレッドを起動する。destinationはエラーメッセージとdead-letterファイルに出力する送信先の名前。
+    /// dead-letterファイルに書き込めなかった場合はerror_logに出力する
+    pub fn new<F>(
+        destination: &str,
+        policy: RetryPolicy,
+        error_log: ErrorLog,
+        mut send: F,
+    ) -> Forwarder
+    where
+        F: FnMut(&str) -> Result<(), String> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<String>();
+        let name = destination.to_string();
+        let drain_timeout = policy.drain_timeout;
+        let deadline = Arc::new(Mutex::new(Option::None));
+        let worker_deadline = Arc::clone(&deadline);
+        let worker = thread::spawn(move || {
+            let mut failed = 0;
+            let mut last_error = String::default();
+            for payload in receiver {
+                let result = if is_expired(&worker_deadline, Duration::ZERO) {
+                    Err(format!(
+                        "Gave up sending because the queue was not drained within {:?}.",
+                        policy.drain_timeout
+                    ))
+                } else {
+                    send_with_retry(&policy, &payload, &worker_deadline, &mut send)
+                };
+                if let Err(err) = result {
+                    failed += 1;
+                    write_dead_letter(&policy, &name, &err, &payload, &error_log);
+                    last_error = err;
+                }
+            }
+            (failed, last_error)
+        });
+        Forwarder {
+            destination: destination.to_string(),
+            drain_timeout,
+            deadline,
+            sender: Mutex::new(Option::Some(sender)),
+            worker: Mutex::new(Option::Some(worker)),
+        }
+    }
+
+    /// 送信するアラートをキューに追加する
+    pub fn push(&self, payload: String) -> Result<(), String> {
+        match self.sender.lock().unwrap().as_ref() {
+            Some(sender) => sender
+                .send(payload)
+                .map_err(|_| format!("{} sender has stopped.", self.destination)),
+            None => Err(format!("{} sender has already finished.", self.destination)),
+        }
+    }
+
+    /// キューに追加した全てのアラートの送信が終わるまで待つ。送信できなかったアラートがある場合は最後のエラーと一緒にErrを返す。
+    /// 送信先が応答しない場合に終了が遅れないように、RetryPolicyのdrain_timeoutが経過した後は残りのアラートを送信しない
+    pub fn finish(&self) -> Result<(), String> {
+        *self.deadline.lock().unwrap() = Option::Some(Instant::now() + self.drain_timeout);
+        self.sender.lock().unwrap().take();
+        let worker = self.worker.lock().unwrap().take();
+        let (failed, last_error) = match worker {
+            Some(worker) => worker
+                .join()
+                .map_err(|_| format!("{} sender has stopped.", self.destination))?,
+            None => (0, String::default()),
+        };
+        if failed == 0 {
+            Ok(())
+        } else {
+            Err(format!(
+                "Failed to send {} alerts to {}. {}",
+                failed, self.destination, last_error
+            ))
+        }
+    }
+}
+
+/// finishで設定した時刻までの残り時間がmargin未満かを返す
+fn is_expired(deadline: &Mutex<Option<Instant>>, margin: Duration) -> bool {
+    matches!(*deadline.lock().unwrap(), Some(deadline) if Instant::now() + margin >= deadline)
+}
+
+/// 送信に失敗した場合は待機時間を倍にしながら再送する。再送を待つとdeadlineを過ぎる場合は再送しない。最後のエラーを返す
+fn send_with_retry<F>(
+    policy: &RetryPolicy,
+    payload: &str,
+    deadline: &Mutex<Option<Instant>>,
+    send: &mut F,
+) -> Result<(), String>
+where
+    F: FnMut(&str) -> Result<(), String>,
+{
+    let mut backoff = policy.backoff;
+    let mut result = send(payload);
+    for _ in 0..policy.max_retries {
+        if result.is_ok() || is_expired(deadline, backoff) {
+            break;
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+        result = send(payload);
+    }
+    result
+}
+
+fn write_dead_letter(
+    policy: &RetryPolicy,
+    destination: &str,
+    error: &str,
+    payload: &str,
+    error_log: &ErrorLog,
+) {
+    let path = match &policy.dead_letter {
+        Some(path) => path,
+        None => return,
+    };
+    let line = serde_json::to_string(&DeadLetter {
+        time: Utc::now().to_rfc3339(),
+        destination,
+        error,
+        payload,
+    })
+    .unwrap();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    let ret = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(err) = ret {
+        error_log.error(&format!(
+            "Failed to write dead-letter file. {} {}",
+            path.display(),
+            err
+        ));
+    }
+}
+
+/// levelがmin_level以上かを判定する。min_levelはinformational、low、medium、high、criticalのいずれか
+pub fn is_notify_level(level: &str, min_level: &str) -> bool {
+    LEVELMAP.get(&level.to_uppercase()).unwrap_or(&0)
+        >= LEVELMAP.get(&min_level.to_uppercase()).unwrap_or(&1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::print::ErrorLog;
+    use crate::notify::forward::{is_notify_level, Forwarder, RetryPolicy};
+    use std::fs::{read_to_string, remove_file};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_retry() {
+        // 2回失敗した後に送信に成功する
+        let sent = Arc::new(Mutex::new(vec![]));
+        let attempts = Arc::new(Mutex::new(0));
+        let forwarder = {
+            let sent = Arc::clone(&sent);
+            let attempts = Arc::clone(&attempts);
+            Forwarder::new(
+                "test",
+                RetryPolicy::new().backoff(Duration::from_millis(1)),
+                ErrorLog::default(),
+                move |payload| {
+                    *attempts.lock().unwrap() += 1;
+                    if *attempts.lock().unwrap() <= 2 {
+                        return Err("error".to_string());
+                    }
+                    sent.lock().unwrap().push(payload.to_string());
+                    Ok(())
+                },
+            )
+        };
+        assert!(forwarder.push("alert".to_string()).is_ok());
+        assert!(forwarder.finish().is_ok());
+        assert_eq!(*attempts.lock().unwrap(), 3);
+        assert_eq!(*sent.lock().unwrap(), vec!["alert".to_string()]);
+        assert!(forwarder.push("alert".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let path = PathBuf::from("./test_forward_dead_letter.jsonl");
+        let forwarder = Forwarder::new(
+            "test-destination",
+            RetryPolicy::new()
+                .max_retries(1)
+                .backoff(Duration::from_millis(1))
+                .dead_letter(path.clone()),
+            ErrorLog::default(),
+            |_| Err("connection refused".to_string()),
+        );
+        assert!(forwarder.push("alert1".to_string()).is_ok());
+        assert!(forwarder.push("alert2".to_string()).is_ok());
+        assert_eq!(
+            forwarder.finish(),
+            Err("Failed to send 2 alerts to test-destination. connection refused".to_string())
+        );
+
+        let lines: Vec<serde_json::Value> = read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(remove_file(&path).is_ok());
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["destination"], "test-destination");
+        assert_eq!(lines[0]["error"], "connection refused");
+        assert_eq!(lines[0]["payload"], "alert1");
+        assert_eq!(lines[1]["payload"], "alert2");
+    }
+
+    #[test]
+    fn test_drain_timeout() {
+        // 送信先が応答しない場合でも、finishはdrain_timeoutの経過後に残りのアラートを送信せずに終了する
+        let attempts = Arc::new(Mutex::new(0));
+        let forwarder = {
+            let attempts = Arc::clone(&attempts);
+            Forwarder::new(
+                "test",
+                RetryPolicy::new()
+                    .backoff(Duration::from_millis(200))
+                    .drain_timeout(Duration::from_millis(100)),
+                ErrorLog::default(),
+                move |_| {
+                    *attempts.lock().unwrap() += 1;
+                    thread::sleep(Duration::from_millis(50));
+                    Err("timed out".to_string())
+                },
+            )
+        };
+        for _ in 0..10 {
+            assert!(forwarder.push("alert".to_string()).is_ok());
+        }
+        let start = Instant::now();
+        assert_eq!(
+            forwarder.finish(),
+            Err(
+                "Failed to send 10 alerts to test. Gave up sending because the queue was not drained within 100ms."
+                    .to_string()
+            )
+        );
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(*attempts.lock().unwrap() < 10);
+    }
+
+    #[test]
+    fn test_dead_letter_error() {
+        // dead-letterファイルに書き込めない場合はErrorLogに出力する
+        let error_log = ErrorLog::default();
+        let forwarder = Forwarder::new(
+            "test",
+            RetryPolicy::new()
+                .max_retries(0)
+                .dead_letter(PathBuf::from("./src")),
+            error_log.clone(),
+            |_| Err("connection refused".to_string()),
+        );
+        assert!(forwarder.push("alert".to_string()).is_ok());
+        assert!(forwarder.finish().is_err());
+        let logs = error_log.logs();
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].starts_with("[ERROR] Failed to write dead-letter file. ./src"));
+    }
+
+    #[test]
+    fn test_is_notify_level() {
+        assert!(is_notify_level("critical", "high"));
+        assert!(is_notify_level("high", "high"));
+        assert!(!is_notify_level("medium", "high"));
+        assert!(is_notify_level("informational", "informational"));
+    }
+}
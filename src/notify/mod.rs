@@ -1 +1,7 @@
+pub mod email;
+pub mod forward;
 pub mod slack;
+pub mod syslog;
+#[cfg(test)]
+mod test_utils;
+pub mod webhook;
@@ -1,7 +1,15 @@
 extern crate slack_hook;
+use crate::detections::configs::LEVELMAP;
+use crate::detections::print::ErrorLog;
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::notify::forward::{Forwarder, RetryPolicy};
 use dotenv::dotenv;
+use hashbrown::HashMap;
 use slack_hook::{PayloadBuilder, Slack};
 use std::env;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct SlackNotify {}
 
@@ -30,12 +38,8 @@ impl SlackNotify {
 
         let channel = env::var("CHANNEL").expect("CHANNEL is not found");
         let webhook_url = env::var("WEBHOOK_URL").expect("WEBHOOK_URL is not found");
-        let ret = SlackNotify::_send_to_slack(msg, &channel, &webhook_url);
-        if ret.is_ok() {
-            Ok(())
-        } else {
-            Err("Slack Notification Failed.".to_string())
-        }
+        SlackNotify::_send_to_slack(msg, &channel, &webhook_url)
+            .map_err(|e| format!("Slack Notification Failed. {}", e))
     }
 
     fn _send_to_slack(
@@ -43,15 +47,302 @@ impl SlackNotify {
         channel: &str,
         webhook_url: &str,
     ) -> Result<(), slack_hook::Error> {
-        let slack = Slack::new(webhook_url).unwrap();
+        let slack = Slack::new(webhook_url)?;
         let p = PayloadBuilder::new()
             .text(msg)
             .channel(channel)
             .username("hayabusa Notify Bot")
             .icon_emoji(":scream:")
-            .build()
-            .unwrap();
+            .build()?;
 
         slack.send(&p)
     }
 }
+
+/// 検知結果をまとめてSlackに通知するsink。
+/// min_level以上の検知結果をmax_lines件ごとに1件のメッセージにまとめて送信する。
+/// Slackのレート制限に掛からないように、前回の送信からintervalが経過するまでは送信せずに次の送信にまとめる。
+/// finishではスキャンの概要(レベルごとの検知数)と未送信の検知結果を送信する。
+/// 送信は別スレッドで行い、失敗した場合はRetryPolicyに従って再送する
+pub struct SlackSink {
+    min_level: u128,
+    max_lines: usize,
+    interval: Duration,
+    /// 未送信の検知結果
+    lines: Mutex<Vec<String>>,
+    /// スキャンの概要に出力するレベルごとの検知数。min_level未満の検知結果も含む
+    level_counts: Mutex<HashMap<String, usize>>,
+    last_sent: Mutex<Option<Instant>>,
+    forwarder: Forwarder,
+}
+
+impl SlackSink {
+    pub fn new(
+        webhook_url: &str,
+        channel: &str,
+        policy: RetryPolicy,
+        error_log: ErrorLog,
+    ) -> SlackSink {
+        let webhook_url = webhook_url.to_string();
+        let channel = channel.to_string();
+        // webhookのURLはシークレットなので、dead-letterファイルには送信先の名前としてSlackを出力する
+        let forwarder = Forwarder::new("Slack", policy, error_log, move |msg| {
+            SlackNotify::_send_to_slack(msg.to_string(), &channel, &webhook_url)
+                .map_err(|e| format!("Slack Notification Failed. {}", e))
+        });
+        SlackSink {
+            min_level: LEVELMAP["HIGH"],
+            max_lines: 20,
+            interval: Duration::from_secs(1),
+            lines: Mutex::new(vec![]),
+            level_counts: Mutex::new(HashMap::new()),
+            last_sent: Mutex::new(Option::None),
+            forwarder,
+        }
+    }
+
+    /// .envのWEBHOOK_URLとCHANNELからSlackSinkを作成する。設定されていない場合はNoneを返す
+    pub fn from_env(policy: RetryPolicy, error_log: ErrorLog) -> Option<SlackSink> {
+        if !SlackNotify::check_setting() {
+            return Option::None;
+        }
+        Option::Some(SlackSink::new(
+            &env::var("WEBHOOK_URL").unwrap(),
+            &env::var("CHANNEL").unwrap(),
+            policy,
+            error_log,
+        ))
+    }
+
+    /// 通知する検知結果の最低レベル (デフォルト: high)
+    pub fn min_level(mut self, min_level: &str) -> Self {
+        self.min_level = *LEVELMAP.get(&min_level.to_uppercase()).unwrap_or(&1);
+        self
+    }
+
+    /// 1件のメッセージにまとめる検知結果の最大数。超えた分は件数のみ通知する (デフォルト: 20)
+    pub fn max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = max_lines.max(1);
+        self
+    }
+
+    /// メッセージを送信する最小の間隔 (デフォルト: 1秒)
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// 通知するメッセージを作成する。max_linesを超えた検知結果は件数のみ出力する
+    fn create_message(&self, summary: Option<String>, lines: &[String]) -> String {
+        let mut msg: Vec<String> = summary.into_iter().collect();
+        msg.extend(lines.iter().take(self.max_lines).cloned());
+        if lines.len() > self.max_lines {
+            msg.push(format!(
+                "... and {} more detections.",
+                lines.len() - self.max_lines
+            ));
+        }
+        msg.join("\n")
+    }
+
+    /// レベルごとの検知数をレベルの高い順に出力したスキャンの概要を作成する
+    fn create_summary(&self) -> String {
+        let level_counts = self.level_counts.lock().unwrap();
+        let total: usize = level_counts.values().sum();
+        if total == 0 {
+            return "Hayabusa scan finished. No detections.".to_string();
+        }
+        let mut counts: Vec<(&String, &usize)> = level_counts.iter().collect();
+        counts.sort_by_key(|(level, _)| {
+            std::cmp::Reverse(*LEVELMAP.get(&level.to_uppercase()).unwrap_or(&0))
+        });
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(level, count)| format!("{}: {}", level, count))
+            .collect();
+        format!(
+            "Hayabusa scan finished. Total detections: {} ({})",
+            total,
+            counts.join(", ")
+        )
+    }
+
+    /// 未送信の検知結果を送信するキューに追加する。waitがfalseの場合、前回の送信からintervalが経過していなければ送信せずに次の送信にまとめる
+    fn send_pending(&self, summary: Option<String>, wait: bool) -> Result<(), String> {
+        // 他のスレッドが送信中の場合、検知処理を止めないように送信を待たない
+        let mut last_sent = if wait {
+            self.last_sent.lock().unwrap()
+        } else {
+            match self.last_sent.try_lock() {
+                Ok(last_sent) => last_sent,
+                Err(_) => return Ok(()),
+            }
+        };
+        if let Some(elapsed) = last_sent.map(|last_sent| last_sent.elapsed()) {
+            if elapsed < self.interval {
+                if !wait {
+                    return Ok(());
+                }
+                thread::sleep(self.interval - elapsed);
+            }
+        }
+
+        let lines = std::mem::take(&mut *self.lines.lock().unwrap());
+        if lines.is_empty() && summary.is_none() {
+            return Ok(());
+        }
+        let msg = self.create_message(summary, &lines);
+        *last_sent = Option::Some(Instant::now());
+        self.forwarder.push(msg)
+    }
+}
+
+impl DetectionSink for SlackSink {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        let detect_info = event.detect_info;
+        *self
+            .level_counts
+            .lock()
+            .unwrap()
+            .entry(detect_info.level.to_lowercase())
+            .or_insert(0) += 1;
+        if *LEVELMAP
+            .get(&detect_info.level.to_uppercase())
+            .unwrap_or(&0)
+            < self.min_level
+        {
+            return Ok(());
+        }
+
+        let pending = {
+            let mut lines = self.lines.lock().unwrap();
+            lines.push(format!(
+                "{} [{}] {} ({}) {}",
+                event.time.to_rfc3339(),
+                detect_info.level,
+                detect_info.alert,
+                detect_info.computername,
+                detect_info.detail
+            ));
+            lines.len()
+        };
+        // max_lines件たまったらスキャン中でも送信する
+        if pending >= self.max_lines {
+            self.send_pending(Option::None, false)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// watchモードでは新しいレコードの検知処理が終わるたびに、それまでの検知結果をまとめて通知する
+    fn flush(&self) -> Result<(), String> {
+        self.send_pending(Option::None, false)
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        let ret = self.send_pending(Option::Some(self.create_summary()), true);
+        self.forwarder.finish().and(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::print::DetectInfo;
+    use crate::detections::print::ErrorLog;
+    use crate::detections::sink::DetectionSink;
+    use crate::notify::forward::RetryPolicy;
+    use crate::notify::slack::SlackSink;
+    use crate::notify::test_utils::{create_detect_info, detect, start_webhook_server};
+    use std::time::Duration;
+
+    fn create_slack_detect_info(level: &str, detail: &str) -> DetectInfo {
+        DetectInfo {
+            detail: detail.to_string(),
+            ..create_detect_info(level)
+        }
+    }
+
+    fn create_slack_sink(url: &str, policy: RetryPolicy) -> SlackSink {
+        SlackSink::new(url, "#test", policy, ErrorLog::default())
+    }
+
+    #[test]
+    fn test_slack_sink_message() {
+        let sink = create_slack_sink("http://127.0.0.1/", RetryPolicy::new()).max_lines(2);
+        let line = "1996-02-27T01:05:01+00:00 [high] test_title (testcomputer) logon failure";
+        let lines = vec![line.to_string(); 3];
+        assert_eq!(
+            sink.create_message(Option::Some("summary".to_string()), &lines),
+            format!("summary\n{}\n{}\n... and 1 more detections.", line, line)
+        );
+
+        assert_eq!(
+            sink.create_summary(),
+            "Hayabusa scan finished. No detections."
+        );
+        for level in ["low", "critical", "high", "low"] {
+            *sink
+                .level_counts
+                .lock()
+                .unwrap()
+                .entry(level.to_string())
+                .or_insert(0) += 1;
+        }
+        assert_eq!(
+            sink.create_summary(),
+            "Hayabusa scan finished. Total detections: 4 (critical: 1, high: 1, low: 2)"
+        );
+    }
+
+    #[test]
+    fn test_slack_sink_batch() {
+        // min_level未満の検知結果は通知せず、max_lines件ごとにまとめて送信する
+        let (url, receiver) = start_webhook_server(vec![]);
+        let sink = create_slack_sink(&url, RetryPolicy::new())
+            .min_level("high")
+            .max_lines(2)
+            .interval(Duration::from_secs(0));
+        detect(&sink, &create_slack_detect_info("low", "low detail"));
+        detect(&sink, &create_slack_detect_info("high", "detail1"));
+        detect(&sink, &create_slack_detect_info("critical", "detail2"));
+        let body = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(body.contains("detail1") && body.contains("detail2"));
+        assert!(!body.contains("low detail"));
+
+        detect(&sink, &create_slack_detect_info("high", "detail3"));
+        assert!(sink.finish().is_ok());
+        let body = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(body.contains("Total detections: 4 (critical: 1, high: 2, low: 1)"));
+        assert!(body.contains("detail3"));
+    }
+
+    #[test]
+    fn test_slack_sink_rate_limit() {
+        // 前回の送信からintervalが経過していない場合は送信せずに次の送信にまとめる
+        let (url, receiver) = start_webhook_server(vec![]);
+        let sink = create_slack_sink(&url, RetryPolicy::new())
+            .max_lines(1)
+            .interval(Duration::from_secs(60));
+        detect(&sink, &create_slack_detect_info("high", "detail1"));
+        assert!(receiver
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap()
+            .contains("detail1"));
+        detect(&sink, &create_slack_detect_info("high", "detail2"));
+        assert!(sink.flush().is_ok());
+        assert!(receiver.recv_timeout(Duration::from_millis(500)).is_err());
+        assert_eq!(sink.lines.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_slack_sink_send_error() {
+        // 送信できなかった場合は送信先の名前とエラーの原因をErrで返す
+        let sink = create_slack_sink(
+            "http://127.0.0.1:1/webhook",
+            RetryPolicy::new().max_retries(0),
+        );
+        detect(&sink, &create_slack_detect_info("high", "detail1"));
+        let err = sink.finish().unwrap_err();
+        assert!(err.starts_with("Failed to send 1 alerts to Slack. Slack Notification Failed. "));
+    }
+}
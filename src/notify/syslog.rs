@@ -0,0 +1,433 @@
+use crate::detections::print::ErrorLog;
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::notify::forward::{is_notify_level, Forwarder, RetryPolicy};
+use chrono::SecondsFormat;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+const APP_NAME: &str = "hayabusa";
+const VENDOR: &str = "Yamato Security";
+const PRODUCT: &str = "Hayabusa";
+/// RFC 5424のSTRUCTURED-DATAのSD-ID。32473はドキュメント用に予約されたPrivate Enterprise Number
+const SD_ID: &str = "hayabusa@32473";
+/// facility: user-level messages
+const FACILITY: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyslogProtocol {
+    Udp,
+    /// RFC 6587のoctet countingでメッセージを区切る
+    Tcp,
+}
+
+impl SyslogProtocol {
+    pub fn parse(protocol: &str) -> Option<SyslogProtocol> {
+        match protocol.to_lowercase().as_str() {
+            "udp" => Some(SyslogProtocol::Udp),
+            "tcp" => Some(SyslogProtocol::Tcp),
+            _ => None,
+        }
+    }
+}
+
+/// RFC 5424のMSG部分の形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyslogFormat {
+    /// 検知結果の項目をSTRUCTURED-DATAに出力し、MSGにはルールのタイトルと詳細を出力する
+    Rfc5424,
+    Cef,
+    Leef,
+}
+
+impl SyslogFormat {
+    pub fn parse(format: &str) -> Option<SyslogFormat> {
+        match format.to_lowercase().as_str() {
+            "rfc5424" => Some(SyslogFormat::Rfc5424),
+            "cef" => Some(SyslogFormat::Cef),
+            "leef" => Some(SyslogFormat::Leef),
+            _ => None,
+        }
+    }
+}
+
+/// 検知結果をRFC 5424形式でsyslogサーバに送信するsink。
+/// 送信は別スレッドで行い、失敗した場合はRetryPolicyに従って再送する。TCPの場合は再送時に接続し直す
+pub struct SyslogSink {
+    format: SyslogFormat,
+    min_level: String,
+    forwarder: Forwarder,
+}
+
+impl SyslogSink {
+    /// addressはhost:portの形式
+    pub fn new(
+        address: &str,
+        protocol: SyslogProtocol,
+        format: SyslogFormat,
+        policy: RetryPolicy,
+        error_log: ErrorLog,
+    ) -> Result<SyslogSink, String> {
+        let addr = address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("Invalid syslog address. {}", address))?;
+        let forwarder = match protocol {
+            SyslogProtocol::Udp => {
+                let bind_addr = if addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(bind_addr).map_err(|e| e.to_string())?;
+                Forwarder::new(address, policy, error_log, move |msg| {
+                    socket
+                        .send_to(msg.as_bytes(), addr)
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                })
+            }
+            SyslogProtocol::Tcp => {
+                let mut stream: Option<TcpStream> = None;
+                Forwarder::new(address, policy, error_log, move |msg| {
+                    let ret = send_tcp(&mut stream, addr, msg);
+                    if ret.is_err() {
+                        stream = None;
+                    }
+                    ret
+                })
+            }
+        };
+        Ok(SyslogSink {
+            format,
+            min_level: "high".to_string(),
+            forwarder,
+        })
+    }
+
+    /// 送信する検知結果の最低レベル (デフォルト: high)
+    pub fn min_level(mut self, min_level: &str) -> Self {
+        self.min_level = min_level.to_string();
+        self
+    }
+}
+
+fn send_tcp(stream: &mut Option<TcpStream>, addr: SocketAddr, msg: &str) -> Result<(), String> {
+    if stream.is_none() {
+        *stream = Some(
+            TcpStream::connect_timeout(&addr, Duration::from_secs(10))
+                .map_err(|e| e.to_string())?,
+        );
+    }
+    let stream = stream.as_mut().unwrap();
+    write!(stream, "{} {}", msg.len(), msg).map_err(|e| e.to_string())
+}
+
+/// levelに対応するsyslogのseverity
+fn get_severity(level: &str) -> u8 {
+    match level {
+        "critical" => 2,
+        "high" => 3,
+        "medium" => 4,
+        "low" => 5,
+        _ => 6,
+    }
+}
+
+/// levelに対応するCEF、LEEFのseverity(0-10)
+fn get_cef_severity(level: &str) -> u8 {
+    match level {
+        "critical" => 10,
+        "high" => 8,
+        "medium" => 5,
+        "low" => 3,
+        _ => 1,
+    }
+}
+
+/// RFC 5424のHOSTNAMEは空白を含まないASCII文字のみのため、それ以外の文字を除く
+fn format_hostname(computername: &str) -> String {
+    let hostname: String = computername
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(255)
+        .collect();
+    if hostname.is_empty() {
+        "-".to_string()
+    } else {
+        hostname
+    }
+}
+
+fn escape_sd_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+fn escape_cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn escape_cef_extension(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+/// LEEFの属性はタブで区切るため、値のタブと改行は空白に置き換える
+fn escape_leef_value(value: &str) -> String {
+    value.replace(['\t', '\r', '\n'], " ")
+}
+
+/// 検知結果を1件のRFC 5424形式のメッセージにする
+pub fn create_message(format: SyslogFormat, event: &DetectionEvent) -> String {
+    let detect_info = event.detect_info;
+    let rule_id = event.rule.yaml["id"]
+        .as_str()
+        .unwrap_or(&detect_info.rulepath);
+    let header = format!(
+        "<{}>1 {} {} {} - - ",
+        FACILITY * 8 + get_severity(&detect_info.level),
+        event.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        format_hostname(&detect_info.computername),
+        APP_NAME
+    );
+    match format {
+        SyslogFormat::Rfc5424 => {
+            let params: Vec<String> = [
+                ("level", detect_info.level.as_str()),
+                ("eventID", &detect_info.eventid),
+                ("ruleTitle", &detect_info.alert),
+                ("ruleID", rule_id),
+                ("mitreAttack", &detect_info.tag_info),
+                ("filePath", &detect_info.filepath),
+            ]
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape_sd_value(value)))
+            .collect();
+            format!(
+                "{}[{} {}] {}: {}",
+                header,
+                SD_ID,
+                params.join(" "),
+                detect_info.alert,
+                detect_info.detail
+            )
+        }
+        SyslogFormat::Cef => {
+            let extensions: Vec<String> = [
+                ("rt", event.time.timestamp_millis().to_string()),
+                ("dvchost", detect_info.computername.to_owned()),
+                ("cs1Label", "EventID".to_string()),
+                ("cs1", detect_info.eventid.to_owned()),
+                ("cs2Label", "Details".to_string()),
+                ("cs2", detect_info.detail.to_owned()),
+                ("cs3Label", "MitreAttack".to_string()),
+                ("cs3", detect_info.tag_info.to_owned()),
+                ("fname", detect_info.filepath.to_owned()),
+            ]
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, escape_cef_extension(value)))
+            .collect();
+            format!(
+                "{}- CEF:0|{}|{}|{}|{}|{}|{}|{}",
+                header,
+                VENDOR,
+                PRODUCT,
+                env!("CARGO_PKG_VERSION"),
+                escape_cef_header(rule_id),
+                escape_cef_header(&detect_info.alert),
+                get_cef_severity(&detect_info.level),
+                extensions.join(" ")
+            )
+        }
+        SyslogFormat::Leef => {
+            let attributes: Vec<String> = [
+                ("devTime", event.time.timestamp_millis().to_string()),
+                ("sev", get_cef_severity(&detect_info.level).to_string()),
+                ("identHostName", detect_info.computername.to_owned()),
+                ("eventID", detect_info.eventid.to_owned()),
+                ("ruleTitle", detect_info.alert.to_owned()),
+                ("details", detect_info.detail.to_owned()),
+                ("mitreAttack", detect_info.tag_info.to_owned()),
+                ("filePath", detect_info.filepath.to_owned()),
+            ]
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, escape_leef_value(value)))
+            .collect();
+            format!(
+                "{}- LEEF:1.0|{}|{}|{}|{}|{}",
+                header,
+                VENDOR,
+                PRODUCT,
+                env!("CARGO_PKG_VERSION"),
+                escape_cef_header(rule_id),
+                attributes.join("\t")
+            )
+        }
+    }
+}
+
+impl DetectionSink for SyslogSink {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        if !is_notify_level(&event.detect_info.level, &self.min_level) {
+            return Ok(());
+        }
+        self.forwarder.push(create_message(self.format, event))
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        self.forwarder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::print::{DetectInfo, ErrorLog};
+    use crate::detections::sink::{DetectionEvent, DetectionSink};
+    use crate::notify::forward::RetryPolicy;
+    use crate::notify::syslog::{create_message, SyslogFormat, SyslogProtocol, SyslogSink};
+    use crate::notify::test_utils::{create_detect_info, create_test_rule, detect, test_time};
+    use std::fs::{read_to_string, remove_file};
+    use std::io::Read;
+    use std::net::{TcpListener, UdpSocket};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn create_syslog_detect_info(level: &str) -> DetectInfo {
+        DetectInfo {
+            computername: "test computer".to_string(),
+            detail: "User: hayabusa | Cmd: \"a=b\"]".to_string(),
+            tag_info: "Cred".to_string(),
+            ..create_detect_info(level)
+        }
+    }
+
+    #[test]
+    fn test_create_message() {
+        let rule = create_test_rule();
+        let detect_info = create_syslog_detect_info("high");
+        let event = DetectionEvent {
+            time: test_time(),
+            detect_info: &detect_info,
+            record: Option::None,
+            rule: &rule,
+        };
+        let header = "<11>1 1996-02-27T01:05:01.000Z testcomputer hayabusa - - ";
+        assert_eq!(
+            create_message(SyslogFormat::Rfc5424, &event),
+            format!("{}[hayabusa@32473 level=\"high\" eventID=\"4625\" ruleTitle=\"test_title\" ruleID=\"1d0e7d7c-4c2a-4d53-9b5b-3c5e2a0bd9f1\" mitreAttack=\"Cred\" filePath=\"test.evtx\"] test_title: User: hayabusa | Cmd: \"a=b\"]", header)
+        );
+        assert_eq!(
+            create_message(SyslogFormat::Cef, &event),
+            format!("{}- CEF:0|Yamato Security|Hayabusa|{}|1d0e7d7c-4c2a-4d53-9b5b-3c5e2a0bd9f1|test_title|8|rt=825383101000 dvchost=test computer cs1Label=EventID cs1=4625 cs2Label=Details cs2=User: hayabusa | Cmd: \"a\\=b\"] cs3Label=MitreAttack cs3=Cred fname=test.evtx", header, env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(
+            create_message(SyslogFormat::Leef, &event),
+            format!("{}- LEEF:1.0|Yamato Security|Hayabusa|{}|1d0e7d7c-4c2a-4d53-9b5b-3c5e2a0bd9f1|devTime=825383101000\tsev=8\tidentHostName=test computer\teventID=4625\truleTitle=test_title\tdetails=User: hayabusa | Cmd: \"a=b\"]\tmitreAttack=Cred\tfilePath=test.evtx", header, env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(SyslogProtocol::parse("TCP"), Some(SyslogProtocol::Tcp));
+        assert_eq!(SyslogProtocol::parse("tls"), None);
+        assert_eq!(SyslogFormat::parse("cef"), Some(SyslogFormat::Cef));
+        assert_eq!(SyslogFormat::parse("json"), None);
+    }
+
+    #[test]
+    fn test_syslog_udp() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let sink = SyslogSink::new(
+            &address,
+            SyslogProtocol::Udp,
+            SyslogFormat::Rfc5424,
+            RetryPolicy::new(),
+            ErrorLog::default(),
+        )
+        .unwrap();
+        detect(&sink, &create_syslog_detect_info("medium"));
+        detect(&sink, &create_syslog_detect_info("critical"));
+        assert!(sink.finish().is_ok());
+
+        let mut buf = [0; 2048];
+        let len = listener.recv(&mut buf).unwrap();
+        let msg = String::from_utf8(buf[..len].to_vec()).unwrap();
+        assert!(msg.starts_with("<10>1 1996-02-27T01:05:01.000Z testcomputer hayabusa "));
+        assert!(msg.contains("level=\"critical\""));
+    }
+
+    #[test]
+    fn test_syslog_tcp() {
+        // octet countingでメッセージを区切る
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let sink = SyslogSink::new(
+            &address,
+            SyslogProtocol::Tcp,
+            SyslogFormat::Cef,
+            RetryPolicy::new(),
+            ErrorLog::default(),
+        )
+        .unwrap()
+        .min_level("low");
+        detect(&sink, &create_syslog_detect_info("low"));
+        detect(&sink, &create_syslog_detect_info("high"));
+        assert!(sink.finish().is_ok());
+        drop(sink);
+
+        let mut received = String::new();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.read_to_string(&mut received).unwrap();
+        let mut messages = vec![];
+        let mut rest = received.as_str();
+        while let Some((len, msg)) = rest.split_once(' ') {
+            let len: usize = len.parse().unwrap();
+            messages.push(&msg[..len]);
+            rest = &msg[len..];
+        }
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].starts_with("<13>1 ") && messages[0].contains("|test_title|3|"));
+        assert!(messages[1].starts_with("<11>1 ") && messages[1].contains("|test_title|8|"));
+    }
+
+    #[test]
+    fn test_syslog_dead_letter() {
+        // 接続できない場合は再送した後にdead-letterファイルに書き出す
+        let address = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let path = PathBuf::from("./test_syslog_dead_letter.jsonl");
+        let policy = RetryPolicy::new()
+            .max_retries(2)
+            .backoff(Duration::from_millis(1))
+            .dead_letter(path.clone());
+        let sink = SyslogSink::new(
+            &address,
+            SyslogProtocol::Tcp,
+            SyslogFormat::Leef,
+            policy,
+            ErrorLog::default(),
+        )
+        .unwrap();
+        detect(&sink, &create_syslog_detect_info("high"));
+        assert!(sink.finish().is_err());
+
+        let line: serde_json::Value =
+            serde_json::from_str(&read_to_string(&path).unwrap()).unwrap();
+        assert!(remove_file(&path).is_ok());
+        assert_eq!(line["destination"], address.as_str());
+        assert!(line["payload"].as_str().unwrap().contains("LEEF:1.0|"));
+    }
+}
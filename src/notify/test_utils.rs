@@ -0,0 +1,89 @@
+//! 通知・転送のテストで共通して使う検知結果と、webhookの代わりのHTTPサーバ
+use crate::detections::print::DetectInfo;
+use crate::detections::rule::{create_rule, RuleNode};
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use chrono::{DateTime, TimeZone, Utc};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use yaml_rust::YamlLoader;
+
+pub const TEST_RULE_ID: &str = "1d0e7d7c-4c2a-4d53-9b5b-3c5e2a0bd9f1";
+
+/// テスト用の検知結果の時刻
+pub fn test_time() -> DateTime<Utc> {
+    Utc.ymd(1996, 2, 27).and_hms(1, 5, 1)
+}
+
+pub fn create_test_rule() -> RuleNode {
+    let rule_yaml = YamlLoader::load_from_str(&format!("title: test_title\nid: {}", TEST_RULE_ID))
+        .unwrap()
+        .remove(0);
+    create_rule("test-rule.yml".to_string(), rule_yaml)
+}
+
+/// テスト用の検知結果。通知先ごとに確認する値は構造体更新記法で書き換えて使う
+pub fn create_detect_info(level: &str) -> DetectInfo {
+    DetectInfo {
+        filepath: "test.evtx".to_string(),
+        rulepath: "test-rule.yml".to_string(),
+        level: level.to_string(),
+        computername: "testcomputer".to_string(),
+        eventid: "4625".to_string(),
+        alert: "test_title".to_string(),
+        detail: "User: \"hayabusa\"".to_string(),
+        tag_info: String::default(),
+        record_information: Option::None,
+    }
+}
+
+/// detect_infoをtest_timeに検知した結果としてsinkに渡す
+pub fn detect(sink: &dyn DetectionSink, detect_info: &DetectInfo) {
+    let rule = create_test_rule();
+    let event = DetectionEvent {
+        time: test_time(),
+        detect_info,
+        record: Option::None,
+        rule: &rule,
+    };
+    assert!(sink.on_detect(&event).is_ok());
+}
+
+/// webhookの代わりにリクエストのボディを受け取るローカルのHTTPサーバを起動する。
+/// statusesの順番にステータスコードを返し、statusesを返し終わった後は200を返す
+pub fn start_webhook_server(statuses: Vec<u16>) -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/webhook", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for (i, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                let line = line.to_lowercase();
+                if let Some(len) = line.strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            // 送信側がレスポンスを受け取る前にボディを渡す
+            if sender.send(String::from_utf8(body).unwrap()).is_err() {
+                break;
+            }
+            let response = format!(
+                "HTTP/1.1 {} Status\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                statuses.get(i).unwrap_or(&200)
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (url, receiver)
+}
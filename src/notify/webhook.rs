@@ -0,0 +1,250 @@
+use crate::detections::print::ErrorLog;
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::notify::forward::{is_notify_level, Forwarder, RetryPolicy};
+use chrono::SecondsFormat;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde_json::Value;
+use std::time::Duration;
+
+/// --webhook-templateを指定しない場合のリクエストのボディ
+pub const DEFAULT_TEMPLATE: &str = r#"{
+    "Timestamp": "%Timestamp%",
+    "Computer": "%Computer%",
+    "EventID": "%EventID%",
+    "Level": "%Level%",
+    "MitreAttack": "%MitreAttack%",
+    "RuleTitle": "%RuleTitle%",
+    "RuleID": "%RuleID%",
+    "Details": "%Details%",
+    "RulePath": "%RulePath%",
+    "FilePath": "%FilePath%"
+}"#;
+
+/// テンプレートで使える項目
+const TEMPLATE_FIELDS: [&str; 10] = [
+    "Timestamp",
+    "Computer",
+    "EventID",
+    "Level",
+    "MitreAttack",
+    "RuleTitle",
+    "RuleID",
+    "Details",
+    "RulePath",
+    "FilePath",
+];
+
+/// 検知結果をテンプレートから作成したJSONにしてwebhookにPOSTするsink。
+/// 送信は別スレッドで行い、失敗した場合はRetryPolicyに従って再送する
+pub struct WebhookSink {
+    template: String,
+    min_level: String,
+    forwarder: Forwarder,
+}
+
+impl WebhookSink {
+    /// templateは%RuleTitle%のように項目名を%で囲んだJSON。値はJSONの文字列としてエスケープして置き換える
+    pub fn new(
+        url: &str,
+        template: &str,
+        policy: RetryPolicy,
+        error_log: ErrorLog,
+    ) -> Result<WebhookSink, String> {
+        let empty_values: Vec<(&str, String)> = TEMPLATE_FIELDS
+            .iter()
+            .map(|field| (*field, String::default()))
+            .collect();
+        serde_json::from_str::<Value>(&render(template, &empty_values))
+            .map_err(|e| format!("Invalid webhook template. {}", e))?;
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let webhook_url = url.to_string();
+        let forwarder = Forwarder::new(url, policy, error_log, move |payload| {
+            let res = client
+                .post(webhook_url.as_str())
+                .header(CONTENT_TYPE, "application/json")
+                .body(payload.to_string())
+                .send()
+                .map_err(|e| e.to_string())?;
+            if res.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!("HTTP status {}", res.status().as_u16()))
+            }
+        });
+        Ok(WebhookSink {
+            template: template.to_string(),
+            min_level: "high".to_string(),
+            forwarder,
+        })
+    }
+
+    /// 送信する検知結果の最低レベル (デフォルト: high)
+    pub fn min_level(mut self, min_level: &str) -> Self {
+        self.min_level = min_level.to_string();
+        self
+    }
+
+    fn create_payload(&self, event: &DetectionEvent) -> String {
+        let detect_info = event.detect_info;
+        let values = vec![
+            (
+                "Timestamp",
+                event.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            ),
+            ("Computer", detect_info.computername.to_owned()),
+            ("EventID", detect_info.eventid.to_owned()),
+            ("Level", detect_info.level.to_owned()),
+            ("MitreAttack", detect_info.tag_info.to_owned()),
+            ("RuleTitle", detect_info.alert.to_owned()),
+            (
+                "RuleID",
+                event.rule.yaml["id"].as_str().unwrap_or("").to_owned(),
+            ),
+            ("Details", detect_info.detail.to_owned()),
+            ("RulePath", detect_info.rulepath.to_owned()),
+            ("FilePath", detect_info.filepath.to_owned()),
+        ];
+        render(&self.template, &values)
+    }
+}
+
+/// テンプレートの%項目名%を、JSONの文字列としてエスケープした値に置き換える。
+/// 置き換えた値に%項目名%が含まれていても再度置き換えないように、テンプレートを先頭から1回だけ走査する
+fn render(template: &str, values: &[(&str, String)]) -> String {
+    let mut ret = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('%') {
+        ret.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('%').and_then(|end| {
+            values
+                .iter()
+                .find(|(field, _)| *field == &after[..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                let escaped = serde_json::to_string(value).unwrap();
+                ret.push_str(&escaped[1..escaped.len() - 1]);
+                rest = &after[end + 1..];
+            }
+            None => {
+                // 項目名ではない%はそのまま出力し、次の%から項目名を探す
+                ret.push('%');
+                rest = after;
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+impl DetectionSink for WebhookSink {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        if !is_notify_level(&event.detect_info.level, &self.min_level) {
+            return Ok(());
+        }
+        self.forwarder.push(self.create_payload(event))
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        self.forwarder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::print::ErrorLog;
+    use crate::detections::sink::DetectionSink;
+    use crate::notify::forward::RetryPolicy;
+    use crate::notify::test_utils::{
+        create_detect_info, detect, start_webhook_server, TEST_RULE_ID,
+    };
+    use crate::notify::webhook::{render, WebhookSink, DEFAULT_TEMPLATE};
+    use serde_json::Value;
+    use std::fs::{read_to_string, remove_file};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn test_webhook_sink() {
+        // 1回目の送信に失敗した場合は再送する
+        let (url, receiver) = start_webhook_server(vec![500, 200]);
+        let policy = RetryPolicy::new().backoff(Duration::from_millis(10));
+        let sink = WebhookSink::new(&url, DEFAULT_TEMPLATE, policy, ErrorLog::default()).unwrap();
+        detect(&sink, &create_detect_info("medium"));
+        detect(&sink, &create_detect_info("high"));
+        assert!(sink.finish().is_ok());
+
+        let bodies: Vec<String> = receiver.try_iter().collect();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0], bodies[1]);
+        let body: Value = serde_json::from_str(&bodies[1]).unwrap();
+        assert_eq!(body["Timestamp"], "1996-02-27T01:05:01.000Z");
+        assert_eq!(body["Level"], "high");
+        assert_eq!(body["RuleID"], TEST_RULE_ID);
+        assert_eq!(body["Details"], "User: \"hayabusa\"");
+    }
+
+    #[test]
+    fn test_webhook_template() {
+        let (url, receiver) = start_webhook_server(vec![200]);
+        let template = r#"{"text": "[%Level%] %RuleTitle% on %Computer%"}"#;
+        let sink = WebhookSink::new(&url, template, RetryPolicy::new(), ErrorLog::default())
+            .unwrap()
+            .min_level("informational");
+        detect(&sink, &create_detect_info("low"));
+        assert!(sink.finish().is_ok());
+        assert_eq!(
+            receiver.recv().unwrap(),
+            r#"{"text": "[low] test_title on testcomputer"}"#
+        );
+
+        assert!(WebhookSink::new(
+            &url,
+            "{\"text\": %Level%}",
+            RetryPolicy::new(),
+            ErrorLog::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        // 値に含まれる%項目名%は置き換えない
+        let values = vec![
+            ("RuleTitle", "%Computer% 100%".to_string()),
+            ("Computer", "testcomputer".to_string()),
+        ];
+        assert_eq!(
+            render("%RuleTitle% on %Computer% (%Unknown%) 50%", &values),
+            "%Computer% 100% on testcomputer (%Unknown%) 50%"
+        );
+        assert_eq!(render("%%Computer%%", &values), "%testcomputer%");
+    }
+
+    #[test]
+    fn test_webhook_dead_letter() {
+        let (url, _receiver) = start_webhook_server(vec![503, 503]);
+        let path = PathBuf::from("./test_webhook_dead_letter.jsonl");
+        let policy = RetryPolicy::new()
+            .max_retries(1)
+            .backoff(Duration::from_millis(10))
+            .dead_letter(path.clone());
+        let sink = WebhookSink::new(&url, DEFAULT_TEMPLATE, policy, ErrorLog::default()).unwrap();
+        detect(&sink, &create_detect_info("critical"));
+        assert!(sink.finish().is_err());
+
+        let line: Value = serde_json::from_str(&read_to_string(&path).unwrap()).unwrap();
+        assert!(remove_file(&path).is_ok());
+        assert_eq!(line["destination"], url.as_str());
+        assert_eq!(line["error"], "HTTP status 503");
+        let payload: Value = serde_json::from_str(line["payload"].as_str().unwrap()).unwrap();
+        assert_eq!(payload["Level"], "critical");
+    }
+}
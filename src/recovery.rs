@@ -0,0 +1,695 @@
+use evtx::{EvtxChunkData, ParserSettings};
+use hashbrown::HashSet;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read};
+use std::ops::Range;
+use std::sync::Arc;
+
+const CHUNK_SIZE: usize = 65536;
+const CHUNK_HEADER_SIZE: usize = 512;
+const CHUNK_SIGNATURE: &[u8] = b"ElfChnk\0";
+const RECORD_SIGNATURE: &[u8] = b"\x2a\x2a\x00\x00";
+// signature(4) + size(4) + record id(8) + timestamp(8) + size copy(4)
+const MIN_RECORD_SIZE: usize = 28;
+const READ_SIZE: usize = 16 * 1024 * 1024;
+
+/// 復旧したレコードと、そのレコードが見つかったファイル内のオフセット
+#[derive(Debug)]
+pub struct RecoveredRecord {
+    /// チャンク単位でパースできた場合はチャンクの、単体で切り出した場合はレコードの先頭オフセット
+    pub offset: u64,
+    pub data: Value,
+}
+
+#[derive(Debug, PartialEq)]
+enum Signature {
+    Chunk,
+    Record,
+}
+
+/// 破損したevtxファイルや未割り当て領域のイメージから、チャンク(ElfChnk)とレコード(**\0\0)のシグネチャを探してレコードを復旧するイテレータ。
+///
+/// チャンクはヘッダのチェックサムを検証せずに1つずつ独立してパースする。
+/// チャンクとしてパースできなかったレコードは、BinXMLのテンプレートや名前の参照がチャンクの先頭からのオフセットのため、
+/// 元のチャンクのデータ上の同じオフセットに置いたままパースする。
+/// チャンクのシグネチャが見つからない場合はレコード内のテンプレート定義の位置からチャンクの先頭を求める。
+/// テンプレートの定義が同じチャンクの別のレコードにある場合はチャンクの先頭が分からないため、エラーとして返す。
+pub struct RecordCarver<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    // bufの先頭のファイル内のオフセット
+    buf_offset: u64,
+    pos: usize,
+    eof: bool,
+    // 直前に見つかったチャンクのシグネチャのオフセット
+    chunk_offset: Option<u64>,
+    // 直前にパースしたチャンクの範囲と、そのチャンクから出力済みのレコードID
+    chunk_range: Range<u64>,
+    emitted_record_ids: HashSet<u64>,
+    pending: VecDeque<Result<RecoveredRecord, String>>,
+    settings: Arc<ParserSettings>,
+}
+
+impl<R: Read> RecordCarver<R> {
+    pub fn new(reader: R, settings: ParserSettings) -> RecordCarver<R> {
+        RecordCarver {
+            reader,
+            buf: vec![],
+            buf_offset: 0,
+            pos: 0,
+            eof: false,
+            chunk_offset: None,
+            chunk_range: 0..0,
+            emitted_record_ids: HashSet::new(),
+            pending: VecDeque::new(),
+            settings: Arc::new(settings),
+        }
+    }
+
+    /// 現在位置から1チャンク分のデータがバッファに無ければ読み込む。
+    /// レコードを元のチャンクのデータ上でパースするため、現在位置の前の1チャンク分のデータは残しておく。
+    /// 読み込みに失敗した場合はエラーを返し、それまでに読み込んだデータまでで探索を終える
+    fn fill(&mut self) {
+        if self.eof || self.buf.len() - self.pos >= CHUNK_SIZE {
+            return;
+        }
+        let drain_size = self.pos.saturating_sub(CHUNK_SIZE);
+        self.buf.drain(..drain_size);
+        self.buf_offset += drain_size as u64;
+        self.pos -= drain_size;
+
+        // 読み込み用のバッファは確保せず、bufの末尾に直接読み込む
+        let len = self.buf.len();
+        self.buf.resize(len + READ_SIZE, 0);
+        let mut read_size = 0;
+        while read_size < READ_SIZE {
+            match self.reader.read(&mut self.buf[len + read_size..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(size) => read_size += size,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.eof = true;
+                    self.pending.push_back(Err(format!(
+                        "Failed to read data. Offset:0x{:x} Error:{}",
+                        self.buf_offset + (len + read_size) as u64,
+                        e
+                    )));
+                    break;
+                }
+            }
+        }
+        self.buf.truncate(len + read_size);
+    }
+
+    fn carve_chunk(&mut self, start: usize) {
+        let chunk_offset = self.buf_offset + start as u64;
+        self.chunk_offset = Some(chunk_offset);
+        if self.buf.len() - start < CHUNK_SIZE {
+            return;
+        }
+        let chunk_data = self.buf[start..start + CHUNK_SIZE].to_vec();
+        let mut chunk_data = match EvtxChunkData::new(chunk_data, false) {
+            Ok(chunk_data) => chunk_data,
+            Err(_) => return, // ヘッダが壊れている場合はレコード単位で切り出す
+        };
+        let mut chunk = match chunk_data.parse(Arc::clone(&self.settings)) {
+            Ok(chunk) => chunk,
+            Err(_) => return,
+        };
+
+        self.chunk_range = chunk_offset..chunk_offset + CHUNK_SIZE as u64;
+        self.emitted_record_ids.clear();
+        // パースに失敗したレコードはこの後のシグネチャの探索で1件ずつ切り出す
+        for record in chunk.iter() {
+            let record = match record {
+                Ok(record) => record.into_json_value(),
+                Err(_) => continue,
+            };
+            if let Ok(record) = record {
+                self.emitted_record_ids.insert(record.event_record_id);
+                self.pending.push_back(Ok(RecoveredRecord {
+                    offset: chunk_offset,
+                    data: record.data,
+                }));
+            }
+        }
+    }
+
+    /// レコードを切り出してパースする。次に探索を始める位置を返す。
+    fn carve_record(&mut self, start: usize) -> usize {
+        let record_offset = self.buf_offset + start as u64;
+        let record_size = match get_record_size(&self.buf[start..]) {
+            Some(size) => size,
+            None => return start + RECORD_SIGNATURE.len(),
+        };
+        let record_id = read_u64(&self.buf[start + 8..]);
+        if self.chunk_range.contains(&record_offset) && self.emitted_record_ids.contains(&record_id)
+        {
+            return start + record_size;
+        }
+
+        let record = self
+            .find_chunk_start(start, record_size)
+            .and_then(|chunk_start| {
+                let chunk_end = self.buf.len().min(chunk_start + CHUNK_SIZE);
+                create_chunk_with_record(
+                    &self.buf[chunk_start..chunk_end],
+                    start - chunk_start,
+                    record_size,
+                    record_id,
+                )
+            })
+            .ok_or_else(|| {
+                "The chunk header was not found and the template is defined outside the record."
+                    .to_string()
+            })
+            .and_then(|chunk_data| {
+                let mut chunk_data =
+                    EvtxChunkData::new(chunk_data, false).map_err(|e| e.to_string())?;
+                let mut chunk = chunk_data
+                    .parse(Arc::clone(&self.settings))
+                    .map_err(|e| e.to_string())?;
+                // 先頭はレコードの前の領域を読み飛ばすためのダミーのレコードのため、最後のレコードを取り出す
+                let record = chunk
+                    .iter()
+                    .last()
+                    .ok_or_else(|| "No record found.".to_string())?
+                    .map_err(|e| e.to_string())?;
+                record.into_json_value().map_err(|e| e.to_string())
+            });
+        match record {
+            Ok(record) => {
+                self.pending.push_back(Ok(RecoveredRecord {
+                    offset: record_offset,
+                    data: record.data,
+                }));
+                start + record_size
+            }
+            Err(e) => {
+                self.pending.push_back(Err(format!(
+                    "Failed to recover record. Offset:0x{:x} RecordID:{} Error:{}",
+                    record_offset, record_id, e
+                )));
+                start + RECORD_SIGNATURE.len()
+            }
+        }
+    }
+
+    /// レコードが含まれていたチャンクの先頭のバッファ内の位置を返す。
+    /// 直前に見つかったチャンクのシグネチャの範囲内にあればそのチャンクの先頭を、無ければレコード内のテンプレート定義の位置から求めた先頭を返す
+    fn find_chunk_start(&self, start: usize, record_size: usize) -> Option<usize> {
+        let record_offset = self.buf_offset + start as u64;
+        let record_end = record_offset + record_size as u64;
+        if let Some(chunk_offset) = self.chunk_offset {
+            if chunk_offset < record_offset
+                && record_end <= chunk_offset + CHUNK_SIZE as u64
+                && chunk_offset >= self.buf_offset
+            {
+                return Some((chunk_offset - self.buf_offset) as usize);
+            }
+        }
+        let offset_in_chunk = get_offset_in_chunk(&self.buf[start..start + record_size])?;
+        start.checked_sub(offset_in_chunk)
+    }
+}
+
+impl<R: Read> Iterator for RecordCarver<R> {
+    type Item = Result<RecoveredRecord, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Some(record);
+            }
+
+            self.fill();
+            if self.pos >= self.buf.len() {
+                return self.pending.pop_front();
+            }
+
+            match find_signature(&self.buf[self.pos..]) {
+                Some((idx, signature)) => {
+                    let start = self.pos + idx;
+                    // チャンク1つ分のデータが読み込まれていない場合は読み込んでから処理する
+                    if !self.eof && self.buf.len() - start < CHUNK_SIZE {
+                        self.pos = start;
+                        continue;
+                    }
+                    self.pos = match signature {
+                        Signature::Chunk => {
+                            self.carve_chunk(start);
+                            start + CHUNK_SIGNATURE.len()
+                        }
+                        Signature::Record => self.carve_record(start),
+                    };
+                }
+                None => {
+                    // バッファの境界にまたがるシグネチャを見落とさないように末尾を残す
+                    self.pos = if self.eof {
+                        self.buf.len()
+                    } else {
+                        self.buf.len() - (CHUNK_SIGNATURE.len() - 1)
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// 最初に見つかったチャンクもしくはレコードのシグネチャの位置を返す
+fn find_signature(data: &[u8]) -> Option<(usize, Signature)> {
+    (0..data.len()).find_map(|idx| match data[idx] {
+        b'E' if data[idx..].starts_with(CHUNK_SIGNATURE) => Some((idx, Signature::Chunk)),
+        b'*' if data[idx..].starts_with(RECORD_SIGNATURE) => Some((idx, Signature::Record)),
+        _ => None,
+    })
+}
+
+/// レコードヘッダのサイズとレコード末尾のサイズのコピーが一致する場合にレコードのサイズを返す
+fn get_record_size(data: &[u8]) -> Option<usize> {
+    if data.len() < MIN_RECORD_SIZE || !data.starts_with(RECORD_SIGNATURE) {
+        return None;
+    }
+    let size = read_u32(&data[4..]) as usize;
+    if !(MIN_RECORD_SIZE..=CHUNK_SIZE - CHUNK_HEADER_SIZE).contains(&size) || size > data.len() {
+        return None;
+    }
+    if read_u32(&data[size - 4..]) as usize != size {
+        return None;
+    }
+    Some(size)
+}
+
+/// テンプレートの定義をレコード内に持つレコードの、チャンクの先頭からのオフセットを返す。
+/// レコード内で定義したテンプレートの参照はテンプレート定義の直前を指すため、その値からレコードの位置が分かる
+fn get_offset_in_chunk(record: &[u8]) -> Option<usize> {
+    // record header(24) + fragment header(4) + template instance(token(1) + unknown(1) + template id(4) + template definition offset(4))
+    const TEMPLATE_DEFINITION_POS: usize = 38;
+    // next template offset(4) + guid(16) + data size(4)
+    const TEMPLATE_DEFINITION_HEADER_SIZE: usize = 24;
+    let fragment_pos = TEMPLATE_DEFINITION_POS + TEMPLATE_DEFINITION_HEADER_SIZE;
+    if record.len() < fragment_pos + 4 || record[24] != 0x0f || record[28] != 0x0c {
+        return None;
+    }
+    // テンプレート定義のデータはフラグメントヘッダから始まり、レコード内に収まる
+    let data_size = read_u32(&record[TEMPLATE_DEFINITION_POS + 20..]) as usize;
+    if record[fragment_pos] != 0x0f || fragment_pos + data_size > record.len() {
+        return None;
+    }
+    let template_offset = read_u32(&record[34..]) as usize;
+    let offset_in_chunk = template_offset.checked_sub(TEMPLATE_DEFINITION_POS)?;
+    if offset_in_chunk < CHUNK_HEADER_SIZE || offset_in_chunk + record.len() > CHUNK_SIZE {
+        return None;
+    }
+    Some(offset_in_chunk)
+}
+
+/// 元のチャンクのデータのrecord_offsetにあるレコードだけをパースするチャンクを組み立てる。チェックサムは検証しないため設定しない。
+///
+/// チャンクは先頭のレコードからパースするため、先頭からレコードまでの領域をパースに失敗するダミーのレコードで読み飛ばす。
+/// ヘッダの名前のテーブルは壊れている可能性があるため、データ内の名前から組み立て直す。
+/// テンプレートのテーブルは空にし、テンプレートの定義はパース時にデータから読み込む。
+fn create_chunk_with_record(
+    chunk_data: &[u8],
+    record_offset: usize,
+    record_size: usize,
+    record_id: u64,
+) -> Option<Vec<u8>> {
+    if record_offset < CHUNK_HEADER_SIZE
+        || (record_offset > CHUNK_HEADER_SIZE
+            && record_offset - CHUNK_HEADER_SIZE < MIN_RECORD_SIZE)
+    {
+        return None;
+    }
+    let mut chunk = chunk_data.to_vec();
+    chunk.resize(CHUNK_SIZE, 0);
+    chunk[..CHUNK_HEADER_SIZE].fill(0);
+    chunk[..CHUNK_SIGNATURE.len()].copy_from_slice(CHUNK_SIGNATURE);
+    // first/last event record number, first/last event record id
+    for field_offset in [8, 16, 24, 32] {
+        chunk[field_offset..field_offset + 8].copy_from_slice(&record_id.to_le_bytes());
+    }
+    // header size
+    chunk[40..44].copy_from_slice(&128u32.to_le_bytes());
+    // last event record data offset
+    chunk[44..48].copy_from_slice(&(record_offset as u32).to_le_bytes());
+    // free space offset
+    chunk[48..52].copy_from_slice(&((record_offset + record_size) as u32).to_le_bytes());
+
+    if record_offset > CHUNK_HEADER_SIZE {
+        let dummy_size = (record_offset - CHUNK_HEADER_SIZE) as u32;
+        let dummy = &mut chunk[CHUNK_HEADER_SIZE..record_offset];
+        dummy[..4].copy_from_slice(RECORD_SIGNATURE);
+        dummy[4..8].copy_from_slice(&dummy_size.to_le_bytes());
+        dummy[8..16].copy_from_slice(&(!record_id).to_le_bytes());
+        dummy[16..24].fill(0);
+        // 不正なトークン
+        dummy[24] = 0xff;
+    }
+
+    for (idx, name_offset) in find_name_chains(&chunk).into_iter().enumerate() {
+        let table_offset = 128 + idx * 4;
+        chunk[table_offset..table_offset + 4].copy_from_slice(&name_offset.to_le_bytes());
+    }
+    Some(chunk)
+}
+
+/// チャンクのデータ内にある名前(next string offset(4) + hash(2) + 文字数(2) + UTF-16の文字列 + NUL(2))を探し、
+/// ほかの名前から参照されていない名前のオフセットを、ヘッダの名前のテーブルに入る64個まで返す
+fn find_name_chains(chunk: &[u8]) -> Vec<u32> {
+    let is_name = |pos: usize| {
+        let len = read_u16(&chunk[pos + 6..]) as usize;
+        let end = pos + 8 + len * 2;
+        if len == 0 || len > 255 || end + 2 > chunk.len() || read_u16(&chunk[end..]) != 0 {
+            return false;
+        }
+        (pos + 8..end).step_by(2).all(|char_pos| {
+            let c = read_u16(&chunk[char_pos..]);
+            c < 0x80 && matches!(c as u8, b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-' | b'.' | b':')
+        })
+    };
+    let next_name = |pos: u32| read_u32(&chunk[pos as usize..]);
+    let mut names: HashSet<u32> = (CHUNK_HEADER_SIZE..chunk.len() - 8)
+        .filter(|&pos| is_name(pos))
+        .map(|pos| pos as u32)
+        .collect();
+    // 次の名前が名前として見つからない場合は、名前のテーブルの読み込みに失敗するため除く
+    loop {
+        let invalid_names: Vec<u32> = names
+            .iter()
+            .copied()
+            .filter(|&pos| next_name(pos) != 0 && !names.contains(&next_name(pos)))
+            .collect();
+        if invalid_names.is_empty() {
+            break;
+        }
+        invalid_names.iter().for_each(|pos| {
+            names.remove(pos);
+        });
+    }
+    let next_names: HashSet<u32> = names.iter().map(|&pos| next_name(pos)).collect();
+    let mut heads: Vec<u32> = names
+        .difference(&next_names)
+        .copied()
+        .filter(|&head| {
+            // 循環している場合は名前のテーブルの読み込みが終わらないため除く
+            let mut pos = head;
+            (0..names.len()).any(|_| {
+                pos = next_name(pos);
+                pos == 0
+            })
+        })
+        .collect();
+    heads.sort_unstable();
+    heads.truncate(64);
+    heads
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::recovery::{
+        find_signature, get_offset_in_chunk, get_record_size, RecordCarver, Signature,
+        CHUNK_HEADER_SIZE, CHUNK_SIZE,
+    };
+    use evtx::{EvtxParser, ParserSettings};
+    use hashbrown::HashMap;
+    use serde_json::Value;
+    use std::io::{self, ErrorKind, Read};
+
+    const TEST_FILE: &str = "test_files/recovery/security.evtx";
+    // ファイルヘッダの後ろにある1つ目のチャンクと、1つ目のレコードのオフセット
+    const CHUNK_OFFSET: u64 = 4096;
+    const FIRST_RECORD_OFFSET: u64 = CHUNK_OFFSET + CHUNK_HEADER_SIZE as u64;
+
+    fn create_record(size: u32, record_id: u64) -> Vec<u8> {
+        let mut record = vec![0x2a, 0x2a, 0x00, 0x00];
+        record.extend_from_slice(&size.to_le_bytes());
+        record.extend_from_slice(&record_id.to_le_bytes());
+        record.resize(size as usize - 4, 0);
+        record.extend_from_slice(&size.to_le_bytes());
+        record
+    }
+
+    #[test]
+    fn test_find_signature() {
+        assert_eq!(
+            find_signature(b"xxxElfChnk\0yyy"),
+            Some((3, Signature::Chunk))
+        );
+        assert_eq!(
+            find_signature(b"xx**\0\0yyyElfChnk\0"),
+            Some((2, Signature::Record))
+        );
+        // 末尾にあるレコードのシグネチャ
+        assert_eq!(find_signature(b"xxx**\0\0"), Some((3, Signature::Record)));
+        assert_eq!(find_signature(b"ElfChnk"), None);
+        assert_eq!(find_signature(b""), None);
+    }
+
+    #[test]
+    fn test_get_record_size() {
+        let record = create_record(64, 10);
+        assert_eq!(get_record_size(&record), Some(64));
+
+        // 末尾のサイズのコピーが一致しない
+        let mut broken_record = record.clone();
+        broken_record[60] = 0xff;
+        assert_eq!(get_record_size(&broken_record), None);
+
+        // データが途中で切れている
+        assert_eq!(get_record_size(&record[..40]), None);
+
+        // シグネチャが異なる
+        assert_eq!(get_record_size(b"ElfChnk\0"), None);
+    }
+
+    /// EvtxParserでパースしたテスト用のevtxファイルのレコードをEventRecordIDごとに返す
+    fn parse_test_file() -> HashMap<u64, Value> {
+        let mut parser = EvtxParser::from_path(TEST_FILE).unwrap();
+        parser
+            .records_json_value()
+            .map(|record| {
+                let record = record.unwrap();
+                (record.event_record_id, record.data)
+            })
+            .collect()
+    }
+
+    /// 復旧したレコードのEventRecordIDとオフセット、データと、エラーを返す
+    fn carve(data: &[u8]) -> (Vec<(u64, u64, Value)>, Vec<String>) {
+        carve_reader(data)
+    }
+
+    fn carve_reader<R: Read>(reader: R) -> (Vec<(u64, u64, Value)>, Vec<String>) {
+        let mut records = vec![];
+        let mut errors = vec![];
+        for record in RecordCarver::new(reader, ParserSettings::default()) {
+            match record {
+                Ok(record) => records.push((
+                    record.data["Event"]["System"]["EventRecordID"]
+                        .as_u64()
+                        .unwrap(),
+                    record.offset,
+                    record.data,
+                )),
+                Err(e) => errors.push(e),
+            }
+        }
+        (records, errors)
+    }
+
+    fn assert_records(actual: &[(u64, u64, Value)], expected: &[(u64, u64)]) {
+        let records = parse_test_file();
+        assert_eq!(
+            actual
+                .iter()
+                .map(|(record_id, offset, _)| (*record_id, *offset))
+                .collect::<Vec<_>>(),
+            expected
+        );
+        for (record_id, _, data) in actual {
+            assert_eq!(data, &records[record_id]);
+        }
+    }
+
+    #[test]
+    fn test_get_offset_in_chunk() {
+        let data = std::fs::read(TEST_FILE).unwrap();
+        let start = FIRST_RECORD_OFFSET as usize;
+        let size = get_record_size(&data[start..]).unwrap();
+        // 1つ目のレコードはテンプレートの定義を持つ
+        assert_eq!(
+            get_offset_in_chunk(&data[start..start + size]),
+            Some(CHUNK_HEADER_SIZE)
+        );
+        // 2つ目のレコードは1つ目のレコードのテンプレートを参照する
+        let next_size = get_record_size(&data[start + size..]).unwrap();
+        assert_eq!(
+            get_offset_in_chunk(&data[start + size..start + size + next_size]),
+            None
+        );
+        assert_eq!(get_offset_in_chunk(&create_record(64, 1)), None);
+    }
+
+    #[test]
+    fn test_carve_chunk() {
+        let data = std::fs::read(TEST_FILE).unwrap();
+        let (records, errors) = carve(&data);
+        assert_records(
+            &records,
+            &[
+                (1, CHUNK_OFFSET),
+                (2, CHUNK_OFFSET),
+                (3, CHUNK_OFFSET),
+                (4, CHUNK_OFFSET),
+            ],
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_carve_broken_record() {
+        // 2つ目のレコードのシグネチャを壊すと、チャンクのパースはそのレコードで終わる
+        let mut data = std::fs::read(TEST_FILE).unwrap();
+        let offsets = get_record_offsets(&data);
+        data[offsets[1] as usize] = b'X';
+        let (records, errors) = carve(&data);
+        // 残りのレコードは元のチャンクのデータ上でパースするため、1つ目のレコードのテンプレートを参照できる
+        assert_records(
+            &records,
+            &[(1, CHUNK_OFFSET), (3, offsets[2]), (4, offsets[3])],
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_carve_broken_chunk_header() {
+        // 名前とテンプレートのテーブルが壊れているとチャンクとしてはパースできない
+        let mut data = std::fs::read(TEST_FILE).unwrap();
+        let offsets = get_record_offsets(&data);
+        let chunk_offset = CHUNK_OFFSET as usize;
+        data[chunk_offset + 128..chunk_offset + CHUNK_HEADER_SIZE].fill(0xff);
+        let (records, errors) = carve(&data);
+        assert_records(
+            &records,
+            &[
+                (1, offsets[0]),
+                (2, offsets[1]),
+                (3, offsets[2]),
+                (4, offsets[3]),
+            ],
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_carve_without_chunk_header() {
+        // チャンクのシグネチャが無い場合は、テンプレートを定義しているレコードだけを復旧する
+        let mut data = std::fs::read(TEST_FILE).unwrap();
+        let offsets = get_record_offsets(&data);
+        data[..FIRST_RECORD_OFFSET as usize].fill(0);
+        let (records, errors) = carve(&data);
+        assert_records(&records, &[(1, offsets[0]), (3, offsets[2])]);
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("RecordID:2"));
+        assert!(errors[1].contains("RecordID:4"));
+    }
+
+    /// テスト用のevtxファイルのレコードのオフセットを返す
+    fn get_record_offsets(data: &[u8]) -> Vec<u64> {
+        let mut offsets = vec![];
+        let mut offset = FIRST_RECORD_OFFSET as usize;
+        while let Some(size) = get_record_size(&data[offset..]) {
+            offsets.push(offset as u64);
+            offset += size;
+        }
+        offsets
+    }
+
+    /// 1回の読み込みで返すサイズを制限し、読み込みのたびにInterruptedを1回返すReader。
+    /// fail_atを指定した場合はそのオフセットまで読み込んだ後にエラーを返す
+    struct FlakyReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+        interrupted: bool,
+        fail_at: Option<usize>,
+    }
+
+    impl Read for FlakyReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(io::Error::from(ErrorKind::Interrupted));
+            }
+            if self.fail_at == Some(self.pos) {
+                return Err(io::Error::from(ErrorKind::InvalidData));
+            }
+            let end = self
+                .data
+                .len()
+                .min(self.pos + 1000)
+                .min(self.fail_at.unwrap_or(usize::MAX));
+            let size = buf.len().min(end - self.pos);
+            buf[..size].copy_from_slice(&self.data[self.pos..self.pos + size]);
+            self.pos += size;
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn test_carve_interrupted_read() {
+        let data = std::fs::read(TEST_FILE).unwrap();
+        let reader = FlakyReader {
+            data: &data,
+            pos: 0,
+            interrupted: false,
+            fail_at: None,
+        };
+        let (records, errors) = carve_reader(reader);
+        assert_eq!(records.len(), 4);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_carve_read_error() {
+        // 2つ目のレコードの途中で読み込みに失敗した場合は、1つ目のレコードまで復旧してエラーを返す
+        let data = std::fs::read(TEST_FILE).unwrap();
+        let offsets = get_record_offsets(&data);
+        let fail_at = offsets[1] as usize + 8;
+        let reader = FlakyReader {
+            data: &data,
+            pos: 0,
+            interrupted: false,
+            fail_at: Some(fail_at),
+        };
+        let (records, errors) = carve_reader(reader);
+        assert_eq!(records.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains(&format!("Offset:0x{:x}", fail_at)));
+    }
+
+    #[test]
+    fn test_carve_no_signature() {
+        let data = vec![0u8; CHUNK_SIZE * 3];
+        let mut carver = RecordCarver::new(&data[..], ParserSettings::default());
+        assert!(carver.next().is_none());
+    }
+}
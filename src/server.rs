@@ -0,0 +1,1161 @@
+use crate::detections::configs::LEVELMAP;
+use crate::detections::print::AlertMessage;
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::engine::Engine;
+use chrono::{Local, SecondsFormat, Utc};
+use hashbrown::HashMap;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsStr;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::BufWriter;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::runtime::Builder;
+use tokio::time::timeout;
+
+/// リクエストラインとヘッダの最大サイズ
+const MAX_HEADER_SIZE: u64 = 64 * 1024;
+/// メモリに読み込むリクエストボディ(JSON)の最大サイズ
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+/// アップロードできるevtxファイルの最大サイズ。ボディはメモリに読み込まずにファイルに書き込む
+const MAX_UPLOAD_SIZE: u64 = 256 * 1024 * 1024;
+/// リクエストの読み込みを待つ時間。アップロードのボディは読み込み1回ごとに待つ
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// 接続の受け付けに失敗した場合に、次の受け付けまで待つ時間
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// 検知結果の1ページあたりのデフォルトの件数
+const DEFAULT_LIMIT: usize = 100;
+/// 検知結果の1ページあたりの最大の件数
+const MAX_LIMIT: usize = 1000;
+
+/// ジョブの状態
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// ジョブの進捗
+#[derive(Debug)]
+struct JobProgress {
+    status: JobStatus,
+    files_total: usize,
+    files_scanned: usize,
+    records_scanned: usize,
+    /// 解析できなかったファイルのエラー
+    errors: Vec<String>,
+    created: String,
+    started: Option<String>,
+    finished: Option<String>,
+}
+
+/// APIで返す検知結果1件分の情報。項目名は--webhook-templateと同じにする
+#[derive(Debug, Clone, Serialize)]
+pub struct JobDetection {
+    #[serde(rename = "Timestamp")]
+    timestamp: String,
+    #[serde(rename = "Computer")]
+    computer: String,
+    #[serde(rename = "EventID")]
+    event_id: String,
+    #[serde(rename = "Level")]
+    level: String,
+    #[serde(rename = "MitreAttack")]
+    mitre_attack: String,
+    #[serde(rename = "RuleTitle")]
+    rule_title: String,
+    #[serde(rename = "RuleID")]
+    rule_id: String,
+    #[serde(rename = "Details")]
+    details: String,
+    #[serde(rename = "RulePath")]
+    rule_path: String,
+    #[serde(rename = "FilePath")]
+    file_path: String,
+}
+
+/// 1回分のスキャン。サーバ上のファイルまたはディレクトリ、アップロードしたevtxファイルを解析する
+pub struct Job {
+    id: u64,
+    /// 解析するファイルまたはディレクトリ
+    target: PathBuf,
+    /// アップロードしたファイルの場合は元のファイル名。解析が終わったらtargetのファイルを削除する
+    upload_name: Option<String>,
+    cancelled: AtomicBool,
+    progress: Mutex<JobProgress>,
+    detections: Mutex<Vec<JobDetection>>,
+}
+
+impl Job {
+    fn new(id: u64, target: PathBuf, upload_name: Option<String>) -> Job {
+        Job {
+            id,
+            target,
+            upload_name,
+            cancelled: AtomicBool::new(false),
+            progress: Mutex::new(JobProgress {
+                status: JobStatus::Queued,
+                files_total: 0,
+                files_scanned: 0,
+                records_scanned: 0,
+                errors: vec![],
+                created: now(),
+                started: Option::None,
+                finished: Option::None,
+            }),
+            detections: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn status(&self) -> JobStatus {
+        self.progress.lock().unwrap().status
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn source(&self) -> String {
+        match &self.upload_name {
+            Some(name) => format!("upload:{}", name),
+            None => self.target.display().to_string(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let progress = self.progress.lock().unwrap();
+        json!({
+            "id": self.id,
+            "source": self.source(),
+            "status": progress.status.as_str(),
+            "files_total": progress.files_total,
+            "files_scanned": progress.files_scanned,
+            "records_scanned": progress.records_scanned,
+            "detections": self.detections.lock().unwrap().len(),
+            "errors": progress.errors,
+            "created": progress.created,
+            "started": progress.started,
+            "finished": progress.finished,
+        })
+    }
+}
+
+/// ジョブの検知結果を保持するsink
+struct JobSink {
+    job: Arc<Job>,
+}
+
+impl DetectionSink for JobSink {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        let detect_info = event.detect_info;
+        self.job.detections.lock().unwrap().push(JobDetection {
+            timestamp: event.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            computer: detect_info.computername.to_owned(),
+            event_id: detect_info.eventid.to_owned(),
+            level: detect_info.level.to_owned(),
+            mitre_attack: detect_info.tag_info.to_owned(),
+            rule_title: detect_info.alert.to_owned(),
+            rule_id: event.rule.yaml["id"].as_str().unwrap_or("").to_owned(),
+            details: detect_info.detail.to_owned(),
+            rule_path: detect_info.rulepath.to_owned(),
+            file_path: detect_info.filepath.to_owned(),
+        });
+        Result::Ok(())
+    }
+}
+
+/// HTTPリクエスト
+#[derive(Debug)]
+pub struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    body: Vec<u8>,
+    /// Authorizationヘッダの値
+    authorization: Option<String>,
+    /// POST /jobs/uploadのボディを書き込んだファイル
+    upload_file: Option<PathBuf>,
+}
+
+impl Request {
+    /// targetはリクエストラインのパスとクエリ文字列
+    pub fn new(method: &str, target: &str, body: Vec<u8>) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (key, value) = param.split_once('=').unwrap_or((param, ""));
+                (decode_query(key), decode_query(value))
+            })
+            .collect();
+        Request {
+            method: method.to_uppercase(),
+            path: path.trim_end_matches('/').to_string(),
+            query,
+            body,
+            authorization: Option::None,
+            upload_file: Option::None,
+        }
+    }
+
+    pub fn authorization(mut self, authorization: &str) -> Request {
+        self.authorization = Option::Some(authorization.to_string());
+        self
+    }
+}
+
+/// HTTPレスポンス。ボディは全てJSON
+#[derive(Debug)]
+pub struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn new(status: u16, body: Value) -> Response {
+        Response { status, body }
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response::new(status, json!({ "error": message }))
+    }
+
+    fn reason(&self) -> &str {
+        match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// クエリ文字列のパーセントエンコーディングと+をデコードする
+fn decode_query(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut ret = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => ret.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let byte = std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match byte {
+                    Some(byte) => {
+                        ret.push(byte);
+                        i += 2;
+                    }
+                    None => ret.push(b'%'),
+                }
+            }
+            byte => ret.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&ret).to_string()
+}
+
+fn now() -> String {
+    Local::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn get_level_num(level: &str) -> Option<u128> {
+    LEVELMAP.get(&level.to_uppercase()).copied()
+}
+
+/// ディレクトリ内の.evtxファイルを再帰的に取得する。隠しファイルは対象外とする
+fn collect_evtx_files(dirpath: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut ret = vec![];
+    let entries = fs::read_dir(dirpath).map_err(|e| format!("{} {}", dirpath.display(), e))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            ret.extend(collect_evtx_files(&path)?);
+        } else if path.extension() == Option::Some(OsStr::new("evtx"))
+            && !path
+                .file_name()
+                .unwrap_or_else(|| OsStr::new("."))
+                .to_string_lossy()
+                .starts_with('.')
+        {
+            ret.push(path);
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// ルールを読み込んだEngineを保持し、REST APIで受け付けたスキャンのジョブを順番に実行するサーバ。
+/// ジョブは1件ずつ実行し、1件のジョブではEngineのthread_numberの数のファイルを並列に解析する
+pub struct Server {
+    engine: Engine,
+    /// アップロードしたevtxファイルを一時的に保存するディレクトリ
+    upload_dir: PathBuf,
+    /// 指定した場合はAuthorizationヘッダに"Bearer {token}"が必要になる
+    token: Option<String>,
+    /// 指定した場合はPOST /jobsで解析できるパスをこのディレクトリ以下に制限する
+    root: Option<PathBuf>,
+    /// 保持する終了したジョブの最大数。超えた分は古いジョブから結果を削除する
+    max_finished_jobs: usize,
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+    queue: Mutex<VecDeque<Arc<Job>>>,
+    queue_cond: Condvar,
+}
+
+impl Server {
+    /// engineは検知結果の出力先を持たないEngine。ジョブごとにEngine::with_sinksで出力先を設定して解析する
+    pub fn new(engine: Engine, upload_dir: PathBuf) -> Server {
+        Server {
+            engine,
+            upload_dir,
+            token: Option::None,
+            root: Option::None,
+            max_finished_jobs: 100,
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(BTreeMap::new()),
+            queue: Mutex::new(VecDeque::new()),
+            queue_cond: Condvar::new(),
+        }
+    }
+
+    pub fn token(mut self, token: &str) -> Server {
+        self.token = Option::Some(token.to_string());
+        self
+    }
+
+    pub fn root(mut self, root: PathBuf) -> Server {
+        self.root = Option::Some(root);
+        self
+    }
+
+    /// 保持する終了したジョブの最大数 (デフォルト: 100)
+    pub fn max_finished_jobs(mut self, max_finished_jobs: usize) -> Server {
+        self.max_finished_jobs = max_finished_jobs;
+        self
+    }
+
+    /// キューに追加されたジョブを実行するスレッドを起動する
+    pub fn start_worker(server: &Arc<Server>) {
+        let server = Arc::clone(server);
+        thread::spawn(move || loop {
+            if let Some(job) = server.pop_job(true) {
+                server.run_job(&job);
+            }
+        });
+    }
+
+    /// キューから次のジョブを取り出す。waitがtrueの場合はジョブが追加されるまで待つ
+    fn pop_job(&self, wait: bool) -> Option<Arc<Job>> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(job) = queue.pop_front() {
+                return Option::Some(job);
+            }
+            if !wait {
+                return Option::None;
+            }
+            queue = self.queue_cond.wait(queue).unwrap();
+        }
+    }
+
+    fn push_job(&self, target: PathBuf, upload_name: Option<String>) -> Arc<Job> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.push_job_with_id(id, target, upload_name)
+    }
+
+    fn push_job_with_id(&self, id: u64, target: PathBuf, upload_name: Option<String>) -> Arc<Job> {
+        self.remove_finished_jobs();
+        let job = Arc::new(Job::new(id, target, upload_name));
+        self.jobs.lock().unwrap().insert(id, Arc::clone(&job));
+        self.queue.lock().unwrap().push_back(Arc::clone(&job));
+        self.queue_cond.notify_one();
+        job
+    }
+
+    /// 終了したジョブがmax_finished_jobsを超えた場合は、古いジョブから削除する
+    fn remove_finished_jobs(&self) {
+        // delete_jobはジョブの進捗をロックしてからjobsをロックするため、jobsをロックしたまま進捗をロックしない
+        let jobs: Vec<Arc<Job>> = self.jobs.lock().unwrap().values().cloned().collect();
+        let finished: Vec<u64> = jobs
+            .iter()
+            .filter(|job| !matches!(job.status(), JobStatus::Queued | JobStatus::Running))
+            .map(|job| job.id)
+            .collect();
+        if finished.len() <= self.max_finished_jobs {
+            return;
+        }
+        let mut jobs = self.jobs.lock().unwrap();
+        for id in &finished[..finished.len() - self.max_finished_jobs] {
+            jobs.remove(id);
+        }
+    }
+
+    fn run_job(&self, job: &Arc<Job>) {
+        {
+            let mut progress = job.progress.lock().unwrap();
+            // キューにある間にキャンセルされたジョブは実行しない
+            if progress.status != JobStatus::Queued {
+                return;
+            }
+            progress.status = JobStatus::Running;
+            progress.started = Option::Some(now());
+        }
+
+        let ret = self.scan(job);
+        if job.upload_name.is_some() {
+            fs::remove_file(&job.target).ok();
+        }
+        // 並列に検知するため、時刻順に並べ替える
+        job.detections
+            .lock()
+            .unwrap()
+            .sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        let mut progress = job.progress.lock().unwrap();
+        progress.status = match ret {
+            Ok(()) if job.is_cancelled() => JobStatus::Cancelled,
+            Ok(()) => JobStatus::Completed,
+            Err(err) => {
+                progress.errors.push(err);
+                JobStatus::Failed
+            }
+        };
+        progress.finished = Option::Some(now());
+    }
+
+    /// ジョブの対象のevtxファイルを解析する。解析できないファイルがあった場合はジョブのエラーに追加して残りのファイルを解析する
+    fn scan(&self, job: &Arc<Job>) -> Result<(), String> {
+        let evtx_files = if job.target.is_dir() {
+            collect_evtx_files(&job.target)?
+        } else {
+            vec![job.target.clone()]
+        };
+        job.progress.lock().unwrap().files_total = evtx_files.len();
+
+        let sink: Arc<dyn DetectionSink> = Arc::new(JobSink {
+            job: Arc::clone(job),
+        });
+        let engine = self.engine.with_sinks(vec![sink]);
+        engine.par_for_each(evtx_files, |evtx_file| {
+            if job.is_cancelled() {
+                return;
+            }
+            let ret = engine.scan_file_with_progress(&evtx_file, |count| {
+                job.progress.lock().unwrap().records_scanned += count;
+                !job.is_cancelled()
+            });
+            let mut progress = job.progress.lock().unwrap();
+            progress.files_scanned += 1;
+            if let Err(err) = ret {
+                progress
+                    .errors
+                    .push(format!("Failed to scan {}. {}", evtx_file.display(), err));
+            }
+        });
+        engine.finish()
+    }
+
+    fn get_job(&self, id: &str) -> Result<Arc<Job>, Response> {
+        id.parse::<u64>()
+            .ok()
+            .and_then(|id| self.jobs.lock().unwrap().get(&id).cloned())
+            .ok_or_else(|| Response::error(404, &format!("Job not found. {}", id)))
+    }
+
+    /// tokenを指定した場合は、リクエストのAuthorizationヘッダを確認する
+    fn authorize(&self, request: &Request) -> Result<(), Response> {
+        match &self.token {
+            Some(token)
+                if request.authorization.as_deref() != Some(&format!("Bearer {}", token)) =>
+            {
+                Err(Response::error(401, "Unauthorized."))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// アップロードしたファイルを書き込むパス。他のリクエストと重ならず、推測できないようにランダムな名前にする
+    fn new_upload_path(&self) -> PathBuf {
+        let random = RandomState::new().build_hasher().finish();
+        self.upload_dir.join(format!("{:016x}.evtx", random))
+    }
+
+    /// リクエストのパスとメソッドに対応する処理を行う
+    pub fn handle(&self, request: &Request) -> Response {
+        if let Err(response) = self.authorize(request) {
+            return response;
+        }
+        let segments: Vec<&str> = request.path.split('/').skip(1).collect();
+        let ret = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["rules"]) => Ok(self.list_rules()),
+            ("GET", ["jobs"]) => Ok(self.list_jobs()),
+            ("POST", ["jobs"]) => self.create_job(request),
+            ("POST", ["jobs", "upload"]) => self.upload_job(request),
+            ("GET", ["jobs", id]) => self
+                .get_job(id)
+                .map(|job| Response::new(200, job.to_json())),
+            ("DELETE", ["jobs", id]) => self.delete_job(id),
+            ("GET", ["jobs", id, "detections"]) => self.list_detections(id, request),
+            (_, ["rules"]) | (_, ["jobs"]) | (_, ["jobs", _]) | (_, ["jobs", _, "detections"]) => {
+                Err(Response::error(405, "Method not allowed."))
+            }
+            _ => Err(Response::error(404, "Not found.")),
+        };
+        ret.unwrap_or_else(|response| response)
+    }
+
+    /// 読み込んだルールのメタデータを返す
+    fn list_rules(&self) -> Response {
+        let rules: Vec<Value> = self
+            .engine
+            .rules()
+            .iter()
+            .map(|rule| {
+                let tags: Vec<&str> = rule.yaml["tags"]
+                    .as_vec()
+                    .map(|tags| tags.iter().filter_map(|tag| tag.as_str()).collect())
+                    .unwrap_or_default();
+                json!({
+                    "id": rule.yaml["id"].as_str().unwrap_or(""),
+                    "title": rule.yaml["title"].as_str().unwrap_or(""),
+                    "level": rule.yaml["level"].as_str().unwrap_or(""),
+                    "status": rule.yaml["status"].as_str().unwrap_or(""),
+                    "author": rule.yaml["author"].as_str().unwrap_or(""),
+                    "description": rule.yaml["description"].as_str().unwrap_or(""),
+                    "tags": tags,
+                    "path": rule.rulepath,
+                })
+            })
+            .collect();
+        Response::new(200, json!({ "total": rules.len(), "rules": rules }))
+    }
+
+    fn list_jobs(&self) -> Response {
+        let jobs: Vec<Value> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| job.to_json())
+            .collect();
+        Response::new(200, json!({ "jobs": jobs }))
+    }
+
+    /// {"path": "..."}で指定したサーバ上のevtxファイルまたはディレクトリを解析するジョブを追加する
+    fn create_job(&self, request: &Request) -> Result<Response, Response> {
+        let body: Value = serde_json::from_slice(&request.body)
+            .map_err(|e| Response::error(400, &format!("Invalid JSON. {}", e)))?;
+        let path = body["path"]
+            .as_str()
+            .ok_or_else(|| Response::error(400, "path is required."))?;
+        if let Some(root) = &self.root {
+            // シンボリックリンクや..でrootの外に出られないように、正規化したパスで比較する。
+            // root外のパスの有無がわからないように、存在しないパスもroot外のパスと同じエラーにする
+            let is_allowed = match (fs::canonicalize(path), fs::canonicalize(root)) {
+                (Ok(path), Ok(root)) => path.starts_with(root),
+                _ => false,
+            };
+            if !is_allowed {
+                return Err(Response::error(
+                    403,
+                    &format!("Path is not allowed. {}", path),
+                ));
+            }
+        }
+        if !Path::new(path).exists() {
+            return Err(Response::error(400, &format!("Path not found. {}", path)));
+        }
+        let job = self.push_job(PathBuf::from(path), Option::None);
+        Ok(Response::new(202, job.to_json()))
+    }
+
+    /// リクエストボディを書き込んだevtxファイルを解析するジョブを追加する。nameクエリでファイル名を指定できる
+    fn upload_job(&self, request: &Request) -> Result<Response, Response> {
+        let target = request
+            .upload_file
+            .clone()
+            .ok_or_else(|| Response::error(400, "The request body is empty."))?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let name = request
+            .query
+            .get("name")
+            .cloned()
+            .unwrap_or_else(|| format!("{}.evtx", id));
+        let job = self.push_job_with_id(id, target, Option::Some(name));
+        Ok(Response::new(202, job.to_json()))
+    }
+
+    /// 実行前または実行中のジョブはキャンセルし、終了したジョブは結果を削除する
+    fn delete_job(&self, id: &str) -> Result<Response, Response> {
+        let job = self.get_job(id)?;
+        let mut progress = job.progress.lock().unwrap();
+        match progress.status {
+            JobStatus::Queued => {
+                progress.status = JobStatus::Cancelled;
+                progress.finished = Option::Some(now());
+                self.queue
+                    .lock()
+                    .unwrap()
+                    .retain(|queued| queued.id != job.id);
+                if job.upload_name.is_some() {
+                    fs::remove_file(&job.target).ok();
+                }
+            }
+            JobStatus::Running => job.cancelled.store(true, Ordering::Relaxed),
+            _ => {
+                self.jobs.lock().unwrap().remove(&job.id);
+            }
+        }
+        drop(progress);
+        Ok(Response::new(200, job.to_json()))
+    }
+
+    /// ジョブの検知結果を返す。level(指定したレベル以上)、rule(ルールのタイトルの部分一致またはID)、computerで絞り込み、offsetとlimitでページングする
+    fn list_detections(&self, id: &str, request: &Request) -> Result<Response, Response> {
+        let job = self.get_job(id)?;
+        let query = &request.query;
+        let min_level = match query.get("level") {
+            Some(level) => get_level_num(level)
+                .ok_or_else(|| Response::error(400, &format!("Invalid level. {}", level)))?,
+            None => 0,
+        };
+        let rule = query.get("rule").map(|rule| rule.to_lowercase());
+        let computer = query
+            .get("computer")
+            .map(|computer| computer.to_lowercase());
+        let parse_num = |key: &str, default: usize| match query.get(key) {
+            Some(value) => value
+                .parse::<usize>()
+                .map_err(|_| Response::error(400, &format!("Invalid {}. {}", key, value))),
+            None => Ok(default),
+        };
+        let offset = parse_num("offset", 0)?;
+        let limit = parse_num("limit", DEFAULT_LIMIT)?.min(MAX_LIMIT);
+
+        let detections = job.detections.lock().unwrap();
+        let filtered: Vec<&JobDetection> = detections
+            .iter()
+            .filter(|detection| get_level_num(&detection.level).unwrap_or(0) >= min_level)
+            .filter(|detection| match &rule {
+                Some(rule) => {
+                    detection.rule_title.to_lowercase().contains(rule)
+                        || detection.rule_id.to_lowercase() == *rule
+                }
+                None => true,
+            })
+            .filter(|detection| match &computer {
+                Some(computer) => detection.computer.to_lowercase() == *computer,
+                None => true,
+            })
+            .collect();
+        let page: Vec<&JobDetection> = filtered.iter().skip(offset).take(limit).copied().collect();
+        Ok(Response::new(
+            200,
+            json!({
+                "id": job.id,
+                "status": job.status().as_str(),
+                "total": filtered.len(),
+                "offset": offset,
+                "limit": limit,
+                "detections": page,
+            }),
+        ))
+    }
+}
+
+/// リクエストラインとヘッダを読み込み、ボディのないリクエストとContent-Lengthを返す
+async fn read_head<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+) -> Result<(Request, u64), Response> {
+    let bad_request = |_| Response::error(400, "Invalid request.");
+    let mut reader = reader.take(MAX_HEADER_SIZE);
+    let mut request_line = String::new();
+    reader
+        .read_line(&mut request_line)
+        .await
+        .map_err(bad_request)?;
+    let mut parts = request_line.split_whitespace();
+    let mut request = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => Request::new(method, target, vec![]),
+        _ => return Err(Response::error(400, "Invalid request.")),
+    };
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.map_err(bad_request)? == 0 {
+            break;
+        }
+        if line.trim().is_empty() {
+            return Ok((request, content_length));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| Response::error(400, "Invalid Content-Length."))?;
+            } else if name.eq_ignore_ascii_case("authorization") {
+                request.authorization = Option::Some(value.trim().to_string());
+            }
+        }
+    }
+    if reader.limit() == 0 {
+        return Err(Response::error(413, "The request header is too large."));
+    }
+    Ok((request, content_length))
+}
+
+/// アップロードのボディをpathのファイルに書き込む
+async fn write_upload<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    content_length: u64,
+    path: &Path,
+) -> Result<(), Response> {
+    let save_error =
+        |e: std::io::Error| Response::error(500, &format!("Failed to save the file. {}", e));
+    tokio::fs::create_dir_all(path.parent().unwrap_or_else(|| Path::new(".")))
+        .await
+        .map_err(save_error)?;
+    // 既にあるファイルやシンボリックリンクには書き込まない
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await
+        .map_err(save_error)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut remaining = content_length;
+    while remaining > 0 {
+        let len = remaining.min(buf.len() as u64) as usize;
+        let read = timeout(READ_TIMEOUT, reader.read(&mut buf[..len]))
+            .await
+            .map_err(|_| Response::error(408, "Request timeout."))?
+            .map_err(|_| Response::error(400, "Invalid request."))?;
+        if read == 0 {
+            return Err(Response::error(400, "The request body is incomplete."));
+        }
+        file.write_all(&buf[..read]).await.map_err(save_error)?;
+        remaining -= read as u64;
+    }
+    file.flush().await.map_err(save_error)
+}
+
+/// HTTPリクエストを読み込む。POST /jobs/uploadのボディはアップロードのディレクトリのファイルに書き込み、
+/// それ以外のボディはメモリに読み込む。不正なリクエストの場合はエラーのレスポンスを返す
+async fn read_request<R: tokio::io::AsyncRead + Unpin>(
+    server: &Server,
+    reader: &mut BufReader<R>,
+) -> Result<Request, Response> {
+    let timeout_error = |_| Response::error(408, "Request timeout.");
+    let (mut request, content_length) = timeout(READ_TIMEOUT, read_head(reader))
+        .await
+        .map_err(timeout_error)??;
+    if request.method == "POST" && request.path == "/jobs/upload" {
+        // 認証できないリクエストのボディはファイルに書き込まない
+        server.authorize(&request)?;
+        if content_length > MAX_UPLOAD_SIZE {
+            return Err(Response::error(413, "The uploaded file is too large."));
+        }
+        if content_length > 0 {
+            let path = server.new_upload_path();
+            if let Err(response) = write_upload(reader, content_length, &path).await {
+                tokio::fs::remove_file(&path).await.ok();
+                return Err(response);
+            }
+            request.upload_file = Option::Some(path);
+        }
+        return Ok(request);
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err(Response::error(413, "The request body is too large."));
+    }
+    let mut body = vec![0; content_length as usize];
+    timeout(READ_TIMEOUT, reader.read_exact(&mut body))
+        .await
+        .map_err(timeout_error)?
+        .map_err(|_| Response::error(400, "Invalid request."))?;
+    request.body = body;
+    Ok(request)
+}
+
+/// listenerで受け付けたHTTPリクエストを処理する。終了しない
+pub fn serve(listener: TcpListener, server: Server) -> Result<(), String> {
+    let server = Arc::new(server);
+    Server::start_worker(&server);
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+    let rt = Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("hayabusa-server")
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+    rt.block_on(async move {
+        let listener = tokio::net::TcpListener::from_std(listener).map_err(|e| e.to_string())?;
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // ファイルディスクリプタの上限などで失敗し続ける場合に、CPUを使い続けないように待つ
+                    AlertMessage::alert(
+                        &mut BufWriter::new(std::io::stderr().lock()),
+                        &format!("Failed to accept a connection. {}", err),
+                    )
+                    .ok();
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                let mut reader = BufReader::new(stream);
+                let response = match read_request(&server, &mut reader).await {
+                    // ファイルの削除などのブロッキングする処理があるため、非同期のタスクの外で処理する
+                    Ok(request) => {
+                        let server = Arc::clone(&server);
+                        tokio::task::spawn_blocking(move || server.handle(&request))
+                            .await
+                            .unwrap_or_else(|e| Response::error(500, &e.to_string()))
+                    }
+                    Err(response) => response,
+                };
+                let body = response.body.to_string();
+                let header = format!(
+                    "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\nDate: {}\r\n\r\n",
+                    response.status,
+                    response.reason(),
+                    body.len(),
+                    Utc::now().to_rfc2822()
+                );
+                let stream = reader.get_mut();
+                stream.write_all(header.as_bytes()).await.ok();
+                stream.write_all(body.as_bytes()).await.ok();
+                stream.shutdown().await.ok();
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{Engine, ScanOptions};
+    use crate::server::{decode_query, serve, Job, JobDetection, JobStatus, Request, Server};
+    use serde_json::{json, Value};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn create_server(upload_dir: &str) -> Server {
+        let options = ScanOptions::new()
+            .rules_path("./test_files/rules/level_yaml")
+            .config_path("./test_files/config")
+            .thread_number(2);
+        Server::new(
+            Engine::new(options, vec![]).unwrap(),
+            PathBuf::from(upload_dir),
+        )
+    }
+
+    fn request(server: &Server, method: &str, target: &str, body: &str) -> (u16, Value) {
+        let response = server.handle(&Request::new(method, target, body.as_bytes().to_vec()));
+        (response.status, response.body)
+    }
+
+    fn create_detection(level: &str, computer: &str, rule_title: &str) -> JobDetection {
+        JobDetection {
+            timestamp: "1996-02-27T01:05:01.000Z".to_string(),
+            computer: computer.to_string(),
+            event_id: "4625".to_string(),
+            level: level.to_string(),
+            mitre_attack: String::default(),
+            rule_title: rule_title.to_string(),
+            rule_id: format!("{}-id", rule_title),
+            details: String::default(),
+            rule_path: "test-rule.yml".to_string(),
+            file_path: "test.evtx".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_request() {
+        let request = Request::new(
+            "get",
+            "/jobs/1/detections/?rule=Test+Rule%21&level=",
+            vec![],
+        );
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/jobs/1/detections");
+        assert_eq!(request.query["rule"], "Test Rule!");
+        assert_eq!(request.query["level"], "");
+        assert_eq!(decode_query("100%"), "100%");
+        assert_eq!(decode_query("%E3%81%82"), "あ");
+    }
+
+    #[test]
+    fn test_list_rules() {
+        let server = create_server("./test_server_uploads_rules");
+        let (status, body) = request(&server, "GET", "/rules", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["total"], server.engine.rules().len());
+        assert!(body["rules"][0]["path"]
+            .as_str()
+            .unwrap()
+            .contains("level_yaml"));
+        assert_eq!(request(&server, "POST", "/rules", "").0, 405);
+        assert_eq!(request(&server, "GET", "/notfound", "").0, 404);
+    }
+
+    #[test]
+    fn test_job_queue() {
+        let server = create_server("./test_server_uploads_queue");
+        let (status, body) = request(&server, "POST", "/jobs", r#"{"path": "./notfound"}"#);
+        assert_eq!(status, 400);
+        assert_eq!(body["error"], "Path not found. ./notfound");
+        assert_eq!(request(&server, "POST", "/jobs", "path").0, 400);
+
+        let path = json!({ "path": "./test_files/evtx" }).to_string();
+        let (status, body) = request(&server, "POST", "/jobs", &path);
+        assert_eq!(status, 202);
+        assert_eq!(body["id"], 1);
+        assert_eq!(body["status"], "queued");
+        assert_eq!(request(&server, "POST", "/jobs", &path).1["id"], 2);
+
+        // キューにあるジョブをキャンセルすると実行されない
+        let (status, body) = request(&server, "DELETE", "/jobs/1", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["status"], "cancelled");
+        let job = server.pop_job(false).unwrap();
+        assert_eq!(job.id, 2);
+        assert!(server.pop_job(false).is_none());
+
+        // ディレクトリ内の.evtxファイルを解析する。解析できないファイルはエラーに追加して処理を続ける
+        server.run_job(&job);
+        let (status, body) = request(&server, "GET", "/jobs/2", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["status"], "completed");
+        assert_eq!(body["files_total"], 3);
+        assert_eq!(body["files_scanned"], 3);
+        assert!(body["finished"].is_string());
+
+        let (_, body) = request(&server, "GET", "/jobs", "");
+        assert_eq!(body["jobs"].as_array().unwrap().len(), 2);
+        // 終了したジョブを削除する
+        assert_eq!(request(&server, "DELETE", "/jobs/2", "").0, 200);
+        assert_eq!(request(&server, "GET", "/jobs/2", "").0, 404);
+        assert_eq!(request(&server, "GET", "/jobs/abc", "").0, 404);
+    }
+
+    #[test]
+    fn test_cancel_running_job() {
+        let server = create_server("./test_server_uploads_cancel");
+        let job = server.push_job(PathBuf::from("./test_files/evtx"), Option::None);
+        server.pop_job(false);
+        job.cancelled
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        server.run_job(&job);
+        assert_eq!(job.status(), JobStatus::Cancelled);
+        assert_eq!(job.progress.lock().unwrap().files_scanned, 0);
+    }
+
+    #[test]
+    fn test_list_detections() {
+        let server = create_server("./test_server_uploads_detections");
+        let job = server.push_job(PathBuf::from("./test_files/evtx"), Option::None);
+        job.detections.lock().unwrap().extend(vec![
+            create_detection("critical", "HOST1", "Mimikatz"),
+            create_detection("high", "host1", "Logon Failure"),
+            create_detection("medium", "host2", "Logon Failure"),
+            create_detection("low", "host2", "Service Installed"),
+        ]);
+
+        let (status, body) = request(&server, "GET", "/jobs/1/detections", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["total"], 4);
+        assert_eq!(body["limit"], 100);
+        assert_eq!(body["detections"][0]["RuleTitle"], "Mimikatz");
+        assert_eq!(body["detections"][0]["RuleID"], "Mimikatz-id");
+
+        let (_, body) = request(&server, "GET", "/jobs/1/detections?level=high", "");
+        assert_eq!(body["total"], 2);
+        let (_, body) = request(&server, "GET", "/jobs/1/detections?rule=logon", "");
+        assert_eq!(body["total"], 2);
+        let (_, body) = request(&server, "GET", "/jobs/1/detections?rule=Mimikatz-id", "");
+        assert_eq!(body["total"], 1);
+        let (_, body) = request(&server, "GET", "/jobs/1/detections?computer=host1", "");
+        assert_eq!(body["total"], 2);
+
+        // ページング
+        let (_, body) = request(&server, "GET", "/jobs/1/detections?offset=1&limit=2", "");
+        assert_eq!(body["total"], 4);
+        assert_eq!(body["detections"].as_array().unwrap().len(), 2);
+        assert_eq!(body["detections"][0]["Level"], "high");
+
+        assert_eq!(
+            request(&server, "GET", "/jobs/1/detections?level=unknown", "").0,
+            400
+        );
+        assert_eq!(
+            request(&server, "GET", "/jobs/1/detections?limit=a", "").0,
+            400
+        );
+    }
+
+    #[test]
+    fn test_serve() {
+        let upload_dir = "./test_server_uploads_serve";
+        let server = create_server(upload_dir);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, server));
+
+        let send = |request: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = send("GET /rules HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+
+        // アップロードしたファイルは解析が終わったら削除する
+        let response =
+            send("POST /jobs/upload?name=test.evtx HTTP/1.1\r\nContent-Length: 4\r\n\r\nElfF");
+        assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n"));
+        let body: Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["source"], "upload:test.evtx");
+        let mut status = String::default();
+        for _ in 0..100 {
+            let response = send("GET /jobs/1 HTTP/1.1\r\n\r\n");
+            let body: Value =
+                serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+            status = body["status"].as_str().unwrap().to_string();
+            if status != "queued" && status != "running" {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert_eq!(status, "completed");
+        assert_eq!(std::fs::read_dir(upload_dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(upload_dir).ok();
+
+        // ボディを読み込む前にサイズを確認する
+        let response = send("POST /jobs HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        let response = send("POST /jobs/upload HTTP/1.1\r\nContent-Length: 2000000000\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(!PathBuf::from(upload_dir).exists());
+    }
+
+    #[test]
+    fn test_authorization() {
+        let server = create_server("./test_server_uploads_authorization").token("secret");
+        let rules = Request::new("GET", "/rules", vec![]);
+        assert_eq!(server.handle(&rules).status, 401);
+        let rules = rules.authorization("Bearer wrong");
+        assert_eq!(server.handle(&rules).status, 401);
+        let rules = rules.authorization("Bearer secret");
+        assert_eq!(server.handle(&rules).status, 200);
+    }
+
+    #[test]
+    fn test_root() {
+        let server =
+            create_server("./test_server_uploads_root").root(PathBuf::from("./test_files/evtx"));
+        let (status, outside) = request(
+            &server,
+            "POST",
+            "/jobs",
+            r#"{"path": "./test_files/evtx/../config"}"#,
+        );
+        assert_eq!(status, 403);
+        // root外のパスが存在するかどうかは返さない
+        let (status, notfound) = request(
+            &server,
+            "POST",
+            "/jobs",
+            r#"{"path": "./test_files/evtx/../notfound"}"#,
+        );
+        assert_eq!(status, 403);
+        assert!(outside["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Path is not allowed."));
+        assert!(notfound["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Path is not allowed."));
+        let (status, _) = request(&server, "POST", "/jobs", r#"{"path": "./test_files/evtx"}"#);
+        assert_eq!(status, 202);
+    }
+
+    #[test]
+    fn test_max_finished_jobs() {
+        // 終了したジョブが上限を超えた場合は古いジョブから削除し、実行前のジョブは削除しない
+        let server = create_server("./test_server_uploads_max_jobs").max_finished_jobs(2);
+        let path = json!({ "path": "./test_files/evtx" }).to_string();
+        for _ in 0..3 {
+            request(&server, "POST", "/jobs", &path);
+        }
+        for id in 1..=3 {
+            assert_eq!(
+                request(&server, "DELETE", &format!("/jobs/{}", id), "").0,
+                200
+            );
+        }
+        request(&server, "POST", "/jobs", &path);
+        assert_eq!(request(&server, "GET", "/jobs/1", "").0, 404);
+        for id in 2..=4 {
+            assert_eq!(request(&server, "GET", &format!("/jobs/{}", id), "").0, 200);
+        }
+    }
+
+    #[test]
+    fn test_job_status() {
+        let job = Job::new(
+            1,
+            PathBuf::from("test.evtx"),
+            Option::Some("a.evtx".to_string()),
+        );
+        assert_eq!(job.status(), JobStatus::Queued);
+        assert_eq!(job.source(), "upload:a.evtx");
+        assert_eq!(JobStatus::Cancelled.as_str(), "cancelled");
+    }
+}
@@ -0,0 +1,257 @@
+use evtx::{EvtxChunkData, ParserSettings};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, Metadata};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+const FILE_HEADER_SIZE: u64 = 4096;
+const CHUNK_SIZE: usize = 65536;
+const CHUNK_SIGNATURE: &[u8] = b"ElfChnk\0";
+// signature(8) + first record number(8) + last record number(8) + first record id(8) + last record id(8)
+const CHUNK_LAST_RECORD_ID_OFFSET: usize = 32;
+
+/// --state-fileで指定したファイルに保存する、evtxファイルごとの解析済みの状態
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScanState {
+    files: BTreeMap<String, FileState>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileState {
+    /// ファイルを識別するための値。ファイルが置き換えられた場合は全てのレコードを解析し直す
+    pub file_id: String,
+    pub size: u64,
+    /// 解析済みのレコードのうち最大のEventRecordID
+    pub last_record_id: u64,
+}
+
+impl ScanState {
+    /// 状態ファイルを読み込む。ファイルが存在しない場合は空の状態を返す
+    pub fn load(path: &Path) -> Result<ScanState, String> {
+        if !path.exists() {
+            return Result::Ok(ScanState::default());
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read state file. {} {}", path.display(), e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse state file. {} {}", path.display(), e))
+    }
+
+    /// 状態ファイルを保存する。書き込み途中で終了しても前回の状態が壊れないように、一時ファイルに書き込んでから置き換える
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let tmp_path = path.with_extension("tmp");
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Failed to save state file. {} {}", path.display(), e))
+    }
+
+    pub fn get(&self, evtx_path: &str) -> Option<&FileState> {
+        self.files.get(evtx_path)
+    }
+
+    pub fn insert(&mut self, evtx_path: String, file_state: FileState) {
+        self.files.insert(evtx_path, file_state);
+    }
+
+    /// 前回の状態から解析済みのEventRecordIDを返す。
+    /// ファイルが置き換えられた場合やサイズが小さくなった場合(ログの消去等)は0を返し、全てのレコードを解析し直す
+    pub fn get_last_record_id(&self, evtx_path: &str, file_id: &str, size: u64) -> u64 {
+        match self.get(evtx_path) {
+            Some(prev) if prev.file_id == file_id && prev.size <= size => prev.last_record_id,
+            _ => 0,
+        }
+    }
+}
+
+/// ファイルを識別する値を作成する。Unix系ではデバイス番号とinode番号、それ以外では作成日時を使う
+pub fn get_file_id(metadata: &Metadata) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        format!("{}:{}", metadata.dev(), metadata.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        metadata
+            .created()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// evtxファイルのチャンクのうち、last_record_idより新しいレコードを含むチャンクのみをパースしてレコードを返すイテレータ。
+///
+/// チャンクヘッダの最後のEventRecordIDを読んで、解析済みのレコードしかないチャンクはパースせずにスキップする。
+/// 新しいレコードを含むチャンクでも、解析済みのレコードは返さない。
+pub struct NewRecords<R: Read + Seek> {
+    reader: R,
+    next_offset: u64,
+    last_record_id: u64,
+    pending: VecDeque<Result<Value, String>>,
+    settings: Arc<ParserSettings>,
+}
+
+impl<R: Read + Seek> NewRecords<R> {
+    pub fn new(reader: R, settings: ParserSettings, last_record_id: u64) -> NewRecords<R> {
+        NewRecords {
+            reader,
+            next_offset: FILE_HEADER_SIZE,
+            last_record_id,
+            pending: VecDeque::new(),
+            settings: Arc::new(settings),
+        }
+    }
+
+    /// 次のチャンクを読み込む。ファイルの終端に達した場合はfalseを返す
+    fn read_chunk(&mut self) -> bool {
+        let offset = self.next_offset;
+        self.next_offset += CHUNK_SIZE as u64;
+        if self.reader.seek(SeekFrom::Start(offset)).is_err() {
+            return false;
+        }
+        let mut chunk_data = vec![0; CHUNK_SIZE];
+        if self.reader.read_exact(&mut chunk_data).is_err() {
+            return false;
+        }
+        if !chunk_data.starts_with(CHUNK_SIGNATURE) {
+            return true;
+        }
+        let chunk_last_record_id = read_u64(&chunk_data[CHUNK_LAST_RECORD_ID_OFFSET..]);
+        if chunk_last_record_id <= self.last_record_id {
+            return true;
+        }
+
+        let mut chunk_data = match EvtxChunkData::new(chunk_data, false) {
+            Ok(chunk_data) => chunk_data,
+            Err(e) => {
+                self.pending
+                    .push_back(Err(format!("offset:0x{:x} {}", offset, e)));
+                return true;
+            }
+        };
+        let mut chunk = match chunk_data.parse(Arc::clone(&self.settings)) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                self.pending
+                    .push_back(Err(format!("offset:0x{:x} {}", offset, e)));
+                return true;
+            }
+        };
+        for record in chunk.iter() {
+            let record = match record {
+                Ok(record) => record.into_json_value(),
+                Err(e) => Err(e),
+            };
+            match record {
+                Ok(record) if record.event_record_id > self.last_record_id => {
+                    self.pending.push_back(Ok(record.data));
+                }
+                Ok(_) => {}
+                Err(e) => self.pending.push_back(Err(e.to_string())),
+            }
+        }
+        true
+    }
+}
+
+impl<R: Read + Seek> Iterator for NewRecords<R> {
+    type Item = Result<Value, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            if !self.read_chunk() {
+                return Option::None;
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::{FileState, NewRecords, ScanState, CHUNK_SIZE, FILE_HEADER_SIZE};
+    use evtx::ParserSettings;
+    use std::fs::remove_file;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    #[test]
+    fn test_save_and_load() {
+        let path = Path::new("./test_state_file.json");
+        let mut state = ScanState::default();
+        let file_state = FileState {
+            file_id: "1:2".to_string(),
+            size: 69632,
+            last_record_id: 100,
+        };
+        state.insert("test.evtx".to_string(), file_state.clone());
+        assert!(state.save(path).is_ok());
+
+        let loaded = ScanState::load(path).unwrap();
+        assert_eq!(loaded.get("test.evtx"), Some(&file_state));
+        assert!(remove_file(path).is_ok());
+        assert!(ScanState::load(path).unwrap().get("test.evtx").is_none());
+    }
+
+    #[test]
+    fn test_get_last_record_id() {
+        let mut state = ScanState::default();
+        state.insert(
+            "test.evtx".to_string(),
+            FileState {
+                file_id: "1:2".to_string(),
+                size: 69632,
+                last_record_id: 100,
+            },
+        );
+        assert_eq!(state.get_last_record_id("test.evtx", "1:2", 69632), 100);
+        assert_eq!(state.get_last_record_id("test.evtx", "1:2", 135168), 100);
+        // ファイルが置き換えられた場合、サイズが小さくなった場合は最初から解析する
+        assert_eq!(state.get_last_record_id("test.evtx", "1:3", 69632), 0);
+        assert_eq!(state.get_last_record_id("test.evtx", "1:2", 4096), 0);
+        assert_eq!(state.get_last_record_id("new.evtx", "1:2", 69632), 0);
+    }
+
+    #[test]
+    fn test_skip_processed_chunk() {
+        // 最後のEventRecordIDが10のチャンク。解析済みの場合はパースしない
+        let mut data = vec![0; FILE_HEADER_SIZE as usize + CHUNK_SIZE];
+        let chunk = &mut data[FILE_HEADER_SIZE as usize..];
+        chunk[..8].copy_from_slice(b"ElfChnk\0");
+        chunk[32..40].copy_from_slice(&10u64.to_le_bytes());
+
+        let mut records = NewRecords::new(Cursor::new(data), ParserSettings::default(), 10);
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn test_load_old_state_file() {
+        // 以前のバージョンで保存したchunk_offsetsを含む状態ファイルも読み込める
+        let path = Path::new("./test_old_state_file.json");
+        std::fs::write(
+            path,
+            r#"{"files":{"test.evtx":{"file_id":"1:2","size":69632,"last_record_id":100,"chunk_offsets":[4096]}}}"#,
+        )
+        .unwrap();
+        let loaded = ScanState::load(path);
+        assert!(remove_file(path).is_ok());
+        assert_eq!(
+            loaded
+                .unwrap()
+                .get_last_record_id("test.evtx", "1:2", 69632),
+            100
+        );
+    }
+}
@@ -0,0 +1,523 @@
+use crate::detections::configs;
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::print::AlertMessage;
+use crate::detections::rule::{get_selection_values, RuleNode};
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::detections::utils;
+use hashbrown::{HashMap, HashSet};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
+
+/// ルールが参照するログ。ChannelとEventIDのどちらかが指定されていない場合は全てを対象とする
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LogSource {
+    pub channel: Option<String>,
+    pub event_id: Option<String>,
+}
+
+impl std::fmt::Display for LogSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let channel = self.channel.as_deref().unwrap_or("*");
+        match &self.event_id {
+            Some(event_id) => write!(f, "{} {}", channel, event_id),
+            None => write!(f, "{}", channel),
+        }
+    }
+}
+
+/// スキャンしたevtxファイルに含まれるChannelごとのEventIDとレコード数
+#[derive(Debug, Default)]
+pub struct LogSources {
+    pub channels: HashMap<String, HashMap<String, usize>>,
+}
+
+impl LogSources {
+    pub fn new() -> LogSources {
+        LogSources::default()
+    }
+
+    pub fn start(&mut self, records: &[EvtxRecordInfo]) {
+        for record in records {
+            self.add(&record.record);
+        }
+    }
+
+    pub fn add(&mut self, record: &Value) {
+        let system = &record["Event"]["System"];
+        let channel = match &system["Channel"] {
+            Value::String(channel) => channel.to_owned(),
+            _ => return,
+        };
+        let event_id = match &system["EventID"] {
+            Value::String(event_id) => event_id.to_owned(),
+            Value::Number(event_id) => event_id.to_string(),
+            _ => return,
+        };
+        *self
+            .channels
+            .entry(channel)
+            .or_default()
+            .entry(event_id)
+            .or_insert(0) += 1;
+    }
+
+    /// ログがスキャンしたevtxファイルに含まれるかを返す。Channelは大文字小文字を区別しない
+    pub fn contains(&self, source: &LogSource) -> bool {
+        self.channels.iter().any(|(channel, event_ids)| {
+            let channel_match = match &source.channel {
+                Some(source_channel) => source_channel.eq_ignore_ascii_case(channel),
+                None => true,
+            };
+            channel_match
+                && match &source.event_id {
+                    Some(event_id) => event_ids.contains_key(event_id),
+                    None => true,
+                }
+        })
+    }
+}
+
+/// 全てのevtxファイルのレコードを集計するsink。スキャン後にoutput_coverageで出力する
+impl DetectionSink for Mutex<LogSources> {
+    fn on_detect(&self, _event: &DetectionEvent) -> Result<(), String> {
+        Result::Ok(())
+    }
+
+    fn on_records(&self, records: &[EvtxRecordInfo]) -> Result<(), String> {
+        self.lock().unwrap().start(records);
+        Result::Ok(())
+    }
+}
+
+/// ルールが参照するChannelとEventIDの組み合わせを取得する。
+/// conditionでnotを付けずに参照しているselectionの連想配列ごとに、同じ連想配列のChannelとEventIDを組み合わせる
+pub fn get_rule_log_sources(rule: &RuleNode) -> Vec<LogSource> {
+    let mut ret = vec![];
+    for values in get_selection_values(rule, &["Channel", "EventID"]) {
+        let get_values = |key: &str| -> Vec<Option<String>> {
+            match values.get(key) {
+                Some(values) => values.iter().cloned().map(Option::Some).collect(),
+                None => vec![Option::None],
+            }
+        };
+        let channels = get_values("Channel");
+        let event_ids = get_values("EventID");
+        for channel in &channels {
+            for event_id in &event_ids {
+                ret.push(LogSource {
+                    channel: channel.clone(),
+                    event_id: event_id.clone(),
+                });
+            }
+        }
+    }
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoverageStatus {
+    /// 参照するログのいずれかがスキャンしたevtxファイルに含まれる
+    Covered,
+    /// 参照するログがスキャンしたevtxファイルに1つも含まれないため、検知できない
+    Blind,
+    /// ChannelとEventIDが指定されていないため判定できない
+    Unknown,
+}
+
+impl CoverageStatus {
+    pub fn name(&self) -> &str {
+        match self {
+            CoverageStatus::Covered => "Covered",
+            CoverageStatus::Blind => "Blind",
+            CoverageStatus::Unknown => "Unknown",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RuleCoverage {
+    pub title: String,
+    pub id: String,
+    pub level: String,
+    pub rulepath: String,
+    pub status: CoverageStatus,
+    pub log_sources: Vec<LogSource>,
+}
+
+/// 有効にする必要があるログと、そのルールの数
+#[derive(Debug, PartialEq)]
+pub struct MissingLogSource {
+    pub source: LogSource,
+    pub setting: String,
+    pub rule_count: usize,
+}
+
+#[derive(Debug)]
+pub struct CoverageReport {
+    pub rules: Vec<RuleCoverage>,
+    pub missing: Vec<MissingLogSource>,
+}
+
+/// ログを記録するために必要な監査ポリシーやログの設定。(Channel, EventID)をキーとし、EventIDが空の場合はChannel全体の設定とする
+#[derive(Debug, Default)]
+pub struct LogSourceSettings {
+    settings: HashMap<(String, String), String>,
+}
+
+impl LogSourceSettings {
+    pub fn new() -> LogSourceSettings {
+        LogSourceSettings::default()
+    }
+
+    pub fn load(path: &str) -> LogSourceSettings {
+        let mut ret = LogSourceSettings::new();
+        let lines = match utils::read_csv(path) {
+            Ok(lines) => lines,
+            Err(err) => {
+                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+                return ret;
+            }
+        };
+        for line in lines {
+            if line.len() != 3 {
+                continue;
+            }
+            ret.insert(&line[0], &line[1], &line[2]);
+        }
+        ret
+    }
+
+    pub fn insert(&mut self, channel: &str, event_id: &str, setting: &str) {
+        self.settings.insert(
+            (channel.to_lowercase(), event_id.to_string()),
+            setting.to_string(),
+        );
+    }
+
+    /// EventIDごとの設定が無い場合はChannel全体の設定を返す
+    pub fn get(&self, source: &LogSource) -> Option<&String> {
+        let channel = source.channel.as_deref().unwrap_or("").to_lowercase();
+        let event_id = source.event_id.as_deref().unwrap_or("");
+        self.settings
+            .get(&(channel.to_owned(), event_id.to_string()))
+            .or_else(|| self.settings.get(&(channel, String::default())))
+    }
+}
+
+/// 読み込んだルールが参照するログと、スキャンしたevtxファイルに含まれるログを比較する
+pub fn create_report(
+    rules: &[RuleNode],
+    log_sources: &LogSources,
+    settings: &LogSourceSettings,
+) -> CoverageReport {
+    let mut missing_rules: BTreeMap<LogSource, HashSet<usize>> = BTreeMap::new();
+    let mut rule_coverages = vec![];
+    for (i, rule) in rules.iter().enumerate() {
+        let sources = get_rule_log_sources(rule);
+        let status = if sources.is_empty() {
+            CoverageStatus::Unknown
+        } else if sources.iter().any(|source| log_sources.contains(source)) {
+            CoverageStatus::Covered
+        } else {
+            CoverageStatus::Blind
+        };
+        if status == CoverageStatus::Blind {
+            for source in &sources {
+                missing_rules.entry(source.clone()).or_default().insert(i);
+            }
+        }
+        let yaml_str = |key: &str| rule.yaml[key].as_str().unwrap_or("-").to_string();
+        rule_coverages.push(RuleCoverage {
+            title: yaml_str("title"),
+            id: yaml_str("id"),
+            level: yaml_str("level"),
+            rulepath: rule.rulepath.to_owned(),
+            status,
+            log_sources: sources,
+        });
+    }
+
+    let mut missing: Vec<MissingLogSource> = missing_rules
+        .into_iter()
+        .map(|(source, rules)| MissingLogSource {
+            setting: settings
+                .get(&source)
+                .cloned()
+                .unwrap_or_else(|| "-".to_string()),
+            source,
+            rule_count: rules.len(),
+        })
+        .collect();
+    missing.sort_by_key(|missing| std::cmp::Reverse(missing.rule_count));
+    CoverageReport {
+        rules: rule_coverages,
+        missing,
+    }
+}
+
+impl CoverageReport {
+    fn count(&self, status: CoverageStatus) -> usize {
+        self.rules
+            .iter()
+            .filter(|rule| rule.status == status)
+            .count()
+    }
+
+    pub fn print<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "Log Source Coverage:")?;
+        writeln!(w, "Total rules: {}", self.rules.len())?;
+        writeln!(w, "Covered rules: {}", self.count(CoverageStatus::Covered))?;
+        writeln!(
+            w,
+            "Blind rules (the referenced logs are not in the scanned files): {}",
+            self.count(CoverageStatus::Blind)
+        )?;
+        writeln!(
+            w,
+            "Rules without Channel or EventID: {}",
+            self.count(CoverageStatus::Unknown)
+        )?;
+
+        writeln!(w)?;
+        writeln!(w, "Missing Log Sources:")?;
+        if self.missing.is_empty() {
+            writeln!(w, "No missing log sources were found.")?;
+        }
+        for missing in &self.missing {
+            writeln!(
+                w,
+                "{} ({} rules): {}",
+                missing.source, missing.rule_count, missing.setting
+            )?;
+        }
+
+        writeln!(w)?;
+        writeln!(w, "Blind Rules:")?;
+        for rule in &self.rules {
+            if rule.status != CoverageStatus::Blind {
+                continue;
+            }
+            let sources: Vec<String> = rule.log_sources.iter().map(|s| s.to_string()).collect();
+            writeln!(
+                w,
+                "[{}] {} ({}) {}",
+                rule.level,
+                rule.title,
+                sources.join(", "),
+                rule.rulepath
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_csv<W: Write>(&self, w: W) -> Result<(), String> {
+        let mut wtr = csv::Writer::from_writer(w);
+        wtr.write_record([
+            "RuleTitle",
+            "RuleID",
+            "Level",
+            "RulePath",
+            "Status",
+            "LogSources",
+        ])
+        .map_err(|e| e.to_string())?;
+        for rule in &self.rules {
+            let sources: Vec<String> = rule.log_sources.iter().map(|s| s.to_string()).collect();
+            wtr.write_record([
+                rule.title.as_str(),
+                rule.id.as_str(),
+                rule.level.as_str(),
+                rule.rulepath.as_str(),
+                rule.status.name(),
+                sources.join(" ¦ ").as_str(),
+            ])
+            .map_err(|e| e.to_string())?;
+        }
+        wtr.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// 読み込んだルールのカバレッジを標準出力に出力する。--outputが指定された場合はルールごとの結果をCSVファイルにも出力する
+pub fn output_coverage(rules: &[RuleNode], log_sources: &LogSources) {
+    let settings = LogSourceSettings::load("config/log_source_settings.txt");
+    let report = create_report(rules, log_sources, &settings);
+    println!();
+    report.print(&mut io::stdout().lock()).ok();
+    if let Some(csv_path) = configs::CONFIG.read().unwrap().args.value_of("output") {
+        let ret = File::create(csv_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| report.write_csv(BufWriter::new(file)));
+        if let Err(err) = ret {
+            AlertMessage::alert(
+                &mut BufWriter::new(std::io::stderr().lock()),
+                &format!("Failed to write the coverage report. {} {}", csv_path, err),
+            )
+            .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::rule::{create_rule, RuleNode};
+    use crate::detections::sink::DetectionSink;
+    use crate::detections::utils;
+    use crate::timeline::coverage::{
+        create_report, get_rule_log_sources, CoverageStatus, LogSource, LogSourceSettings,
+        LogSources,
+    };
+    use serde_json::json;
+    use std::sync::Mutex;
+    use yaml_rust::YamlLoader;
+
+    fn parse_rule(rule_str: &str) -> RuleNode {
+        let rule_yaml = YamlLoader::load_from_str(rule_str).unwrap().remove(0);
+        let mut rule = create_rule("testpath".to_string(), rule_yaml);
+        assert!(rule.init().is_ok());
+        rule
+    }
+
+    fn source(channel: Option<&str>, event_id: Option<&str>) -> LogSource {
+        LogSource {
+            channel: channel.map(|s| s.to_string()),
+            event_id: event_id.map(|s| s.to_string()),
+        }
+    }
+
+    fn create_log_sources() -> LogSources {
+        let mut log_sources = LogSources::new();
+        for (channel, event_id) in [("Security", 4624), ("Security", 4625), ("System", 7045)] {
+            log_sources.add(&json!({
+                "Event": {"System": {"Channel": channel, "EventID": event_id}}
+            }));
+        }
+        log_sources
+    }
+
+    #[test]
+    fn test_get_rule_log_sources() {
+        let rule = parse_rule(
+            r#"
+        title: test
+        detection:
+            selection:
+                Channel: Security
+                EventID:
+                    - 4624
+                    - 4625
+            sysmon:
+                Channel: Microsoft-Windows-Sysmon/Operational
+            filter:
+                TargetUserName: 'ANONYMOUS LOGON'
+            condition: (selection or sysmon) and not filter
+        "#,
+        );
+        assert_eq!(
+            get_rule_log_sources(&rule),
+            vec![
+                source(Some("Microsoft-Windows-Sysmon/Operational"), None),
+                source(Some("Security"), Some("4624")),
+                source(Some("Security"), Some("4625")),
+            ]
+        );
+
+        // リストの連想配列ごとにChannelとEventIDを組み合わせ、notで参照するselectionは含めない
+        let rule = parse_rule(
+            r#"
+        title: test
+        detection:
+            selection:
+                - Channel: Security
+                  EventID: 4624
+                - Channel: System
+                  EventID: 7045
+            filter:
+                Channel: Application
+            condition: selection and not filter
+        "#,
+        );
+        assert_eq!(
+            get_rule_log_sources(&rule),
+            vec![
+                source(Some("Security"), Some("4624")),
+                source(Some("System"), Some("7045")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_log_sources() {
+        let log_sources = create_log_sources();
+        assert!(log_sources.contains(&source(Some("security"), Some("4624"))));
+        assert!(log_sources.contains(&source(Some("System"), None)));
+        assert!(log_sources.contains(&source(None, Some("7045"))));
+        assert!(!log_sources.contains(&source(Some("Security"), Some("4688"))));
+        assert!(!log_sources.contains(&source(Some("Microsoft-Windows-Sysmon/Operational"), None)));
+    }
+
+    #[test]
+    fn test_sink() {
+        // 複数のevtxファイルのレコードを同じsinkで集計する
+        let sink = Mutex::new(LogSources::new());
+        for path in ["file1.evtx", "file2.evtx"] {
+            let record = json!({
+                "Event": {"System": {"Channel": "Security", "EventID": 4624}}
+            });
+            let records = vec![utils::create_rec_info(record, path.to_string(), &[])];
+            assert!(sink.on_records(&records).is_ok());
+        }
+        let log_sources = sink.into_inner().unwrap();
+        assert_eq!(log_sources.channels["Security"]["4624"], 2);
+    }
+
+    #[test]
+    fn test_create_report() {
+        let rules = vec![
+            parse_rule("title: logon\ndetection:\n    selection:\n        Channel: Security\n        EventID: 4624\n"),
+            parse_rule("title: sysmon\nlevel: high\ndetection:\n    selection:\n        Channel: Microsoft-Windows-Sysmon/Operational\n        EventID: 1\n"),
+            parse_rule("title: process\ndetection:\n    selection:\n        Channel: Security\n        EventID: 4688\n"),
+            parse_rule("title: keyword\ndetection:\n    selection:\n        - mimikatz\n"),
+        ];
+        let mut settings = LogSourceSettings::new();
+        settings.insert("Security", "4688", "Audit Process Creation");
+        settings.insert("Microsoft-Windows-Sysmon/Operational", "", "Install Sysmon");
+
+        let report = create_report(&rules, &create_log_sources(), &settings);
+        let statuses: Vec<CoverageStatus> = report.rules.iter().map(|rule| rule.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                CoverageStatus::Covered,
+                CoverageStatus::Blind,
+                CoverageStatus::Blind,
+                CoverageStatus::Unknown
+            ]
+        );
+        assert_eq!(report.missing.len(), 2);
+        assert_eq!(report.missing[0].setting, "Install Sysmon");
+        assert_eq!(report.missing[1].setting, "Audit Process Creation");
+
+        let mut out = vec![];
+        assert!(report.print(&mut out).is_ok());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Blind rules (the referenced logs are not in the scanned files): 2\n"));
+        assert!(out.contains("Security 4688 (1 rules): Audit Process Creation\n"));
+        assert!(out.contains("[high] sysmon (Microsoft-Windows-Sysmon/Operational 1) testpath\n"));
+
+        let mut csv = vec![];
+        assert!(report.write_csv(&mut csv).is_ok());
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "RuleTitle,RuleID,Level,RulePath,Status,LogSources"
+        );
+        assert_eq!(lines[1], "logon,-,-,testpath,Covered,Security 4624");
+        assert_eq!(lines[4], "keyword,-,-,testpath,Unknown,");
+    }
+}
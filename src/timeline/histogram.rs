@@ -0,0 +1,592 @@
+use crate::afterfact::format_time;
+use crate::detections::configs;
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::print::{AlertMessage, Message};
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::detections::utils;
+use chrono::{DateTime, TimeZone, Utc};
+use hashbrown::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
+
+/// ターミナルに出力するスパークラインの最大の幅
+const SPARKLINE_WIDTH: usize = 60;
+const SPARKLINE_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// ログの消去を示すイベント。(Channel, EventID)
+const LOG_CLEARED_EVENTS: [(&str, &str); 2] = [("Security", "1102"), ("System", "104")];
+
+const HOUR: i64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interval {
+    Hour,
+    Day,
+}
+
+impl Interval {
+    pub fn parse(interval: &str) -> Result<Interval, String> {
+        match interval.to_lowercase().as_str() {
+            "hour" => Ok(Interval::Hour),
+            "day" => Ok(Interval::Day),
+            _ => Err(format!(
+                "Invalid histogram interval: {}. Please specify hour or day.",
+                interval
+            )),
+        }
+    }
+
+    pub fn seconds(&self) -> i64 {
+        match self {
+            Interval::Hour => HOUR,
+            Interval::Day => 24 * HOUR,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Interval::Hour => "hour",
+            Interval::Day => "day",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramConfig {
+    pub interval: Interval,
+    /// この時間(秒)以上イベントが無い期間をギャップとして出力する
+    pub gap_threshold: i64,
+}
+
+impl HistogramConfig {
+    pub fn new(interval: Interval) -> HistogramConfig {
+        HistogramConfig {
+            interval,
+            gap_threshold: 24 * HOUR,
+        }
+    }
+
+    /// ギャップとして出力するイベントが無い期間の時間数 (デフォルト: 24)
+    pub fn gap_threshold_hours(mut self, hours: i64) -> Self {
+        self.gap_threshold = hours * HOUR;
+        self
+    }
+
+    /// --histogramと--gap-thresholdの設定を読み込む
+    pub fn from_args() -> Result<HistogramConfig, String> {
+        let conf = configs::CONFIG.read().unwrap();
+        let interval = Interval::parse(conf.args.value_of("histogram").unwrap_or("hour"))?;
+        let config = HistogramConfig::new(interval);
+        match conf.args.value_of("gap-threshold") {
+            Some(hours) => match hours.parse::<i64>() {
+                Ok(hours) if hours > 0 => Ok(config.gap_threshold_hours(hours)),
+                _ => Err(format!(
+                    "Invalid gap threshold: {}. Please specify a number of hours.",
+                    hours
+                )),
+            },
+            None => Ok(config),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HistogramKey {
+    pub computer: String,
+    pub channel: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogCleared {
+    pub time: DateTime<Utc>,
+    pub computer: String,
+    pub channel: String,
+    pub event_id: String,
+}
+
+/// コンピュータとチャンネルごとの1時間ごとのイベント数。出力する時に指定された間隔でまとめ直す
+#[derive(Debug, Default)]
+pub struct Histogram {
+    pub counts: HashMap<HistogramKey, BTreeMap<i64, usize>>,
+    pub log_cleared: Vec<LogCleared>,
+}
+
+/// 1つのコンピュータとチャンネルの、最初のイベントから最後のイベントまでの間隔ごとのイベント数。
+/// 長い期間でもメモリを使わないように、イベントが無い間隔は保持しない
+#[derive(Debug, PartialEq)]
+pub struct Series {
+    pub key: HistogramKey,
+    /// 最初と最後のイベントがある間隔の開始時刻
+    pub start: i64,
+    pub end: i64,
+    pub buckets: BTreeMap<i64, usize>,
+}
+
+impl Series {
+    pub fn total(&self) -> usize {
+        self.buckets.values().sum()
+    }
+
+    /// スパークラインに出力する間隔ごとのイベント数。SPARKLINE_WIDTHより長い場合は複数の間隔をまとめる
+    fn sparkline_counts(&self, interval: i64) -> Vec<usize> {
+        let len = ((self.end - self.start) / interval) as usize + 1;
+        let group_size = (len - 1) / SPARKLINE_WIDTH + 1;
+        let mut counts = vec![0; (len - 1) / group_size + 1];
+        for (bucket, count) in &self.buckets {
+            counts[((bucket - self.start) / interval) as usize / group_size] += count;
+        }
+        counts
+    }
+}
+
+/// 閾値以上イベントが無い期間
+#[derive(Debug, PartialEq)]
+pub struct Gap {
+    pub key: HistogramKey,
+    pub from: i64,
+    pub to: i64,
+}
+
+#[derive(Debug)]
+pub struct HistogramReport {
+    pub interval: Interval,
+    pub series: Vec<Series>,
+    pub gaps: Vec<Gap>,
+    pub log_cleared: Vec<LogCleared>,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram::default()
+    }
+
+    pub fn start(&mut self, records: &[EvtxRecordInfo]) {
+        for record in records {
+            self.add(&record.record);
+        }
+    }
+
+    pub fn add(&mut self, record: &Value) {
+        let time = match Message::get_event_time(record) {
+            Some(time) => time,
+            None => return,
+        };
+        let system = &record["Event"]["System"];
+        let key = HistogramKey {
+            computer: utils::value_to_output_string(&system["Computer"]),
+            channel: utils::value_to_output_string(&system["Channel"]),
+        };
+        let event_id = utils::value_to_output_string(&system["EventID"]);
+        if LOG_CLEARED_EVENTS
+            .iter()
+            .any(|(channel, id)| *channel == key.channel && *id == event_id)
+        {
+            self.log_cleared.push(LogCleared {
+                time,
+                computer: key.computer.to_owned(),
+                channel: key.channel.to_owned(),
+                event_id,
+            });
+        }
+        let timestamp = time.timestamp();
+        *self
+            .counts
+            .entry(key)
+            .or_default()
+            .entry(timestamp - timestamp.rem_euclid(HOUR))
+            .or_insert(0) += 1;
+    }
+
+    /// 指定された間隔でまとめ直し、ギャップを検出する
+    pub fn create_report(&self, config: &HistogramConfig) -> HistogramReport {
+        let interval = config.interval.seconds();
+        let mut keys: Vec<&HistogramKey> = self.counts.keys().collect();
+        keys.sort();
+
+        let mut series = vec![];
+        let mut gaps = vec![];
+        for key in keys {
+            let mut buckets: BTreeMap<i64, usize> = BTreeMap::new();
+            for (hour, count) in &self.counts[key] {
+                *buckets.entry(hour - hour.rem_euclid(interval)).or_insert(0) += count;
+            }
+            let (start, end) = match (buckets.keys().next(), buckets.keys().next_back()) {
+                (Some(start), Some(end)) => (*start, *end),
+                _ => continue,
+            };
+            let mut prev: Option<i64> = Option::None;
+            for bucket in buckets.keys() {
+                if let Some(prev) = prev {
+                    let from = prev + interval;
+                    if *bucket - from >= config.gap_threshold {
+                        gaps.push(Gap {
+                            key: key.clone(),
+                            from,
+                            to: *bucket,
+                        });
+                    }
+                }
+                prev = Option::Some(*bucket);
+            }
+            series.push(Series {
+                key: key.clone(),
+                start,
+                end,
+                buckets,
+            });
+        }
+
+        let mut log_cleared = self.log_cleared.clone();
+        log_cleared.sort_by_key(|cleared| cleared.time);
+        HistogramReport {
+            interval: config.interval,
+            series,
+            gaps,
+            log_cleared,
+        }
+    }
+}
+
+impl HistogramReport {
+    pub fn print<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let interval = self.interval.seconds();
+        writeln!(w, "Event Timeline (per {}):", self.interval.name())?;
+        let name_width = self
+            .series
+            .iter()
+            .map(|series| series_name(&series.key).chars().count())
+            .max()
+            .unwrap_or(0);
+        for series in &self.series {
+            writeln!(
+                w,
+                "{:<3$} |{}| {} events",
+                series_name(&series.key),
+                sparkline(&series.sparkline_counts(interval)),
+                series.total(),
+                name_width,
+            )?;
+            writeln!(
+                w,
+                "{:<1$}  {2} - {3}",
+                "",
+                name_width,
+                format_bucket(series.start),
+                format_bucket(series.end)
+            )?;
+        }
+
+        writeln!(w)?;
+        writeln!(w, "Gaps:")?;
+        if self.gaps.is_empty() {
+            writeln!(w, "No gaps were found.")?;
+        }
+        for gap in &self.gaps {
+            writeln!(
+                w,
+                "{}: no events from {} to {} ({} hours)",
+                series_name(&gap.key),
+                format_bucket(gap.from),
+                format_bucket(gap.to),
+                (gap.to - gap.from) / HOUR
+            )?;
+        }
+
+        writeln!(w)?;
+        writeln!(w, "Log Cleared Events:")?;
+        if self.log_cleared.is_empty() {
+            writeln!(w, "No log cleared events were found.")?;
+        }
+        for cleared in &self.log_cleared {
+            writeln!(
+                w,
+                "{} {} {} EventID: {}",
+                format_time(&cleared.time),
+                cleared.computer,
+                cleared.channel,
+                cleared.event_id
+            )?;
+        }
+        Ok(())
+    }
+
+    /// 間隔ごとのイベント数をCSVで出力する。イベントがある間隔と、ギャップが始まる間隔のみ出力し、
+    /// GapHoursはギャップが始まる行にイベントが無い時間数を出力する
+    pub fn write_csv<W: Write>(&self, w: W) -> Result<(), String> {
+        let interval = self.interval.seconds();
+        let mut wtr = csv::Writer::from_writer(w);
+        wtr.write_record([
+            "Computer",
+            "Channel",
+            "Timestamp",
+            "Count",
+            "LogCleared",
+            "GapHours",
+        ])
+        .map_err(|e| e.to_string())?;
+        // ログの消去とギャップを、系列と間隔ごとに引けるようにする
+        let mut log_cleared: HashMap<(&str, &str, i64), usize> = HashMap::new();
+        for cleared in &self.log_cleared {
+            let timestamp = cleared.time.timestamp();
+            let bucket = timestamp - timestamp.rem_euclid(interval);
+            *log_cleared
+                .entry((&cleared.computer, &cleared.channel, bucket))
+                .or_insert(0) += 1;
+        }
+        let mut gaps: HashMap<&HistogramKey, BTreeMap<i64, i64>> = HashMap::new();
+        for gap in &self.gaps {
+            gaps.entry(&gap.key)
+                .or_default()
+                .insert(gap.from, (gap.to - gap.from) / HOUR);
+        }
+
+        for series in &self.series {
+            let series_gaps = gaps.get(&series.key);
+            let mut buckets: BTreeSet<i64> = series.buckets.keys().copied().collect();
+            buckets.extend(series_gaps.into_iter().flat_map(|gaps| gaps.keys()));
+            for bucket in buckets {
+                let log_cleared = log_cleared
+                    .get(&(
+                        series.key.computer.as_str(),
+                        series.key.channel.as_str(),
+                        bucket,
+                    ))
+                    .unwrap_or(&0);
+                let gap_hours = series_gaps
+                    .and_then(|gaps| gaps.get(&bucket))
+                    .map(|hours| hours.to_string())
+                    .unwrap_or_default();
+                wtr.write_record([
+                    series.key.computer.to_owned(),
+                    series.key.channel.to_owned(),
+                    format_bucket(bucket),
+                    series.buckets.get(&bucket).unwrap_or(&0).to_string(),
+                    log_cleared.to_string(),
+                    gap_hours,
+                ])
+                .map_err(|e| e.to_string())?;
+            }
+        }
+        wtr.flush().map_err(|e| e.to_string())
+    }
+}
+
+fn series_name(key: &HistogramKey) -> String {
+    format!("{} / {}", key.computer, key.channel)
+}
+
+fn format_bucket(timestamp: i64) -> String {
+    format_time(&Utc.timestamp(timestamp, 0))
+}
+
+/// イベント数をスパークラインにする。イベントが無い期間は空白にする。
+/// SPARKLINE_WIDTHより長い場合は複数の間隔をまとめる
+pub fn sparkline(counts: &[usize]) -> String {
+    let group_size = counts.len().saturating_sub(1) / SPARKLINE_WIDTH + 1;
+    let counts: Vec<usize> = counts
+        .chunks(group_size)
+        .map(|chunk| chunk.iter().sum())
+        .collect();
+    let max = counts.iter().max().copied().unwrap_or(0);
+    counts
+        .iter()
+        .map(|count| {
+            if *count == 0 {
+                ' '
+            } else {
+                let len = SPARKLINE_CHARS.len();
+                SPARKLINE_CHARS[((count * len - 1) / max).min(len - 1)]
+            }
+        })
+        .collect()
+}
+
+/// 全てのevtxファイルのレコードを集計するsink。スキャン後にoutput_histogramで出力する
+impl DetectionSink for Mutex<Histogram> {
+    fn on_detect(&self, _event: &DetectionEvent) -> Result<(), String> {
+        Result::Ok(())
+    }
+
+    fn on_records(&self, records: &[EvtxRecordInfo]) -> Result<(), String> {
+        self.lock().unwrap().start(records);
+        Result::Ok(())
+    }
+}
+
+/// 全てのevtxファイルのヒストグラムを標準出力に出力する。--outputが指定された場合はCSVファイルにも出力する
+pub fn output_histogram(histogram: &Histogram) {
+    let config = match HistogramConfig::from_args() {
+        Ok(config) => config,
+        Err(err) => {
+            AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &err).ok();
+            return;
+        }
+    };
+    let report = histogram.create_report(&config);
+    println!();
+    report.print(&mut io::stdout().lock()).ok();
+    if let Some(csv_path) = configs::CONFIG.read().unwrap().args.value_of("output") {
+        let ret = File::create(csv_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| report.write_csv(BufWriter::new(file)));
+        if let Err(err) = ret {
+            AlertMessage::alert(
+                &mut BufWriter::new(std::io::stderr().lock()),
+                &format!("Failed to write the histogram. {} {}", csv_path, err),
+            )
+            .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::detection::EvtxRecordInfo;
+    use crate::detections::sink::DetectionSink;
+    use crate::detections::utils;
+    use crate::timeline::histogram::{sparkline, Histogram, HistogramConfig, Interval};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+
+    fn create_record(computer: &str, channel: &str, event_id: u64, time: &str) -> Value {
+        json!({
+            "Event": {
+                "System": {
+                    "EventID": event_id,
+                    "Channel": channel,
+                    "Computer": computer,
+                    "TimeCreated_attributes": {"SystemTime": time}
+                }
+            }
+        })
+    }
+
+    fn create_histogram() -> Histogram {
+        // 複数のevtxファイルのレコードを同じsinkで集計する
+        let sink = Mutex::new(Histogram::new());
+        let file1 = vec![
+            create_record("DC1", "Security", 4624, "2022-01-01T00:10:00Z"),
+            create_record("DC1", "Security", 4624, "2022-01-01T00:50:00Z"),
+            create_record("DC1", "Security", 4624, "2022-01-01T02:00:00Z"),
+            create_record("DC1", "Security", 1102, "2022-01-03T05:00:00Z"),
+        ];
+        let file2 = vec![
+            create_record("PC1", "System", 104, "2022-01-02T00:00:00Z"),
+            create_record("PC1", "Security", 104, "2022-01-02T00:00:00Z"),
+        ];
+        for (path, records) in [("file1.evtx", file1), ("file2.evtx", file2)] {
+            let records: Vec<EvtxRecordInfo> = records
+                .into_iter()
+                .map(|record| utils::create_rec_info(record, path.to_string(), &[]))
+                .collect();
+            assert!(sink.on_records(&records).is_ok());
+        }
+        sink.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_interval() {
+        assert_eq!(Interval::parse("hour"), Ok(Interval::Hour));
+        assert_eq!(Interval::parse("Day"), Ok(Interval::Day));
+        assert!(Interval::parse("week").is_err());
+    }
+
+    #[test]
+    fn test_create_report() {
+        let histogram = create_histogram();
+        let config = HistogramConfig::new(Interval::Hour).gap_threshold_hours(12);
+        let report = histogram.create_report(&config);
+        assert_eq!(report.series.len(), 3);
+        assert_eq!(report.series[0].key.computer, "DC1");
+        // 2022-01-01T00:00:00Zから2022-01-03T05:00:00Zまで
+        assert_eq!(report.series[0].start, 1640995200);
+        assert_eq!(report.series[0].end, 1640995200 + 53 * 3600);
+        // イベントが無い間隔は保持しない
+        assert_eq!(
+            report.series[0].buckets.iter().collect::<Vec<_>>(),
+            vec![
+                (&1640995200, &2),
+                (&(1640995200 + 2 * 3600), &1),
+                (&(1640995200 + 53 * 3600), &1)
+            ]
+        );
+        assert_eq!(report.series[0].total(), 4);
+        assert_eq!(report.gaps.len(), 1);
+        assert_eq!(report.gaps[0].from, 1640995200 + 3 * 3600);
+        assert_eq!(report.gaps[0].to, 1640995200 + 53 * 3600);
+
+        // 1102はSecurity、104はSystemのみログの消去として扱う
+        assert_eq!(report.log_cleared.len(), 2);
+        assert_eq!(report.log_cleared[0].event_id, "104");
+        assert_eq!(report.log_cleared[1].event_id, "1102");
+
+        let config = HistogramConfig::new(Interval::Day);
+        let report = histogram.create_report(&config);
+        assert_eq!(
+            report.series[0].buckets.values().collect::<Vec<_>>(),
+            vec![&3, &1]
+        );
+        assert_eq!(report.series[0].sparkline_counts(24 * 3600), vec![3, 0, 1]);
+        assert_eq!(report.gaps.len(), 1);
+        assert_eq!(report.gaps[0].to - report.gaps[0].from, 24 * 3600);
+    }
+
+    #[test]
+    fn test_output() {
+        let histogram = create_histogram();
+        let config = HistogramConfig::new(Interval::Day);
+        let report = histogram.create_report(&config);
+
+        let mut csv = vec![];
+        assert!(report.write_csv(&mut csv).is_ok());
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Computer,Channel,Timestamp,Count,LogCleared,GapHours"
+        );
+        assert_eq!(lines.len(), 6);
+        assert!(lines[1].starts_with("DC1,Security,") && lines[1].ends_with(",3,0,"));
+        assert!(lines[2].ends_with(",0,0,24"));
+        assert!(lines[3].ends_with(",1,1,"));
+        assert!(lines[5].starts_with("PC1,System,") && lines[5].ends_with(",1,1,"));
+
+        let mut out = vec![];
+        assert!(report.print(&mut out).is_ok());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("DC1 / Security |█ ▃| 4 events"));
+        assert!(out.contains("DC1 / Security: no events from "));
+        assert!(out.contains(" DC1 Security EventID: 1102\n"));
+    }
+
+    #[test]
+    fn test_output_hour() {
+        let histogram = create_histogram();
+        let config = HistogramConfig::new(Interval::Hour).gap_threshold_hours(12);
+        let report = histogram.create_report(&config);
+
+        let mut csv = vec![];
+        assert!(report.write_csv(&mut csv).is_ok());
+        let csv = String::from_utf8(csv).unwrap();
+        // ギャップではないイベントが無い間隔(01:00)は出力しない
+        let lines: Vec<&str> = csv
+            .lines()
+            .filter(|line| line.starts_with("DC1,Security,"))
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].ends_with(",2,0,"));
+        assert!(lines[1].ends_with(",1,0,"));
+        assert!(lines[2].ends_with(",0,0,50"));
+        assert!(lines[3].ends_with(",1,1,"));
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0, 1, 4, 8]), " ▁▄█");
+        assert_eq!(sparkline(&[]), "");
+        assert_eq!(sparkline(&[1; 120]).chars().count(), 60);
+    }
+}
@@ -0,0 +1,433 @@
+use crate::afterfact::format_time;
+use crate::detections::configs;
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::print::{AlertMessage, Message};
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::detections::utils;
+use crate::timeline::write_table;
+use chrono::{DateTime, Utc};
+use hashbrown::HashMap;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
+
+/// 集計するSecurityのEventIDとその名前。出力もこの順番にする
+const LOGON_EVENTS: [(&str, &str); 5] = [
+    ("4624", "Successful Logon"),
+    ("4625", "Failed Logon"),
+    ("4634", "Logoff"),
+    ("4648", "Explicit Credential Logon"),
+    ("4672", "Admin Logon"),
+];
+
+const HEADER: [&str; 11] = [
+    "Event",
+    "EventID",
+    "TargetUser",
+    "LogonType",
+    "SourceIP",
+    "SourceWorkstation",
+    "Computer",
+    "AuthPackage",
+    "Count",
+    "FirstSeen",
+    "LastSeen",
+];
+
+/// 集計のキー。ログオンイベントに存在しない項目は"-"にする
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LogonKey {
+    pub event_id: String,
+    pub target_user: String,
+    pub logon_type: String,
+    pub source_ip: String,
+    pub source_workstation: String,
+    pub computer: String,
+    pub auth_package: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogonStats {
+    pub count: usize,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+impl LogonStats {
+    fn add(&mut self, time: Option<DateTime<Utc>>) {
+        self.count += 1;
+        self.first_seen = utils::min_time(self.first_seen, time);
+        self.last_seen = utils::max_time(self.last_seen, time);
+    }
+}
+
+/// Securityログのログオンに関するイベントを、ユーザ、ログオンタイプ、接続元、コンピュータ、認証パッケージごとに集計する
+#[derive(Debug, Default)]
+pub struct LogonSummary {
+    pub stats: HashMap<LogonKey, LogonStats>,
+}
+
+impl LogonSummary {
+    pub fn new() -> LogonSummary {
+        LogonSummary::default()
+    }
+
+    pub fn start(&mut self, records: &[EvtxRecordInfo]) {
+        for record in records {
+            self.add(&record.record);
+        }
+    }
+
+    /// レコードがSecurityのログオンイベントの場合は集計する
+    pub fn add(&mut self, record: &Value) {
+        let key = match create_key(record) {
+            Some(key) => key,
+            None => return,
+        };
+        let time = Message::get_event_time(record);
+        self.stats
+            .entry(key)
+            .or_insert(LogonStats {
+                count: 0,
+                first_seen: Option::None,
+                last_seen: Option::None,
+            })
+            .add(time);
+    }
+
+    /// LOGON_EVENTSの順番、同じEventIDの中では件数の多い順に並べ替えた集計結果
+    pub fn sorted_rows(&self) -> Vec<(&LogonKey, &LogonStats)> {
+        let event_order = |event_id: &str| {
+            LOGON_EVENTS
+                .iter()
+                .position(|(id, _)| *id == event_id)
+                .unwrap_or(LOGON_EVENTS.len())
+        };
+        let mut rows: Vec<(&LogonKey, &LogonStats)> = self.stats.iter().collect();
+        rows.sort_by(|a, b| {
+            event_order(&a.0.event_id)
+                .cmp(&event_order(&b.0.event_id))
+                .then_with(|| b.1.count.cmp(&a.1.count))
+                .then_with(|| a.0.cmp(b.0))
+        });
+        rows
+    }
+
+    fn create_records(&self) -> Vec<Vec<String>> {
+        self.sorted_rows()
+            .into_iter()
+            .map(|(key, stats)| {
+                let event_name = LOGON_EVENTS
+                    .iter()
+                    .find(|(id, _)| *id == key.event_id)
+                    .map(|(_, name)| *name)
+                    .unwrap_or("-");
+                let time = |time: &Option<DateTime<Utc>>| match time {
+                    Some(time) => format_time(time),
+                    None => "-".to_string(),
+                };
+                vec![
+                    event_name.to_string(),
+                    key.event_id.to_owned(),
+                    key.target_user.to_owned(),
+                    logon_type_name(&key.logon_type),
+                    key.source_ip.to_owned(),
+                    key.source_workstation.to_owned(),
+                    key.computer.to_owned(),
+                    key.auth_package.to_owned(),
+                    stats.count.to_string(),
+                    time(&stats.first_seen),
+                    time(&stats.last_seen),
+                ]
+            })
+            .collect()
+    }
+
+    /// 列の幅を揃えた表形式で出力する
+    pub fn print<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let records = self.create_records();
+        write_table(w, &HEADER, &records)
+    }
+
+    pub fn write_csv<W: Write>(&self, w: W) -> Result<(), String> {
+        let mut wtr = csv::Writer::from_writer(w);
+        wtr.write_record(HEADER).map_err(|e| e.to_string())?;
+        for record in self.create_records() {
+            wtr.write_record(record).map_err(|e| e.to_string())?;
+        }
+        wtr.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// レコードがSecurityのログオンイベントの場合は集計のキーを作成する
+fn create_key(record: &Value) -> Option<LogonKey> {
+    let system = &record["Event"]["System"];
+    if system["Channel"].as_str() != Option::Some("Security") {
+        return Option::None;
+    }
+    let event_id = utils::value_to_output_string(&system["EventID"]);
+    if !LOGON_EVENTS.iter().any(|(id, _)| *id == event_id) {
+        return Option::None;
+    }
+
+    let event_data = &record["Event"]["EventData"];
+    let field = |name: &str| utils::value_to_output_string(&event_data[name]);
+    // 4672は特権を割り当てられたユーザがSubject側に記録される
+    let target_user = match event_id.as_str() {
+        "4672" => join_domain_user(&field("SubjectDomainName"), &field("SubjectUserName")),
+        _ => join_domain_user(&field("TargetDomainName"), &field("TargetUserName")),
+    };
+    Option::Some(LogonKey {
+        target_user,
+        logon_type: field("LogonType"),
+        source_ip: field("IpAddress"),
+        source_workstation: field("WorkstationName"),
+        computer: utils::value_to_output_string(&system["Computer"]),
+        auth_package: field("AuthenticationPackageName"),
+        event_id,
+    })
+}
+
+fn join_domain_user(domain: &str, user: &str) -> String {
+    if domain == "-" || user == "-" {
+        user.to_string()
+    } else {
+        format!("{}\\{}", domain, user)
+    }
+}
+
+/// ログオンタイプの番号に名前を付ける
+pub fn logon_type_name(logon_type: &str) -> String {
+    let name = match logon_type {
+        "0" => "System",
+        "2" => "Interactive",
+        "3" => "Network",
+        "4" => "Batch",
+        "5" => "Service",
+        "7" => "Unlock",
+        "8" => "NetworkCleartext",
+        "9" => "NewCredentials",
+        "10" => "RemoteInteractive",
+        "11" => "CachedInteractive",
+        "12" => "CachedRemoteInteractive",
+        "13" => "CachedUnlock",
+        _ => return logon_type.to_string(),
+    };
+    format!("{} - {}", logon_type, name)
+}
+
+/// 全てのevtxファイルのレコードを集計するsink。Engineに渡したレコードを集計し、スキャン後にoutput_logon_summaryで出力する
+impl DetectionSink for Mutex<LogonSummary> {
+    fn on_detect(&self, _event: &DetectionEvent) -> Result<(), String> {
+        Result::Ok(())
+    }
+
+    fn on_records(&self, records: &[EvtxRecordInfo]) -> Result<(), String> {
+        self.lock().unwrap().start(records);
+        Result::Ok(())
+    }
+}
+
+/// 全てのevtxファイルの集計結果を標準出力に出力する。--outputが指定された場合はCSVファイルにも出力する
+pub fn output_logon_summary(summary: &LogonSummary) {
+    println!();
+    println!("Logon Summary:");
+    if summary.stats.is_empty() {
+        println!("No logon events were found.");
+    } else {
+        summary.print(&mut io::stdout().lock()).ok();
+    }
+    if let Some(csv_path) = configs::CONFIG.read().unwrap().args.value_of("output") {
+        let ret = File::create(csv_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| summary.write_csv(BufWriter::new(file)));
+        if let Err(err) = ret {
+            AlertMessage::alert(
+                &mut BufWriter::new(std::io::stderr().lock()),
+                &format!("Failed to write the logon summary. {} {}", csv_path, err),
+            )
+            .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::detection::EvtxRecordInfo;
+    use crate::detections::sink::DetectionSink;
+    use crate::detections::utils;
+    use crate::timeline::logon_summary::{logon_type_name, LogonKey, LogonSummary};
+    use chrono::{TimeZone, Utc};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+
+    fn create_record(event_id: u64, time: &str, event_data: Value) -> Value {
+        json!({
+            "Event": {
+                "System": {
+                    "EventID": event_id,
+                    "Channel": "Security",
+                    "Computer": "DC1",
+                    "TimeCreated_attributes": {"SystemTime": time}
+                },
+                "EventData": event_data
+            }
+        })
+    }
+
+    fn create_logon(event_id: u64, time: &str, user: &str, ip: &str) -> Value {
+        create_record(
+            event_id,
+            time,
+            json!({
+                "TargetUserName": user,
+                "TargetDomainName": "HAYABUSA",
+                "LogonType": 3,
+                "IpAddress": ip,
+                "WorkstationName": "-",
+                "AuthenticationPackageName": "NTLM"
+            }),
+        )
+    }
+
+    fn create_summary() -> LogonSummary {
+        let mut summary = LogonSummary::new();
+        summary.add(&create_logon(
+            4624,
+            "2022-01-02T00:00:00Z",
+            "admin",
+            "10.0.0.1",
+        ));
+        summary.add(&create_logon(
+            4624,
+            "2022-01-01T00:00:00Z",
+            "admin",
+            "10.0.0.1",
+        ));
+        summary.add(&create_logon(
+            4624,
+            "2022-01-01T00:00:00Z",
+            "user",
+            "10.0.0.2",
+        ));
+        summary.add(&create_logon(
+            4625,
+            "2022-01-03T00:00:00Z",
+            "admin",
+            "10.0.0.3",
+        ));
+        summary.add(&create_record(
+            4672,
+            "2022-01-01T00:00:01Z",
+            json!({"SubjectUserName": "admin", "SubjectDomainName": "HAYABUSA"}),
+        ));
+        summary
+    }
+
+    #[test]
+    fn test_logon_summary() {
+        let summary = create_summary();
+        let rows = summary.sorted_rows();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(
+            rows[0].0,
+            &LogonKey {
+                event_id: "4624".to_string(),
+                target_user: "HAYABUSA\\admin".to_string(),
+                logon_type: "3".to_string(),
+                source_ip: "10.0.0.1".to_string(),
+                source_workstation: "-".to_string(),
+                computer: "DC1".to_string(),
+                auth_package: "NTLM".to_string(),
+            }
+        );
+        assert_eq!(rows[0].1.count, 2);
+        assert_eq!(
+            rows[0].1.first_seen,
+            Some(Utc.ymd(2022, 1, 1).and_hms(0, 0, 0))
+        );
+        assert_eq!(
+            rows[0].1.last_seen,
+            Some(Utc.ymd(2022, 1, 2).and_hms(0, 0, 0))
+        );
+        assert_eq!(rows[1].0.target_user, "HAYABUSA\\user");
+        assert_eq!(rows[2].0.event_id, "4625");
+        assert_eq!(rows[3].0.event_id, "4672");
+        assert_eq!(rows[3].0.target_user, "HAYABUSA\\admin");
+        assert_eq!(rows[3].0.logon_type, "-");
+    }
+
+    #[test]
+    fn test_ignore_other_events() {
+        let mut summary = LogonSummary::new();
+        let mut record = create_logon(4624, "2022-01-01T00:00:00Z", "admin", "10.0.0.1");
+        record["Event"]["System"]["Channel"] = json!("Microsoft-Windows-Sysmon/Operational");
+        summary.add(&record);
+        summary.add(&create_logon(
+            4688,
+            "2022-01-01T00:00:00Z",
+            "admin",
+            "10.0.0.1",
+        ));
+        assert!(summary.stats.is_empty());
+    }
+
+    #[test]
+    fn test_sink() {
+        // 複数のevtxファイルのレコードを同じsinkで集計する
+        let sink = Mutex::new(create_summary());
+        let records = vec![
+            create_logon(4624, "2021-12-31T00:00:00Z", "admin", "10.0.0.1"),
+            create_logon(4634, "2022-01-05T00:00:00Z", "admin", "-"),
+        ];
+        let records: Vec<EvtxRecordInfo> = records
+            .into_iter()
+            .map(|record| utils::create_rec_info(record, "test.evtx".to_string(), &[]))
+            .collect();
+        assert!(sink.on_records(&records).is_ok());
+        let summary = sink.into_inner().unwrap();
+        let rows = summary.sorted_rows();
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0].1.count, 3);
+        assert_eq!(
+            rows[0].1.first_seen,
+            Some(Utc.ymd(2021, 12, 31).and_hms(0, 0, 0))
+        );
+        assert_eq!(
+            rows[0].1.last_seen,
+            Some(Utc.ymd(2022, 1, 2).and_hms(0, 0, 0))
+        );
+        assert_eq!(rows[3].0.event_id, "4634");
+    }
+
+    #[test]
+    fn test_output() {
+        let summary = create_summary();
+        let mut csv = vec![];
+        assert!(summary.write_csv(&mut csv).is_ok());
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "Event,EventID,TargetUser,LogonType,SourceIP,SourceWorkstation,Computer,AuthPackage,Count,FirstSeen,LastSeen");
+        assert!(lines[1].starts_with(
+            "Successful Logon,4624,HAYABUSA\\admin,3 - Network,10.0.0.1,-,DC1,NTLM,2,"
+        ));
+        assert_eq!(lines.len(), 5);
+
+        let mut table = vec![];
+        assert!(summary.print(&mut table).is_ok());
+        let table = String::from_utf8(table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("Event            | EventID | TargetUser"));
+        assert!(lines[2].starts_with("Successful Logon | 4624    | HAYABUSA\\admin"));
+        assert!(lines[5].starts_with("Admin Logon"));
+    }
+
+    #[test]
+    fn test_logon_type_name() {
+        assert_eq!(logon_type_name("3"), "3 - Network");
+        assert_eq!(logon_type_name("10"), "10 - RemoteInteractive");
+        assert_eq!(logon_type_name("-"), "-");
+        assert_eq!(logon_type_name("99"), "99");
+    }
+}
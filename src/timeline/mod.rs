@@ -1,2 +1,63 @@
+use std::io::{self, Write};
+
+pub mod coverage;
+pub mod histogram;
+pub mod logon_summary;
+pub mod process_tree;
+pub mod sessions;
 pub mod statistics;
 pub mod timelines;
+
+/// 列の幅を揃えた表形式で出力する。列は" | "で区切り、ヘッダの下に区切り線を出力する
+pub fn write_table<W: Write>(
+    w: &mut W,
+    header: &[&str],
+    records: &[Vec<String>],
+) -> io::Result<()> {
+    let mut widths: Vec<usize> = header.iter().map(|column| column.len()).collect();
+    for record in records {
+        for (width, column) in widths.iter_mut().zip(record) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+    let format_line = |columns: Vec<&str>| {
+        columns
+            .iter()
+            .zip(&widths)
+            .map(|(column, width)| format!("{:<1$}", column, width))
+            .collect::<Vec<String>>()
+            .join(" | ")
+            .trim_end()
+            .to_string()
+    };
+    writeln!(w, "{}", format_line(header.to_vec()))?;
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    writeln!(w, "{}", separator.join("-|-"))?;
+    for record in records {
+        writeln!(
+            w,
+            "{}",
+            format_line(record.iter().map(|s| s.as_str()).collect())
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timeline::write_table;
+
+    #[test]
+    fn test_write_table() {
+        let mut output = vec![];
+        let records = vec![
+            vec!["alice".to_string(), "あいう".to_string()],
+            vec!["b".to_string(), String::default()],
+        ];
+        write_table(&mut output, &["User", "Note"], &records).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "User  | Note\n------|-----\nalice | あいう\nb     |\n"
+        );
+    }
+}
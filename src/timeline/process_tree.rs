@@ -0,0 +1,600 @@
+use crate::afterfact::{format_time, output_level};
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::print::Message;
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::detections::utils;
+use chrono::{DateTime, Utc};
+use hashbrown::{HashMap, HashSet};
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::sync::{Mutex, MutexGuard};
+
+const SYSMON_CHANNEL: &str = "Microsoft-Windows-Sysmon/Operational";
+
+/// 親をたどる最大の深さ。プロセスIDの再利用で循環した場合に止めるため
+const MAX_DEPTH: usize = 64;
+
+/// Sysmonの1もしくはSecurityの4688から取得したプロセス
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessNode {
+    pub computer: String,
+    pub time: DateTime<Utc>,
+    pub pid: u64,
+    /// SysmonのProcessGuid。4688の場合はNone
+    pub guid: Option<String>,
+    pub parent_pid: Option<u64>,
+    pub parent_guid: Option<String>,
+    pub image: String,
+    pub command_line: String,
+    pub user: String,
+}
+
+impl ProcessNode {
+    /// レコードがSysmonの1もしくはSecurityの4688の場合はプロセスを作成する
+    pub fn from_record(record: &Value) -> Option<ProcessNode> {
+        let system = &record["Event"]["System"];
+        let event_data = &record["Event"]["EventData"];
+        let channel = system["Channel"].as_str()?;
+        let event_id = get_field(system, "EventID");
+        let computer = get_field(system, "Computer");
+        let time = Message::get_event_time(record)?;
+        if channel == SYSMON_CHANNEL && event_id == "1" {
+            Option::Some(ProcessNode {
+                computer,
+                time,
+                pid: parse_pid(&event_data["ProcessId"])?,
+                guid: get_guid(&event_data["ProcessGuid"]),
+                parent_pid: parse_pid(&event_data["ParentProcessId"]),
+                parent_guid: get_guid(&event_data["ParentProcessGuid"]),
+                image: get_field(event_data, "Image"),
+                command_line: get_field(event_data, "CommandLine"),
+                user: get_field(event_data, "User"),
+            })
+        } else if channel == "Security" && event_id == "4688" {
+            // 4688のProcessIdは親プロセスのID
+            Option::Some(ProcessNode {
+                computer,
+                time,
+                pid: parse_pid(&event_data["NewProcessId"])?,
+                guid: Option::None,
+                parent_pid: parse_pid(&event_data["ProcessId"]),
+                parent_guid: Option::None,
+                image: get_field(event_data, "NewProcessName"),
+                command_line: get_field(event_data, "CommandLine"),
+                user: get_field(event_data, "SubjectUserName"),
+            })
+        } else {
+            Option::None
+        }
+    }
+
+    fn label(&self) -> String {
+        let name = if self.command_line == "-" {
+            &self.image
+        } else {
+            &self.command_line
+        };
+        format!("{} (PID: {}, User: {})", name, self.pid, self.user)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "Timestamp": format_time(&self.time),
+            "Computer": self.computer,
+            "ProcessId": self.pid,
+            "ProcessGuid": self.guid,
+            "Image": self.image,
+            "CommandLine": self.command_line,
+            "User": self.user,
+        })
+    }
+}
+
+/// 検知したレコードのプロセスを探すための情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessRef {
+    pub computer: String,
+    pub time: DateTime<Utc>,
+    pub pid: Option<u64>,
+    pub guid: Option<String>,
+}
+
+impl ProcessRef {
+    /// 検知したレコードのProcessGuidとプロセスIDを取得する。4688の場合は作成されたプロセス、それ以外はイベントを記録したプロセスになる
+    pub fn from_record(record: &Value, time: DateTime<Utc>) -> Option<ProcessRef> {
+        let system = &record["Event"]["System"];
+        let event_data = &record["Event"]["EventData"];
+        let pid = match get_field(system, "EventID").as_str() {
+            "4688" => parse_pid(&event_data["NewProcessId"]),
+            _ => parse_pid(&event_data["ProcessId"]),
+        };
+        let guid = get_guid(&event_data["ProcessGuid"]);
+        if pid.is_none() && guid.is_none() {
+            return Option::None;
+        }
+        Option::Some(ProcessRef {
+            computer: get_field(system, "Computer"),
+            time,
+            pid,
+            guid,
+        })
+    }
+}
+
+/// プロセスの親子関係。Sysmonの場合はParentProcessGuid、4688の場合は同じコンピュータで直前に作成された同じプロセスIDのプロセスを親とする
+#[derive(Debug, Default)]
+pub struct ProcessTree {
+    pub nodes: Vec<ProcessNode>,
+    parents: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    guids: HashMap<String, usize>,
+    /// (コンピュータ, プロセスID)ごとの作成時刻順のプロセス
+    pids: HashMap<(String, u64), Vec<usize>>,
+}
+
+impl ProcessTree {
+    pub fn new() -> ProcessTree {
+        ProcessTree::default()
+    }
+
+    pub fn start(&mut self, records: &[EvtxRecordInfo]) {
+        for record in records {
+            self.add(&record.record);
+        }
+    }
+
+    pub fn add(&mut self, record: &Value) {
+        if let Some(node) = ProcessNode::from_record(record) {
+            self.nodes.push(node);
+        }
+    }
+
+    /// 全てのファイルのプロセスを追加した後に、親子関係を作成する
+    pub fn build(&mut self) {
+        self.nodes
+            .sort_by(|a, b| (a.time, &a.computer, a.pid).cmp(&(b.time, &b.computer, b.pid)));
+        self.guids.clear();
+        self.pids.clear();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(guid) = &node.guid {
+                self.guids.insert(guid.to_owned(), i);
+            }
+            self.pids
+                .entry((node.computer.to_owned(), node.pid))
+                .or_default()
+                .push(i);
+        }
+
+        self.parents = vec![Option::None; self.nodes.len()];
+        self.children = vec![vec![]; self.nodes.len()];
+        for i in 0..self.nodes.len() {
+            let node = &self.nodes[i];
+            let parent = node
+                .parent_guid
+                .as_ref()
+                .and_then(|guid| self.guids.get(guid).copied())
+                .or_else(|| {
+                    let parent_pid = node.parent_pid?;
+                    self.find_by_pid(&node.computer, parent_pid, node.time, i)
+                })
+                .filter(|parent| *parent != i);
+            if let Some(parent) = parent {
+                self.parents[i] = Option::Some(parent);
+                self.children[parent].push(i);
+            }
+        }
+    }
+
+    /// timeまでに作成された同じプロセスIDのプロセスのうち、最も新しいものを返す
+    fn find_by_pid(
+        &self,
+        computer: &str,
+        pid: u64,
+        time: DateTime<Utc>,
+        exclude: usize,
+    ) -> Option<usize> {
+        self.pids
+            .get(&(computer.to_string(), pid))?
+            .iter()
+            .rev()
+            .find(|i| **i != exclude && self.nodes[**i].time <= time)
+            .copied()
+    }
+
+    /// 検知したレコードのプロセスを探す
+    pub fn find(&self, process: &ProcessRef) -> Option<usize> {
+        if let Some(i) = process.guid.as_ref().and_then(|guid| self.guids.get(guid)) {
+            return Option::Some(*i);
+        }
+        self.find_by_pid(&process.computer, process.pid?, process.time, usize::MAX)
+    }
+
+    /// ルートから親までのプロセス
+    pub fn ancestors(&self, i: usize) -> Vec<usize> {
+        let mut ancestors = vec![];
+        let mut visited = HashSet::new();
+        visited.insert(i);
+        let mut current = i;
+        while let Some(parent) = self.parents.get(current).copied().flatten() {
+            if !visited.insert(parent) || ancestors.len() >= MAX_DEPTH {
+                break;
+            }
+            ancestors.push(parent);
+            current = parent;
+        }
+        ancestors.reverse();
+        ancestors
+    }
+
+    pub fn children(&self, i: usize) -> &[usize] {
+        self.children.get(i).map(|c| c.as_slice()).unwrap_or(&[])
+    }
+
+    /// 祖先、検知したプロセス(*を付ける)、子プロセスをASCIIのツリーにする
+    pub fn render(&self, i: usize) -> Vec<String> {
+        let mut lines = vec![];
+        let mut indent = String::default();
+        let chain: Vec<usize> = self.ancestors(i).into_iter().chain([i]).collect();
+        for (depth, node) in chain.iter().enumerate() {
+            let mark = if *node == i { "* " } else { "" };
+            if depth == 0 {
+                lines.push(format!("{}{}", mark, self.nodes[*node].label()));
+            } else {
+                lines.push(format!(
+                    "{}`-- {}{}",
+                    indent,
+                    mark,
+                    self.nodes[*node].label()
+                ));
+                indent.push_str("    ");
+            }
+        }
+        let children = self.children(i);
+        for (n, child) in children.iter().enumerate() {
+            let branch = if n + 1 == children.len() {
+                "`--"
+            } else {
+                "|--"
+            };
+            lines.push(format!(
+                "{}{} {}",
+                indent,
+                branch,
+                self.nodes[*child].label()
+            ));
+        }
+        lines
+    }
+
+    /// JSONの出力に追加する、検知したプロセスと祖先、子プロセス
+    pub fn to_json(&self, i: usize) -> Value {
+        let nodes = |indexes: &[usize]| -> Vec<Value> {
+            indexes.iter().map(|i| self.nodes[*i].to_json()).collect()
+        };
+        json!({
+            "Process": self.nodes[i].to_json(),
+            "Ancestors": nodes(&self.ancestors(i)),
+            "Children": nodes(self.children(i)),
+        })
+    }
+}
+
+fn get_field(data: &Value, name: &str) -> String {
+    utils::value_to_output_string(&data[name])
+}
+
+/// ProcessGuidは{}の有無や大文字小文字が異なる場合があるため揃える
+fn get_guid(value: &Value) -> Option<String> {
+    let guid = value
+        .as_str()?
+        .trim()
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .to_lowercase();
+    if guid.is_empty() || guid.chars().all(|c| c == '0' || c == '-') {
+        Option::None
+    } else {
+        Option::Some(guid)
+    }
+}
+
+/// プロセスIDを数値にする。4688は0x1a4のような16進数の文字列で記録される
+fn parse_pid(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(pid) => pid.as_u64(),
+        Value::String(pid) => {
+            let pid = pid.trim();
+            match pid.strip_prefix("0x").or_else(|| pid.strip_prefix("0X")) {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => pid.parse().ok(),
+            }
+        }
+        _ => Option::None,
+    }
+}
+
+/// プロセスツリーを表示する検知結果
+#[derive(Debug, Clone)]
+struct ProcessDetection {
+    time: DateTime<Utc>,
+    level: String,
+    rule_title: String,
+    computer: String,
+    process: ProcessRef,
+}
+
+/// 全てのレコードからプロセスツリーを作成し、検知したレコードのプロセスを記録するsink。
+/// プロセスツリーはfinishで作成し、スキャン後にoutput_process_treesで表示する
+#[derive(Default)]
+pub struct ProcessTreeSink {
+    tree: Mutex<ProcessTree>,
+    detections: Mutex<Vec<ProcessDetection>>,
+}
+
+impl ProcessTreeSink {
+    pub fn new() -> ProcessTreeSink {
+        ProcessTreeSink::default()
+    }
+
+    /// 全てのevtxファイルのプロセス作成イベントから作成したプロセスツリー。親子関係はfinishの後に参照できる
+    pub fn tree(&self) -> MutexGuard<'_, ProcessTree> {
+        self.tree.lock().unwrap()
+    }
+
+    /// 検知結果ごとにプロセスツリーを表示する。同じルールで同じプロセスを検知した場合は1回だけ表示する
+    pub fn print<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let tree = self.tree();
+        let mut detections = self.detections.lock().unwrap().clone();
+        detections.sort_by(|a, b| {
+            (a.time, &a.rule_title, &a.computer).cmp(&(b.time, &b.rule_title, &b.computer))
+        });
+        let mut printed = HashSet::new();
+        for detection in detections {
+            let i = match tree.find(&detection.process) {
+                Some(i) => i,
+                None => continue,
+            };
+            if !printed.insert((detection.rule_title.to_owned(), i)) {
+                continue;
+            }
+            writeln!(
+                w,
+                "[{}] {} - {} - {}",
+                output_level(&detection.level),
+                detection.rule_title,
+                detection.computer,
+                format_time(&detection.time)
+            )?;
+            for line in tree.render(i) {
+                writeln!(w, "  {}", line)?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+}
+
+impl DetectionSink for ProcessTreeSink {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        // aggregation conditionの検知結果は元のレコードが無いため、プロセスを特定できない
+        let process = match event
+            .record
+            .and_then(|record_info| ProcessRef::from_record(&record_info.record, event.time))
+        {
+            Some(process) => process,
+            None => return Result::Ok(()),
+        };
+        self.detections.lock().unwrap().push(ProcessDetection {
+            time: event.time,
+            level: event.detect_info.level.to_owned(),
+            rule_title: event.detect_info.alert.to_owned(),
+            computer: event.detect_info.computername.to_owned(),
+            process,
+        });
+        Result::Ok(())
+    }
+
+    fn on_records(&self, records: &[EvtxRecordInfo]) -> Result<(), String> {
+        self.tree().start(records);
+        Result::Ok(())
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        self.tree().build();
+        Result::Ok(())
+    }
+}
+
+/// スキャン後に検知結果ごとのプロセスツリーを標準出力に出力する
+pub fn output_process_trees(sink: &ProcessTreeSink) {
+    println!();
+    println!("Process Trees:");
+    if sink.tree().nodes.is_empty() {
+        println!("No Sysmon 1 or Security 4688 events were found.");
+        return;
+    }
+    sink.print(&mut io::stdout().lock()).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::detection::EvtxRecordInfo;
+    use crate::detections::print::DetectInfo;
+    use crate::detections::rule::create_rule;
+    use crate::detections::sink::{DetectionEvent, DetectionSink};
+    use crate::detections::utils;
+    use crate::timeline::process_tree::{ProcessRef, ProcessTree, ProcessTreeSink};
+    use chrono::{TimeZone, Utc};
+    use serde_json::{json, Value};
+    use yaml_rust::YamlLoader;
+
+    fn create_sysmon(time: &str, guid: &str, pid: u64, parent_guid: &str, image: &str) -> Value {
+        json!({
+            "Event": {
+                "System": {
+                    "EventID": 1,
+                    "Channel": "Microsoft-Windows-Sysmon/Operational",
+                    "Computer": "PC1",
+                    "TimeCreated_attributes": {"SystemTime": time}
+                },
+                "EventData": {
+                    "ProcessGuid": guid,
+                    "ProcessId": pid,
+                    "ParentProcessGuid": parent_guid,
+                    "ParentProcessId": 0,
+                    "Image": image,
+                    "CommandLine": "",
+                    "User": "PC1\\alice"
+                }
+            }
+        })
+    }
+
+    fn create_4688(time: &str, pid: &str, parent_pid: &str, image: &str) -> Value {
+        json!({
+            "Event": {
+                "System": {
+                    "EventID": 4688,
+                    "Channel": "Security",
+                    "Computer": "PC2",
+                    "TimeCreated_attributes": {"SystemTime": time}
+                },
+                "EventData": {
+                    "NewProcessId": pid,
+                    "ProcessId": parent_pid,
+                    "NewProcessName": image,
+                    "CommandLine": format!("{} /c", image),
+                    "SubjectUserName": "bob"
+                }
+            }
+        })
+    }
+
+    fn create_sysmon_sink() -> ProcessTreeSink {
+        let sink = ProcessTreeSink::new();
+        // ファイルの順番に関わらず時刻順に並べ替える
+        let file1 = vec![
+            create_sysmon("2022-01-01T00:00:02Z", "{B}", 20, "{A}", "cmd.exe"),
+            create_sysmon("2022-01-01T00:00:03Z", "{C}", 30, "{B}", "powershell.exe"),
+            create_sysmon("2022-01-01T00:00:04Z", "{D}", 40, "{C}", "whoami.exe"),
+            create_sysmon("2022-01-01T00:00:05Z", "{E}", 50, "{C}", "net.exe"),
+        ];
+        let file2 = vec![create_sysmon(
+            "2022-01-01T00:00:01Z",
+            "{A}",
+            10,
+            "{0}",
+            "explorer.exe",
+        )];
+        for (path, records) in [("file1.evtx", file1), ("file2.evtx", file2)] {
+            let records: Vec<EvtxRecordInfo> = records
+                .into_iter()
+                .map(|record| utils::create_rec_info(record, path.to_string(), &[]))
+                .collect();
+            assert!(sink.on_records(&records).is_ok());
+        }
+        // 親子関係は全てのファイルのレコードを受け取った後のfinishで作成する
+        assert!(sink.finish().is_ok());
+        sink
+    }
+
+    #[test]
+    fn test_sysmon_tree() {
+        let sink = create_sysmon_sink();
+        let tree = sink.tree();
+        let process = ProcessRef {
+            computer: "PC1".to_string(),
+            time: Utc.ymd(2022, 1, 1).and_hms(0, 0, 3),
+            pid: Option::Some(30),
+            guid: Option::Some("c".to_string()),
+        };
+        let i = tree.find(&process).unwrap();
+        assert_eq!(tree.nodes[i].image, "powershell.exe");
+        assert_eq!(tree.ancestors(i).len(), 2);
+        assert_eq!(
+            tree.render(i),
+            vec![
+                "explorer.exe (PID: 10, User: PC1\\alice)",
+                "`-- cmd.exe (PID: 20, User: PC1\\alice)",
+                "    `-- * powershell.exe (PID: 30, User: PC1\\alice)",
+                "        |-- whoami.exe (PID: 40, User: PC1\\alice)",
+                "        `-- net.exe (PID: 50, User: PC1\\alice)",
+            ]
+        );
+
+        let json = tree.to_json(i);
+        assert_eq!(json["Process"]["ProcessGuid"], "c");
+        assert_eq!(json["Ancestors"][0]["Image"], "explorer.exe");
+        assert_eq!(json["Children"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_4688_tree() {
+        // プロセスID 0x100が再利用された場合は、直前に作成されたプロセスを親とする
+        let mut tree = ProcessTree::new();
+        for record in [
+            create_4688("2022-01-01T00:00:01Z", "0x100", "0x4", "a.exe"),
+            create_4688("2022-01-01T00:00:02Z", "0x200", "0x100", "b.exe"),
+            create_4688("2022-01-01T00:00:03Z", "0x100", "0x4", "c.exe"),
+            create_4688("2022-01-01T00:00:04Z", "0x300", "0x100", "d.exe"),
+        ] {
+            tree.add(&record);
+        }
+        tree.build();
+
+        let find = |record: &Value, second: u32| {
+            let process =
+                ProcessRef::from_record(record, Utc.ymd(2022, 1, 1).and_hms(0, 0, second)).unwrap();
+            tree.find(&process).unwrap()
+        };
+        let b = find(&create_4688("", "0x200", "0x100", "b.exe"), 2);
+        let d = find(&create_4688("", "0x300", "0x100", "d.exe"), 4);
+        assert_eq!(tree.nodes[b].pid, 0x200);
+        assert_eq!(tree.nodes[tree.ancestors(b)[0]].image, "a.exe");
+        assert_eq!(tree.nodes[tree.ancestors(d)[0]].image, "c.exe");
+        assert_eq!(tree.render(d)[0], "c.exe /c (PID: 256, User: bob)");
+    }
+
+    #[test]
+    fn test_process_tree_sink() {
+        let sink = create_sysmon_sink();
+        let rule_yaml = YamlLoader::load_from_str("title: test").unwrap().remove(0);
+        let rule = create_rule("test-rule.yml".to_string(), rule_yaml);
+        let detect_info = DetectInfo {
+            filepath: "test.evtx".to_string(),
+            rulepath: "test-rule.yml".to_string(),
+            level: "high".to_string(),
+            computername: "PC1".to_string(),
+            eventid: "1".to_string(),
+            alert: "Suspicious PowerShell".to_string(),
+            detail: "detail".to_string(),
+            tag_info: String::default(),
+            record_information: Option::None,
+        };
+        let record = create_sysmon("2022-01-01T00:00:05Z", "{E}", 50, "{C}", "net.exe");
+        let record_info = utils::create_rec_info(record, "test.evtx".to_string(), &[]);
+        // 同じルールで同じプロセスを検知した場合は1回だけ表示する。レコードが無い検知結果は表示しない
+        for record in [
+            Option::Some(&record_info),
+            Option::Some(&record_info),
+            Option::None,
+        ] {
+            let event = DetectionEvent {
+                time: Utc.ymd(2022, 1, 1).and_hms(0, 0, 5),
+                detect_info: &detect_info,
+                record,
+                rule: &rule,
+            };
+            assert!(sink.on_detect(&event).is_ok());
+        }
+
+        let mut output = vec![];
+        sink.print(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output.matches("[high] Suspicious PowerShell - PC1").count(),
+            1
+        );
+        assert!(output.contains("          `-- * net.exe (PID: 50, User: PC1\\alice)"));
+    }
+}
@@ -0,0 +1,775 @@
+use crate::afterfact::{format_time, output_level};
+use crate::detections::configs;
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::print::{AlertMessage, Message};
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use crate::detections::utils;
+use crate::timeline::logon_summary::logon_type_name;
+use crate::timeline::write_table;
+use chrono::{DateTime, Utc};
+use hashbrown::HashMap;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Mutex, MutexGuard};
+
+/// ログオンIDを取得するフィールド。ログオン、ログオフのイベントはTarget側に記録される
+const LOGON_ID_FIELDS: [&str; 3] = ["TargetLogonId", "SubjectLogonId", "LogonId"];
+
+const HEADER: [&str; 11] = [
+    "Computer",
+    "LogonId",
+    "User",
+    "LogonType",
+    "SourceIP",
+    "Start",
+    "End",
+    "Duration",
+    "Events",
+    "Processes",
+    "Detections",
+];
+
+/// セッションのキー。ログオンIDはコンピュータごとに割り当てられる
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionKey {
+    pub computer: String,
+    pub logon_id: String,
+}
+
+impl SessionKey {
+    /// レコードのコンピュータとログオンIDからキーを作成する。0x0や空のログオンIDは無視する
+    pub fn from_record(record: &Value) -> Option<(SessionKey, &'static str)> {
+        let computer = record["Event"]["System"]["Computer"].as_str()?;
+        let event_data = &record["Event"]["EventData"];
+        LOGON_ID_FIELDS.iter().find_map(|field| {
+            let logon_id = normalize_logon_id(&event_data[*field])?;
+            Option::Some((
+                SessionKey {
+                    computer: computer.to_string(),
+                    logon_id,
+                },
+                *field,
+            ))
+        })
+    }
+}
+
+/// --sessionの指定。COMPUTER:LOGONIDもしくはLOGONIDで指定する
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionFilter {
+    pub computer: Option<String>,
+    pub logon_id: String,
+}
+
+impl SessionFilter {
+    pub fn parse(session: &str) -> SessionFilter {
+        match session.rsplit_once(':') {
+            Some((computer, logon_id)) => SessionFilter {
+                computer: Option::Some(computer.to_string()),
+                logon_id: logon_id.trim().to_lowercase(),
+            },
+            None => SessionFilter {
+                computer: Option::None,
+                logon_id: session.trim().to_lowercase(),
+            },
+        }
+    }
+
+    pub fn matches(&self, key: &SessionKey) -> bool {
+        key.logon_id == self.logon_id
+            && match &self.computer {
+                Some(computer) => key.computer.eq_ignore_ascii_case(computer),
+                None => true,
+            }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionDetection {
+    pub time: DateTime<Utc>,
+    pub level: String,
+    pub rule_title: String,
+}
+
+/// --sessionで指定したセッションのイベント
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionEvent {
+    pub time: Option<DateTime<Utc>>,
+    pub channel: String,
+    pub event_id: String,
+}
+
+/// 同じコンピュータとログオンIDのイベントをまとめたセッション
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Session {
+    pub user: Option<String>,
+    pub logon_type: Option<String>,
+    pub source_ip: Option<String>,
+    /// 4624のログオン時刻
+    pub logon_time: Option<DateTime<Utc>>,
+    /// 4634もしくは4647のログオフ時刻
+    pub logoff_time: Option<DateTime<Utc>>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub event_count: usize,
+    /// 4688もしくはSysmonの1で作成されたプロセス
+    pub processes: Vec<(Option<DateTime<Utc>>, String)>,
+    pub detections: Vec<SessionDetection>,
+    pub events: Vec<SessionEvent>,
+}
+
+impl Session {
+    /// ログオンからログオフまで。どちらかが無い場合は最初と最後のイベントの時刻を使う
+    pub fn start(&self) -> Option<DateTime<Utc>> {
+        self.logon_time.or(self.first_seen)
+    }
+
+    pub fn end(&self) -> Option<DateTime<Utc>> {
+        self.logoff_time.or(self.last_seen)
+    }
+
+    pub fn duration(&self) -> String {
+        match (self.start(), self.end()) {
+            (Some(start), Some(end)) if start <= end => {
+                let seconds = (end - start).num_seconds();
+                format!(
+                    "{:02}:{:02}:{:02}",
+                    seconds / 3600,
+                    seconds % 3600 / 60,
+                    seconds % 60
+                )
+            }
+            _ => "-".to_string(),
+        }
+    }
+
+    /// 作成されたプロセスの名前。重複は除く
+    pub fn process_names(&self) -> Vec<&str> {
+        let names: BTreeSet<&str> = self
+            .processes
+            .iter()
+            .map(|(_, name)| name.as_str())
+            .collect();
+        names.into_iter().collect()
+    }
+
+    /// 検知したルールのタイトル。重複は除く
+    pub fn detection_titles(&self) -> Vec<&str> {
+        let titles: BTreeSet<&str> = self
+            .detections
+            .iter()
+            .map(|detection| detection.rule_title.as_str())
+            .collect();
+        titles.into_iter().collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SessionRow {
+    pub computer: String,
+    pub logon_id: String,
+    pub user: String,
+    pub logon_type: String,
+    #[serde(rename = "SourceIP")]
+    pub source_ip: String,
+    pub start: String,
+    pub end: String,
+    pub duration: String,
+    pub events: usize,
+    pub processes: String,
+    pub process_count: usize,
+    pub detections: String,
+    pub detection_count: usize,
+}
+
+/// コンピュータとログオンIDごとのセッション
+#[derive(Debug, Default)]
+pub struct Sessions {
+    pub sessions: HashMap<SessionKey, Session>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions::default()
+    }
+
+    pub fn start(&mut self, records: &[EvtxRecordInfo], filter: Option<&SessionFilter>) {
+        for record in records {
+            self.add(&record.record, filter);
+        }
+    }
+
+    /// レコードをセッションに追加する。filterに一致するセッションの場合はイベントも記録する
+    pub fn add(&mut self, record: &Value, filter: Option<&SessionFilter>) {
+        let (key, logon_id_field) = match SessionKey::from_record(record) {
+            Some(key) => key,
+            None => return,
+        };
+        let system = &record["Event"]["System"];
+        let event_data = &record["Event"]["EventData"];
+        let channel = get_field(system, "Channel");
+        let event_id = get_field(system, "EventID");
+        let time = Message::get_event_time(record);
+        let is_target = filter.map(|filter| filter.matches(&key)).unwrap_or(false);
+
+        let session = self.sessions.entry(key).or_default();
+        session.event_count += 1;
+        session.first_seen = utils::min_time(session.first_seen, time);
+        session.last_seen = utils::max_time(session.last_seen, time);
+        if is_target {
+            session.events.push(SessionEvent {
+                time,
+                channel: channel.to_owned(),
+                event_id: event_id.to_owned(),
+            });
+        }
+
+        match (channel.as_str(), event_id.as_str()) {
+            ("Security", "4624") => {
+                session.user = get_user(event_data, "Target");
+                session.logon_type = get_value(event_data, "LogonType");
+                session.source_ip = get_value(event_data, "IpAddress");
+                session.logon_time = utils::min_time(session.logon_time, time);
+            }
+            ("Security", "4634") | ("Security", "4647") => {
+                session.logoff_time = utils::max_time(session.logoff_time, time);
+            }
+            ("Security", "4688") => {
+                if let Some(process) = get_value(event_data, "NewProcessName") {
+                    session.processes.push((time, process));
+                }
+            }
+            ("Microsoft-Windows-Sysmon/Operational", "1") => {
+                if let Some(process) = get_value(event_data, "Image") {
+                    session.processes.push((time, process));
+                }
+            }
+            _ => {}
+        }
+        // 4624以外のイベントでは、ログオンIDを取得した側のユーザを使う
+        if session.user.is_none() {
+            session.user = match logon_id_field {
+                "TargetLogonId" => get_user(event_data, "Target"),
+                "SubjectLogonId" => get_user(event_data, "Subject"),
+                _ => get_value(event_data, "User"),
+            };
+        }
+    }
+
+    /// 検知結果をレコードのセッションに追加する
+    pub fn add_detection(&mut self, record: &Value, detection: SessionDetection) {
+        if let Some((key, _)) = SessionKey::from_record(record) {
+            self.sessions
+                .entry(key)
+                .or_default()
+                .detections
+                .push(detection);
+        }
+    }
+
+    /// 開始時刻順に並べ替えたセッション。filterを指定した場合は一致するセッションのみ返す
+    pub fn sorted_sessions(&self, filter: Option<&SessionFilter>) -> Vec<(&SessionKey, &Session)> {
+        let mut sessions: Vec<(&SessionKey, &Session)> = self
+            .sessions
+            .iter()
+            .filter(|(key, _)| filter.map(|filter| filter.matches(key)).unwrap_or(true))
+            .collect();
+        sessions.sort_by(|a, b| (a.1.start(), a.0).cmp(&(b.1.start(), b.0)));
+        sessions
+    }
+
+    pub fn create_rows(&self, filter: Option<&SessionFilter>) -> Vec<SessionRow> {
+        self.sorted_sessions(filter)
+            .into_iter()
+            .map(|(key, session)| SessionRow {
+                computer: key.computer.to_owned(),
+                logon_id: key.logon_id.to_owned(),
+                user: session.user.clone().unwrap_or_else(|| "-".to_string()),
+                logon_type: session
+                    .logon_type
+                    .as_ref()
+                    .map(|logon_type| logon_type_name(logon_type))
+                    .unwrap_or_else(|| "-".to_string()),
+                source_ip: session.source_ip.clone().unwrap_or_else(|| "-".to_string()),
+                start: format_seen(&session.start()),
+                end: format_seen(&session.end()),
+                duration: session.duration(),
+                events: session.event_count,
+                processes: session.process_names().join(" ¦ "),
+                process_count: session.processes.len(),
+                detections: session.detection_titles().join(" ¦ "),
+                detection_count: session.detections.len(),
+            })
+            .collect()
+    }
+
+    /// 列の幅を揃えた表形式で出力する
+    pub fn print<W: Write>(&self, w: &mut W, rows: &[SessionRow]) -> io::Result<()> {
+        let records: Vec<Vec<String>> = rows
+            .iter()
+            .map(|row| {
+                vec![
+                    row.computer.to_owned(),
+                    row.logon_id.to_owned(),
+                    row.user.to_owned(),
+                    row.logon_type.to_owned(),
+                    row.source_ip.to_owned(),
+                    row.start.to_owned(),
+                    row.end.to_owned(),
+                    row.duration.to_owned(),
+                    row.events.to_string(),
+                    row.process_count.to_string(),
+                    row.detection_count.to_string(),
+                ]
+            })
+            .collect();
+        write_table(w, &HEADER, &records)
+    }
+
+    /// --sessionで指定したセッションの詳細を出力する
+    pub fn print_detail<W: Write>(&self, w: &mut W, filter: &SessionFilter) -> io::Result<()> {
+        let rows = self.create_rows(Option::Some(filter));
+        for (row, (_, session)) in rows.iter().zip(self.sorted_sessions(Option::Some(filter))) {
+            writeln!(w, "Session: {} {}", row.computer, row.logon_id)?;
+            writeln!(w, "User: {}", row.user)?;
+            writeln!(w, "Logon Type: {}", row.logon_type)?;
+            writeln!(w, "Source IP: {}", row.source_ip)?;
+            writeln!(w, "Start: {}", row.start)?;
+            writeln!(w, "End: {}", row.end)?;
+            writeln!(w, "Duration: {}", row.duration)?;
+            writeln!(w, "Events: {}", row.events)?;
+            writeln!(w)?;
+            writeln!(w, "Processes:")?;
+            let mut processes = session.processes.clone();
+            processes.sort();
+            for (time, process) in &processes {
+                writeln!(w, "  {} {}", format_seen(time), process)?;
+            }
+            writeln!(w)?;
+            writeln!(w, "Detections:")?;
+            let mut detections: Vec<&SessionDetection> = session.detections.iter().collect();
+            detections.sort_by_key(|detection| detection.time);
+            for detection in detections {
+                writeln!(
+                    w,
+                    "  {} [{}] {}",
+                    format_time(&detection.time),
+                    output_level(&detection.level),
+                    detection.rule_title
+                )?;
+            }
+            writeln!(w)?;
+            writeln!(w, "Events:")?;
+            for event in sorted_events(session) {
+                writeln!(
+                    w,
+                    "  {} {} {}",
+                    format_seen(&event.time),
+                    event.channel,
+                    event.event_id
+                )?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_csv<W: Write>(&self, w: W, rows: &[SessionRow]) -> Result<(), String> {
+        let mut wtr = csv::Writer::from_writer(w);
+        for row in rows {
+            wtr.serialize(row).map_err(|e| e.to_string())?;
+        }
+        wtr.flush().map_err(|e| e.to_string())
+    }
+
+    pub fn write_json<W: Write>(&self, w: W, rows: &[SessionRow]) -> Result<(), String> {
+        serde_json::to_writer_pretty(w, rows).map_err(|e| e.to_string())
+    }
+
+    /// --sessionで指定したセッションのイベントをCSVで出力する
+    pub fn write_events_csv<W: Write>(&self, w: W, filter: &SessionFilter) -> Result<(), String> {
+        let mut wtr = csv::Writer::from_writer(w);
+        wtr.write_record(["Computer", "LogonId", "Timestamp", "Channel", "EventID"])
+            .map_err(|e| e.to_string())?;
+        for (key, session) in self.sorted_sessions(Option::Some(filter)) {
+            for event in sorted_events(session) {
+                wtr.write_record([
+                    key.computer.as_str(),
+                    key.logon_id.as_str(),
+                    &format_seen(&event.time),
+                    event.channel.as_str(),
+                    event.event_id.as_str(),
+                ])
+                .map_err(|e| e.to_string())?;
+            }
+        }
+        wtr.flush().map_err(|e| e.to_string())
+    }
+
+    /// --sessionで指定したセッションの概要、プロセス、検知結果、イベントをJSONで出力する
+    pub fn write_events_json<W: Write>(&self, w: W, filter: &SessionFilter) -> Result<(), String> {
+        let rows = self.create_rows(Option::Some(filter));
+        let sessions: Vec<Value> = rows
+            .iter()
+            .zip(self.sorted_sessions(Option::Some(filter)))
+            .map(|(row, (_, session))| {
+                let mut processes = session.processes.clone();
+                processes.sort();
+                json!({
+                    "Session": row,
+                    "Processes": processes
+                        .iter()
+                        .map(|(time, process)| json!({"Timestamp": format_seen(time), "Process": process}))
+                        .collect::<Vec<Value>>(),
+                    "Detections": session
+                        .detections
+                        .iter()
+                        .map(|detection| json!({
+                            "Timestamp": format_time(&detection.time),
+                            "Level": output_level(&detection.level),
+                            "RuleTitle": detection.rule_title,
+                        }))
+                        .collect::<Vec<Value>>(),
+                    "Events": sorted_events(session)
+                        .iter()
+                        .map(|event| json!({
+                            "Timestamp": format_seen(&event.time),
+                            "Channel": event.channel,
+                            "EventID": event.event_id,
+                        }))
+                        .collect::<Vec<Value>>(),
+                })
+            })
+            .collect();
+        serde_json::to_writer_pretty(w, &sessions).map_err(|e| e.to_string())
+    }
+}
+
+fn sorted_events(session: &Session) -> Vec<&SessionEvent> {
+    let mut events: Vec<&SessionEvent> = session.events.iter().collect();
+    events.sort_by_key(|event| event.time);
+    events
+}
+
+fn format_seen(time: &Option<DateTime<Utc>>) -> String {
+    match time {
+        Some(time) => format_time(time),
+        None => "-".to_string(),
+    }
+}
+
+/// ログオンIDを小文字に揃える。0x0や空の場合はNoneを返す
+fn normalize_logon_id(value: &Value) -> Option<String> {
+    let logon_id = match value {
+        Value::String(value) => value.trim().to_lowercase(),
+        Value::Number(value) => format!("0x{:x}", value.as_u64()?),
+        _ => return Option::None,
+    };
+    if logon_id.is_empty() || logon_id == "-" || logon_id == "0x0" || logon_id == "0" {
+        Option::None
+    } else {
+        Option::Some(logon_id)
+    }
+}
+
+/// フィールドの値を文字列で取得する
+fn get_field(data: &Value, name: &str) -> String {
+    utils::value_to_string(&data[name]).unwrap_or_default()
+}
+
+/// フィールドの値を取得する。存在しない場合や空、"-"の場合はNoneを返す
+fn get_value(data: &Value, name: &str) -> Option<String> {
+    utils::value_to_string(&data[name]).filter(|value| !value.is_empty() && value != "-")
+}
+
+/// prefixはTargetもしくはSubject。ドメインがある場合はDOMAIN\userにする
+fn get_user(data: &Value, prefix: &str) -> Option<String> {
+    let user = get_value(data, &format!("{}UserName", prefix))?;
+    match get_value(data, &format!("{}DomainName", prefix)) {
+        Some(domain) => Option::Some(format!("{}\\{}", domain, user)),
+        None => Option::Some(user),
+    }
+}
+
+/// 全てのレコードからセッションを作成し、検知結果を検知したレコードのセッションに追加するsink。
+/// スキャン後にoutput_sessionsで出力する
+#[derive(Default)]
+pub struct SessionSink {
+    sessions: Mutex<Sessions>,
+    /// --sessionで指定したセッション。指定したセッションのみ全てのイベントを記録する
+    filter: Option<SessionFilter>,
+}
+
+impl SessionSink {
+    pub fn new(filter: Option<SessionFilter>) -> SessionSink {
+        SessionSink {
+            sessions: Mutex::new(Sessions::new()),
+            filter,
+        }
+    }
+
+    pub fn sessions(&self) -> MutexGuard<'_, Sessions> {
+        self.sessions.lock().unwrap()
+    }
+
+    pub fn filter(&self) -> Option<&SessionFilter> {
+        self.filter.as_ref()
+    }
+}
+
+impl DetectionSink for SessionSink {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        // aggregation conditionの検知結果は元のレコードが無いため、セッションを特定できない
+        if let Some(record_info) = event.record {
+            self.sessions().add_detection(
+                &record_info.record,
+                SessionDetection {
+                    time: event.time,
+                    level: event.detect_info.level.to_owned(),
+                    rule_title: event.detect_info.alert.to_owned(),
+                },
+            );
+        }
+        Result::Ok(())
+    }
+
+    fn on_records(&self, records: &[EvtxRecordInfo]) -> Result<(), String> {
+        self.sessions().start(records, self.filter());
+        Result::Ok(())
+    }
+}
+
+/// 全てのevtxファイルのセッションを標準出力に出力する。--outputが指定された場合はCSVもしくはJSONファイルにも出力する。
+/// --sessionが指定された場合は、指定したセッションの詳細とイベントを出力する
+pub fn output_sessions(sink: &SessionSink) {
+    let sessions = sink.sessions();
+    let filter = sink.filter();
+    let rows = sessions.create_rows(filter);
+    println!();
+    println!("Sessions:");
+    if rows.is_empty() {
+        println!("No sessions were found.");
+        return;
+    }
+    match filter {
+        Some(filter) => sessions.print_detail(&mut io::stdout().lock(), filter).ok(),
+        None => sessions.print(&mut io::stdout().lock(), &rows).ok(),
+    };
+
+    if let Some(output_path) = configs::CONFIG.read().unwrap().args.value_of("output") {
+        let is_json = utils::is_json_output(output_path);
+        let ret = File::create(output_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                let writer = BufWriter::new(file);
+                match (filter, is_json) {
+                    (Some(filter), true) => sessions.write_events_json(writer, filter),
+                    (Some(filter), false) => sessions.write_events_csv(writer, filter),
+                    (None, true) => sessions.write_json(writer, &rows),
+                    (None, false) => sessions.write_csv(writer, &rows),
+                }
+            });
+        if let Err(err) = ret {
+            AlertMessage::alert(
+                &mut BufWriter::new(std::io::stderr().lock()),
+                &format!("Failed to write the sessions. {} {}", output_path, err),
+            )
+            .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::detection::EvtxRecordInfo;
+    use crate::detections::print::DetectInfo;
+    use crate::detections::rule::create_rule;
+    use crate::detections::sink::{DetectionEvent, DetectionSink};
+    use crate::detections::utils;
+    use crate::timeline::sessions::{SessionFilter, SessionKey, SessionSink, Sessions};
+    use chrono::{TimeZone, Utc};
+    use serde_json::{json, Value};
+    use yaml_rust::YamlLoader;
+
+    fn create_record(channel: &str, event_id: u64, time: &str, event_data: Value) -> Value {
+        json!({
+            "Event": {
+                "System": {
+                    "EventID": event_id,
+                    "Channel": channel,
+                    "Computer": "DC1",
+                    "TimeCreated_attributes": {"SystemTime": time}
+                },
+                "EventData": event_data
+            }
+        })
+    }
+
+    fn create_records() -> Vec<Value> {
+        vec![
+            create_record(
+                "Security",
+                4624,
+                "2022-01-01T00:00:00Z",
+                json!({
+                    "SubjectUserName": "DC1$",
+                    "SubjectLogonId": "0x3E7",
+                    "TargetUserName": "alice",
+                    "TargetDomainName": "CORP",
+                    "TargetLogonId": "0x1A2B",
+                    "LogonType": 10,
+                    "IpAddress": "10.0.0.1"
+                }),
+            ),
+            create_record(
+                "Security",
+                4688,
+                "2022-01-01T00:10:00Z",
+                json!({
+                    "SubjectUserName": "alice",
+                    "SubjectLogonId": "0x1a2b",
+                    "TargetLogonId": "0x0",
+                    "NewProcessName": "C:\\Windows\\System32\\cmd.exe"
+                }),
+            ),
+            create_record(
+                "Microsoft-Windows-Sysmon/Operational",
+                1,
+                "2022-01-01T00:20:00Z",
+                json!({"LogonId": "0x1a2b", "Image": "C:\\Windows\\System32\\whoami.exe"}),
+            ),
+            create_record(
+                "Security",
+                4634,
+                "2022-01-01T01:02:03Z",
+                json!({"TargetUserName": "alice", "TargetLogonId": "0x1a2b"}),
+            ),
+            create_record("Security", 4672, "2022-01-01T00:00:00Z", json!({})),
+        ]
+    }
+
+    fn create_sessions(filter: Option<&SessionFilter>) -> Sessions {
+        let mut sessions = Sessions::new();
+        for record in create_records() {
+            sessions.add(&record, filter);
+        }
+        sessions
+    }
+
+    #[test]
+    fn test_session() {
+        let sessions = create_sessions(Option::None);
+        // ログオンIDの無い4672はセッションにならず、4624のSubjectLogonIdではなくTargetLogonIdを使う
+        assert_eq!(sessions.sessions.len(), 1);
+        let session = &sessions.sessions[&SessionKey {
+            computer: "DC1".to_string(),
+            logon_id: "0x1a2b".to_string(),
+        }];
+        assert_eq!(session.user, Some("CORP\\alice".to_string()));
+        assert_eq!(session.event_count, 4);
+        assert_eq!(session.processes.len(), 2);
+        assert!(session.events.is_empty());
+        assert_eq!(session.duration(), "01:02:03");
+
+        let rows = sessions.create_rows(Option::None);
+        assert_eq!(rows[0].logon_type, "10 - RemoteInteractive");
+        assert_eq!(rows[0].source_ip, "10.0.0.1");
+        assert_eq!(
+            rows[0].processes,
+            "C:\\Windows\\System32\\cmd.exe ¦ C:\\Windows\\System32\\whoami.exe"
+        );
+    }
+
+    #[test]
+    fn test_sink() {
+        // 複数のevtxファイルのレコードと検知結果を同じsinkで集計する
+        let sink = SessionSink::new(Option::None);
+        // 別のファイルでは4624が無く、SubjectUserNameをユーザとする
+        let file2 = vec![create_record(
+            "Security",
+            4688,
+            "2022-01-01T02:00:00Z",
+            json!({"SubjectUserName": "bob", "SubjectLogonId": "0x99", "NewProcessName": "a.exe"}),
+        )];
+        for (path, records) in [("file1.evtx", create_records()), ("file2.evtx", file2)] {
+            let records: Vec<EvtxRecordInfo> = records
+                .into_iter()
+                .map(|record| utils::create_rec_info(record, path.to_string(), &[]))
+                .collect();
+            assert!(sink.on_records(&records).is_ok());
+        }
+
+        let record = create_record(
+            "Security",
+            4688,
+            "2022-01-01T00:10:00Z",
+            json!({"SubjectLogonId": "0x1A2B"}),
+        );
+        let rule_yaml = YamlLoader::load_from_str("title: test").unwrap().remove(0);
+        let rule = create_rule("test-rule.yml".to_string(), rule_yaml);
+        let detect_info = DetectInfo {
+            filepath: "file1.evtx".to_string(),
+            rulepath: "test-rule.yml".to_string(),
+            level: "high".to_string(),
+            computername: "DC1".to_string(),
+            eventid: "4688".to_string(),
+            alert: "Suspicious Cmd".to_string(),
+            detail: "detail".to_string(),
+            tag_info: String::default(),
+            record_information: Option::None,
+        };
+        let record_info = utils::create_rec_info(record, "file1.evtx".to_string(), &[]);
+        let event = DetectionEvent {
+            time: Utc.ymd(2022, 1, 1).and_hms(0, 10, 0),
+            detect_info: &detect_info,
+            record: Option::Some(&record_info),
+            rule: &rule,
+        };
+        assert!(sink.on_detect(&event).is_ok());
+
+        let rows = sink.sessions().create_rows(sink.filter());
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].logon_id, "0x1a2b");
+        assert_eq!(rows[0].detections, "Suspicious Cmd");
+        assert_eq!(rows[0].detection_count, 1);
+        assert_eq!(rows[0].events, 4);
+        assert_eq!(rows[1].user, "bob");
+        assert_eq!(rows[1].duration, "00:00:00");
+    }
+
+    #[test]
+    fn test_session_filter() {
+        let filter = SessionFilter::parse("dc1:0x1A2B");
+        assert_eq!(filter.computer, Some("dc1".to_string()));
+        assert!(filter.matches(&SessionKey {
+            computer: "DC1".to_string(),
+            logon_id: "0x1a2b".to_string(),
+        }));
+        assert!(!SessionFilter::parse("DC2:0x1a2b").matches(&SessionKey {
+            computer: "DC1".to_string(),
+            logon_id: "0x1a2b".to_string(),
+        }));
+
+        let sessions = create_sessions(Option::Some(&SessionFilter::parse("0x1a2b")));
+        let mut output = vec![];
+        sessions
+            .print_detail(&mut output, &SessionFilter::parse("0x1a2b"))
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Session: DC1 0x1a2b\nUser: CORP\\alice\n"));
+        assert!(output.contains("Duration: 01:02:03\n"));
+        assert_eq!(output.matches(" Security 46").count(), 3);
+
+        let mut csv = vec![];
+        sessions
+            .write_events_csv(&mut csv, &SessionFilter::parse("0x1a2b"))
+            .unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 5);
+        assert!(csv.starts_with("Computer,LogonId,Timestamp,Channel,EventID\n"));
+    }
+}
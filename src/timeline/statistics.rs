@@ -1,5 +1,15 @@
+use crate::afterfact::format_time;
+use crate::detections::configs::EventInfoConfig;
+use crate::detections::print::{AlertMessage, Message};
+use crate::detections::sink::{DetectionEvent, DetectionSink};
 use crate::detections::{configs, detection::EvtxRecordInfo, utils};
-use hashbrown::HashMap;
+use chrono::{DateTime, Utc};
+use hashbrown::{HashMap, HashSet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
 
 #[derive(Debug)]
 pub struct EventStatistics {
@@ -94,3 +104,330 @@ impl EventStatistics {
         //        return evtstat_map;
     }
 }
+
+/// 集計のキー。存在しない項目は"-"にする
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StatsKey {
+    pub channel: String,
+    pub provider: String,
+    pub event_id: String,
+}
+
+/// 出力する集計結果の1行
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StatsRow {
+    pub count: usize,
+    pub percent: f64,
+    pub channel: String,
+    pub provider: String,
+    #[serde(rename = "EventID")]
+    pub event_id: String,
+    pub event: String,
+}
+
+/**
+* 全てのevtxファイルのイベントをChannel、Provider、EventIDごとに集計する
+*/
+#[derive(Debug, Default)]
+pub struct AggregatedStatistics {
+    pub total: usize,
+    /// レコードを集計したevtxファイルのパス
+    pub files: HashSet<String>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub stats: HashMap<StatsKey, usize>,
+}
+
+impl AggregatedStatistics {
+    pub fn new() -> AggregatedStatistics {
+        AggregatedStatistics::default()
+    }
+
+    pub fn start(&mut self, records: &[EvtxRecordInfo]) {
+        for record in records {
+            if !self.files.contains(get_file_path(&record.evtx_filepath)) {
+                self.files
+                    .insert(get_file_path(&record.evtx_filepath).to_string());
+            }
+            self.add(&record.record);
+        }
+    }
+
+    pub fn add(&mut self, record: &Value) {
+        let system = &record["Event"]["System"];
+        let key = StatsKey {
+            channel: utils::value_to_output_string(&system["Channel"]),
+            provider: utils::value_to_output_string(&system["Provider_attributes"]["Name"]),
+            event_id: utils::value_to_output_string(&system["EventID"]),
+        };
+        *self.stats.entry(key).or_insert(0) += 1;
+        self.total += 1;
+        let time = Message::get_event_time(record);
+        self.start_time = utils::min_time(self.start_time, time);
+        self.end_time = utils::max_time(self.end_time, time);
+    }
+
+    /// 件数の多い順に並べ替えた集計結果。イベントのタイトルはstatistics_event_info.txtから取得する
+    pub fn create_rows(&self, event_info: &EventInfoConfig) -> Vec<StatsRow> {
+        let mut stats: Vec<(&StatsKey, &usize)> = self.stats.iter().collect();
+        stats.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        stats
+            .into_iter()
+            .map(|(key, count)| {
+                let rate = *count as f64 / self.total as f64;
+                StatsRow {
+                    count: *count,
+                    percent: (rate * 1000.0).round() / 10.0,
+                    channel: key.channel.to_owned(),
+                    provider: key.provider.to_owned(),
+                    event_id: key.event_id.to_owned(),
+                    event: match event_info.get_event_id(&key.event_id) {
+                        Some(e) => e.evttitle.to_owned(),
+                        None => "Unknown".to_string(),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    pub fn print<W: Write>(&self, w: &mut W, rows: &[StatsRow]) -> io::Result<()> {
+        let time = |time: &Option<DateTime<Utc>>| match time {
+            Some(time) => format_time(time),
+            None => "-".to_string(),
+        };
+        writeln!(w, "---------------------------------------")?;
+        writeln!(w, "Evtx Files: {}", self.files.len())?;
+        writeln!(w, "Total Event Records: {}\n", self.total)?;
+        writeln!(w, "First Timestamp: {}", time(&self.start_time))?;
+        writeln!(w, "Last Timestamp: {}\n", time(&self.end_time))?;
+        writeln!(w, "Count (Percent)\tChannel\tProvider\tID\tEvent\t")?;
+        writeln!(
+            w,
+            "--------------- ------- -------- ------- ---------------"
+        )?;
+        for row in rows {
+            writeln!(
+                w,
+                "{0} ({1:.1}%)\t{2}\t{3}\t{4}\t{5}",
+                row.count, row.percent, row.channel, row.provider, row.event_id, row.event
+            )?;
+        }
+        writeln!(w, "---------------------------------------")
+    }
+
+    pub fn write_csv<W: Write>(&self, w: W, rows: &[StatsRow]) -> Result<(), String> {
+        let mut wtr = csv::Writer::from_writer(w);
+        for row in rows {
+            wtr.serialize(row).map_err(|e| e.to_string())?;
+        }
+        wtr.flush().map_err(|e| e.to_string())
+    }
+
+    pub fn write_json<W: Write>(&self, w: W, rows: &[StatsRow]) -> Result<(), String> {
+        let time = |time: &Option<DateTime<Utc>>| match time {
+            Some(time) => Value::String(format_time(time)),
+            None => Value::Null,
+        };
+        let value = json!({
+            "EvtxFiles": self.files.len(),
+            "TotalEventRecords": self.total,
+            "FirstTimestamp": time(&self.start_time),
+            "LastTimestamp": time(&self.end_time),
+            "Statistics": rows,
+        });
+        serde_json::to_writer_pretty(w, &value).map_err(|e| e.to_string())
+    }
+}
+
+/// --recover-recordsで復旧したレコードのパスから、末尾に付与したファイル内のオフセット(@0x...)を除く
+fn get_file_path(evtx_filepath: &str) -> &str {
+    match evtx_filepath.rsplit_once("@0x") {
+        Some((path, offset))
+            if !offset.is_empty() && offset.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            path
+        }
+        _ => evtx_filepath,
+    }
+}
+
+/// 全てのevtxファイルのレコードを集計するsink。スキャン後にoutput_aggregated_statisticsで出力する
+impl DetectionSink for Mutex<AggregatedStatistics> {
+    fn on_detect(&self, _event: &DetectionEvent) -> Result<(), String> {
+        Result::Ok(())
+    }
+
+    fn on_records(&self, records: &[EvtxRecordInfo]) -> Result<(), String> {
+        self.lock().unwrap().start(records);
+        Result::Ok(())
+    }
+}
+
+/// 全てのevtxファイルの統計情報を標準出力に出力する。--outputが指定された場合は拡張子が.jsonならJSON、それ以外はCSVで出力する
+pub fn output_aggregated_statistics(statistics: &AggregatedStatistics) {
+    let conf = configs::CONFIG.read().unwrap();
+    let rows = statistics.create_rows(&conf.event_timeline_config);
+    statistics.print(&mut io::stdout().lock(), &rows).ok();
+
+    if let Some(output_path) = conf.args.value_of("output") {
+        let is_json = utils::is_json_output(output_path);
+        let ret = File::create(output_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                if is_json {
+                    statistics.write_json(BufWriter::new(file), &rows)
+                } else {
+                    statistics.write_csv(BufWriter::new(file), &rows)
+                }
+            });
+        if let Err(err) = ret {
+            AlertMessage::alert(
+                &mut BufWriter::new(std::io::stderr().lock()),
+                &format!("Failed to write the statistics. {} {}", output_path, err),
+            )
+            .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::configs::EventInfoConfig;
+    use crate::detections::detection::EvtxRecordInfo;
+    use crate::detections::sink::DetectionSink;
+    use crate::detections::utils;
+    use crate::timeline::statistics::{get_file_path, AggregatedStatistics, StatsKey};
+    use chrono::{TimeZone, Utc};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+
+    fn create_record(channel: &str, provider: &str, event_id: u64, time: &str) -> Value {
+        json!({
+            "Event": {
+                "System": {
+                    "EventID": event_id,
+                    "Channel": channel,
+                    "Provider_attributes": {"Name": provider},
+                    "TimeCreated_attributes": {"SystemTime": time}
+                }
+            }
+        })
+    }
+
+    fn create_statistics() -> AggregatedStatistics {
+        let security = "Microsoft-Windows-Security-Auditing";
+        let records = vec![
+            (
+                "file1.evtx",
+                create_record("Security", security, 4624, "2022-01-02T00:00:00Z"),
+            ),
+            (
+                "file1.evtx",
+                create_record("Security", security, 4624, "2022-01-03T00:00:00Z"),
+            ),
+            (
+                "file1.evtx",
+                create_record(
+                    "System",
+                    "Service Control Manager",
+                    7045,
+                    "2022-01-02T00:00:00Z",
+                ),
+            ),
+            // --recover-recordsで復旧したレコードは同じファイルとして数える
+            (
+                "file2.evtx@0x1200",
+                create_record("Security", security, 4624, "2022-01-01T00:00:00Z"),
+            ),
+        ];
+        let records: Vec<EvtxRecordInfo> = records
+            .into_iter()
+            .map(|(path, record)| utils::create_rec_info(record, path.to_string(), &[]))
+            .collect();
+
+        // 複数のevtxファイルのレコードを同じsinkで集計する
+        let sink = Mutex::new(AggregatedStatistics::new());
+        assert!(sink.on_records(&records[..3]).is_ok());
+        assert!(sink.on_records(&records[3..]).is_ok());
+        assert!(sink.on_records(&[]).is_ok());
+        sink.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_aggregated_statistics() {
+        let statistics = create_statistics();
+        assert_eq!(statistics.files.len(), 2);
+        assert_eq!(statistics.total, 4);
+        assert_eq!(
+            statistics.start_time,
+            Some(Utc.ymd(2022, 1, 1).and_hms(0, 0, 0))
+        );
+        assert_eq!(
+            statistics.end_time,
+            Some(Utc.ymd(2022, 1, 3).and_hms(0, 0, 0))
+        );
+        let key = StatsKey {
+            channel: "Security".to_string(),
+            provider: "Microsoft-Windows-Security-Auditing".to_string(),
+            event_id: "4624".to_string(),
+        };
+        assert_eq!(statistics.stats.get(&key), Some(&3));
+
+        let rows = statistics.create_rows(&EventInfoConfig::new());
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].count, 3);
+        assert_eq!(rows[0].percent, 75.0);
+        assert_eq!(rows[0].event, "Unknown");
+        assert_eq!(rows[1].channel, "System");
+        assert_eq!(rows[1].event_id, "7045");
+    }
+
+    #[test]
+    fn test_get_file_path() {
+        assert_eq!(get_file_path("a.evtx@0x1a00"), "a.evtx");
+        assert_eq!(get_file_path("a@0xfoo.evtx"), "a@0xfoo.evtx");
+        assert_eq!(get_file_path("a.evtx"), "a.evtx");
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        let mut statistics = AggregatedStatistics::new();
+        statistics.add(&json!({"Event": {"System": {"EventID": 1}}}));
+        let rows = statistics.create_rows(&EventInfoConfig::new());
+        assert_eq!(rows[0].channel, "-");
+        assert_eq!(rows[0].provider, "-");
+        assert_eq!(statistics.start_time, None);
+    }
+
+    #[test]
+    fn test_output() {
+        let statistics = create_statistics();
+        let rows = statistics.create_rows(&EventInfoConfig::new());
+
+        let mut csv = vec![];
+        assert!(statistics.write_csv(&mut csv, &rows).is_ok());
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "Count,Percent,Channel,Provider,EventID,Event");
+        assert_eq!(
+            lines[1],
+            "3,75.0,Security,Microsoft-Windows-Security-Auditing,4624,Unknown"
+        );
+        assert_eq!(lines.len(), 3);
+
+        let mut json = vec![];
+        assert!(statistics.write_json(&mut json, &rows).is_ok());
+        let json: Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["EvtxFiles"], 2);
+        assert_eq!(json["TotalEventRecords"], 4);
+        assert_eq!(json["Statistics"][1]["EventID"], "7045");
+        assert_eq!(json["Statistics"][1]["Provider"], "Service Control Manager");
+
+        let mut table = vec![];
+        assert!(statistics.print(&mut table, &rows).is_ok());
+        let table = String::from_utf8(table).unwrap();
+        assert!(table.contains("Evtx Files: 2\n"));
+        assert!(table.contains("1 (25.0%)\tSystem\tService Control Manager\t7045\tUnknown\n"));
+    }
+}
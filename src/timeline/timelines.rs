@@ -2,6 +2,7 @@ use crate::detections::{configs, detection::EvtxRecordInfo};
 
 use super::statistics::EventStatistics;
 use hashbrown::HashMap;
+use std::io::Write;
 
 #[derive(Debug)]
 pub struct Timeline {
@@ -57,11 +58,13 @@ impl Timeline {
         // イベントID毎の出力メッセージ生成
         let stats_msges: Vec<String> = self.tm_stats_set_msg(mapsorted);
 
+        // 複数のevtxファイルを並列に解析するため、ファイルごとの出力が混ざらないように標準出力をロックしてから出力する
+        let mut stdout = std::io::stdout().lock();
         for msgprint in sammsges.iter() {
-            println!("{}", msgprint);
+            writeln!(stdout, "{}", msgprint).ok();
         }
         for msgprint in stats_msges.iter() {
-            println!("{}", msgprint);
+            writeln!(stdout, "{}", msgprint).ok();
         }
     }
     // イベントID毎の出力メッセージ生成
@@ -0,0 +1,885 @@
+use crate::afterfact::{self, format_time, output_level};
+use crate::detections::configs::{self, LEVELMAP};
+use crate::detections::sink::{DetectionEvent, DetectionSink};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use hashbrown::HashMap;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use tui::backend::{Backend, CrosstermBackend};
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState, Wrap};
+use tui::{Frame, Terminal};
+
+/// 同じログオンセッションとみなすEventDataのフィールド
+const LOGON_ID_FIELDS: [&str; 3] = ["TargetLogonId", "SubjectLogonId", "LogonId"];
+/// 同じユーザとみなすEventDataのフィールド
+const USER_FIELDS: [&str; 4] = ["TargetUserName", "SubjectUserName", "User", "UserName"];
+/// 関連するイベントの検索で無視する値
+const IGNORE_RELATED_VALUES: [&str; 3] = ["", "-", "0x0"];
+/// レベルのフィルタで切り替える順番
+const FILTER_LEVELS: [&str; 5] = ["informational", "low", "medium", "high", "critical"];
+/// タイムラインのTimestampの形式。--rfc-2822はparse_from_rfc2822、--rfc-3339はparse_from_rfc3339で読み込む
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f %:z";
+
+/// タイムラインの検知結果1件分の情報。項目名は--json-outputで出力するJSON Linesと同じ
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct TimelineEntry {
+    /// 並べ替えと時刻の絞り込みに使う時刻。Timestampを読み込めなかった場合はNone
+    #[serde(skip)]
+    time: Option<DateTime<Utc>>,
+    /// 表示用の時刻
+    timestamp: String,
+    computer: String,
+    #[serde(rename = "EventID")]
+    event_id: String,
+    level: String,
+    mitre_attack: String,
+    rule_title: String,
+    #[serde(rename = "RuleID")]
+    rule_id: String,
+    details: String,
+    record_information: Option<String>,
+    rule_path: String,
+    file_path: String,
+    /// 検知したレコード。aggregation conditionの場合はNone
+    record: Option<Value>,
+}
+
+impl TimelineEntry {
+    /// recordのEventDataから、fieldsのいずれかのフィールドの値を小文字で返す
+    fn related_values(&self, fields: &[&str]) -> Vec<String> {
+        let event_data = match &self.record {
+            Some(record) => &record["Event"]["EventData"],
+            None => return vec![],
+        };
+        fields
+            .iter()
+            .filter_map(|field| match &event_data[field] {
+                Value::String(value) => Option::Some(value.to_lowercase()),
+                Value::Number(value) => Option::Some(value.to_string()),
+                _ => Option::None,
+            })
+            .filter(|value| !IGNORE_RELATED_VALUES.contains(&value.as_str()))
+            .collect()
+    }
+}
+
+/// タイムラインのTimestampを読み込む。--rfc-2822、--rfc-3339で出力した形式も読み込める
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+        .or_else(|_| DateTime::parse_from_rfc3339(timestamp))
+        .or_else(|_| DateTime::parse_from_rfc2822(timestamp))
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// 絞り込みの時刻を読み込み、開始時刻と時刻の精度を返す。
+/// UTCオフセットを含まない時刻はtzの時刻として扱い、日付のみの場合はその日全体を表す
+fn parse_time_bound<Tz: TimeZone>(value: &str, tz: &Tz) -> Option<(DateTime<Utc>, Duration)> {
+    if let Some(time) = parse_timestamp(value) {
+        return Option::Some((time, Duration::milliseconds(1)));
+    }
+    let naive = [
+        ("%Y-%m-%d %H:%M:%S%.f", Duration::milliseconds(1)),
+        ("%Y-%m-%d %H:%M", Duration::minutes(1)),
+    ]
+    .iter()
+    .find_map(|(format, precision)| {
+        NaiveDateTime::parse_from_str(value, format)
+            .ok()
+            .map(|time| (time, *precision))
+    })
+    .or_else(|| {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .ok()
+            .map(|date| (date.and_hms(0, 0, 0), Duration::days(1)))
+    });
+    naive.and_then(|(time, precision)| {
+        tz.from_local_datetime(&time)
+            .earliest()
+            .map(|time| (time.with_timezone(&Utc), precision))
+    })
+}
+
+/// --json-outputで出力したJSON Lines形式のタイムラインを読み込み、時刻順に並べ替える
+pub fn load_json_timeline(path: &Path) -> Result<Vec<TimelineEntry>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read file. {} {}", path.display(), e))?;
+    let mut entries = vec![];
+    for (idx, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut entry: TimelineEntry = serde_json::from_str(line).map_err(|e| {
+            format!(
+                "Failed to parse JSON. {} line {}: {}",
+                path.display(),
+                idx + 1,
+                e
+            )
+        })?;
+        entry.time = parse_timestamp(&entry.timestamp);
+        entries.push(entry);
+    }
+    entries.sort_by_key(|entry| entry.time);
+    Ok(entries)
+}
+
+/// TUIで表示するために、元のレコードを含めて検知結果を保持するsink
+#[derive(Default)]
+pub struct TimelineSink {
+    entries: Mutex<Vec<TimelineEntry>>,
+}
+
+impl TimelineSink {
+    pub fn new() -> TimelineSink {
+        TimelineSink::default()
+    }
+
+    /// 保持した検知結果を時刻順に並べ替えて返す
+    pub fn take_entries(&self) -> Vec<TimelineEntry> {
+        let mut entries = std::mem::take(&mut *self.entries.lock().unwrap());
+        entries.sort_by_key(|entry| entry.time);
+        entries
+    }
+}
+
+impl DetectionSink for TimelineSink {
+    fn on_detect(&self, event: &DetectionEvent) -> Result<(), String> {
+        let detect_info = event.detect_info;
+        self.entries.lock().unwrap().push(TimelineEntry {
+            time: Option::Some(event.time),
+            timestamp: format_time(&event.time),
+            computer: detect_info.computername.to_owned(),
+            event_id: detect_info.eventid.to_owned(),
+            level: output_level(&detect_info.level).to_owned(),
+            mitre_attack: detect_info.tag_info.to_owned(),
+            rule_title: detect_info.alert.to_owned(),
+            rule_id: event.rule.yaml["id"].as_str().unwrap_or("").to_owned(),
+            details: detect_info.detail.to_owned(),
+            record_information: detect_info.record_information.to_owned(),
+            rule_path: detect_info.rulepath.to_owned(),
+            file_path: detect_info.filepath.to_owned(),
+            record: event.record.map(|record_info| record_info.record.clone()),
+        });
+        Result::Ok(())
+    }
+}
+
+/// タイムラインの絞り込み条件
+#[derive(Debug, Default, Clone)]
+struct Filter {
+    /// FILTER_LEVELSのインデックス。このレベル以上の検知結果を表示する
+    min_level: usize,
+    /// コンピュータ名の部分一致
+    computer: String,
+    /// ルールのタイトルの部分一致またはルールID
+    rule: String,
+    /// 時刻の範囲。"開始..終了"の形式で、開始と終了はどちらかを省略できる
+    time_range: String,
+    /// time_rangeを読み込んだ開始時刻と終了時刻
+    time_bounds: TimeBounds,
+}
+
+impl Filter {
+    /// 時刻の範囲を設定する。UTCオフセットを含まない時刻は表示している時刻と同じタイムゾーン(--utcの場合はUTC、それ以外はローカル時刻)として扱う
+    fn set_time_range(&mut self, time_range: String) -> Result<(), String> {
+        self.time_bounds = if configs::CONFIG.read().unwrap().args.is_present("utc") {
+            parse_time_range(&time_range, &Utc)?
+        } else {
+            parse_time_range(&time_range, &Local)?
+        };
+        self.time_range = time_range;
+        Ok(())
+    }
+
+    fn is_match(&self, entry: &TimelineEntry) -> bool {
+        let level = match entry.level.as_str() {
+            "info" => "informational",
+            level => level,
+        };
+        if LEVELMAP.get(&level.to_uppercase()).unwrap_or(&0)
+            < LEVELMAP
+                .get(&FILTER_LEVELS[self.min_level].to_uppercase())
+                .unwrap_or(&0)
+        {
+            return false;
+        }
+        if !self.computer.is_empty()
+            && !entry
+                .computer
+                .to_lowercase()
+                .contains(&self.computer.to_lowercase())
+        {
+            return false;
+        }
+        if !self.rule.is_empty()
+            && !entry
+                .rule_title
+                .to_lowercase()
+                .contains(&self.rule.to_lowercase())
+            && !entry.rule_id.eq_ignore_ascii_case(&self.rule)
+        {
+            return false;
+        }
+        match (self.time_bounds, entry.time) {
+            ((None, None), _) => true,
+            (_, None) => false,
+            ((from, to), Some(time)) => {
+                from.iter().all(|from| time >= *from) && to.iter().all(|to| time < *to)
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut ret = vec![format!("Level>={}", FILTER_LEVELS[self.min_level])];
+        for (name, value) in [
+            ("Computer", &self.computer),
+            ("Rule", &self.rule),
+            ("Time", &self.time_range),
+        ] {
+            if !value.is_empty() {
+                ret.push(format!("{}={}", name, value));
+            }
+        }
+        ret.join("  ")
+    }
+}
+
+/// 時刻の絞り込みの開始時刻と終了時刻。終了時刻はこの時刻を含まない
+type TimeBounds = (Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+/// "開始..終了"の形式の時刻の範囲を読み込む
+fn parse_time_range<Tz: TimeZone>(time_range: &str, tz: &Tz) -> Result<TimeBounds, String> {
+    let (from, to) = time_range.split_once("..").unwrap_or((time_range, ""));
+    let parse = |value: &str| match value.trim() {
+        "" => Ok(Option::None),
+        value => parse_time_bound(value, tz)
+            .map(Option::Some)
+            .ok_or_else(|| format!("Invalid time. {}", value)),
+    };
+    let from = parse(from)?.map(|(from, _)| from);
+    let to = parse(to)?.map(|(to, precision)| to + precision);
+    Ok((from, to))
+}
+
+/// キー入力の状態。絞り込み条件の入力中は入力した文字列を保持する
+#[derive(Debug, PartialEq)]
+enum InputMode {
+    Normal,
+    Computer(String),
+    Rule(String),
+    TimeRange(String),
+}
+
+/// キー操作の対象
+#[derive(Debug, PartialEq)]
+enum Focus {
+    Table,
+    Detail,
+}
+
+/// 検知結果のタイムラインを表示するTUIの状態
+pub struct TimelineView {
+    entries: Vec<TimelineEntry>,
+    filter: Filter,
+    /// 絞り込み条件に一致するentriesのインデックス
+    filtered: Vec<usize>,
+    /// filteredの中で選択している位置
+    selected: usize,
+    /// テーブルの先頭に表示しているfilteredの位置
+    offset: usize,
+    input_mode: InputMode,
+    show_detail: bool,
+    focus: Focus,
+    detail_scroll: u16,
+    status: String,
+    level_colors: HashMap<String, Color>,
+    /// ルールファイルのパスと内容
+    rule_cache: HashMap<String, String>,
+}
+
+impl TimelineView {
+    pub fn new(entries: Vec<TimelineEntry>) -> TimelineView {
+        let level_colors = afterfact::load_level_color()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(level, color)| (level, Color::Rgb(color[0], color[1], color[2])))
+            .collect();
+        let mut view = TimelineView {
+            entries,
+            filter: Filter::default(),
+            filtered: vec![],
+            selected: 0,
+            offset: 0,
+            input_mode: InputMode::Normal,
+            show_detail: false,
+            focus: Focus::Table,
+            detail_scroll: 0,
+            status: String::default(),
+            level_colors,
+            rule_cache: HashMap::new(),
+        };
+        view.apply_filter();
+        view
+    }
+
+    /// 絞り込み条件を適用する。選択していた検知結果が条件に一致する場合は選択したままにする
+    fn apply_filter(&mut self) {
+        let current = self.filtered.get(self.selected).copied();
+        self.filtered = (0..self.entries.len())
+            .filter(|idx| self.filter.is_match(&self.entries[*idx]))
+            .collect();
+        self.selected = current
+            .and_then(|current| self.filtered.iter().position(|idx| *idx >= current))
+            .unwrap_or(0)
+            .min(self.filtered.len().saturating_sub(1));
+        self.detail_scroll = 0;
+    }
+
+    fn selected_entry(&self) -> Option<&TimelineEntry> {
+        self.filtered
+            .get(self.selected)
+            .map(|idx| &self.entries[*idx])
+    }
+
+    fn select(&mut self, selected: usize) {
+        self.selected = selected.min(self.filtered.len().saturating_sub(1));
+        self.detail_scroll = 0;
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let selected = (self.selected as isize + delta).max(0) as usize;
+        self.select(selected);
+    }
+
+    /// 選択している検知結果とfieldsの値が同じ検知結果に移動する。forwardがfalseの場合は前の検知結果を探す
+    fn jump_related(&mut self, fields: &[&str], name: &str, forward: bool) {
+        let values = match self.selected_entry() {
+            Some(entry) => entry.related_values(fields),
+            None => return,
+        };
+        if values.is_empty() {
+            self.status = format!("The selected event has no {}.", name);
+            return;
+        }
+        let is_related = |pos: &usize| {
+            self.entries[self.filtered[*pos]]
+                .related_values(fields)
+                .iter()
+                .any(|value| values.contains(value))
+        };
+        let found = if forward {
+            (self.selected + 1..self.filtered.len()).find(is_related)
+        } else {
+            (0..self.selected).rev().find(is_related)
+        };
+        match found {
+            Some(pos) => {
+                self.select(pos);
+                self.status = format!(
+                    "Jumped to the event with the same {}: {}",
+                    name,
+                    values.join(", ")
+                );
+            }
+            None => {
+                self.status = format!(
+                    "No more events with the same {}: {}",
+                    name,
+                    values.join(", ")
+                );
+            }
+        }
+    }
+
+    /// キー入力を処理する。終了する場合はtrueを返す
+    pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if self.input_mode != InputMode::Normal {
+            self.handle_input_key(key);
+            return false;
+        }
+        self.status.clear();
+        match key.code {
+            KeyCode::Char('q') => return true,
+            KeyCode::Esc if self.show_detail => {
+                self.show_detail = false;
+                self.focus = Focus::Table;
+            }
+            KeyCode::Esc => return true,
+            KeyCode::Enter => {
+                self.show_detail = !self.show_detail;
+                self.focus = Focus::Table;
+            }
+            KeyCode::Tab if self.show_detail => {
+                self.focus = match self.focus {
+                    Focus::Table => Focus::Detail,
+                    Focus::Detail => Focus::Table,
+                };
+            }
+            KeyCode::Down | KeyCode::Char('j') if self.focus == Focus::Detail => {
+                self.detail_scroll = self.detail_scroll.saturating_add(1);
+            }
+            KeyCode::Up | KeyCode::Char('k') if self.focus == Focus::Detail => {
+                self.detail_scroll = self.detail_scroll.saturating_sub(1);
+            }
+            KeyCode::PageDown if self.focus == Focus::Detail => {
+                self.detail_scroll = self.detail_scroll.saturating_add(10);
+            }
+            KeyCode::PageUp if self.focus == Focus::Detail => {
+                self.detail_scroll = self.detail_scroll.saturating_sub(10);
+            }
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::PageDown => self.move_selection(20),
+            KeyCode::PageUp => self.move_selection(-20),
+            KeyCode::Home | KeyCode::Char('g') => self.select(0),
+            KeyCode::End | KeyCode::Char('G') => self.select(self.filtered.len()),
+            KeyCode::Char('v') => {
+                self.filter.min_level = (self.filter.min_level + 1) % FILTER_LEVELS.len();
+                self.apply_filter();
+            }
+            KeyCode::Char('c') => {
+                self.input_mode = InputMode::Computer(self.filter.computer.clone())
+            }
+            KeyCode::Char('r') => self.input_mode = InputMode::Rule(self.filter.rule.clone()),
+            KeyCode::Char('t') => {
+                self.input_mode = InputMode::TimeRange(self.filter.time_range.clone())
+            }
+            KeyCode::Char('x') => {
+                self.filter = Filter::default();
+                self.apply_filter();
+            }
+            KeyCode::Char('l') => self.jump_related(&LOGON_ID_FIELDS, "LogonId", true),
+            KeyCode::Char('L') => self.jump_related(&LOGON_ID_FIELDS, "LogonId", false),
+            KeyCode::Char('u') => self.jump_related(&USER_FIELDS, "user", true),
+            KeyCode::Char('U') => self.jump_related(&USER_FIELDS, "user", false),
+            _ => {}
+        }
+        false
+    }
+
+    /// 絞り込み条件の入力中のキー入力を処理する。Enterで適用し、Escで取り消す
+    fn handle_input_key(&mut self, key: KeyEvent) {
+        let input = match &mut self.input_mode {
+            InputMode::Computer(input) | InputMode::Rule(input) | InputMode::TimeRange(input) => {
+                input
+            }
+            InputMode::Normal => return,
+        };
+        match key.code {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Esc => self.input_mode = InputMode::Normal,
+            KeyCode::Enter => {
+                match std::mem::replace(&mut self.input_mode, InputMode::Normal) {
+                    InputMode::Computer(input) => self.filter.computer = input,
+                    InputMode::Rule(input) => self.filter.rule = input,
+                    InputMode::TimeRange(input) => {
+                        if let Err(err) = self.filter.set_time_range(input) {
+                            self.status = err;
+                        }
+                    }
+                    InputMode::Normal => {}
+                }
+                self.apply_filter();
+            }
+            _ => {}
+        }
+    }
+
+    fn level_style(&self, level: &str) -> Style {
+        let level = match level {
+            "info" => "informational",
+            level => level,
+        };
+        match self.level_colors.get(level) {
+            Some(color) => Style::default().fg(*color),
+            None => Style::default(),
+        }
+    }
+
+    /// 詳細ペインに表示する、選択している検知結果の全項目、元のレコード、ルールのYAML
+    fn detail_lines(&mut self) -> Vec<Spans<'static>> {
+        let entry = match self.selected_entry() {
+            Some(entry) => entry.clone(),
+            None => return vec![],
+        };
+        let heading = |title: &str| {
+            Spans::from(Span::styled(
+                title.to_string(),
+                Style::default().add_modifier(Modifier::BOLD),
+            ))
+        };
+        let mut lines = vec![heading("Detection")];
+        for (name, value) in [
+            ("Timestamp", &entry.timestamp),
+            ("Computer", &entry.computer),
+            ("EventID", &entry.event_id),
+            ("Level", &entry.level),
+            ("MitreAttack", &entry.mitre_attack),
+            ("RuleTitle", &entry.rule_title),
+            ("RuleID", &entry.rule_id),
+            ("Details", &entry.details),
+            ("FilePath", &entry.file_path),
+            ("RulePath", &entry.rule_path),
+        ] {
+            lines.push(Spans::from(format!("{}: {}", name, value)));
+        }
+
+        lines.push(Spans::default());
+        lines.push(heading("Record"));
+        let record = match (&entry.record, &entry.record_information) {
+            (Some(record), _) => serde_json::to_string_pretty(record).unwrap_or_default(),
+            (None, Some(record_information)) => record_information.to_owned(),
+            (None, None) => "No record. (The rule has an aggregation condition.)".to_string(),
+        };
+        lines.extend(record.lines().map(|line| Spans::from(line.to_string())));
+
+        lines.push(Spans::default());
+        lines.push(heading("Rule"));
+        let rule = self
+            .rule_cache
+            .entry(entry.rule_path.clone())
+            .or_insert_with(|| {
+                fs::read_to_string(&entry.rule_path)
+                    .unwrap_or_else(|e| format!("Failed to read the rule file. {}", e))
+            });
+        lines.extend(rule.lines().map(|line| Spans::from(line.to_string())));
+        lines
+    }
+
+    fn draw<B: Backend>(&mut self, f: &mut Frame<B>) {
+        let mut constraints = vec![Constraint::Length(1), Constraint::Min(5)];
+        if self.show_detail {
+            constraints.push(Constraint::Percentage(50));
+        }
+        constraints.push(Constraint::Length(1));
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(constraints)
+            .split(f.size());
+
+        let position = match self.filtered.is_empty() {
+            true => 0,
+            false => self.selected + 1,
+        };
+        let header = format!(
+            " Hayabusa Timeline  {}/{} (total: {})  {}",
+            position,
+            self.filtered.len(),
+            self.entries.len(),
+            self.filter.describe()
+        );
+        f.render_widget(
+            Paragraph::new(header).style(Style::default().add_modifier(Modifier::REVERSED)),
+            chunks[0],
+        );
+        self.draw_table(f, chunks[1]);
+        if self.show_detail {
+            let lines = self.detail_lines();
+            let border_style = match self.focus {
+                Focus::Detail => Style::default().fg(Color::Cyan),
+                Focus::Table => Style::default(),
+            };
+            let detail = Paragraph::new(lines)
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .border_style(border_style)
+                        .title("Detail"),
+                )
+                .wrap(Wrap { trim: false })
+                .scroll((self.detail_scroll, 0));
+            f.render_widget(detail, chunks[2]);
+        }
+
+        let footer = match &self.input_mode {
+            InputMode::Computer(input) => format!("Computer: {}_", input),
+            InputMode::Rule(input) => format!("Rule title or ID: {}_", input),
+            InputMode::TimeRange(input) => {
+                format!("Time range (e.g. 2022-01-01 09:00..2022-01-02): {}_", input)
+            }
+            InputMode::Normal if !self.status.is_empty() => self.status.clone(),
+            InputMode::Normal => "q:Quit  Up/Down:Move  Enter:Detail  Tab:Focus  v:Level  c:Computer  r:Rule  t:Time  x:Clear  l/L:Same LogonId  u/U:Same user".to_string(),
+        };
+        f.render_widget(Paragraph::new(footer), chunks[chunks.len() - 1]);
+    }
+
+    fn draw_table<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect) {
+        // 枠とヘッダを除いた行数分だけ作成する
+        let height = area.height.saturating_sub(3).max(1) as usize;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected >= self.offset + height {
+            self.offset = self.selected + 1 - height;
+        }
+        let rows: Vec<Row> = self
+            .filtered
+            .iter()
+            .skip(self.offset)
+            .take(height)
+            .map(|idx| {
+                let entry = &self.entries[*idx];
+                Row::new(vec![
+                    Cell::from(entry.timestamp.clone()),
+                    Cell::from(entry.computer.clone()),
+                    Cell::from(entry.event_id.clone()),
+                    Cell::from(entry.level.clone()).style(self.level_style(&entry.level)),
+                    Cell::from(entry.rule_title.clone()),
+                    Cell::from(entry.details.clone()),
+                ])
+            })
+            .collect();
+        let header = Row::new(vec![
+            "Timestamp",
+            "Computer",
+            "EventID",
+            "Level",
+            "RuleTitle",
+            "Details",
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let border_style = match self.focus {
+            Focus::Table => Style::default().fg(Color::Cyan),
+            Focus::Detail => Style::default(),
+        };
+        let widths = [
+            Constraint::Length(29),
+            Constraint::Length(20),
+            Constraint::Length(7),
+            Constraint::Length(6),
+            Constraint::Percentage(30),
+            Constraint::Percentage(70),
+        ];
+        let table = Table::new(rows)
+            .header(header)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(border_style),
+            )
+            .widths(&widths)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state = TableState::default();
+        if !self.filtered.is_empty() {
+            state.select(Option::Some(self.selected - self.offset));
+        }
+        f.render_stateful_widget(table, area, &mut state);
+    }
+
+    fn event_loop<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        loop {
+            terminal.draw(|f| self.draw(f))?;
+            if let Event::Key(key) = event::read()? {
+                // Windowsではキーを離した時のイベントも通知されるため、押した時のみ処理する
+                if key.kind == KeyEventKind::Press && self.handle_key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// 検知結果をTUIで表示する。qまたはEscで終了する
+pub fn run(entries: Vec<TimelineEntry>) -> Result<(), String> {
+    let mut view = TimelineView::new(entries);
+    enable_raw_mode().map_err(|e| e.to_string())?;
+    let mut stdout = io::stdout();
+    if let Err(err) = execute!(stdout, EnterAlternateScreen) {
+        disable_raw_mode().ok();
+        return Err(err.to_string());
+    }
+    let ret = Terminal::new(CrosstermBackend::new(stdout)).and_then(|mut terminal| {
+        let ret = view.event_loop(&mut terminal);
+        terminal.show_cursor().ok();
+        ret
+    });
+    // エラーの場合も端末の設定を元に戻す
+    disable_raw_mode().ok();
+    execute!(io::stdout(), LeaveAlternateScreen).ok();
+    ret.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tui::{
+        load_json_timeline, parse_time_bound, parse_timestamp, InputMode, TimelineEntry,
+        TimelineView,
+    };
+    use chrono::{Duration, FixedOffset, TimeZone, Utc};
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use serde_json::json;
+    use std::fs::{remove_file, write};
+    use std::path::Path;
+    use tui::backend::TestBackend;
+    use tui::Terminal;
+
+    fn create_entry(timestamp: &str, level: &str, computer: &str, logon_id: &str) -> TimelineEntry {
+        TimelineEntry {
+            time: parse_timestamp(timestamp),
+            timestamp: timestamp.to_string(),
+            computer: computer.to_string(),
+            event_id: "4624".to_string(),
+            level: level.to_string(),
+            rule_title: format!("{} rule", level),
+            rule_id: format!("{}-id", level),
+            rule_path: "./test_files/rules/yaml/1.yml".to_string(),
+            record: Option::Some(json!({
+                "Event": {"EventData": {"TargetLogonId": logon_id, "TargetUserName": "Hayabusa"}}
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn create_view() -> TimelineView {
+        TimelineView::new(vec![
+            create_entry("2022-01-01 00:00:00.000 +09:00", "info", "HOST1", "0x1"),
+            create_entry("2022-01-01 10:00:00.000 +09:00", "high", "host2", "0x2"),
+            create_entry("2022-01-02 00:00:00.000 +09:00", "medium", "host1", "0x0"),
+            create_entry("2022-01-03 00:00:00.000 +09:00", "critical", "host1", "0x1"),
+        ])
+    }
+
+    fn press(view: &mut TimelineView, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                c => KeyCode::Char(c),
+            };
+            view.handle_key(KeyEvent::new(code, KeyModifiers::NONE));
+        }
+    }
+
+    #[test]
+    fn test_load_json_timeline() {
+        let path = Path::new("./test_load_json_timeline.jsonl");
+        let lines = [
+            r#"{"Timestamp":"2022-01-02 00:00:00.000 +09:00","Computer":"host1","EventID":"4625","Level":"high","RuleTitle":"test","RuleID":"id","Details":"","RulePath":"test.yml","FilePath":"test.evtx","Record":{"Event":{}}}"#,
+            "",
+            r#"{"Timestamp":"2022-01-01 00:00:00.000 +09:00","Computer":"host2","EventID":"4624","Level":"info","RuleTitle":"test2"}"#,
+        ];
+        write(path, lines.join("\n")).unwrap();
+        let entries = load_json_timeline(path).unwrap();
+        write(path, "{\"Timestamp\":").unwrap();
+        let ret = load_json_timeline(path);
+        assert!(remove_file(path).is_ok());
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].computer, "host2");
+        assert!(entries[0].record.is_none());
+        assert_eq!(entries[1].event_id, "4625");
+        assert_eq!(entries[1].rule_id, "id");
+        assert!(entries[1].record.is_some());
+        assert!(ret.unwrap_err().contains("line 1"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut view = create_view();
+        assert_eq!(view.filtered, vec![0, 1, 2, 3]);
+        // informational -> low -> medium
+        press(&mut view, "vv");
+        assert_eq!(view.filtered, vec![1, 2, 3]);
+        press(&mut view, "chost1\n");
+        assert_eq!(view.filtered, vec![2, 3]);
+        press(&mut view, "x");
+        press(&mut view, "rCRITICAL-ID\n");
+        assert_eq!(view.filtered, vec![3]);
+        press(&mut view, "x");
+        press(
+            &mut view,
+            "t2022-01-01 09:00:00.000 +09:00..2022-01-02 00:00:00.000 +09:00\n",
+        );
+        assert_eq!(view.filtered, vec![1, 2]);
+        press(&mut view, "x");
+        press(&mut view, "t2022-01-02 00:00:00.000 +09:00..\n");
+        assert_eq!(view.filtered, vec![2, 3]);
+        // 読み込めない時刻の場合は絞り込み条件を変更しない
+        press(&mut view, "tabc\n");
+        assert_eq!(view.status, "Invalid time. abc");
+        assert_eq!(view.filtered, vec![2, 3]);
+
+        // Escで入力を取り消す
+        press(&mut view, "cabc");
+        assert_eq!(view.input_mode, InputMode::Computer("abc".to_string()));
+        view.handle_key(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE));
+        assert_eq!(view.input_mode, InputMode::Normal);
+        assert!(view.filter.computer.is_empty());
+    }
+
+    #[test]
+    fn test_parse_time_bound() {
+        let jst = FixedOffset::east(9 * 3600);
+        assert_eq!(
+            parse_time_bound("2022-01-01 09:00", &jst),
+            Option::Some((Utc.ymd(2022, 1, 1).and_hms(0, 0, 0), Duration::minutes(1)))
+        );
+        assert_eq!(
+            parse_time_bound("2022-01-02", &jst),
+            Option::Some((Utc.ymd(2022, 1, 1).and_hms(15, 0, 0), Duration::days(1)))
+        );
+        // UTCオフセットを含む時刻はtzを使わない
+        assert_eq!(
+            parse_time_bound("2022-01-01T09:00:00+00:00", &jst),
+            Option::Some((
+                Utc.ymd(2022, 1, 1).and_hms(9, 0, 0),
+                Duration::milliseconds(1)
+            ))
+        );
+        assert_eq!(parse_time_bound("2022-13-01", &jst), Option::None);
+    }
+
+    #[test]
+    fn test_jump_related() {
+        let mut view = create_view();
+        // 0x1のLogonIdの次のイベントに移動する。0x0は無視する
+        press(&mut view, "l");
+        assert_eq!(view.selected, 3);
+        press(&mut view, "l");
+        assert_eq!(view.selected, 3);
+        assert!(view
+            .status
+            .starts_with("No more events with the same LogonId"));
+        press(&mut view, "L");
+        assert_eq!(view.selected, 0);
+        // ユーザ名は大文字小文字を区別しない
+        press(&mut view, "u");
+        assert_eq!(view.selected, 1);
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut view = create_view();
+        press(&mut view, "j\n");
+        let mut terminal = Terminal::new(TestBackend::new(160, 40)).unwrap();
+        terminal.draw(|f| view.draw(f)).unwrap();
+        let text: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol.as_str())
+            .collect();
+        assert!(text.contains("2/4 (total: 4)"));
+        assert!(text.contains("high rule"));
+        assert!(text.contains("RuleID: high-id"));
+        assert!(text.contains("\"TargetLogonId\": \"0x2\""));
+        // qで終了する
+        assert!(view.handle_key(KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE)));
+    }
+}
@@ -2,15 +2,12 @@ extern crate serde_derive;
 extern crate yaml_rust;
 
 use crate::detections::configs;
-use crate::detections::print::AlertMessage;
-use crate::detections::print::ERROR_LOG_STACK;
-use crate::detections::print::QUIET_ERRORS_FLAG;
+use crate::detections::print::ErrorLog;
 use crate::filter::RuleExclude;
 use hashbrown::HashMap;
 use std::ffi::OsStr;
 use std::fs;
 use std::io;
-use std::io::BufWriter;
 use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
 use yaml_rust::Yaml;
@@ -21,6 +18,8 @@ pub struct ParseYaml {
     pub rulecounter: HashMap<String, u128>,
     pub ignorerule_count: u128,
     pub errorrule_count: u128,
+    pub enable_deprecated_rules: bool,
+    error_log: ErrorLog,
 }
 
 impl Default for ParseYaml {
@@ -31,11 +30,25 @@ impl Default for ParseYaml {
 
 impl ParseYaml {
     pub fn new() -> ParseYaml {
+        ParseYaml::with_error_log(
+            configs::CONFIG
+                .read()
+                .unwrap()
+                .args
+                .is_present("enable-deprecated-rules"),
+            ErrorLog::from_config(),
+        )
+    }
+
+    /// コマンドライン引数を参照せずにParseYamlを作成する。ルールファイルの読み込みエラーはerror_logに出力する
+    pub fn with_error_log(enable_deprecated_rules: bool, error_log: ErrorLog) -> ParseYaml {
         ParseYaml {
             files: Vec::new(),
             rulecounter: HashMap::new(),
             ignorerule_count: 0,
             errorrule_count: 0,
+            enable_deprecated_rules,
+            error_log,
         }
     }
 
@@ -64,15 +77,7 @@ impl ParseYaml {
                 "fail to read metadata of file: {}",
                 path.as_ref().to_path_buf().display(),
             );
-            if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                AlertMessage::alert(&mut BufWriter::new(std::io::stderr().lock()), &errmsg)?;
-            }
-            if !*QUIET_ERRORS_FLAG {
-                ERROR_LOG_STACK
-                    .lock()
-                    .unwrap()
-                    .push(format!("[ERROR] {}", errmsg));
-            }
+            self.error_log.error(&errmsg);
             return io::Result::Ok(String::default());
         }
         let mut yaml_docs = vec![];
@@ -96,15 +101,7 @@ impl ParseYaml {
                     path.as_ref().to_path_buf().display(),
                     read_content.unwrap_err()
                 );
-                if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                    AlertMessage::warn(&mut BufWriter::new(std::io::stderr().lock()), &errmsg)?;
-                }
-                if !*QUIET_ERRORS_FLAG {
-                    ERROR_LOG_STACK
-                        .lock()
-                        .unwrap()
-                        .push(format!("[WARN] {}", errmsg));
-                }
+                self.error_log.warn(&errmsg);
                 self.errorrule_count += 1;
                 return io::Result::Ok(String::default());
             }
@@ -117,15 +114,7 @@ impl ParseYaml {
                     path.as_ref().to_path_buf().display(),
                     yaml_contents.unwrap_err()
                 );
-                if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                    AlertMessage::warn(&mut BufWriter::new(std::io::stderr().lock()), &errmsg)?;
-                }
-                if !*QUIET_ERRORS_FLAG {
-                    ERROR_LOG_STACK
-                        .lock()
-                        .unwrap()
-                        .push(format!("[WARN] {}", errmsg));
-                }
+                self.error_log.warn(&errmsg);
                 self.errorrule_count += 1;
                 return io::Result::Ok(String::default());
             }
@@ -167,15 +156,7 @@ impl ParseYaml {
                         entry.path().display(),
                         read_content.unwrap_err()
                     );
-                    if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                        AlertMessage::warn(&mut BufWriter::new(std::io::stderr().lock()), &errmsg)?;
-                    }
-                    if !*QUIET_ERRORS_FLAG {
-                        ERROR_LOG_STACK
-                            .lock()
-                            .unwrap()
-                            .push(format!("[WARN] {}", errmsg));
-                    }
+                    self.error_log.warn(&errmsg);
                     self.errorrule_count += 1;
                     return io::Result::Ok(ret);
                 }
@@ -188,15 +169,7 @@ impl ParseYaml {
                         entry.path().display(),
                         yaml_contents.unwrap_err()
                     );
-                    if configs::CONFIG.read().unwrap().args.is_present("verbose") {
-                        AlertMessage::warn(&mut BufWriter::new(std::io::stderr().lock()), &errmsg)?;
-                    }
-                    if !*QUIET_ERRORS_FLAG {
-                        ERROR_LOG_STACK
-                            .lock()
-                            .unwrap()
-                            .push(format!("[WARN] {}", errmsg));
-                    }
+                    self.error_log.warn(&errmsg);
                     self.errorrule_count += 1;
                     return io::Result::Ok(ret);
                 }
@@ -236,7 +209,7 @@ impl ParseYaml {
                         + 1,
                 );
 
-                if configs::CONFIG.read().unwrap().args.is_present("verbose") {
+                if self.error_log.is_verbose() {
                     println!("Loaded yml file path: {}", filepath);
                 }
 
@@ -252,12 +225,7 @@ impl ParseYaml {
                     return Option::None;
                 }
 
-                if !configs::CONFIG
-                    .read()
-                    .unwrap()
-                    .args
-                    .is_present("enable-deprecated-rules")
-                {
+                if !self.enable_deprecated_rules {
                     let rule_status = &yaml_doc["status"].as_str();
                     if rule_status.is_some() && rule_status.unwrap() == "deprecated" {
                         self.ignorerule_count += 1;